@@ -0,0 +1,108 @@
+//! Devices and unpair command implementations.
+
+use anyhow::{bail, Context};
+use clap::Args;
+use omniclip_core::{Config, OmniclipService};
+use uuid::Uuid;
+
+use super::run::OutputFormat;
+use crate::control::{self, Device, Reply, Request};
+use crate::process::{self, InstanceLock};
+use crate::state::open_service;
+
+/// Options for the devices command.
+#[derive(Args)]
+pub struct DevicesArgs {
+    /// Output format for the list
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+}
+
+/// Options for the unpair command.
+#[derive(Args)]
+pub struct UnpairArgs {
+    /// ID of the paired device to remove, as listed by `omniclip devices`
+    pub id: String,
+}
+
+/// Load the saved state for a command that changes or reads it while no
+/// instance is running.
+///
+/// The returned lock keeps `omniclip run` from starting, and saving over
+/// the state, until the command is done.
+async fn open_stopped(device_name: String, config: Config) -> anyhow::Result<(OmniclipService, InstanceLock)> {
+    let lock = InstanceLock::acquire(&config.data_dir, false).await?;
+    let mut service = open_service(device_name, config, false)?;
+    service.load_state().await?;
+    Ok((service, lock))
+}
+
+/// List the paired devices, asking the running instance if there is one.
+pub async fn list_devices(device_name: String, config: Config, args: DevicesArgs) -> anyhow::Result<()> {
+    let devices = match process::control_endpoint(&config.data_dir).await {
+        Some(endpoint) => {
+            let Reply::Devices(devices) = control::send(&endpoint, Request::Devices).await? else {
+                bail!("unexpected reply from the running instance");
+            };
+            devices
+        }
+        None => {
+            let (service, _lock) = open_stopped(device_name, config).await?;
+            Device::list(&service).await
+        }
+    };
+
+    if let OutputFormat::Json = args.output {
+        println!("{}", serde_json::to_string(&devices)?);
+        return Ok(());
+    }
+    if devices.is_empty() {
+        println!("No paired devices.");
+        return Ok(());
+    }
+
+    println!("\n\x1b[1mPaired Devices\x1b[0m");
+    println!("═══════════════════════════════════════");
+    for device in devices {
+        println!("\x1b[1m{}\x1b[0m", device.device_name);
+        println!("  \x1b[1mID:\x1b[0m          {}", device.device_id);
+        println!("  \x1b[1mFingerprint:\x1b[0m {}", device.fingerprint);
+        if let Some(epoch) = device.key_epoch {
+            println!("  \x1b[1mSession key:\x1b[0m epoch {}", epoch);
+        }
+    }
+    println!();
+    Ok(())
+}
+
+/// Remove a paired device.
+///
+/// A running instance unpairs it and tells it right away if it is on the
+/// network. Otherwise the device is told the next time `omniclip run`
+/// finds it.
+pub async fn unpair(device_name: String, config: Config, args: UnpairArgs) -> anyhow::Result<()> {
+    let device_id: Uuid = args.id.trim().parse()
+        .with_context(|| format!("{:?} isn't a device ID; `omniclip devices` lists them", args.id))?;
+
+    let (name, running) = match process::control_endpoint(&config.data_dir).await {
+        Some(endpoint) => {
+            let Reply::Unpaired(name) = control::send(&endpoint, Request::Unpair(device_id)).await? else {
+                bail!("unexpected reply from the running instance");
+            };
+            (name, true)
+        }
+        None => {
+            let (service, _lock) = open_stopped(device_name, config).await?;
+            let name = control::unpair(&service, device_id).await;
+            service.shutdown().await?;
+            (name, false)
+        }
+    };
+    let name = name.with_context(|| format!("no paired device with ID {}", device_id))?;
+
+    println!("\x1b[1;32m✓\x1b[0m Unpaired \x1b[1m{}\x1b[0m ({})", name, device_id);
+    if !running {
+        println!("\x1b[2mIt will be told the next time omniclip runs and finds it on the network.\x1b[0m");
+    }
+    Ok(())
+}
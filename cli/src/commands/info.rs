@@ -1,10 +1,22 @@
 //! Info command implementation.
 
-use omniclip_core::OmniclipService;
+use clap::Args;
+use omniclip_core::Config;
+
+use crate::state::open_service;
+use crate::ui::print_qr_code;
+
+/// Options for the info command.
+#[derive(Args, Default)]
+pub struct InfoArgs {
+    /// Don't show the identity QR code
+    #[arg(long)]
+    pub no_qr: bool,
+}
 
 /// Display device information.
-pub fn show_info(device_name: String) {
-    let service = OmniclipService::new(device_name);
+pub fn show_info(device_name: String, config: Config, args: InfoArgs) -> anyhow::Result<()> {
+    let service = open_service(device_name, config, false)?;
 
     println!("\n\x1b[1mOmniclip Device Info\x1b[0m");
     println!("═══════════════════════════════════════");
@@ -16,5 +28,14 @@ pub fn show_info(device_name: String) {
     for ip in omniclip_core::discovery::get_local_ips() {
         println!("  • {}", ip);
     }
+
+    // Identifies the device only; pairing still needs a session QR
+    let identity_url = service.identity_url();
+    println!("\n\x1b[1mIdentity:\x1b[0m    {}", identity_url);
+    if !args.no_qr {
+        println!();
+        print_qr_code(&identity_url);
+    }
     println!();
+    Ok(())
 }
@@ -1,7 +1,17 @@
 //! CLI command implementations.
 
+mod devices;
 mod info;
+mod pair;
+mod pairings;
+mod ping;
 mod run;
 
-pub use info::show_info;
-pub use run::run_service;
+pub use devices::{list_devices, unpair, DevicesArgs, UnpairArgs};
+pub use info::{show_info, InfoArgs};
+pub use pair::{pair, PairArgs};
+pub use pairings::{pairings, PairingsArgs};
+pub use ping::{ping_target, PingArgs};
+#[cfg(feature = "tui")]
+pub use run::format_preview;
+pub use run::{run_service, RunArgs};
@@ -0,0 +1,89 @@
+//! Pair command implementation.
+
+use std::io::{BufRead, Write};
+
+use clap::Args;
+use omniclip_core::crypto::{decode_preshared_key, generate_preshared_key};
+use omniclip_core::protocol::IdentityQrData;
+use omniclip_core::{Config, Error};
+
+use super::run::{serve, RunArgs};
+use crate::state::open_service;
+
+/// Options for the pair command.
+#[derive(Args)]
+pub struct PairArgs {
+    /// Pairing URL shown by the other device (omniclip://pair?...).
+    ///
+    /// Pairs over the network with a key exchange. Without it, pairing uses
+    /// a pre-shared key instead.
+    #[arg(conflicts_with = "psk")]
+    pub url: Option<String>,
+
+    /// Session key shared out of band, base64 encoded (32 bytes).
+    ///
+    /// Both devices must use exactly the same key. A new one is generated
+    /// and printed if omitted. Pre-shared keys never change, so there is no
+    /// forward secrecy: anyone who later learns the key can decrypt
+    /// recorded traffic.
+    #[arg(long)]
+    pub psk: Option<String>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+/// Pair with another device, then run the service.
+pub async fn pair(device_name: String, file: Option<Config>, args: PairArgs) -> anyhow::Result<()> {
+    match args.url.clone() {
+        Some(url) => pair_url(device_name, file, &url, args).await,
+        None => pair_preshared(device_name, file, args).await,
+    }
+}
+
+/// Pair with the device that showed `url`, then run the service.
+async fn pair_url(device_name: String, file: Option<Config>, url: &str, args: PairArgs) -> anyhow::Result<()> {
+    let replace_running = file.is_none();
+    let service = open_service(device_name, args.run.config(file), args.run.encrypt_state)?;
+    let (device_id, name) = match service.pair_with_url(url).await {
+        Ok(paired) => paired,
+        Err(Error::NotPaired(reason)) => anyhow::bail!("pairing rejected: {}", reason),
+        Err(e) => return Err(e.into()),
+    };
+    println!("\x1b[1;32m✓\x1b[0m Paired with \x1b[1m{}\x1b[0m ({})\n", name, device_id);
+
+    serve(service, args.run, replace_running).await
+}
+
+/// Pair with another device using a pre-shared key, then run the service.
+async fn pair_preshared(device_name: String, file: Option<Config>, args: PairArgs) -> anyhow::Result<()> {
+    let psk = match args.psk {
+        Some(psk) => psk,
+        None => {
+            let psk = generate_preshared_key();
+            println!("\x1b[1mPre-shared key:\x1b[0m {}", psk);
+            println!("\x1b[2mRun `omniclip pair --psk <key>` with this key on the other device.\x1b[0m\n");
+            psk
+        }
+    };
+    let key = decode_preshared_key(&psk)?;
+
+    let replace_running = file.is_none();
+    let service = open_service(device_name, args.run.config(file), args.run.encrypt_state)?;
+    println!("\x1b[1mThis device:\x1b[0m {}", service.identity_url());
+
+    let peer = IdentityQrData::from_url(&prompt("Other device's identity URL: ")?)?;
+    service.add_preshared_pairing(peer.device_id, peer.name.clone(), peer.verifying_key()?, &key).await?;
+    println!("\x1b[1;32m✓\x1b[0m Paired with \x1b[1m{}\x1b[0m ({})\n", peer.name, peer.device_id);
+
+    serve(service, args.run, replace_running).await
+}
+
+/// Read one line from stdin after printing `message`.
+fn prompt(message: &str) -> anyhow::Result<String> {
+    print!("{}", message);
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
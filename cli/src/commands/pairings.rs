@@ -0,0 +1,63 @@
+//! Pairings command implementation.
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context};
+use clap::Args;
+use omniclip_core::Config;
+use uuid::Uuid;
+
+use crate::control::{self, Reply, Request};
+use crate::process;
+
+/// Options for the pairings command.
+#[derive(Args)]
+pub struct PairingsArgs {
+    /// Cancel the pairing session with this ID instead of listing them
+    #[arg(long, value_name = "ID")]
+    pub cancel: Option<Uuid>,
+}
+
+/// List or cancel the pairing sessions of the instance running from the
+/// configured data directory.
+pub async fn pairings(config: Config, args: PairingsArgs) -> anyhow::Result<()> {
+    let endpoint = process::control_endpoint(&config.data_dir).await.with_context(|| {
+        format!(
+            "omniclip isn't running from {}; pairing sessions only exist while it runs",
+            config.data_dir.display()
+        )
+    })?;
+
+    if let Some(session_id) = args.cancel {
+        match control::send(&endpoint, Request::CancelPairing(session_id)).await? {
+            Reply::Cancelled(true) => println!("\x1b[1;32m✓\x1b[0m Cancelled pairing session {}", session_id),
+            Reply::Cancelled(false) => bail!("no active pairing session with ID {}", session_id),
+            _ => bail!("unexpected reply from the running instance"),
+        }
+        return Ok(());
+    }
+
+    let Reply::Pairings(sessions) = control::send(&endpoint, Request::Pairings).await? else {
+        bail!("unexpected reply from the running instance");
+    };
+    if sessions.is_empty() {
+        println!("No active pairing sessions.");
+        return Ok(());
+    }
+
+    println!("\n\x1b[1mPairing Sessions\x1b[0m");
+    println!("═══════════════════════════════════════");
+    for session in sessions {
+        let age = SystemTime::now().duration_since(session.created_at).unwrap_or_default();
+        println!("\x1b[1m{}\x1b[0m", session.session_id);
+        println!(
+            "  \x1b[1mStarted:\x1b[0m {} ago",
+            humantime::format_duration(Duration::from_secs(age.as_secs()))
+        );
+        if let Some(hint) = session.device_name_hint {
+            println!("  \x1b[1mFor:\x1b[0m     {}", hint);
+        }
+    }
+    println!();
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! Ping command implementation.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::Args;
+use omniclip_core::discovery::{DiscoveryEvent, DiscoveryService};
+use omniclip_core::{sync, PeerInfo};
+use uuid::Uuid;
+
+/// Options for the ping command.
+#[derive(Args)]
+pub struct PingArgs {
+    /// Device name or ID to find on the network, or an address like
+    /// 192.168.1.20:17394
+    pub target: String,
+
+    /// Seconds to wait for each reply
+    #[arg(long, default_value_t = 3)]
+    pub timeout: u64,
+
+    /// Seconds to look for the device on the network
+    #[arg(long, default_value_t = 5)]
+    pub wait: u64,
+}
+
+/// Check that a device's sync server answers and print the round-trip time.
+pub async fn ping_target(args: PingArgs) -> anyhow::Result<()> {
+    let timeout = Duration::from_secs(args.timeout);
+
+    let (label, rtt) = match args.target.parse::<SocketAddr>() {
+        Ok(addr) => (addr.to_string(), sync::ping(addr, timeout).await?),
+        Err(_) => {
+            let peer = find_peer(&args.target, Duration::from_secs(args.wait)).await?;
+            (peer.device_name.clone(), sync::ping_peer(&peer, timeout).await?)
+        }
+    };
+
+    println!(
+        "\x1b[1;32m✓\x1b[0m Reply from \x1b[1m{}\x1b[0m in {:.1} ms",
+        label,
+        rtt.as_secs_f64() * 1000.0
+    );
+    Ok(())
+}
+
+/// Browse mDNS until a device with this name or ID shows up.
+async fn find_peer(target: &str, wait: Duration) -> anyhow::Result<PeerInfo> {
+    let discovery = DiscoveryService::new(Uuid::new_v4())?;
+    let mut events = discovery.browse()?;
+    let matches = |peer: &PeerInfo| {
+        peer.device_name.eq_ignore_ascii_case(target) || peer.device_id.to_string() == target
+    };
+
+    let found = tokio::time::timeout(wait, async {
+        while let Some(event) = events.recv().await {
+            if let DiscoveryEvent::PeerFound(peer) | DiscoveryEvent::PeerUpdated(peer) = event {
+                if matches(&peer) {
+                    return Some(peer);
+                }
+            }
+        }
+        None
+    }).await;
+
+    discovery.shutdown()?;
+    found.ok().flatten()
+        .with_context(|| format!("no device named {} found on the network", target))
+}
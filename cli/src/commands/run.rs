@@ -1,21 +1,153 @@
 //! Run command implementation.
 
-use omniclip_core::{ClipboardContent, OmniclipService, ServiceEvent};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-use crate::process::kill_previous_instances;
+use clap::{Args, ValueEnum};
+use omniclip_core::{ClipboardContent, Config, ContentKind, OmniclipService, ServiceEvent, SessionState};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::control;
+use crate::process::InstanceLock;
+use crate::state::open_service;
 use crate::ui::{print_banner, print_qr_code};
 
-/// Run the omniclip service.
-pub async fn run_service(device_name: String) -> anyhow::Result<()> {
-    kill_previous_instances();
-    print_banner();
+/// Options for the run command.
+#[derive(Args, Default)]
+pub struct RunArgs {
+    /// Show a live dashboard instead of scrolling output
+    #[cfg(feature = "tui")]
+    #[arg(long)]
+    pub tui: bool,
+    /// Don't sync images
+    #[arg(long)]
+    pub no_images: bool,
+    /// Only sync text and rich text
+    #[arg(long)]
+    pub text_only: bool,
+    /// Exit after the first discovered peer or pairing, for health checks
+    #[arg(long)]
+    pub once: bool,
+    /// How long --once waits before giving up (e.g. 5s, 500ms)
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration, requires = "once")]
+    pub timeout: Duration,
+    /// Output format for the --once result
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+    /// Sync with the device at this address even if mDNS can't find it;
+    /// can be given more than once, and is remembered for later runs
+    #[arg(long = "peer", value_name = "IP:PORT")]
+    pub peers: Vec<SocketAddr>,
+    /// Also sync the X11 primary selection (text you highlight)
+    #[arg(long)]
+    pub primary_selection: bool,
+    /// Encrypt whole connections, hiding device names and message types
+    /// from the network; every device must use it
+    #[arg(long)]
+    pub encrypt_transport: bool,
+    /// Encrypt the keys and paired devices saved in the data directory with
+    /// a passphrase, asked for now and on every later start
+    #[arg(long)]
+    pub encrypt_state: bool,
+}
+
+/// How to print command results.
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// A single JSON object
+    Json,
+}
+
+impl RunArgs {
+    /// Whether the dashboard was requested.
+    pub fn tui(&self) -> bool {
+        #[cfg(feature = "tui")]
+        return self.tui;
+        #[cfg(not(feature = "tui"))]
+        return false;
+    }
+
+    /// Content kinds to sync, after applying the filter flags.
+    pub fn content_kinds(&self) -> HashSet<ContentKind> {
+        ContentKind::ALL.into_iter()
+            .filter(|kind| match kind {
+                ContentKind::Text | ContentKind::RichText => true,
+                ContentKind::Image => !self.no_images && !self.text_only,
+                // Copied files aren't read from the clipboard yet
+                ContentKind::Files => !self.text_only,
+                ContentKind::Raw => !self.text_only,
+            })
+            .collect()
+    }
+
+    /// Service config: `file` (or the defaults) with these options applied.
+    pub fn config(&self, file: Option<Config>) -> Config {
+        let base = file.unwrap_or_default();
+        Config {
+            // Leave a running instance alone and take any free port beside it
+            port: if self.once { 0 } else { base.port },
+            allowed_content_types: self.content_kinds(),
+            sync_primary_selection: self.primary_selection || base.sync_primary_selection,
+            transport_encryption: self.encrypt_transport || base.transport_encryption,
+            ..base
+        }
+    }
+}
+
+/// Run the omniclip service, with settings from a config file if given.
+pub async fn run_service(device_name: String, file: Option<Config>, args: RunArgs) -> anyhow::Result<()> {
+    let replace_running = file.is_none();
+    let service = open_service(device_name, args.config(file), args.encrypt_state)?;
+    serve(service, args, replace_running).await
+}
+
+/// Run a service that has already been created.
+///
+/// An instance already running from the same data directory is stopped
+/// first with `replace_running`, and otherwise makes this fail.
+pub async fn serve(mut service: OmniclipService, args: RunArgs, replace_running: bool) -> anyhow::Result<()> {
+    for peer in &args.peers {
+        service.add_manual_peer(*peer, None).await?;
+    }
 
-    let mut service = OmniclipService::new(device_name);
+    if args.once {
+        let report = run_once(&mut service, args.timeout).await?;
+        service.shutdown().await?;
+        report.print(args.output)?;
+        if !report.ok {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let lock = InstanceLock::acquire(service.data_dir(), replace_running).await?;
+    let (control, mut requests) = control::listen().await?;
+
+    #[cfg(feature = "tui")]
+    if args.tui() {
+        let events = service.start().await?;
+        lock.record(service.port().unwrap_or_default(), &control)?;
+        let pairing_url = service.start_pairing().await?;
+        let result = crate::ui::run_dashboard(&service, events, requests, pairing_url).await;
+        service.shutdown().await?;
+        return result;
+    }
+
+    print_banner();
 
     println!("\x1b[1mDevice:\x1b[0m {}", service.device_name());
     println!("\x1b[1mID:\x1b[0m     {}", service.device_id());
     println!("\x1b[1mKey:\x1b[0m    {}", service.fingerprint());
 
+    // Start the service first, so the QR code has the port it bound
+    let mut events = service.start().await?;
+    lock.record(service.port().unwrap_or_default(), &control)?;
+
     // Start pairing session and show QR
     let pairing_url = service.start_pairing().await?;
 
@@ -23,9 +155,6 @@ pub async fn run_service(device_name: String) -> anyhow::Result<()> {
     print_qr_code(&pairing_url);
     println!("\n\x1b[2mOr enter manually: {}\x1b[0m\n", pairing_url);
 
-    // Start the service
-    let mut events = service.start().await?;
-
     println!("\x1b[1;32m✓\x1b[0m Listening for devices and clipboard changes...");
     println!("\x1b[2mPress Ctrl+C to stop.\x1b[0m\n");
 
@@ -40,6 +169,9 @@ pub async fn run_service(device_name: String) -> anyhow::Result<()> {
             Some(event) = events.recv() => {
                 handle_event(event);
             }
+            Some(request) = requests.recv() => {
+                request.answer(&service).await;
+            }
             _ = rx.recv() => {
                 println!("\n\x1b[1;33mShutting down...\x1b[0m");
                 break;
@@ -47,14 +179,88 @@ pub async fn run_service(device_name: String) -> anyhow::Result<()> {
         }
     }
 
+    service.shutdown().await?;
     Ok(())
 }
 
+/// Outcome of a `run --once` health check.
+#[derive(Serialize)]
+struct OnceReport {
+    ok: bool,
+    device_id: Uuid,
+    elapsed_ms: u64,
+    /// "discovered" or "paired", if anything happened before the timeout
+    activity: Option<&'static str>,
+    peer_id: Option<Uuid>,
+    peer_name: Option<String>,
+}
+
+impl OnceReport {
+    fn print(&self, format: OutputFormat) -> anyhow::Result<()> {
+        match format {
+            OutputFormat::Json => println!("{}", serde_json::to_string(self)?),
+            OutputFormat::Text => match (self.activity, &self.peer_name) {
+                (Some(activity), Some(name)) => {
+                    println!("ok: {} {} in {}ms", activity, name, self.elapsed_ms)
+                }
+                _ => println!("no peers found within {}ms", self.elapsed_ms),
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Start the service and wait up to `timeout` for a peer to show up.
+async fn run_once(service: &mut OmniclipService, timeout: Duration) -> anyhow::Result<OnceReport> {
+    let started = Instant::now();
+    let mut events = service.start().await?;
+
+    let mut report = OnceReport {
+        ok: false,
+        device_id: service.device_id(),
+        elapsed_ms: 0,
+        activity: None,
+        peer_id: None,
+        peer_name: None,
+    };
+
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = &mut deadline => break,
+        };
+        let (activity, peer_id, peer_name) = match event {
+            Some(ServiceEvent::DeviceDiscovered(peer)) => ("discovered", peer.device_id, peer.device_name),
+            Some(ServiceEvent::PairingRequest { device_id, device_name }) => ("paired", device_id, device_name),
+            Some(_) => continue,
+            None => break,
+        };
+        report.ok = true;
+        report.activity = Some(activity);
+        report.peer_id = Some(peer_id);
+        report.peer_name = Some(peer_name);
+        break;
+    }
+
+    report.elapsed_ms = started.elapsed().as_millis() as u64;
+    Ok(report)
+}
+
 /// Handle a service event and print appropriate output.
 fn handle_event(event: ServiceEvent) {
     match event {
         ServiceEvent::DeviceDiscovered(peer) => {
-            println!("\x1b[1;32m⬤\x1b[0m Found: \x1b[1m{}\x1b[0m", peer.device_name);
+            let pairing = if peer.accepting_pairing { " (accepting pairing)" } else { "" };
+            let version = peer.protocol_version.map(|v| format!(" \x1b[2mv{}\x1b[0m", v)).unwrap_or_default();
+            println!("\x1b[1;32m⬤\x1b[0m Found: \x1b[1m{}\x1b[0m{}{}", peer.device_name, version, pairing);
+            for addr in &peer.addresses {
+                println!("    {}:{}", addr, peer.port);
+            }
+        }
+        ServiceEvent::DeviceUpdated(peer) => {
+            println!("\x1b[1;33m⬤\x1b[0m Updated: \x1b[1m{}\x1b[0m", peer.device_name);
             for addr in &peer.addresses {
                 println!("    {}:{}", addr, peer.port);
             }
@@ -62,6 +268,9 @@ fn handle_event(event: ServiceEvent) {
         ServiceEvent::DeviceLost(id) => {
             println!("\x1b[1;31m⬤\x1b[0m Lost: {}", id);
         }
+        ServiceEvent::DeviceUnpaired(id) => {
+            println!("\x1b[1;31m✂\x1b[0m Unpaired by remote device: {}", id);
+        }
         ServiceEvent::PairingRequest { device_id, device_name } => {
             println!(
                 "\x1b[1;35m⚡\x1b[0m Pairing request from: \x1b[1m{}\x1b[0m ({})",
@@ -72,9 +281,57 @@ fn handle_event(event: ServiceEvent) {
             let preview = format_preview(&content);
             println!("\x1b[1;34m📋\x1b[0m Received from {}: \"{}\"", from_device, preview);
         }
+        ServiceEvent::ClipboardWithheld { from_device, content } => {
+            let preview = format_preview(&content);
+            println!("\x1b[1;33m⛔\x1b[0m Not written, {} is blocked: \"{}\"", from_device, preview);
+        }
+        ServiceEvent::Rekeyed { device_id, epoch } => {
+            println!("\x1b[1;32m🔑\x1b[0m Session key refreshed with {} ({})", device_id, epoch);
+        }
+        ServiceEvent::ReplayRejected { device_id, message_id } => {
+            eprintln!("\x1b[1;33m⚠\x1b[0m Dropped replayed or stale message {} from {}", message_id, device_id);
+        }
+        ServiceEvent::PairingRejected { reason } => {
+            eprintln!("\x1b[1;31m✗\x1b[0m Pairing rejected: {}", reason);
+        }
+        ServiceEvent::IncompatiblePeer { device_name, protocol_version, .. } => {
+            eprintln!(
+                "\x1b[1;33m⚠\x1b[0m {} uses protocol version {}, this device {}; update omniclip so both match",
+                device_name, protocol_version, omniclip_core::protocol::constants::PROTOCOL_VERSION
+            );
+        }
+        ServiceEvent::PairingUrlChanged { url } => {
+            println!("\n\x1b[1;33mAddress changed, scan this QR code instead:\x1b[0m\n");
+            print_qr_code(&url);
+            println!("\n\x1b[2mOr enter manually: {}\x1b[0m\n", url);
+        }
+        ServiceEvent::ClipboardTooLarge { size, limit } => {
+            println!("\x1b[1;33m⚠\x1b[0m Clipboard not sent: {} bytes is over the {} byte limit", size, limit);
+        }
+        ServiceEvent::SyncSuppressed { hash } => {
+            println!("\x1b[2m↺ Not sent back, exchanged recently ({})\x1b[0m", hash);
+        }
         ServiceEvent::ClipboardSent { to_devices } => {
             println!("\x1b[1;34m📤\x1b[0m Sent to {} device(s)", to_devices.len());
         }
+        ServiceEvent::DeliveryConfirmed { device_id, .. } => {
+            println!("\x1b[1;32m✓\x1b[0m Delivered to {}", device_id);
+        }
+        ServiceEvent::SessionStateChanged(SessionState::Locked) => {
+            println!("\x1b[1;33m⏸\x1b[0m Session locked, sync paused");
+        }
+        ServiceEvent::SessionStateChanged(SessionState::Active) => {
+            println!("\x1b[1;32m▶\x1b[0m Session unlocked, sync resumed");
+        }
+        ServiceEvent::PauseChanged { paused: true } => {
+            println!("\x1b[1;33m⏸\x1b[0m Sync paused");
+        }
+        ServiceEvent::PauseChanged { paused: false } => {
+            println!("\x1b[1;32m▶\x1b[0m Sync resumed");
+        }
+        ServiceEvent::Backpressure { waited } => {
+            eprintln!("\x1b[1;33m⚠\x1b[0m Falling behind on events, sync waited {:?}", waited);
+        }
         ServiceEvent::Error(e) => {
             eprintln!("\x1b[1;31m✗\x1b[0m Error: {}", e);
         }
@@ -82,17 +339,87 @@ fn handle_event(event: ServiceEvent) {
 }
 
 /// Format clipboard content for preview display.
-fn format_preview(content: &ClipboardContent) -> String {
+pub fn format_preview(content: &ClipboardContent) -> String {
     const MAX_PREVIEW_LEN: usize = 50;
 
     let text = match content {
         ClipboardContent::Text(t) => t,
         ClipboardContent::RichText { plain, .. } => plain,
+        ClipboardContent::Raw { mime, data } => return format!("[{}, {} bytes]", mime, data.len()),
+        ClipboardContent::Image { width, height, .. } => return format!("[image, {}x{}]", width, height),
     };
 
-    if text.len() > MAX_PREVIEW_LEN {
-        format!("{}...", &text[..MAX_PREVIEW_LEN])
+    // Counted in characters, as slicing bytes could split one
+    if text.chars().count() > MAX_PREVIEW_LEN {
+        format!("{}...", text.chars().take(MAX_PREVIEW_LEN).collect::<String>())
     } else {
         text.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use omniclip_core::clipboard::ClipboardBackend;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        run: RunArgs,
+    }
+
+    /// In-memory clipboard shared with the test
+    struct MemoryClipboard(Arc<Mutex<Option<ClipboardContent>>>);
+
+    impl ClipboardBackend for MemoryClipboard {
+        fn read(&self) -> omniclip_core::Result<Option<ClipboardContent>> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+
+        fn write(&self, content: &ClipboardContent) -> omniclip_core::Result<()> {
+            *self.0.lock().unwrap() = Some(content.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_preview_truncates_on_char_boundaries() {
+        let preview = format_preview(&ClipboardContent::Text("é".repeat(60)));
+        assert_eq!(preview, format!("{}...", "é".repeat(50)));
+        assert_eq!(format_preview(&ClipboardContent::Text("short".to_string())), "short");
+    }
+
+    #[tokio::test]
+    async fn test_image_flags_keep_images_local() {
+        for flag in ["--no-images", "--text-only"] {
+            let args = Cli::try_parse_from(["omniclip", flag]).unwrap().run;
+            assert!(!args.content_kinds().contains(&ContentKind::Image));
+
+            let dir = std::env::temp_dir().join(format!("omniclip-cli-kinds-{}", Uuid::new_v4()));
+            let config = args.config(Some(Config { data_dir: dir.clone(), port: 0, ..Config::default() }));
+            let clipboard = Arc::new(Mutex::new(None));
+            let mut service = OmniclipService::with_config("desk".to_string(), config)
+                .with_clipboard_backend(MemoryClipboard(clipboard.clone()));
+            let _events = service.start().await.unwrap();
+
+            *clipboard.lock().unwrap() = Some(ClipboardContent::Image { width: 1, height: 1, rgba: vec![0; 4] });
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            *clipboard.lock().unwrap() = Some(ClipboardContent::Text("after".to_string()));
+
+            // Only the text goes out, and so into the history
+            let started = Instant::now();
+            let mut history = service.history().await;
+            while history.is_empty() && started.elapsed() < Duration::from_secs(5) {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                history = service.history().await;
+            }
+            let sent: Vec<_> = history.into_iter().map(|entry| entry.hash).collect();
+            assert_eq!(sent, vec![ClipboardContent::Text("after".to_string()).hash()], "with {}", flag);
+
+            service.shutdown().await.unwrap();
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
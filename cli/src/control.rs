@@ -0,0 +1,259 @@
+//! Control of a running instance by later commands.
+//!
+//! `omniclip run` listens on a loopback port recorded in its lockfile, next to
+//! a random token. The lockfile is only readable by the user, so only their
+//! commands can present the token. Each connection carries one JSON request
+//! line and gets one JSON reply line.
+
+use std::net::Ipv4Addr;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context};
+use omniclip_core::{NegotiatedFeatures, OmniclipService, PairingSessionInfo, SyncDirection};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+/// Longest request or reply line read, in bytes
+const MAX_LINE: u64 = 64 * 1024;
+
+/// How long a command waits for the running instance to answer
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How to reach a running instance's control listener
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Endpoint {
+    port: u16,
+    token: String,
+}
+
+/// What a command asks the running instance to do
+#[derive(Serialize, Deserialize)]
+pub enum Request {
+    /// List the active pairing sessions
+    Pairings,
+    /// Cancel the pairing session with this ID
+    CancelPairing(Uuid),
+    /// List the paired devices
+    Devices,
+    /// Unpair the device with this ID
+    Unpair(Uuid),
+}
+
+/// The running instance's answer to a [`Request`]
+#[derive(Serialize, Deserialize)]
+pub enum Reply {
+    Pairings(Vec<Pairing>),
+    /// Whether there was such a session to cancel
+    Cancelled(bool),
+    Devices(Vec<Device>),
+    /// Name of the device unpaired, if there was one with the ID
+    Unpaired(Option<String>),
+}
+
+/// A pairing session as listed to other commands
+#[derive(Serialize, Deserialize)]
+pub struct Pairing {
+    pub session_id: Uuid,
+    pub created_at: SystemTime,
+    pub device_name_hint: Option<String>,
+}
+
+impl From<PairingSessionInfo> for Pairing {
+    fn from(info: PairingSessionInfo) -> Self {
+        Self {
+            session_id: info.session_id,
+            created_at: info.created_at,
+            device_name_hint: info.device_name_hint,
+        }
+    }
+}
+
+/// A paired device as listed to other commands
+#[derive(Serialize, Deserialize)]
+pub struct Device {
+    pub device_id: Uuid,
+    pub device_name: String,
+    pub fingerprint: String,
+    pub direction: SyncDirection,
+    /// Epoch of the current session key, counting rekeys and rotations
+    pub key_epoch: Option<u32>,
+    pub features: Option<NegotiatedFeatures>,
+}
+
+impl Device {
+    /// The devices paired with `service`, sorted as it lists them
+    pub async fn list(service: &OmniclipService) -> Vec<Device> {
+        let mut devices = Vec::new();
+        for device in service.get_paired_devices().await {
+            devices.push(Device {
+                key_epoch: service.key_epoch(device.device_id).await.map(|epoch| epoch.0),
+                features: service.negotiated_features(device.device_id).await,
+                device_id: device.device_id,
+                device_name: device.device_name,
+                fingerprint: device.fingerprint,
+                direction: device.direction,
+            });
+        }
+        devices
+    }
+}
+
+/// Unpair the device with `device_id` from `service`, returning its name,
+/// or `None` if no such device is paired
+pub async fn unpair(service: &OmniclipService, device_id: Uuid) -> Option<String> {
+    let name = service.get_paired_devices().await
+        .into_iter()
+        .find(|device| device.device_id == device_id)?
+        .device_name;
+    service.unpair_device(device_id).await;
+    Some(name)
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    token: String,
+    request: Request,
+}
+
+/// A request from another command, waiting for the service to answer it
+pub struct Pending {
+    request: Request,
+    reply: oneshot::Sender<Reply>,
+}
+
+impl Pending {
+    /// Carry out the request on `service` and send back the result.
+    pub async fn answer(self, service: &OmniclipService) {
+        let reply = match self.request {
+            Request::Pairings => Reply::Pairings(
+                service.active_pairing_sessions().await.into_iter().map(Pairing::from).collect(),
+            ),
+            Request::CancelPairing(session_id) => Reply::Cancelled(service.cancel_pairing(session_id).await),
+            Request::Devices => Reply::Devices(Device::list(service).await),
+            Request::Unpair(device_id) => Reply::Unpaired(unpair(service, device_id).await),
+        };
+        let _ = self.reply.send(reply);
+    }
+}
+
+/// Listen for requests from other commands on a loopback port.
+///
+/// Requests are handed over on the returned channel; listening stops once
+/// it is dropped.
+pub async fn listen() -> anyhow::Result<(Endpoint, mpsc::Receiver<Pending>)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await
+        .context("couldn't listen for control requests")?;
+    let endpoint = Endpoint {
+        port: listener.local_addr()?.port(),
+        token: Uuid::new_v4().simple().to_string(),
+    };
+
+    let (tx, rx) = mpsc::channel(4);
+    let token = endpoint.token.clone();
+    tokio::spawn(async move {
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::debug!("control accept failed: {}", e);
+                        continue;
+                    }
+                },
+                _ = tx.closed() => break,
+            };
+            let (tx, token) = (tx.clone(), token.clone());
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, &token, tx).await {
+                    tracing::debug!("control request failed: {}", e);
+                }
+            });
+        }
+    });
+
+    Ok((endpoint, rx))
+}
+
+async fn serve(mut stream: TcpStream, token: &str, requests: mpsc::Sender<Pending>) -> anyhow::Result<()> {
+    let (read, mut write) = stream.split();
+    let mut line = String::new();
+    BufReader::new(read.take(MAX_LINE)).read_line(&mut line).await?;
+    let envelope: Envelope = serde_json::from_str(&line)?;
+    if envelope.token != token {
+        bail!("wrong control token");
+    }
+
+    let (reply, replied) = oneshot::channel();
+    requests.send(Pending { request: envelope.request, reply }).await?;
+    let mut reply = serde_json::to_vec(&replied.await?)?;
+    reply.push(b'\n');
+    write.write_all(&reply).await?;
+    Ok(())
+}
+
+/// Send `request` to the running instance at `endpoint` and wait for its reply.
+pub async fn send(endpoint: &Endpoint, request: Request) -> anyhow::Result<Reply> {
+    let exchange = async {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, endpoint.port)).await?;
+        let mut line = serde_json::to_vec(&Envelope { token: endpoint.token.clone(), request })?;
+        line.push(b'\n');
+        stream.write_all(&line).await?;
+
+        let mut reply = String::new();
+        BufReader::new(stream.take(MAX_LINE)).read_line(&mut reply).await?;
+        anyhow::Ok(serde_json::from_str(&reply)?)
+    };
+    tokio::time::timeout(REPLY_TIMEOUT, exchange).await
+        .context("the running instance didn't answer")?
+        .context("couldn't reach the running instance")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_requests_need_the_token() {
+        let (endpoint, mut requests) = listen().await.unwrap();
+        tokio::spawn(async move {
+            while let Some(pending) = requests.recv().await {
+                let _ = pending.reply.send(Reply::Cancelled(true));
+            }
+        });
+
+        let reply = send(&endpoint, Request::CancelPairing(Uuid::new_v4())).await.unwrap();
+        assert!(matches!(reply, Reply::Cancelled(true)));
+
+        let forged = Endpoint { port: endpoint.port, token: Uuid::new_v4().simple().to_string() };
+        assert!(send(&forged, Request::Pairings).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_devices_listed_and_unpaired_by_running_instance() {
+        let service = OmniclipService::new("desk".to_string());
+        let phone = Uuid::new_v4();
+        let phone_key = omniclip_core::crypto::SigningKey::generate().verifying_key();
+        service.add_preshared_pairing(phone, "phone".to_string(), phone_key, &[1u8; 32]).await.unwrap();
+        let (endpoint, mut requests) = listen().await.unwrap();
+        tokio::spawn(async move {
+            while let Some(pending) = requests.recv().await {
+                pending.answer(&service).await;
+            }
+        });
+
+        let Reply::Devices(devices) = send(&endpoint, Request::Devices).await.unwrap() else {
+            panic!("expected the paired devices");
+        };
+        assert_eq!(devices.len(), 1);
+        assert_eq!((devices[0].device_id, devices[0].device_name.as_str()), (phone, "phone"));
+        assert_eq!(devices[0].key_epoch, Some(0));
+
+        let reply = send(&endpoint, Request::Unpair(phone)).await.unwrap();
+        assert!(matches!(reply, Reply::Unpaired(Some(name)) if name == "phone"));
+        assert!(matches!(send(&endpoint, Request::Unpair(phone)).await.unwrap(), Reply::Unpaired(None)));
+        assert!(matches!(send(&endpoint, Request::Devices).await.unwrap(), Reply::Devices(devices) if devices.is_empty()));
+    }
+}
@@ -1,12 +1,20 @@
 //! Omniclip CLI - Cross-platform clipboard sync.
 
 mod commands;
+mod control;
 mod process;
+mod state;
 mod ui;
 
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
+use omniclip_core::Config;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::EnvFilter;
 
+use commands::{DevicesArgs, InfoArgs, PairArgs, PairingsArgs, PingArgs, RunArgs, UnpairArgs};
+
 #[derive(Parser)]
 #[command(name = "omniclip")]
 #[command(about = "Cross-platform clipboard sync", long_about = None)]
@@ -15,6 +23,13 @@ struct Cli {
     #[arg(short, long, default_value_t = default_device_name())]
     name: String,
 
+    /// TOML file setting the port, service name and data directory.
+    ///
+    /// Other running instances are left alone when this is given, so
+    /// several can run on one host with different files.
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -28,26 +43,55 @@ fn default_device_name() -> String {
 #[derive(Subcommand)]
 enum Commands {
     /// Start the omniclip service (default)
-    Run,
+    Run(RunArgs),
     /// Show device info
-    Info,
+    Info(InfoArgs),
+    /// Pair with a device's pairing URL, or using a key shared out of band,
+    /// then run the service
+    Pair(PairArgs),
+    /// List or cancel the pairing sessions of the running instance
+    Pairings(PairingsArgs),
+    /// Check that a device on the network answers
+    Ping(PingArgs),
+    /// List paired devices
+    Devices(DevicesArgs),
+    /// Remove a paired device
+    Unpair(UnpairArgs),
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Commands::Run(RunArgs::default()));
+
+    // Log lines would corrupt the dashboard, so drop them there
+    let writer = match &command {
+        Commands::Run(args) if args.tui() => BoxMakeWriter::new(std::io::sink),
+        // Keep stdout to the result so scripts can parse it
+        Commands::Run(args) if args.once => BoxMakeWriter::new(std::io::stderr),
+        Commands::Pair(args) if args.run.tui() => BoxMakeWriter::new(std::io::sink),
+        Commands::Pair(args) if args.run.once => BoxMakeWriter::new(std::io::stderr),
+        Commands::Devices(_) | Commands::Unpair(_) | Commands::Pairings(_) => BoxMakeWriter::new(std::io::stderr),
+        _ => BoxMakeWriter::new(std::io::stdout),
+    };
     tracing_subscriber::fmt()
         .with_env_filter(
             EnvFilter::from_default_env()
                 .add_directive("omniclip=info".parse()?)
                 .add_directive("mdns_sd=warn".parse()?),
         )
+        .with_writer(writer)
         .init();
 
-    let cli = Cli::parse();
-
-    match cli.command.unwrap_or(Commands::Run) {
-        Commands::Run => commands::run_service(cli.name).await?,
-        Commands::Info => commands::show_info(cli.name),
+    let config = cli.config.as_deref().map(Config::from_file).transpose()?;
+    match command {
+        Commands::Run(args) => commands::run_service(cli.name, config, args).await?,
+        Commands::Info(args) => commands::show_info(cli.name, config.unwrap_or_default(), args)?,
+        Commands::Pair(args) => commands::pair(cli.name, config, args).await?,
+        Commands::Pairings(args) => commands::pairings(config.unwrap_or_default(), args).await?,
+        Commands::Ping(args) => commands::ping_target(args).await?,
+        Commands::Devices(args) => commands::list_devices(cli.name, config.unwrap_or_default(), args).await?,
+        Commands::Unpair(args) => commands::unpair(cli.name, config.unwrap_or_default(), args).await?,
     }
 
     Ok(())
@@ -1,30 +1,145 @@
 //! Process management utilities.
 
-use std::process::Command;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-/// Kill any previously running omniclip instances.
+use anyhow::{bail, Context};
+use omniclip_core::sync;
+use serde::{Deserialize, Serialize};
+
+use crate::control::Endpoint;
+
+/// File in the data directory naming the instance running from it
+const LOCK_FILE: &str = "omniclip.lock";
+
+/// How long to wait for a replaced instance to stop serving
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a running instance records in its lockfile
+#[derive(Serialize, Deserialize)]
+struct LockInfo {
+    pid: u32,
+    /// Port its sync server listens on; 0 until it has started
+    port: u16,
+    /// Where later commands can reach it, once it is serving
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    control: Option<Endpoint>,
+}
+
+/// Claim on a data directory by this process, released when dropped.
 ///
-/// This prevents port conflicts when restarting the service.
-pub fn kill_previous_instances() {
-    let my_pid = std::process::id();
-
-    // Use pkill to kill other omniclip processes
-    let _ = Command::new("pkill")
-        .args(["-9", "-f", "target/.*omniclip"])
-        .output();
-
-    // Kill by process name, excluding our PID
-    if let Ok(output) = Command::new("pgrep").args(["-f", "omniclip"]).output() {
-        let pids = String::from_utf8_lossy(&output.stdout);
-        for pid_str in pids.lines() {
-            if let Ok(pid) = pid_str.trim().parse::<u32>() {
-                if pid != my_pid {
-                    let _ = Command::new("kill").args(["-9", &pid.to_string()]).output();
+/// Two instances sharing a data directory share an identity and port, so
+/// only one may run at a time.
+pub struct InstanceLock {
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    /// Claim `data_dir` for this process.
+    ///
+    /// An instance still serving on the port its lockfile records is asked
+    /// to stop when `replace` is set, and otherwise makes this fail. A
+    /// lockfile left by an instance that is gone is taken over.
+    pub async fn acquire(data_dir: &Path, replace: bool) -> anyhow::Result<Self> {
+        let path = data_dir.join(LOCK_FILE);
+        if let Some(previous) = read(&path) {
+            if previous.pid != std::process::id() && serving(previous.port).await {
+                if !replace {
+                    bail!(
+                        "omniclip is already running from {} (pid {}, port {})",
+                        data_dir.display(),
+                        previous.pid,
+                        previous.port
+                    );
+                }
+                stop(previous.pid)?;
+                let started = Instant::now();
+                while serving(previous.port).await {
+                    if started.elapsed() > REPLACE_TIMEOUT {
+                        bail!("omniclip (pid {}) didn't stop within {:?}", previous.pid, REPLACE_TIMEOUT);
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+
+        std::fs::create_dir_all(data_dir)
+            .with_context(|| format!("couldn't create {}", data_dir.display()))?;
+        let lock = Self { path };
+        lock.write(&LockInfo { pid: std::process::id(), port: 0, control: None })?;
+        Ok(lock)
+    }
+
+    /// Record the port this instance's sync server listens on, and where
+    /// other commands can control it.
+    pub fn record(&self, port: u16, control: &Endpoint) -> anyhow::Result<()> {
+        self.write(&LockInfo { pid: std::process::id(), port, control: Some(control.clone()) })
+    }
+
+    fn write(&self, info: &LockInfo) -> anyhow::Result<()> {
+        let write = || -> std::io::Result<()> {
+            // The control token in it must stay with this user
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+                options.mode(0o600);
+                // Lockfiles from older versions were created readable by others
+                if self.path.exists() {
+                    std::fs::set_permissions(&self.path, std::fs::Permissions::from_mode(0o600))?;
                 }
             }
+            std::io::Write::write_all(&mut options.open(&self.path)?, &serde_json::to_vec(info)?)
+        };
+        write().with_context(|| format!("couldn't write {}", self.path.display()))
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // Leave it alone if another instance has since taken over
+        if read(&self.path).is_some_and(|info| info.pid == std::process::id()) {
+            let _ = std::fs::remove_file(&self.path);
         }
     }
+}
+
+/// Where to reach the instance running from `data_dir`, if one is.
+///
+/// A lockfile left by an instance that is gone names no endpoint.
+pub async fn control_endpoint(data_dir: &Path) -> Option<Endpoint> {
+    let info = read(&data_dir.join(LOCK_FILE))?;
+    if !serving(info.port).await {
+        return None;
+    }
+    info.control
+}
+
+fn read(path: &Path) -> Option<LockInfo> {
+    serde_json::from_slice(&std::fs::read(path).ok()?).ok()
+}
+
+/// Whether an omniclip sync server answers on `port` locally
+async fn serving(port: u16) -> bool {
+    port != 0 && sync::ping(SocketAddr::from(([127, 0, 0, 1], port)), Duration::from_secs(1)).await.is_ok()
+}
+
+/// Ask the instance with `pid` to shut down as it would on Ctrl+C
+#[cfg(unix)]
+fn stop(pid: u32) -> anyhow::Result<()> {
+    let status = std::process::Command::new("kill")
+        .args(["-INT", &pid.to_string()])
+        .status()
+        .context("couldn't run kill")?;
+    if !status.success() {
+        bail!("couldn't stop omniclip (pid {})", pid);
+    }
+    Ok(())
+}
 
-    // Brief pause to let the OS release the port
-    std::thread::sleep(std::time::Duration::from_millis(100));
+#[cfg(not(unix))]
+fn stop(pid: u32) -> anyhow::Result<()> {
+    bail!("omniclip is already running (pid {}); stop it first", pid)
 }
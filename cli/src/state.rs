@@ -0,0 +1,37 @@
+//! Persisted identity and state, and the passphrase protecting them.
+
+use anyhow::bail;
+use omniclip_core::storage::StateStore;
+use omniclip_core::{Config, DeviceIdentity, OmniclipService};
+
+/// Create the service for `config` with the identity kept in its data
+/// directory.
+///
+/// The state passphrase is asked for if the state is already encrypted, or
+/// if `encrypt` is set, in which case unencrypted state is encrypted with it.
+/// The store is opened once and shared with the service, so the passphrase
+/// is only stretched once.
+pub fn open_service(device_name: String, config: Config, encrypt: bool) -> anyhow::Result<OmniclipService> {
+    let data_dir = config.data_dir.clone();
+    let encrypted = StateStore::is_encrypted(&data_dir);
+    let store = if encrypt || encrypted {
+        StateStore::open_encrypted(&data_dir, &read_passphrase(!encrypted)?)?
+    } else {
+        StateStore::open(&data_dir)?
+    };
+
+    let identity = DeviceIdentity::load_or_create_in(&store, device_name)?;
+    Ok(OmniclipService::with_identity(identity, config).with_state_store(store))
+}
+
+/// Ask for the state passphrase on the terminal, twice if it is being set.
+fn read_passphrase(new: bool) -> anyhow::Result<String> {
+    let passphrase = rpassword::prompt_password("State passphrase: ")?;
+    if passphrase.is_empty() {
+        bail!("the state passphrase can't be empty");
+    }
+    if new && rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+        bail!("the passphrases don't match");
+    }
+    Ok(passphrase)
+}
@@ -0,0 +1,387 @@
+//! Live terminal dashboard for the run command.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use omniclip_core::{
+    OmniclipService, PairedDeviceSummary, PairingSessionInfo, PeerInfo, ServiceEvent, SessionState,
+};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::commands::format_preview;
+use crate::control::Pending;
+use crate::ui::qr_lines;
+
+/// Maximum number of activity lines kept on screen.
+const MAX_ACTIVITY: usize = 200;
+
+/// How long to wait for a key press before redrawing.
+const TICK: Duration = Duration::from_millis(100);
+
+/// How often each online paired device is pinged for its round-trip time.
+const RTT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a ping may hold up the dashboard before counting as unanswered.
+const RTT_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Dashboard state built from service events.
+struct Dashboard {
+    started: Instant,
+    /// URL of the newest pairing session, shown as a QR code
+    pairing_url: Option<String>,
+    pairing_sessions: Vec<PairingSessionInfo>,
+    discovered: BTreeMap<Uuid, PeerInfo>,
+    paired: Vec<PairedDeviceSummary>,
+    /// When each paired device was last pinged, and its round-trip time if
+    /// it answered
+    rtts: HashMap<Uuid, (Instant, Option<Duration>)>,
+    activity: VecDeque<String>,
+    selected: ListState,
+    /// Whether sync is paused, manually or by a locked session
+    paused: bool,
+}
+
+impl Dashboard {
+    fn new(pairing_url: String) -> Self {
+        Self {
+            started: Instant::now(),
+            pairing_url: Some(pairing_url),
+            pairing_sessions: Vec::new(),
+            discovered: BTreeMap::new(),
+            paired: Vec::new(),
+            rtts: HashMap::new(),
+            activity: VecDeque::new(),
+            selected: ListState::default(),
+            paused: false,
+        }
+    }
+
+    fn log(&mut self, message: String) {
+        let elapsed = self.started.elapsed().as_secs();
+        self.activity.push_front(format!("[{:>4}s] {}", elapsed, message));
+        self.activity.truncate(MAX_ACTIVITY);
+    }
+
+    fn handle_event(&mut self, event: ServiceEvent) {
+        match event {
+            ServiceEvent::DeviceDiscovered(peer) => {
+                self.log(format!("found {}", peer.device_name));
+                self.discovered.insert(peer.device_id, peer);
+            }
+            ServiceEvent::DeviceUpdated(peer) => {
+                self.log(format!("updated {}", peer.device_name));
+                self.discovered.insert(peer.device_id, peer);
+            }
+            ServiceEvent::DeviceLost(id) => {
+                let name = self.name_of(&id);
+                self.log(format!("lost {}", name));
+                self.discovered.remove(&id);
+            }
+            ServiceEvent::DeviceUnpaired(id) => {
+                let name = self.name_of(&id);
+                self.log(format!("unpaired by {}", name));
+            }
+            ServiceEvent::PairingRequest { device_name, .. } => {
+                self.log(format!("paired with {}", device_name));
+            }
+            ServiceEvent::ClipboardReceived { from_device, content } => {
+                let name = self.name_of(&from_device);
+                self.log(format!("received from {}: \"{}\"", name, format_preview(&content)));
+            }
+            ServiceEvent::ClipboardWithheld { from_device, content } => {
+                let name = self.name_of(&from_device);
+                self.log(format!("withheld from {} (blocked): \"{}\"", name, format_preview(&content)));
+            }
+            ServiceEvent::Rekeyed { device_id, epoch } => {
+                let name = self.name_of(&device_id);
+                self.log(format!("session key refreshed with {} ({})", name, epoch));
+            }
+            ServiceEvent::ReplayRejected { device_id, .. } => {
+                let name = self.name_of(&device_id);
+                self.log(format!("dropped a replayed or stale message from {}", name));
+            }
+            ServiceEvent::PairingRejected { reason } => {
+                self.log(format!("pairing rejected: {}", reason));
+            }
+            ServiceEvent::IncompatiblePeer { device_name, protocol_version, .. } => {
+                self.log(format!("{} uses incompatible protocol version {}", device_name, protocol_version));
+            }
+            ServiceEvent::PairingUrlChanged { url } => {
+                self.log("address changed, pairing QR updated".to_string());
+                if self.pairing_url.is_some() {
+                    self.pairing_url = Some(url);
+                }
+            }
+            ServiceEvent::ClipboardTooLarge { size, limit } => {
+                self.log(format!("not sent: {} bytes is over the {} byte limit", size, limit));
+            }
+            ServiceEvent::SyncSuppressed { hash } => {
+                self.log(format!("not sent back, exchanged recently ({})", hash));
+            }
+            ServiceEvent::ClipboardSent { to_devices } => {
+                self.log(format!("sent to {} device(s)", to_devices.len()));
+            }
+            ServiceEvent::DeliveryConfirmed { device_id, .. } => {
+                let name = self.name_of(&device_id);
+                self.log(format!("delivered to {}", name));
+            }
+            ServiceEvent::SessionStateChanged(SessionState::Locked) => {
+                self.log("session locked, sync paused".to_string());
+            }
+            ServiceEvent::SessionStateChanged(SessionState::Active) => {
+                self.log("session unlocked, sync resumed".to_string());
+            }
+            ServiceEvent::PauseChanged { paused: true } => {
+                self.log("sync paused".to_string());
+            }
+            ServiceEvent::PauseChanged { paused: false } => {
+                self.log("sync resumed".to_string());
+            }
+            ServiceEvent::Backpressure { waited } => {
+                self.log(format!("falling behind on events, sync waited {:?}", waited));
+            }
+            ServiceEvent::Error(e) => {
+                self.log(format!("error: {}", e));
+            }
+        }
+    }
+
+    fn name_of(&self, id: &Uuid) -> String {
+        self.paired.iter()
+            .find(|d| d.device_id == *id)
+            .map(|d| d.device_name.clone())
+            .or_else(|| self.discovered.get(id).map(|p| p.device_name.clone()))
+            .unwrap_or_else(|| id.to_string())
+    }
+
+    fn set_paired(&mut self, paired: Vec<PairedDeviceSummary>) {
+        self.paired = paired;
+        match self.selected.selected() {
+            _ if self.paired.is_empty() => self.selected.select(None),
+            Some(i) if i >= self.paired.len() => self.selected.select(Some(self.paired.len() - 1)),
+            None => self.selected.select(Some(0)),
+            _ => {}
+        }
+    }
+
+    fn is_online(&self, device: &PairedDeviceSummary) -> bool {
+        device.connected || self.discovered.contains_key(&device.device_id)
+    }
+
+    /// An online paired device whose round-trip time is due to be measured
+    fn rtt_due(&self) -> Option<Uuid> {
+        self.paired.iter()
+            .filter(|device| self.is_online(device))
+            .map(|device| device.device_id)
+            .find(|id| self.rtts.get(id).is_none_or(|(checked, _)| checked.elapsed() >= RTT_INTERVAL))
+    }
+
+    fn selected_device(&self) -> Option<(Uuid, String)> {
+        self.selected.selected()
+            .and_then(|i| self.paired.get(i))
+            .map(|d| (d.device_id, d.device_name.clone()))
+    }
+
+    fn render(&mut self, frame: &mut Frame) {
+        let [main, footer] = Layout::vertical([Constraint::Min(0), Constraint::Length(1)])
+            .areas(frame.area());
+        let [left, qr_area] = Layout::horizontal([Constraint::Min(40), Constraint::Length(62)])
+            .areas(main);
+        let [peers_area, activity_area] = Layout::vertical([Constraint::Percentage(40), Constraint::Percentage(60)])
+            .areas(left);
+
+        let paired_items: Vec<ListItem> = self.paired.iter()
+            .map(|device| {
+                let online = self.is_online(device);
+                let (dot, color) = if online { ("●", Color::Green) } else { ("○", Color::DarkGray) };
+                let rtt = match self.rtts.get(&device.device_id) {
+                    Some((_, Some(rtt))) if online => format!("  {}ms", rtt.as_millis()),
+                    Some((_, None)) if online => "  no reply".to_string(),
+                    _ => String::new(),
+                };
+                ListItem::new(Line::from(vec![
+                    Span::styled(format!("{} ", dot), Style::new().fg(color)),
+                    Span::raw(device.device_name.clone()).bold(),
+                    Span::raw(if online { "  online" } else { "  offline" }).dim(),
+                    Span::raw(rtt).dim(),
+                ]))
+            })
+            .collect();
+
+        let unpaired: Vec<Line> = self.discovered.values()
+            .filter(|p| !self.paired.iter().any(|d| d.device_id == p.device_id))
+            .map(|p| Line::from(vec![
+                Span::styled("◌ ", Style::new().fg(Color::Yellow)),
+                Span::raw(p.device_name.clone()),
+                Span::raw(if p.accepting_pairing { "  accepting pairing" } else { "  discovered" }).dim(),
+                Span::raw(p.protocol_version.map(|v| format!("  v{}", v)).unwrap_or_default()).dim(),
+            ]))
+            .collect();
+
+        let peers_block = Block::bordered().title(" Peers ");
+        let peers_inner = peers_block.inner(peers_area);
+        frame.render_widget(peers_block, peers_area);
+
+        let [paired_area, discovered_area] = Layout::vertical([
+            Constraint::Length(self.paired.len().max(1) as u16),
+            Constraint::Min(0),
+        ]).areas(peers_inner);
+
+        if self.paired.is_empty() {
+            frame.render_widget(Paragraph::new("no paired devices").dim(), paired_area);
+        } else {
+            let list = List::new(paired_items)
+                .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+            frame.render_stateful_widget(list, paired_area, &mut self.selected);
+        }
+        frame.render_widget(Paragraph::new(unpaired), discovered_area);
+
+        let activity: Vec<Line> = self.activity.iter().map(|l| Line::raw(l.clone())).collect();
+        frame.render_widget(
+            Paragraph::new(activity).block(Block::bordered().title(" Activity ")),
+            activity_area,
+        );
+
+        let [qr_area, sessions_area] = Layout::vertical([
+            Constraint::Min(0),
+            Constraint::Length(self.pairing_sessions.len().max(1) as u16 + 2),
+        ]).areas(qr_area);
+
+        let qr: Vec<Line> = match &self.pairing_url {
+            Some(url) => qr_lines(url)
+                .map(|lines| lines.into_iter().map(Line::raw).collect())
+                .unwrap_or_else(|e| vec![Line::raw(format!("QR error: {}", e))]),
+            None => vec![Line::raw("no active pairing session, press n to start one").dim()],
+        };
+        frame.render_widget(
+            Paragraph::new(qr).block(Block::bordered().title(" Scan to pair ")),
+            qr_area,
+        );
+
+        let sessions: Vec<Line> = if self.pairing_sessions.is_empty() {
+            vec![Line::raw("none").dim()]
+        } else {
+            self.pairing_sessions.iter()
+                .map(|s| {
+                    let age = s.created_at.elapsed().unwrap_or_default().as_secs();
+                    Line::from(vec![
+                        Span::raw(s.session_id.to_string()[..8].to_string()).bold(),
+                        Span::raw(format!("  {}s ago", age)).dim(),
+                        Span::raw(s.device_name_hint.as_deref().map(|h| format!("  for {}", h)).unwrap_or_default()),
+                    ])
+                })
+                .collect()
+        };
+        frame.render_widget(
+            Paragraph::new(sessions).block(Block::bordered().title(" Pairing sessions ")),
+            sessions_area,
+        );
+
+        let mut help = vec![
+            Span::raw(" ↑/↓ select   u unpair selected   n new pairing   x cancel older pairings   p pause/resume   q quit").dim(),
+        ];
+        if self.paused {
+            help.insert(0, Span::styled(" PAUSED ", Style::new().fg(Color::Black).bg(Color::Yellow)));
+        }
+        frame.render_widget(Paragraph::new(Line::from(help)), footer);
+    }
+}
+
+/// Run the dashboard until the user quits.
+pub async fn run_dashboard(
+    service: &OmniclipService,
+    mut events: mpsc::Receiver<ServiceEvent>,
+    mut requests: mpsc::Receiver<Pending>,
+    pairing_url: String,
+) -> anyhow::Result<()> {
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, service, &mut events, &mut requests, pairing_url).await;
+    ratatui::restore();
+    result
+}
+
+async fn event_loop(
+    terminal: &mut DefaultTerminal,
+    service: &OmniclipService,
+    events: &mut mpsc::Receiver<ServiceEvent>,
+    requests: &mut mpsc::Receiver<Pending>,
+    pairing_url: String,
+) -> anyhow::Result<()> {
+    let mut dashboard = Dashboard::new(pairing_url);
+
+    loop {
+        while let Ok(event) = events.try_recv() {
+            dashboard.handle_event(event);
+        }
+        // From `omniclip pairings` run elsewhere
+        while let Ok(request) = requests.try_recv() {
+            request.answer(service).await;
+        }
+        dashboard.set_paired(service.get_paired_devices().await);
+        // One device per tick, so an unresponsive one stalls a redraw at most
+        if let Some(device_id) = dashboard.rtt_due() {
+            let rtt = service.ping_device(device_id, RTT_TIMEOUT).await.ok();
+            dashboard.rtts.insert(device_id, (Instant::now(), rtt));
+        }
+        dashboard.paused = service.is_paused();
+        dashboard.pairing_sessions = service.active_pairing_sessions().await;
+        if dashboard.pairing_sessions.is_empty() {
+            // Used by a device, cancelled or expired
+            dashboard.pairing_url = None;
+        }
+
+        terminal.draw(|frame| dashboard.render(frame))?;
+
+        if !event::poll(TICK)? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => break,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+            KeyCode::Up => dashboard.selected.select_previous(),
+            KeyCode::Down => dashboard.selected.select_next(),
+            KeyCode::Char('u') => {
+                if let Some((id, name)) = dashboard.selected_device() {
+                    service.unpair_device(id).await;
+                    dashboard.log(format!("unpaired {}", name));
+                }
+            }
+            KeyCode::Char('n') => {
+                dashboard.pairing_url = Some(service.start_pairing().await?);
+                dashboard.log("started new pairing session".to_string());
+            }
+            KeyCode::Char('p') => {
+                if service.is_paused() {
+                    service.resume();
+                } else {
+                    service.pause();
+                }
+            }
+            KeyCode::Char('x') => {
+                // Keep only the newest session, whose QR is on screen
+                let older = dashboard.pairing_sessions.len().saturating_sub(1);
+                for session in &dashboard.pairing_sessions[..older] {
+                    service.cancel_pairing(session.session_id).await;
+                }
+                dashboard.log(format!("cancelled {} pairing session(s)", older));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
@@ -1,7 +1,13 @@
 //! UI utilities for terminal output.
 
 mod banner;
+#[cfg(feature = "tui")]
+mod dashboard;
 mod qr;
 
 pub use banner::print_banner;
+#[cfg(feature = "tui")]
+pub use dashboard::run_dashboard;
 pub use qr::print_qr_code;
+#[cfg(feature = "tui")]
+pub use qr::qr_lines;
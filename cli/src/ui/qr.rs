@@ -3,17 +3,23 @@
 use qrcode::QrCode;
 
 /// Print a QR code to the terminal.
+pub fn print_qr_code(data: &str) {
+    match qr_lines(data) {
+        Ok(lines) => {
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+        Err(e) => eprintln!("Failed to generate QR code: {}", e),
+    }
+}
+
+/// Render a QR code as lines of text, including a quiet zone.
 ///
 /// Uses Unicode block characters for compact display where
 /// each character represents 2 vertical modules.
-pub fn print_qr_code(data: &str) {
-    let code = match QrCode::new(data.as_bytes()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Failed to generate QR code: {}", e);
-            return;
-        }
-    };
+pub fn qr_lines(data: &str) -> Result<Vec<String>, qrcode::types::QrError> {
+    let code = QrCode::new(data.as_bytes())?;
 
     let colors = code.to_colors();
     let width = code.width();
@@ -25,12 +31,13 @@ pub fn print_qr_code(data: &str) {
     // (space) = both white
 
     let quiet = "  ";
+    let mut lines = Vec::new();
 
     // Top quiet zone
-    println!("{}{}", quiet, " ".repeat(width + 4));
+    lines.push(format!("{}{}", quiet, " ".repeat(width + 4)));
 
     for y in (0..colors.len()).step_by(width * 2) {
-        print!("{}  ", quiet);
+        let mut line = format!("{}  ", quiet);
         for x in 0..width {
             let top = colors.get(y + x).map(|c| *c == qrcode::Color::Dark).unwrap_or(false);
             let bottom = colors.get(y + width + x).map(|c| *c == qrcode::Color::Dark).unwrap_or(false);
@@ -41,11 +48,14 @@ pub fn print_qr_code(data: &str) {
                 (false, true) => '▄',
                 (false, false) => ' ',
             };
-            print!("{}", ch);
+            line.push(ch);
         }
-        println!("  ");
+        line.push_str("  ");
+        lines.push(line);
     }
 
     // Bottom quiet zone
-    println!("{}{}", quiet, " ".repeat(width + 4));
+    lines.push(format!("{}{}", quiet, " ".repeat(width + 4)));
+
+    Ok(lines)
 }
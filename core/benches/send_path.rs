@@ -0,0 +1,79 @@
+//! Throughput of the clipboard send path: serialize, encrypt and frame.
+//!
+//! Run with `cargo bench -p omniclip-core`. Allocations per message are
+//! printed for each size before timing starts.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use omniclip_core::protocol::{clipboard_aad, ClipboardSyncMessage, ContentHash};
+use omniclip_core::{ClipboardContent, Message, SessionKey};
+use uuid::Uuid;
+
+/// Counts allocations so the bench can report them per message
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const SIZES: [usize; 3] = [1024, 64 * 1024, 1024 * 1024];
+
+/// Produce the wire frame for `content`, as the service does when sending
+fn send_frame(key: &SessionKey, sender_id: Uuid, content: &ClipboardContent) -> Vec<u8> {
+    let message_id = Uuid::new_v4();
+    let aad = clipboard_aad(sender_id, message_id, 0);
+    let message = Message::ClipboardSync(ClipboardSyncMessage {
+        message_id,
+        sender_id,
+        content_hash: ContentHash([0u8; 32]),
+        encrypted_content: key.encrypt_in_place_with_aad(content.to_bytes().unwrap(), &aad).unwrap(),
+        timestamp: 0,
+        key_epoch: 0,
+        channel: String::new(),
+        compressed: false,
+    });
+    message.to_frame().unwrap()
+}
+
+fn bench_send_path(c: &mut Criterion) {
+    let key = SessionKey::from_bytes(&[7u8; 32]);
+    let sender_id = Uuid::new_v4();
+
+    let mut group = c.benchmark_group("send_path");
+    for size in SIZES {
+        let content = ClipboardContent::Text("x".repeat(size));
+
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        std::hint::black_box(send_frame(&key, sender_id, &content));
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        println!("send_path/{}: {} allocations per message", size, allocations);
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &content, |b, content| {
+            b.iter(|| send_frame(&key, sender_id, content));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_send_path);
+criterion_main!(benches);
@@ -0,0 +1,173 @@
+//! Append-only audit log of clipboard syncs and pairing events
+//!
+//! Only metadata is recorded (time, peer, direction, size, hash); clipboard
+//! content itself is never written. Entries are JSON lines in `audit.log`
+//! under the data directory. When the file grows past the size limit it is
+//! rotated to `audit.log.1`, replacing any previous rotation.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::protocol::ContentHash;
+use crate::{Error, Result};
+
+/// File name of the active audit log
+pub const AUDIT_LOG_FILE: &str = "audit.log";
+
+/// Kind of audited event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// Clipboard content was sent or accepted
+    ClipboardSync,
+    /// Incoming clipboard content was rejected
+    ClipboardRejected,
+    /// A device was paired
+    DevicePaired,
+    /// A device was unpaired
+    DeviceUnpaired,
+}
+
+/// Whether the event originated locally or from the peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// A single audit log line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Unix timestamp in seconds
+    pub timestamp: u64,
+    pub event: AuditEvent,
+    pub peer: Uuid,
+    pub direction: Direction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<ContentHash>,
+}
+
+impl AuditEntry {
+    /// Create an entry timestamped now
+    pub fn new(event: AuditEvent, peer: Uuid, direction: Direction) -> Self {
+        Self {
+            timestamp: crate::clock::unix_seconds(std::time::SystemTime::now()),
+            event,
+            peer,
+            direction,
+            size: None,
+            hash: None,
+        }
+    }
+
+    /// Attach content metadata
+    pub fn with_content(mut self, hash: ContentHash, size: usize) -> Self {
+        self.hash = Some(hash);
+        self.size = Some(size);
+        self
+    }
+}
+
+/// Size-rotated JSON lines audit log
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Open (or create) the audit log in `data_dir`
+    pub fn open(data_dir: &Path, max_size: u64) -> Result<Self> {
+        fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(AUDIT_LOG_FILE);
+        let file = Self::open_file(&path)?;
+        Ok(Self {
+            path,
+            max_size,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Path of the active log file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append an entry, rotating first if it would exceed the size limit
+    pub fn record(&self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock()
+            .map_err(|_| Error::Io(std::io::Error::other("audit log lock poisoned")))?;
+
+        let current = file.metadata()?.len();
+        if current > 0 && current + line.len() as u64 > self.max_size {
+            fs::rename(&self.path, self.path.with_extension("log.1"))?;
+            *file = Self::open_file(&self.path)?;
+        }
+
+        file.write_all(&line)?;
+        Ok(())
+    }
+
+    fn open_file(path: &Path) -> Result<File> {
+        Ok(OpenOptions::new().create(true).append(true).open(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("omniclip-audit-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_records_json_lines() {
+        let dir = temp_dir();
+        let log = AuditLog::open(&dir, 1024 * 1024).unwrap();
+        let peer = Uuid::new_v4();
+
+        log.record(&AuditEntry::new(AuditEvent::DevicePaired, peer, Direction::Inbound)).unwrap();
+        log.record(&AuditEntry::new(AuditEvent::ClipboardSync, peer, Direction::Outbound)
+            .with_content(ContentHash([7u8; 32]), 42)).unwrap();
+
+        let contents = fs::read_to_string(log.path()).unwrap();
+        let entries: Vec<AuditEntry> = contents.lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].event, AuditEvent::DevicePaired);
+        assert_eq!(entries[1].size, Some(42));
+        assert_eq!(entries[1].peer, peer);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotates_by_size() {
+        let dir = temp_dir();
+        let log = AuditLog::open(&dir, 300).unwrap();
+        let entry = AuditEntry::new(AuditEvent::ClipboardSync, Uuid::new_v4(), Direction::Inbound)
+            .with_content(ContentHash([1u8; 32]), 10);
+
+        for _ in 0..5 {
+            log.record(&entry).unwrap();
+        }
+
+        assert!(dir.join("audit.log.1").exists());
+        assert!(fs::metadata(log.path()).unwrap().len() <= 300);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
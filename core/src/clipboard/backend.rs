@@ -0,0 +1,435 @@
+//! Platform clipboard backends
+
+use std::borrow::Cow;
+
+use arboard::{Clipboard as ArboardClipboard, ImageData};
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+use tokio::sync::mpsc;
+
+use crate::protocol::constants::MAX_IMAGE_CONTENT_SIZE;
+#[cfg(windows)]
+use crate::protocol::constants::MAX_RAW_CONTENT_SIZE;
+use crate::protocol::ClipboardContent;
+use crate::{Error, Result};
+
+/// Access to a clipboard that [`ClipboardManager`](super::ClipboardManager) can poll
+pub trait ClipboardBackend: Send {
+    /// Read the current clipboard content
+    fn read(&self) -> Result<Option<ClipboardContent>>;
+
+    /// Replace the clipboard content
+    fn write(&self, content: &ClipboardContent) -> Result<()>;
+
+    /// Cheap indicator that advances whenever the clipboard changes.
+    ///
+    /// Returns `None` where the platform has no such counter, in which case
+    /// change detection falls back to hashing the full content.
+    fn change_token(&self) -> Option<u64> {
+        None
+    }
+
+    /// Notifications of clipboard changes, one message per change.
+    ///
+    /// Returns `None` where the platform can't notify, in which case the
+    /// monitor polls. The monitor also falls back to polling if the
+    /// channel closes.
+    fn change_events(&self) -> Option<mpsc::UnboundedReceiver<()>> {
+        None
+    }
+
+    /// Whether the application that set the current content marked it as
+    /// concealed, as password managers do. `false` where there is no such
+    /// marker.
+    fn is_concealed(&self) -> bool {
+        false
+    }
+
+    /// Read the X11 primary selection, the text last selected
+    ///
+    /// `None` where there is no primary selection, as on macOS and Windows.
+    fn read_primary(&self) -> Result<Option<ClipboardContent>> {
+        Ok(None)
+    }
+
+    /// Replace the primary selection; does nothing where there is none
+    fn write_primary(&self, _content: &ClipboardContent) -> Result<()> {
+        Ok(())
+    }
+
+    /// Notifications of primary selection changes, like
+    /// [`change_events`](Self::change_events)
+    fn primary_change_events(&self) -> Option<mpsc::UnboundedReceiver<()>> {
+        None
+    }
+}
+
+/// System clipboard via arboard, with native change counters where available
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClipboard;
+
+impl ClipboardBackend for SystemClipboard {
+    fn read(&self) -> Result<Option<ClipboardContent>> {
+        let mut clipboard = ArboardClipboard::new()
+            .map_err(|e| Error::Clipboard(e.to_string()))?;
+
+        // Try to get text content, with any HTML alongside it, then an image
+        match clipboard.get_text() {
+            Ok(text) if !text.is_empty() => {
+                return Ok(Some(match clipboard.get().html() {
+                    Ok(html) if !html.is_empty() => ClipboardContent::RichText { plain: text, html },
+                    _ => ClipboardContent::Text(text),
+                }));
+            }
+            Ok(_) => return Ok(None),
+            Err(arboard::Error::ContentNotAvailable) => {}
+            Err(e) => return Err(Error::Clipboard(e.to_string())),
+        }
+        match clipboard.get_image() {
+            Ok(image) => Ok(fit_image(image.width, image.height, image.bytes.into_owned())),
+            Err(arboard::Error::ContentNotAvailable) => Ok(read_raw()),
+            Err(e) => Err(Error::Clipboard(e.to_string())),
+        }
+    }
+
+    fn write(&self, content: &ClipboardContent) -> Result<()> {
+        let mut clipboard = ArboardClipboard::new()
+            .map_err(|e| Error::Clipboard(e.to_string()))?;
+
+        match content {
+            ClipboardContent::Text(text) => {
+                clipboard.set_text(text)
+                    .map_err(|e| Error::Clipboard(e.to_string()))
+            }
+            ClipboardContent::RichText { plain, html } => {
+                // The plain text is offered alongside for applications that
+                // don't take HTML
+                match clipboard.set_html(html.as_str(), Some(plain.as_str())) {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        tracing::debug!("couldn't write HTML to the clipboard, writing plain text: {}", e);
+                        clipboard.set_text(plain)
+                            .map_err(|e| Error::Clipboard(e.to_string()))
+                    }
+                }
+            }
+            ClipboardContent::Raw { mime, data } => {
+                drop(clipboard);
+                write_raw(mime, data)
+            }
+            ClipboardContent::Image { width, height, rgba } => {
+                clipboard.set_image(ImageData {
+                    width: *width,
+                    height: *height,
+                    bytes: Cow::Borrowed(rgba),
+                })
+                .map_err(|e| Error::Clipboard(e.to_string()))
+            }
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn change_token(&self) -> Option<u64> {
+        use objc2_app_kit::NSPasteboard;
+        Some(NSPasteboard::generalPasteboard().changeCount() as u64)
+    }
+
+    #[cfg(windows)]
+    fn change_token(&self) -> Option<u64> {
+        clipboard_win::raw::seq_num().map(|n| u64::from(n.get()))
+    }
+
+    #[cfg(all(unix, not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))))]
+    fn change_events(&self) -> Option<mpsc::UnboundedReceiver<()>> {
+        x11_change_events(b"CLIPBOARD")
+    }
+
+    /// `org.nspasteboard.ConcealedType` among the pasteboard's types
+    #[cfg(target_os = "macos")]
+    fn is_concealed(&self) -> bool {
+        use objc2_app_kit::NSPasteboard;
+        NSPasteboard::generalPasteboard().types()
+            .is_some_and(|types| types.to_vec().iter().any(|t| t.to_string() == "org.nspasteboard.ConcealedType"))
+    }
+
+    /// The clipboard format Windows documents for keeping content out of
+    /// clipboard monitors
+    #[cfg(windows)]
+    fn is_concealed(&self) -> bool {
+        use clipboard_win::raw;
+        raw::register_format("ExcludeClipboardContentFromMonitorProcessing")
+            .is_some_and(|format| raw::is_format_avail(format.get()))
+    }
+
+    /// KDE's `x-kde-passwordManagerHint` among the selection's targets
+    #[cfg(all(unix, not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))))]
+    fn is_concealed(&self) -> bool {
+        x11_has_target(b"x-kde-passwordManagerHint").unwrap_or(false)
+    }
+
+    #[cfg(all(unix, not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))))]
+    fn read_primary(&self) -> Result<Option<ClipboardContent>> {
+        use arboard::{GetExtLinux, LinuxClipboardKind};
+
+        let mut clipboard = ArboardClipboard::new()
+            .map_err(|e| Error::Clipboard(e.to_string()))?;
+        match clipboard.get().clipboard(LinuxClipboardKind::Primary).text() {
+            Ok(text) if !text.is_empty() => Ok(Some(ClipboardContent::Text(text))),
+            Ok(_) | Err(arboard::Error::ContentNotAvailable) => Ok(None),
+            Err(e) => Err(Error::Clipboard(e.to_string())),
+        }
+    }
+
+    /// Only text is written; the primary selection holds what was selected,
+    /// which other applications paste as text
+    #[cfg(all(unix, not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))))]
+    fn write_primary(&self, content: &ClipboardContent) -> Result<()> {
+        use arboard::{LinuxClipboardKind, SetExtLinux};
+
+        let text = match content {
+            ClipboardContent::Text(text) | ClipboardContent::RichText { plain: text, .. } => text,
+            other => {
+                return Err(Error::Clipboard(format!("the primary selection only holds text, not {}", other.kind())));
+            }
+        };
+        let mut clipboard = ArboardClipboard::new()
+            .map_err(|e| Error::Clipboard(e.to_string()))?;
+        clipboard.set().clipboard(LinuxClipboardKind::Primary).text(text.as_str())
+            .map_err(|e| Error::Clipboard(e.to_string()))
+    }
+
+    #[cfg(all(unix, not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))))]
+    fn primary_change_events(&self) -> Option<mpsc::UnboundedReceiver<()>> {
+        x11_change_events(b"PRIMARY")
+    }
+}
+
+/// Watch for changes of the owner of an X11 selection, such as `CLIPBOARD`,
+/// with XFixes
+///
+/// Events are read on a dedicated thread. Wayland sessions aren't watched,
+/// since XWayland only sees changes made by X11 clients.
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))))]
+fn x11_change_events(selection: &[u8]) -> Option<mpsc::UnboundedReceiver<()>> {
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xfixes::{ConnectionExt as _, SelectionEventMask};
+    use x11rb::protocol::xproto::{ConnectionExt as _, CreateWindowAux, WindowClass};
+    use x11rb::protocol::Event;
+
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        return None;
+    }
+    let (conn, screen) = x11rb::connect(None).ok()?;
+    conn.xfixes_query_version(5, 0).ok()?.reply().ok()?;
+
+    let root = conn.setup().roots.get(screen)?.root;
+    let window = conn.generate_id().ok()?;
+    conn.create_window(
+        0, window, root, 0, 0, 1, 1, 0,
+        WindowClass::INPUT_ONLY, x11rb::COPY_FROM_PARENT, &CreateWindowAux::new(),
+    ).ok()?;
+    let selection = conn.intern_atom(false, selection).ok()?.reply().ok()?.atom;
+    conn.xfixes_select_selection_input(
+        window,
+        selection,
+        SelectionEventMask::SET_SELECTION_OWNER
+            | SelectionEventMask::SELECTION_WINDOW_DESTROY
+            | SelectionEventMask::SELECTION_CLIENT_CLOSE,
+    ).ok()?;
+    conn.flush().ok()?;
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::Builder::new()
+        .name("omniclip-x11-clipboard".to_string())
+        .spawn(move || {
+            while let Ok(event) = conn.wait_for_event() {
+                if matches!(event, Event::XfixesSelectionNotify(_)) && tx.send(()).is_err() {
+                    break;
+                }
+            }
+        })
+        .ok()?;
+    Some(rx)
+}
+
+/// Connection for asking the X11 `CLIPBOARD` owner which targets it offers,
+/// kept open between checks
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))))]
+struct X11Targets {
+    conn: x11rb::rust_connection::RustConnection,
+    window: x11rb::protocol::xproto::Window,
+    clipboard: x11rb::protocol::xproto::Atom,
+    targets: x11rb::protocol::xproto::Atom,
+    property: x11rb::protocol::xproto::Atom,
+}
+
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))))]
+impl X11Targets {
+    fn connect() -> Option<Self> {
+        use x11rb::connection::Connection;
+        use x11rb::protocol::xproto::{ConnectionExt as _, CreateWindowAux, WindowClass};
+
+        let (conn, screen) = x11rb::connect(None).ok()?;
+        let root = conn.setup().roots.get(screen)?.root;
+        let window = conn.generate_id().ok()?;
+        conn.create_window(
+            0, window, root, 0, 0, 1, 1, 0,
+            WindowClass::INPUT_ONLY, x11rb::COPY_FROM_PARENT, &CreateWindowAux::new(),
+        ).ok()?;
+        let atom = |name: &[u8]| conn.intern_atom(false, name).ok()?.reply().ok().map(|reply| reply.atom);
+        let (clipboard, targets, property) = (atom(b"CLIPBOARD")?, atom(b"TARGETS")?, atom(b"OMNICLIP_TARGETS")?);
+        Some(Self { conn, window, clipboard, targets, property })
+    }
+
+    /// Whether the selection is offered as `target`, or `None` if its owner
+    /// didn't answer in time
+    fn has_target(&self, target: &[u8]) -> std::result::Result<Option<bool>, x11rb::errors::ReplyOrIdError> {
+        use std::time::{Duration, Instant};
+
+        use x11rb::connection::Connection;
+        use x11rb::protocol::xproto::{AtomEnum, ConnectionExt as _};
+        use x11rb::protocol::Event;
+
+        const REPLY_TIMEOUT: Duration = Duration::from_millis(200);
+
+        let wanted = self.conn.intern_atom(false, target)?.reply()?.atom;
+        // Answers to earlier checks that timed out
+        while self.conn.poll_for_event()?.is_some() {}
+        self.conn.convert_selection(self.window, self.clipboard, self.targets, self.property, x11rb::CURRENT_TIME)?;
+        self.conn.flush()?;
+
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        while Instant::now() < deadline {
+            match self.conn.poll_for_event()? {
+                Some(Event::SelectionNotify(notify)) if notify.requestor == self.window => {
+                    if notify.property == x11rb::NONE {
+                        return Ok(Some(false));
+                    }
+                    let reply = self.conn.get_property(false, self.window, self.property, AtomEnum::ATOM, 0, 1024)?.reply()?;
+                    return Ok(Some(reply.value32().is_some_and(|mut offered| offered.any(|atom| atom == wanted))));
+                }
+                Some(_) => {}
+                None => std::thread::sleep(Duration::from_millis(5)),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Whether the X11 `CLIPBOARD` selection is offered as `target`
+///
+/// One connection is shared by every check, and opened again only after it
+/// fails. `None` if there is no X server or the owner didn't answer in time.
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))))]
+fn x11_has_target(target: &[u8]) -> Option<bool> {
+    use std::sync::{Mutex, PoisonError};
+
+    static CONNECTION: Mutex<Option<X11Targets>> = Mutex::new(None);
+
+    let mut connection = CONNECTION.lock().unwrap_or_else(PoisonError::into_inner);
+    if connection.is_none() {
+        *connection = X11Targets::connect();
+    }
+    match connection.as_ref()?.has_target(target) {
+        Ok(offered) => offered,
+        Err(e) => {
+            tracing::debug!("X11 connection for clipboard targets failed: {}", e);
+            *connection = None;
+            None
+        }
+    }
+}
+
+/// Image content from RGBA pixels, downscaled to fit in
+/// [`MAX_IMAGE_CONTENT_SIZE`] with its aspect ratio kept
+///
+/// `None` if the pixel data doesn't match the dimensions.
+fn fit_image(width: usize, height: usize, rgba: Vec<u8>) -> Option<ClipboardContent> {
+    if rgba.len() <= MAX_IMAGE_CONTENT_SIZE {
+        return (width * height * 4 == rgba.len()).then_some(ClipboardContent::Image { width, height, rgba });
+    }
+
+    let image = RgbaImage::from_raw(width.try_into().ok()?, height.try_into().ok()?, rgba)?;
+    let scale = (MAX_IMAGE_CONTENT_SIZE as f64 / (width * height * 4) as f64).sqrt();
+    let fitted_width = ((width as f64 * scale) as u32).max(1);
+    let fitted_height = ((height as f64 * scale) as u32).max(1);
+    tracing::debug!("downscaling {}x{} clipboard image to {}x{}", width, height, fitted_width, fitted_height);
+
+    let fitted = imageops::resize(&image, fitted_width, fitted_height, FilterType::Triangle);
+    Some(ClipboardContent::Image {
+        width: fitted_width as usize,
+        height: fitted_height as usize,
+        rgba: fitted.into_raw(),
+    })
+}
+
+/// Read the first application-registered clipboard format verbatim
+///
+/// Registered formats (ids from 0xC000) are the custom ones applications
+/// such as spreadsheets add, named rather than numbered.
+#[cfg(windows)]
+fn read_raw() -> Option<ClipboardContent> {
+    use clipboard_win::{raw, Clipboard};
+
+    const FIRST_REGISTERED_FORMAT: u32 = 0xC000;
+
+    let _guard = Clipboard::new_attempts(10).ok()?;
+    for format in raw::EnumFormats::new().filter(|f| *f >= FIRST_REGISTERED_FORMAT) {
+        if raw::size(format).is_some_and(|size| size.get() > MAX_RAW_CONTENT_SIZE) {
+            continue;
+        }
+        let Some(mime) = raw::format_name_big(format) else {
+            continue;
+        };
+        let mut data = Vec::new();
+        if raw::get_vec(format, &mut data).is_ok() && !data.is_empty() {
+            return Some(ClipboardContent::Raw { mime, data });
+        }
+    }
+    None
+}
+
+#[cfg(not(windows))]
+fn read_raw() -> Option<ClipboardContent> {
+    None
+}
+
+/// Put data on the clipboard under a named format
+#[cfg(windows)]
+fn write_raw(mime: &str, data: &[u8]) -> Result<()> {
+    use clipboard_win::{raw, Clipboard};
+
+    let format = raw::register_format(mime)
+        .ok_or_else(|| Error::Clipboard(format!("can't register clipboard format {}", mime)))?;
+    let _guard = Clipboard::new_attempts(10)
+        .map_err(|e| Error::Clipboard(e.to_string()))?;
+    raw::set(format.get(), data)
+        .map_err(|e| Error::Clipboard(e.to_string()))
+}
+
+#[cfg(not(windows))]
+fn write_raw(mime: &str, _data: &[u8]) -> Result<()> {
+    Err(Error::Clipboard(format!("can't write {} data, raw formats aren't supported on this platform", mime)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_large_image_downscaled_to_fit() {
+        let (width, height) = (2000, 1000);
+        let rgba = vec![200u8; width * height * 4];
+        let Some(ClipboardContent::Image { width: w, height: h, rgba }) = fit_image(width, height, rgba) else {
+            panic!("expected an image");
+        };
+        assert!(rgba.len() <= MAX_IMAGE_CONTENT_SIZE);
+        assert_eq!(rgba.len(), w * h * 4);
+        assert_eq!(w / h, 2);
+        assert!(w > 1000, "shrunk more than needed: {}x{}", w, h);
+
+        // Small images are left alone; mismatched ones are dropped
+        assert!(matches!(fit_image(2, 2, vec![0; 16]), Some(ClipboardContent::Image { width: 2, height: 2, .. })));
+        assert!(fit_image(2, 2, vec![0; 15]).is_none());
+    }
+}
@@ -1,80 +1,233 @@
 //! Cross-platform clipboard abstraction
 
+use std::sync::{Arc, Mutex, PoisonError};
 use std::time::Duration;
-use tokio::sync::mpsc;
-use arboard::Clipboard as ArboardClipboard;
+use tokio::sync::{mpsc, oneshot, watch};
 
-use crate::protocol::{ClipboardContent, ContentHash};
+mod backend;
+mod filter;
+mod normalize;
+mod sink;
+
+pub use backend::{ClipboardBackend, SystemClipboard};
+pub use filter::{ContentFilter, FilterRules};
+pub use normalize::{LineEnding, NormalizeConfig, Normalization};
+pub use sink::{build_sink, ClipboardSink, FileSink, SinkConfig, SinkFuture, SystemClipboardSink};
+
+use crate::protocol::constants::{
+    CLIPBOARD_ACCESS_ATTEMPTS, CLIPBOARD_RETRY_DELAY_MS, CLIPBOARD_TOKEN_POLL_INTERVAL_MS,
+};
+use crate::protocol::{ClipboardContent, ClipboardSelection, ContentHash};
 use crate::{Error, Result};
 
 /// Clipboard manager for reading, writing, and monitoring changes
 pub struct ClipboardManager {
+    backend: Box<dyn ClipboardBackend>,
     /// Last known content hash (for change detection)
     last_hash: Option<ContentHash>,
+    /// Backend change token at the last full read, if supported
+    last_token: Option<u64>,
+    /// Delay before a second read that must agree with the first, if set
+    confirm_delay: Option<Duration>,
+    /// Content never reported as a change, if set
+    filter: Option<Arc<ContentFilter>>,
+    /// Whether the primary selection is watched too
+    watch_primary: bool,
+    /// Last known primary selection hash
+    last_primary_hash: Option<ContentHash>,
 }
 
 impl ClipboardManager {
     pub fn new() -> Self {
-        Self { last_hash: None }
+        Self::with_backend(SystemClipboard)
+    }
+
+    /// Create a manager over a specific backend
+    pub fn with_backend(backend: impl ClipboardBackend + 'static) -> Self {
+        Self {
+            backend: Box::new(backend),
+            last_hash: None,
+            last_token: None,
+            confirm_delay: None,
+            filter: None,
+            watch_primary: false,
+            last_primary_hash: None,
+        }
+    }
+
+    /// Confirm each apparent change with a second read after `delay`,
+    /// ignoring it unless both reads agree
+    pub fn with_confirm_reads(mut self, delay: Duration) -> Self {
+        self.confirm_delay = Some(delay);
+        self
+    }
+
+    /// Drop content rejected by `filter` instead of reporting it. The
+    /// filter is shared, so reloading its rules takes effect here too.
+    pub fn with_filter(mut self, filter: Arc<ContentFilter>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Also report changes of the X11 primary selection, through
+    /// [`ClipboardWriter::primary_changes`]. Does nothing where there is no
+    /// primary selection.
+    pub fn with_primary_selection(mut self) -> Self {
+        self.watch_primary = true;
+        self
+    }
+
+    /// Run `access` against the backend, trying again a few times while it
+    /// fails with a clipboard error, as when another process holds the
+    /// clipboard
+    fn retrying<T>(&self, access: impl Fn(&dyn ClipboardBackend) -> Result<T>) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match access(self.backend.as_ref()) {
+                Err(Error::Clipboard(e)) if attempt < CLIPBOARD_ACCESS_ATTEMPTS => {
+                    tracing::debug!("clipboard unavailable ({}), trying again", e);
+                    std::thread::sleep(Duration::from_millis(CLIPBOARD_RETRY_DELAY_MS));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
     }
 
     /// Read current clipboard content
     pub fn read(&self) -> Result<Option<ClipboardContent>> {
-        let mut clipboard = ArboardClipboard::new()
-            .map_err(|e| Error::Clipboard(e.to_string()))?;
+        self.retrying(|backend| backend.read())
+    }
 
-        // Try to get text content
-        match clipboard.get_text() {
-            Ok(text) if !text.is_empty() => Ok(Some(ClipboardContent::Text(text))),
-            Ok(_) => Ok(None),
-            Err(arboard::Error::ContentNotAvailable) => Ok(None),
-            Err(e) => Err(Error::Clipboard(e.to_string())),
+    /// Read the current content of a selection
+    pub fn read_selection(&self, selection: ClipboardSelection) -> Result<Option<ClipboardContent>> {
+        match selection {
+            ClipboardSelection::Clipboard => self.read(),
+            ClipboardSelection::Primary => self.retrying(|backend| backend.read_primary()),
         }
     }
 
+    /// Read current clipboard content, or `None` if the filter rejects it
+    fn read_allowed(&self) -> Result<Option<ClipboardContent>> {
+        Ok(self.read()?.filter(|content| self.allowed(content)))
+    }
+
+    /// Whether the filter, if any, lets the current `content` be synced
+    fn allowed(&self, content: &ClipboardContent) -> bool {
+        let Some(filter) = &self.filter else {
+            return true;
+        };
+        if filter.excludes_concealed() && self.backend.is_concealed() {
+            tracing::debug!("clipboard content is marked concealed, not syncing it");
+            return false;
+        }
+        if !filter.should_sync(content) {
+            tracing::debug!("clipboard {} matches an exclusion rule, not syncing it", content.kind());
+            return false;
+        }
+        true
+    }
+
     /// Write content to clipboard
     pub fn write(&self, content: &ClipboardContent) -> Result<()> {
-        let mut clipboard = ArboardClipboard::new()
-            .map_err(|e| Error::Clipboard(e.to_string()))?;
+        self.retrying(|backend| backend.write(content))
+    }
 
-        match content {
-            ClipboardContent::Text(text) => {
-                clipboard.set_text(text)
-                    .map_err(|e| Error::Clipboard(e.to_string()))
-            }
-            ClipboardContent::RichText { plain, .. } => {
-                // For now, just set plain text (rich text support varies by platform)
-                clipboard.set_text(plain)
-                    .map_err(|e| Error::Clipboard(e.to_string()))
-            }
+    /// Replace the content of a selection
+    pub fn write_selection(&self, selection: ClipboardSelection, content: &ClipboardContent) -> Result<()> {
+        match selection {
+            ClipboardSelection::Clipboard => self.write(content),
+            ClipboardSelection::Primary => self.retrying(|backend| backend.write_primary(content)),
         }
     }
 
     /// Check if clipboard content has changed since last check
+    ///
+    /// When the backend provides a change token, the full content is only
+    /// read after the token advances. With confirmation reads enabled, an
+    /// apparent change is only reported once a second read agrees with it.
     pub fn check_change(&mut self) -> Result<Option<ClipboardContent>> {
+        let token = self.backend.change_token();
+        if token.is_some() && token == self.last_token {
+            return Ok(None);
+        }
+
         let content = self.read()?;
+        let hash = content.as_ref().map(|c| c.hash());
+        if hash == self.last_hash {
+            self.last_token = token;
+            return Ok(None);
+        }
 
-        match &content {
-            Some(c) => {
-                let hash = c.hash();
-                if self.last_hash.as_ref() != Some(&hash) {
-                    self.last_hash = Some(hash);
-                    Ok(content)
-                } else {
-                    Ok(None)
-                }
-            }
-            None => {
-                self.last_hash = None;
-                Ok(None)
+        if let Some(delay) = self.confirm_delay {
+            std::thread::sleep(delay);
+            let again = self.read()?;
+            if again.as_ref().map(|c| c.hash()) != hash {
+                // Still settling; leave the token so the next poll reads again
+                return Ok(None);
             }
         }
+        self.last_token = token;
+        self.last_hash = hash;
+        // Remembered by hash above, so rejected content is checked only once
+        Ok(content.filter(|content| self.allowed(content)))
+    }
+
+    /// Check if the primary selection has changed since last check, if it
+    /// is watched
+    pub fn check_primary_change(&mut self) -> Result<Option<ClipboardContent>> {
+        if !self.watch_primary {
+            return Ok(None);
+        }
+        let content = self.read_selection(ClipboardSelection::Primary)?;
+        let hash = content.as_ref().map(|c| c.hash());
+        if hash == self.last_primary_hash {
+            return Ok(None);
+        }
+        self.last_primary_hash = hash;
+        Ok(content.filter(|content| self.allowed(content)))
+    }
+
+    /// Change notifications from the backend, if it has them, covering the
+    /// primary selection too when it is watched
+    fn change_events(&self) -> Option<mpsc::UnboundedReceiver<()>> {
+        let events = self.backend.change_events()?;
+        if !self.watch_primary {
+            return Some(events);
+        }
+        let primary = self.backend.primary_change_events()?;
+        let (tx, merged) = mpsc::unbounded_channel();
+        for mut source in [events, primary] {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                while source.recv().await.is_some() {
+                    if tx.send(()).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        Some(merged)
+    }
+
+    /// Whether the backend has a cheap change counter
+    fn has_change_token(&self) -> bool {
+        self.backend.change_token().is_some()
     }
 
     /// Update the last hash without triggering a change event
     /// (used when we write content ourselves)
     pub fn update_hash(&mut self, content: &ClipboardContent) {
         self.last_hash = Some(content.hash());
+        self.last_token = self.backend.change_token();
+    }
+
+    /// [`update_hash`](Self::update_hash) for content written to `selection`
+    pub fn update_selection_hash(&mut self, selection: ClipboardSelection, content: &ClipboardContent) {
+        match selection {
+            ClipboardSelection::Clipboard => self.update_hash(content),
+            ClipboardSelection::Primary => self.last_primary_hash = Some(content.hash()),
+        }
     }
 }
 
@@ -89,24 +242,211 @@ impl Default for ClipboardManager {
 pub struct ClipboardChange {
     pub content: ClipboardContent,
     pub hash: ContentHash,
+    /// Where the content was copied, so peers write it to the same place
+    pub selection: ClipboardSelection,
+}
+
+/// A write for the monitor to make
+struct WriteRequest {
+    selection: ClipboardSelection,
+    content: ClipboardContent,
+    /// Report the content as a local change once written
+    publish: bool,
+    reply: oneshot::Sender<Result<()>>,
+}
+
+/// Handle for writing to the clipboard through a running monitor.
+///
+/// Writes go through the monitor's own [`ClipboardManager`], which records
+/// the written content so it is not reported back as a local change.
+#[derive(Clone)]
+pub struct ClipboardWriter {
+    tx: mpsc::Sender<WriteRequest>,
+    read_tx: mpsc::Sender<oneshot::Sender<Result<Option<ClipboardContent>>>>,
+    primary: Option<ChangeReceiver>,
 }
 
-/// Start a clipboard monitoring task that sends changes to a channel
+impl ClipboardWriter {
+    /// Read the current clipboard content without affecting change detection
+    pub async fn read(&self) -> Result<Option<ClipboardContent>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.read_tx.send(reply_tx).await
+            .map_err(|_| Error::Clipboard("clipboard monitor stopped".to_string()))?;
+        reply_rx.await
+            .map_err(|_| Error::Clipboard("clipboard monitor stopped".to_string()))?
+    }
+
+    /// Write content to the clipboard
+    pub async fn write(&self, content: ClipboardContent) -> Result<()> {
+        self.write_selection(ClipboardSelection::Clipboard, content).await
+    }
+
+    /// Write content to a selection
+    pub async fn write_selection(&self, selection: ClipboardSelection, content: ClipboardContent) -> Result<()> {
+        self.request(selection, content, false).await
+    }
+
+    /// Write content to the clipboard and report it as a local change, as
+    /// if it had been copied
+    ///
+    /// Content the filter rejects is written but not reported.
+    pub async fn publish(&self, content: ClipboardContent) -> Result<()> {
+        self.request(ClipboardSelection::Clipboard, content, true).await
+    }
+
+    async fn request(&self, selection: ClipboardSelection, content: ClipboardContent, publish: bool) -> Result<()> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.tx.send(WriteRequest { selection, content, publish, reply }).await
+            .map_err(|_| Error::Clipboard("clipboard monitor stopped".to_string()))?;
+        reply_rx.await
+            .map_err(|_| Error::Clipboard("clipboard monitor stopped".to_string()))?
+    }
+
+    /// Changes of the primary selection, if the monitor's manager was
+    /// created [`with_primary_selection`](ClipboardManager::with_primary_selection)
+    pub fn primary_changes(&self) -> Option<ChangeReceiver> {
+        self.primary.clone()
+    }
+}
+
+/// How the clipboard monitor notices changes
+///
+/// With [`Events`](Self::Events), each platform uses the cheapest signal it
+/// has:
+///
+/// - X11: XFixes selection notifications; the clipboard is read only when
+///   another application takes ownership of it
+/// - macOS (`NSPasteboard` change count) and Windows (clipboard sequence
+///   number): the counter is checked every
+///   [`CLIPBOARD_TOKEN_POLL_INTERVAL_MS`], and the clipboard read only when
+///   it moves
+/// - Wayland and anything else: polling at the given interval, as with
+///   [`Poll`](Self::Poll)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipboardMonitorMode {
+    /// Check the clipboard every interval
+    Poll(Duration),
+    /// React to platform change notifications, polling at the interval
+    /// where there are none
+    Events(Duration),
+}
+
+impl From<Duration> for ClipboardMonitorMode {
+    fn from(interval: Duration) -> Self {
+        ClipboardMonitorMode::Poll(interval)
+    }
+}
+
+/// What the monitor waits on between checks
+enum Wakeup {
+    Every(Duration),
+    Notified {
+        events: mpsc::UnboundedReceiver<()>,
+        fallback: Duration,
+    },
+}
+
+impl Wakeup {
+    fn new(mode: ClipboardMonitorMode, manager: &ClipboardManager) -> Self {
+        match mode {
+            ClipboardMonitorMode::Poll(interval) => Wakeup::Every(interval),
+            ClipboardMonitorMode::Events(fallback) => match manager.change_events() {
+                Some(events) => Wakeup::Notified { events, fallback },
+                None if manager.has_change_token() => {
+                    Wakeup::Every(Duration::from_millis(CLIPBOARD_TOKEN_POLL_INTERVAL_MS))
+                }
+                None => Wakeup::Every(fallback),
+            },
+        }
+    }
+
+    /// Wait until the clipboard should be checked
+    async fn wait(&mut self) {
+        match self {
+            Wakeup::Every(interval) => tokio::time::sleep(*interval).await,
+            Wakeup::Notified { events, fallback } => {
+                let fallback = *fallback;
+                if events.recv().await.is_none() {
+                    tracing::warn!("clipboard change notifications stopped, polling instead");
+                    *self = Wakeup::Every(fallback);
+                }
+            }
+        }
+    }
+}
+
+/// Receiver for the latest local clipboard change.
+///
+/// Holds a single slot that each change overwrites, so a consumer that falls
+/// behind skips straight to the current content instead of working through
+/// stale changes. `None` until the first change.
+pub type ChangeReceiver = watch::Receiver<Option<ClipboardChange>>;
+
+/// Start a clipboard monitoring task that publishes changes
+///
+/// `mode` is a [`ClipboardMonitorMode`], or a plain poll interval.
 pub fn start_monitor(
-    poll_interval: Duration,
-) -> (mpsc::Receiver<ClipboardChange>, tokio::task::JoinHandle<()>) {
-    let (tx, rx) = mpsc::channel(16);
+    mode: impl Into<ClipboardMonitorMode>,
+) -> (ChangeReceiver, ClipboardWriter, tokio::task::JoinHandle<()>) {
+    start_monitor_with(ClipboardManager::new(), mode)
+}
 
-    let handle = tokio::spawn(async move {
-        let mut manager = ClipboardManager::new();
+/// Run `access` against the monitor's manager on the blocking pool, so slow
+/// or retried clipboard access and confirmation delays don't stall the
+/// runtime
+async fn blocking<T: Send + 'static>(
+    manager: &Arc<Mutex<ClipboardManager>>,
+    access: impl FnOnce(&mut ClipboardManager) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let manager = manager.clone();
+    tokio::task::spawn_blocking(move || access(&mut manager.lock().unwrap_or_else(PoisonError::into_inner)))
+        .await
+        .map_err(|e| Error::Clipboard(format!("clipboard access failed: {}", e)))?
+}
+
+/// Start a clipboard monitoring task over a specific manager
+pub fn start_monitor_with(
+    manager: ClipboardManager,
+    mode: impl Into<ClipboardMonitorMode>,
+) -> (ChangeReceiver, ClipboardWriter, tokio::task::JoinHandle<()>) {
+    let (tx, rx) = watch::channel(None);
+    let (primary_tx, primary_rx) = watch::channel(None);
+    let primary = manager.watch_primary.then_some(primary_rx);
+    let (write_tx, mut write_rx) = mpsc::channel::<WriteRequest>(16);
+    let (read_tx, mut read_rx) = mpsc::channel::<oneshot::Sender<Result<Option<ClipboardContent>>>>(4);
+    let mut wakeup = Wakeup::new(mode.into(), &manager);
+    let manager = Arc::new(Mutex::new(manager));
 
+    let handle = tokio::spawn(async move {
         loop {
-            tokio::time::sleep(poll_interval).await;
+            tokio::select! {
+                _ = wakeup.wait() => {}
+                Some(WriteRequest { selection, content, publish, reply }) = write_rx.recv() => {
+                    let written = blocking(&manager, move |manager| {
+                        manager.write_selection(selection, &content)?;
+                        manager.update_selection_hash(selection, &content);
+                        Ok((publish && manager.allowed(&content)).then_some(content))
+                    }).await;
+                    let result = written.map(|published| {
+                        if let Some(content) = published {
+                            let hash = content.hash();
+                            let _ = tx.send(Some(ClipboardChange { content, hash, selection }));
+                        }
+                    });
+                    let _ = reply.send(result);
+                    continue;
+                }
+                Some(reply) = read_rx.recv() => {
+                    let _ = reply.send(blocking(&manager, |manager| manager.read_allowed()).await);
+                    continue;
+                }
+            }
 
-            match manager.check_change() {
+            match blocking(&manager, ClipboardManager::check_change).await {
                 Ok(Some(content)) => {
                     let hash = content.hash();
-                    if tx.send(ClipboardChange { content, hash }).await.is_err() {
+                    let selection = ClipboardSelection::Clipboard;
+                    if tx.send(Some(ClipboardChange { content, hash, selection })).is_err() {
                         // Receiver dropped, stop monitoring
                         break;
                     }
@@ -116,15 +456,27 @@ pub fn start_monitor(
                     tracing::warn!("clipboard read error: {}", e);
                 }
             }
+            match blocking(&manager, ClipboardManager::check_primary_change).await {
+                Ok(Some(content)) => {
+                    let hash = content.hash();
+                    let _ = primary_tx.send(Some(ClipboardChange { content, hash, selection: ClipboardSelection::Primary }));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::warn!("primary selection read error: {}", e);
+                }
+            }
         }
     });
 
-    (rx, handle)
+    (rx, ClipboardWriter { tx: write_tx, read_tx, primary }, handle)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
 
     #[test]
     fn test_clipboard_roundtrip() {
@@ -141,6 +493,361 @@ mod tests {
         }
     }
 
+    /// In-memory backend that counts full reads
+    struct CountingBackend {
+        content: Mutex<Option<ClipboardContent>>,
+        token: AtomicU64,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl ClipboardBackend for CountingBackend {
+        fn read(&self) -> Result<Option<ClipboardContent>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            Ok(self.content.lock().unwrap().clone())
+        }
+
+        fn write(&self, content: &ClipboardContent) -> Result<()> {
+            *self.content.lock().unwrap() = Some(content.clone());
+            self.token.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn change_token(&self) -> Option<u64> {
+            Some(self.token.load(Ordering::SeqCst))
+        }
+    }
+
+    #[test]
+    fn test_change_token_skips_reads() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let backend = CountingBackend {
+            content: Mutex::new(Some(ClipboardContent::Text("a".to_string()))),
+            token: AtomicU64::new(1),
+            reads: reads.clone(),
+        };
+        let mut manager = ClipboardManager::with_backend(backend);
+
+        assert!(manager.check_change().unwrap().is_some());
+        assert!(manager.check_change().unwrap().is_none());
+        assert!(manager.check_change().unwrap().is_none());
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        manager.write(&ClipboardContent::Text("b".to_string())).unwrap();
+        assert!(manager.check_change().unwrap().is_some());
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+
+        // Our own writes don't cause a full read once recorded
+        let ours = ClipboardContent::Text("c".to_string());
+        manager.write(&ours).unwrap();
+        manager.update_hash(&ours);
+        assert!(manager.check_change().unwrap().is_none());
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+    }
+
+    /// In-memory backend shared with the test
+    struct SharedBackend(Arc<Mutex<Option<ClipboardContent>>>);
+
+    impl ClipboardBackend for SharedBackend {
+        fn read(&self) -> Result<Option<ClipboardContent>> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+
+        fn write(&self, content: &ClipboardContent) -> Result<()> {
+            *self.0.lock().unwrap() = Some(content.clone());
+            Ok(())
+        }
+    }
+
+    /// Backend whose content is always marked concealed
+    struct ConcealedBackend(SharedBackend);
+
+    impl ClipboardBackend for ConcealedBackend {
+        fn read(&self) -> Result<Option<ClipboardContent>> {
+            self.0.read()
+        }
+
+        fn write(&self, content: &ClipboardContent) -> Result<()> {
+            self.0.write(content)
+        }
+
+        fn is_concealed(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_filtered_content_not_reported() {
+        let content = Arc::new(Mutex::new(Some(ClipboardContent::Text("hunter2".to_string()))));
+        let filter = Arc::new(ContentFilter::new(&FilterRules {
+            exclude_patterns: vec!["hunter".to_string()],
+            ..FilterRules::default()
+        }).unwrap());
+        let mut manager = ClipboardManager::with_backend(SharedBackend(content.clone())).with_filter(filter.clone());
+        assert!(manager.check_change().unwrap().is_none());
+        assert!(manager.read_allowed().unwrap().is_none());
+
+        *content.lock().unwrap() = Some(ClipboardContent::Text("fine".to_string()));
+        assert!(manager.check_change().unwrap().is_some());
+
+        // Reloaded rules apply to the next change
+        filter.reload(&FilterRules { exclude_patterns: vec!["fi".to_string()], ..FilterRules::default() }).unwrap();
+        *content.lock().unwrap() = Some(ClipboardContent::Text("final".to_string()));
+        assert!(manager.check_change().unwrap().is_none());
+
+        let mut concealed = ClipboardManager::with_backend(ConcealedBackend(SharedBackend(content.clone())))
+            .with_filter(Arc::new(ContentFilter::default()));
+        assert!(concealed.check_change().unwrap().is_none());
+        let mut shown = ClipboardManager::with_backend(ConcealedBackend(SharedBackend(content)))
+            .with_filter(Arc::new(ContentFilter::new(&FilterRules { exclude_concealed: false, ..FilterRules::default() }).unwrap()));
+        assert!(shown.check_change().unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_stalled_consumer_sees_latest_change() {
+        let content = Arc::new(Mutex::new(None));
+        let manager = ClipboardManager::with_backend(SharedBackend(content.clone()));
+        let (mut rx, _writer, _handle) = start_monitor_with(manager, Duration::from_millis(1));
+
+        // Far more changes than the old queue held, none consumed meanwhile
+        for i in 0..40 {
+            *content.lock().unwrap() = Some(ClipboardContent::Text(format!("copy {}", i)));
+            tokio::time::sleep(Duration::from_millis(3)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert!(rx.has_changed().unwrap());
+        let latest = rx.borrow_and_update().clone().unwrap();
+        assert_eq!(latest.hash, ClipboardContent::Text("copy 39".to_string()).hash());
+        assert!(!rx.has_changed().unwrap());
+    }
+
+    /// Backend with an X11-style primary selection beside the clipboard
+    struct SelectionBackend {
+        clipboard: SharedBackend,
+        primary: Arc<Mutex<Option<ClipboardContent>>>,
+    }
+
+    impl ClipboardBackend for SelectionBackend {
+        fn read(&self) -> Result<Option<ClipboardContent>> {
+            self.clipboard.read()
+        }
+
+        fn write(&self, content: &ClipboardContent) -> Result<()> {
+            self.clipboard.write(content)
+        }
+
+        fn read_primary(&self) -> Result<Option<ClipboardContent>> {
+            Ok(self.primary.lock().unwrap().clone())
+        }
+
+        fn write_primary(&self, content: &ClipboardContent) -> Result<()> {
+            *self.primary.lock().unwrap() = Some(content.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_primary_selection_reported_separately() {
+        let clipboard = Arc::new(Mutex::new(None));
+        let primary = Arc::new(Mutex::new(None));
+        let backend = SelectionBackend { clipboard: SharedBackend(clipboard.clone()), primary: primary.clone() };
+        let manager = ClipboardManager::with_backend(backend).with_primary_selection();
+        let (mut rx, writer, _handle) = start_monitor_with(manager, Duration::from_millis(1));
+        let mut primary_rx = writer.primary_changes().unwrap();
+
+        *primary.lock().unwrap() = Some(ClipboardContent::Text("selected".to_string()));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let change = primary_rx.borrow_and_update().clone().unwrap();
+        assert_eq!(change.selection, ClipboardSelection::Primary);
+        assert_eq!(change.hash, ClipboardContent::Text("selected".to_string()).hash());
+        assert!(!rx.has_changed().unwrap());
+
+        *clipboard.lock().unwrap() = Some(ClipboardContent::Text("copied".to_string()));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(rx.borrow_and_update().clone().unwrap().selection, ClipboardSelection::Clipboard);
+        assert!(!primary_rx.has_changed().unwrap());
+
+        // Our own writes to the primary selection aren't reported back
+        let received = ClipboardContent::Text("from a peer".to_string());
+        writer.write_selection(ClipboardSelection::Primary, received.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(primary.lock().unwrap().as_ref().map(|c| c.hash()), Some(received.hash()));
+        assert!(!primary_rx.has_changed().unwrap());
+
+        // Not watched unless asked for
+        let manager = ClipboardManager::with_backend(SharedBackend(clipboard));
+        let (_rx, writer, _handle) = start_monitor_with(manager, Duration::from_millis(1));
+        assert!(writer.primary_changes().is_none());
+    }
+
+    /// Backend that fails a set number of times before each access succeeds
+    struct BusyBackend {
+        content: SharedBackend,
+        busy_for: usize,
+        attempts: AtomicUsize,
+    }
+
+    impl BusyBackend {
+        fn access(&self) -> Result<()> {
+            if self.attempts.fetch_add(1, Ordering::SeqCst) % (self.busy_for + 1) < self.busy_for {
+                return Err(Error::Clipboard("clipboard is held by another process".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    impl ClipboardBackend for BusyBackend {
+        fn read(&self) -> Result<Option<ClipboardContent>> {
+            self.access()?;
+            self.content.read()
+        }
+
+        fn write(&self, content: &ClipboardContent) -> Result<()> {
+            self.access()?;
+            self.content.write(content)
+        }
+    }
+
+    #[test]
+    fn test_busy_clipboard_retried() {
+        let busy = |busy_for| BusyBackend {
+            content: SharedBackend(Arc::default()),
+            busy_for,
+            attempts: AtomicUsize::new(0),
+        };
+        let text = ClipboardContent::Text("a".to_string());
+
+        // Held briefly: the write and the read both go through
+        let manager = ClipboardManager::with_backend(busy(CLIPBOARD_ACCESS_ATTEMPTS as usize - 1));
+        manager.write(&text).unwrap();
+        assert_eq!(manager.read().unwrap().map(|c| c.hash()), Some(text.hash()));
+
+        // Held throughout: the error surfaces once the attempts run out
+        let manager = ClipboardManager::with_backend(busy(CLIPBOARD_ACCESS_ATTEMPTS as usize));
+        assert!(matches!(manager.write(&text), Err(Error::Clipboard(_))));
+    }
+
+    #[tokio::test]
+    async fn test_busy_clipboard_leaves_runtime_free() {
+        let backend = BusyBackend {
+            content: SharedBackend(Arc::default()),
+            busy_for: 1000,
+            attempts: AtomicUsize::new(0),
+        };
+        let (_rx, writer, _handle) = start_monitor_with(ClipboardManager::with_backend(backend), Duration::from_secs(60));
+        let writes = tokio::spawn(async move {
+            for _ in 0..5 {
+                assert!(writer.write(ClipboardContent::Text("a".to_string())).await.is_err());
+            }
+        });
+
+        // Other tasks keep running while the monitor waits between attempts
+        let started = std::time::Instant::now();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(started.elapsed() < Duration::from_millis(250), "runtime stalled for {:?}", started.elapsed());
+        writes.await.unwrap();
+    }
+
+    /// Backend that plays back a fixed sequence of reads, then repeats the last
+    struct ScriptedBackend(Mutex<std::collections::VecDeque<Option<ClipboardContent>>>);
+
+    impl ClipboardBackend for ScriptedBackend {
+        fn read(&self) -> Result<Option<ClipboardContent>> {
+            let mut reads = self.0.lock().unwrap();
+            if reads.len() > 1 {
+                Ok(reads.pop_front().unwrap())
+            } else {
+                Ok(reads.front().cloned().flatten())
+            }
+        }
+
+        fn write(&self, _content: &ClipboardContent) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_confirm_reads_ignores_transient_empty() {
+        let text = Some(ClipboardContent::Text("a".to_string()));
+        let hash = text.as_ref().map(|c| c.hash());
+        let script = || ScriptedBackend(Mutex::new([text.clone(), text.clone(), None, text.clone()].into()));
+
+        let mut manager = ClipboardManager::with_backend(script()).with_confirm_reads(Duration::ZERO);
+        assert_eq!(manager.check_change().unwrap().map(|c| c.hash()), hash);
+        assert!(manager.check_change().unwrap().is_none());
+        assert!(manager.check_change().unwrap().is_none());
+
+        // Without confirmation the blip reads as a clear and then a new copy
+        let mut manager = ClipboardManager::with_backend(script());
+        assert_eq!(manager.check_change().unwrap().map(|c| c.hash()), hash);
+        assert!(manager.check_change().unwrap().is_none());
+        assert!(manager.check_change().unwrap().is_none());
+        assert_eq!(manager.check_change().unwrap().map(|c| c.hash()), hash);
+    }
+
+    #[tokio::test]
+    async fn test_confirm_delay_leaves_runtime_free() {
+        let text = ClipboardContent::Text("a".to_string());
+        let content = Arc::new(Mutex::new(Some(text.clone())));
+        let delay = Duration::from_millis(500);
+        let manager = ClipboardManager::with_backend(SharedBackend(content)).with_confirm_reads(delay);
+        let (mut rx, _writer, _handle) = start_monitor_with(manager, Duration::from_millis(1));
+
+        // Other tasks keep running while the monitor waits to confirm
+        let started = std::time::Instant::now();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(started.elapsed() < delay / 2, "runtime stalled for {:?}", started.elapsed());
+
+        tokio::time::timeout(delay * 4, rx.changed()).await.unwrap().unwrap();
+        assert_eq!(rx.borrow_and_update().as_ref().unwrap().hash, text.hash());
+    }
+
+    /// In-memory backend that notifies through a channel the test controls
+    struct NotifyingBackend {
+        content: Arc<Mutex<Option<ClipboardContent>>>,
+        events: Mutex<Option<mpsc::UnboundedReceiver<()>>>,
+    }
+
+    impl ClipboardBackend for NotifyingBackend {
+        fn read(&self) -> Result<Option<ClipboardContent>> {
+            Ok(self.content.lock().unwrap().clone())
+        }
+
+        fn write(&self, content: &ClipboardContent) -> Result<()> {
+            *self.content.lock().unwrap() = Some(content.clone());
+            Ok(())
+        }
+
+        fn change_events(&self) -> Option<mpsc::UnboundedReceiver<()>> {
+            self.events.lock().unwrap().take()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_event_mode_reads_on_notification() {
+        let content = Arc::new(Mutex::new(None));
+        let (notify, events) = mpsc::unbounded_channel();
+        let manager = ClipboardManager::with_backend(NotifyingBackend {
+            content: content.clone(),
+            events: Mutex::new(Some(events)),
+        });
+        let fallback = Duration::from_secs(60);
+        let (mut rx, _writer, _handle) = start_monitor_with(manager, ClipboardMonitorMode::Events(fallback));
+
+        // Nothing is read until the platform reports a change
+        *content.lock().unwrap() = Some(ClipboardContent::Text("copied".to_string()));
+        assert!(tokio::time::timeout(Duration::from_secs(1), rx.changed()).await.is_err());
+        notify.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), rx.changed()).await.unwrap().unwrap();
+        assert_eq!(rx.borrow_and_update().as_ref().unwrap().hash, ClipboardContent::Text("copied".to_string()).hash());
+
+        // Without notifications it goes back to polling
+        drop(notify);
+        *content.lock().unwrap() = Some(ClipboardContent::Text("later".to_string()));
+        tokio::time::timeout(fallback * 2, rx.changed()).await.unwrap().unwrap();
+        assert_eq!(rx.borrow_and_update().as_ref().unwrap().hash, ClipboardContent::Text("later".to_string()).hash());
+    }
+
     #[test]
     fn test_change_detection() {
         let mut manager = ClipboardManager::new();
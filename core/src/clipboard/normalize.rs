@@ -0,0 +1,162 @@
+//! Optional text cleanup applied to clipboard content in transit
+//!
+//! Text copied on Windows carries CRLF line endings that show up as stray
+//! carriage returns on Unix, and copied text often drags trailing spaces or
+//! invisible zero-width characters along. Each direction has its own
+//! [`Normalization`], all off by default. Only plain text is touched: rich
+//! text keeps its HTML as-is and raw formats pass through unchanged.
+
+use crate::clipboard::ClipboardChange;
+use crate::protocol::ClipboardContent;
+
+/// Characters removed by `strip_zero_width`
+const ZERO_WIDTH: [char; 5] = ['\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{FEFF}'];
+
+/// Line ending convention to convert text to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    /// The convention of the platform we're running on
+    Native,
+}
+
+impl LineEnding {
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Native if cfg!(windows) => "\r\n",
+            LineEnding::Native => "\n",
+        }
+    }
+}
+
+/// Cleanup applied to text in one direction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Normalization {
+    /// Convert every line ending to this convention
+    pub line_endings: Option<LineEnding>,
+    /// Remove whitespace at the end of each line and of the text
+    pub trim_trailing_whitespace: bool,
+    /// Remove zero-width spaces, joiners and byte order marks
+    pub strip_zero_width: bool,
+}
+
+/// Normalization for each sync direction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NormalizeConfig {
+    /// Applied to content from peers before it is written locally
+    pub inbound: Normalization,
+    /// Applied to local changes before they are sent
+    pub outbound: Normalization,
+}
+
+impl Normalization {
+    /// Whether this normalization never changes anything
+    pub fn is_noop(&self) -> bool {
+        *self == Normalization::default()
+    }
+
+    /// Normalized copy of `content`
+    pub fn apply(&self, content: &ClipboardContent) -> ClipboardContent {
+        match content {
+            ClipboardContent::Text(text) => ClipboardContent::Text(self.text(text)),
+            ClipboardContent::RichText { plain, html } => ClipboardContent::RichText {
+                plain: self.text(plain),
+                html: html.clone(),
+            },
+            ClipboardContent::Raw { .. } | ClipboardContent::Image { .. } => content.clone(),
+        }
+    }
+
+    /// Normalize a change, rehashing it if the content changed
+    pub fn apply_change(&self, change: ClipboardChange) -> ClipboardChange {
+        if self.is_noop() {
+            return change;
+        }
+        let content = self.apply(&change.content);
+        ClipboardChange { hash: content.hash(), content, selection: change.selection }
+    }
+
+    fn text(&self, text: &str) -> String {
+        let text = if self.strip_zero_width {
+            text.replace(ZERO_WIDTH, "")
+        } else {
+            text.to_string()
+        };
+        if self.line_endings.is_none() && !self.trim_trailing_whitespace {
+            return text;
+        }
+
+        let mut out = String::with_capacity(text.len());
+        for segment in text.split_inclusive('\n') {
+            let (line, ending) = match segment.strip_suffix("\r\n") {
+                Some(line) => (line, "\r\n"),
+                None => match segment.strip_suffix('\n') {
+                    Some(line) => (line, "\n"),
+                    None => (segment, ""),
+                },
+            };
+            out.push_str(if self.trim_trailing_whitespace { line.trim_end() } else { line });
+            if !ending.is_empty() {
+                out.push_str(self.line_endings.map_or(ending, LineEnding::as_str));
+            }
+        }
+        if self.trim_trailing_whitespace {
+            out.truncate(out.trim_end().len());
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(normalization: Normalization, input: &str) -> String {
+        match normalization.apply(&ClipboardContent::Text(input.to_string())) {
+            ClipboardContent::Text(text) => text,
+            other => panic!("unexpected content {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_line_ending_conversion() {
+        let lf = Normalization { line_endings: Some(LineEnding::Lf), ..Default::default() };
+        let crlf = Normalization { line_endings: Some(LineEnding::CrLf), ..Default::default() };
+
+        assert_eq!(text(lf, "a\r\nb\r\nc"), "a\nb\nc");
+        assert_eq!(text(crlf, "a\nb\nc\n"), "a\r\nb\r\nc\r\n");
+        // Already-converted endings aren't doubled
+        assert_eq!(text(crlf, "a\r\nb\n"), "a\r\nb\r\n");
+        // A lone carriage return isn't a line ending
+        assert_eq!(text(lf, "a\rb"), "a\rb");
+    }
+
+    #[test]
+    fn test_trim_and_strip() {
+        let trim = Normalization { trim_trailing_whitespace: true, ..Default::default() };
+        assert_eq!(text(trim, "fn main() {  \r\n}\t\r\n\r\n"), "fn main() {\r\n}");
+
+        let strip = Normalization { strip_zero_width: true, ..Default::default() };
+        assert_eq!(text(strip, "\u{FEFF}pass\u{200B}word"), "password");
+    }
+
+    #[test]
+    fn test_noop_leaves_content_alone() {
+        let content = ClipboardContent::Text("a \r\n\u{200B}".to_string());
+        let change = ClipboardChange {
+            hash: content.hash(),
+            content: content.clone(),
+            selection: crate::protocol::ClipboardSelection::Clipboard,
+        };
+        let unchanged = Normalization::default().apply_change(change);
+        assert_eq!(unchanged.hash, content.hash());
+
+        let html = ClipboardContent::RichText { plain: "a\r\nb".to_string(), html: "<p>a</p>\r\n".to_string() };
+        let lf = Normalization { line_endings: Some(LineEnding::Lf), ..Default::default() };
+        assert!(matches!(lf.apply(&html), ClipboardContent::RichText { plain, html }
+            if plain == "a\nb" && html == "<p>a</p>\r\n"));
+    }
+}
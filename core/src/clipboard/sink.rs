@@ -0,0 +1,229 @@
+//! Destinations for clipboard content received from peers
+//!
+//! Received content normally goes to the system clipboard. Headless relay
+//! nodes may have no clipboard at all, so content can instead (or also) be
+//! written to a directory, one file per item.
+
+use std::borrow::Cow;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::clipboard::ClipboardWriter;
+use crate::clock::unix_seconds;
+use crate::protocol::ClipboardContent;
+use crate::{Error, Result};
+
+/// Future returned by [`ClipboardSink::store`]
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// Somewhere received clipboard content is delivered
+pub trait ClipboardSink: Send + Sync {
+    fn store<'a>(&'a self, content: &'a ClipboardContent) -> SinkFuture<'a>;
+}
+
+/// Which sinks receive content from peers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SinkConfig {
+    /// Write to the system clipboard
+    #[default]
+    System,
+    /// Write each item to a file in the directory instead
+    File(PathBuf),
+    /// Write to the system clipboard and to files in the directory
+    SystemAndFile(PathBuf),
+}
+
+/// Build the sink described by `config`
+pub fn build_sink(config: &SinkConfig, writer: ClipboardWriter) -> Arc<dyn ClipboardSink> {
+    match config {
+        SinkConfig::System => Arc::new(SystemClipboardSink::new(writer)),
+        SinkConfig::File(dir) => Arc::new(FileSink::new(dir.clone())),
+        SinkConfig::SystemAndFile(dir) => Arc::new(Both(
+            SystemClipboardSink::new(writer),
+            FileSink::new(dir.clone()),
+        )),
+    }
+}
+
+/// Writes received content to the system clipboard through the monitor
+pub struct SystemClipboardSink {
+    writer: ClipboardWriter,
+}
+
+impl SystemClipboardSink {
+    pub fn new(writer: ClipboardWriter) -> Self {
+        Self { writer }
+    }
+}
+
+impl ClipboardSink for SystemClipboardSink {
+    fn store<'a>(&'a self, content: &'a ClipboardContent) -> SinkFuture<'a> {
+        Box::pin(self.writer.write(content.clone()))
+    }
+}
+
+/// Writes each received item to a timestamped file in a directory
+///
+/// Text is written as `.txt`, rich text as `.html`, PNG data as `.png` and
+/// other raw formats as `.bin`. Names are `<unix seconds>-<hash prefix>`,
+/// so the same content received twice in a second is written once.
+pub struct FileSink {
+    dir: PathBuf,
+}
+
+impl FileSink {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// File name for `content` received at `time`
+    fn file_name(content: &ClipboardContent, time: SystemTime) -> String {
+        let hash: String = content.hash().as_bytes()[..4]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let extension = match content {
+            ClipboardContent::Text(_) => "txt",
+            ClipboardContent::RichText { .. } => "html",
+            ClipboardContent::Raw { mime, .. } if is_png(mime) => "png",
+            ClipboardContent::Raw { .. } => "bin",
+            ClipboardContent::Image { .. } => "png",
+        };
+        format!("{}-{}.{}", unix_seconds(time), hash, extension)
+    }
+}
+
+impl ClipboardSink for FileSink {
+    fn store<'a>(&'a self, content: &'a ClipboardContent) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let bytes = match content {
+                ClipboardContent::Text(text) => Cow::Borrowed(text.as_bytes()),
+                ClipboardContent::RichText { html, .. } => Cow::Borrowed(html.as_bytes()),
+                ClipboardContent::Raw { data, .. } => Cow::Borrowed(data.as_slice()),
+                ClipboardContent::Image { width, height, rgba } => Cow::Owned(encode_png(*width, *height, rgba)?),
+            };
+            let path = self.dir.join(Self::file_name(content, SystemTime::now()));
+
+            tokio::fs::create_dir_all(&self.dir).await
+                .map_err(|e| Error::Clipboard(format!("{}: {}", self.dir.display(), e)))?;
+            tokio::fs::write(&path, &bytes).await
+                .map_err(|e| Error::Clipboard(format!("{}: {}", path.display(), e)))
+        })
+    }
+}
+
+/// Encode RGBA pixels as a PNG file
+fn encode_png(width: usize, height: usize, rgba: &[u8]) -> Result<Vec<u8>> {
+    let invalid = || Error::Clipboard(format!("{}x{} image doesn't match its pixel data", width, height));
+    let image = image::RgbaImage::from_raw(
+        width.try_into().map_err(|_| invalid())?,
+        height.try_into().map_err(|_| invalid())?,
+        rgba.to_vec(),
+    ).ok_or_else(invalid)?;
+    let mut png = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut png, image::ImageFormat::Png)
+        .map_err(|e| Error::Clipboard(format!("PNG encoding failed: {}", e)))?;
+    Ok(png.into_inner())
+}
+
+/// Platform names for PNG clipboard data
+fn is_png(mime: &str) -> bool {
+    mime.eq_ignore_ascii_case("image/png")
+        || mime.eq_ignore_ascii_case("png")
+        || mime == "public.png"
+}
+
+/// Delivers to the system clipboard and a file sink
+struct Both(SystemClipboardSink, FileSink);
+
+impl ClipboardSink for Both {
+    fn store<'a>(&'a self, content: &'a ClipboardContent) -> SinkFuture<'a> {
+        Box::pin(async move {
+            // Keep the file copy even when the clipboard is unavailable
+            let file = self.1.store(content).await;
+            self.0.store(content).await?;
+            file
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("omniclip-sink-{}", Uuid::new_v4()))
+    }
+
+    fn files_in(dir: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut files: Vec<_> = std::fs::read_dir(dir).unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, std::fs::read(&path).unwrap())
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn test_file_sink_writes_each_item() {
+        let dir = temp_dir();
+        let sink = FileSink::new(dir.clone());
+
+        sink.store(&ClipboardContent::Text("hello".to_string())).await.unwrap();
+        sink.store(&ClipboardContent::RichText {
+            plain: "bold".to_string(),
+            html: "<b>bold</b>".to_string(),
+        }).await.unwrap();
+        sink.store(&ClipboardContent::Raw {
+            mime: "image/png".to_string(),
+            data: vec![0x89, b'P', b'N', b'G'],
+        }).await.unwrap();
+
+        let files = files_in(&dir);
+        assert_eq!(files.len(), 3);
+        let by_ext = |ext: &str| files.iter().find(|(name, _)| name.ends_with(ext)).unwrap().1.clone();
+        assert_eq!(by_ext(".txt"), b"hello");
+        assert_eq!(by_ext(".html"), b"<b>bold</b>");
+        assert_eq!(by_ext(".png"), vec![0x89, b'P', b'N', b'G']);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_sink_saves_images_as_png() {
+        let dir = temp_dir();
+        let sink = FileSink::new(dir.clone());
+
+        sink.store(&ClipboardContent::Image { width: 2, height: 1, rgba: vec![255, 0, 0, 255, 0, 0, 255, 255] }).await.unwrap();
+
+        let files = files_in(&dir);
+        assert!(files[0].0.ends_with(".png"));
+        let image = image::load_from_memory_with_format(&files[0].1, image::ImageFormat::Png).unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), (2, 1));
+        assert_eq!(image.into_raw(), vec![255, 0, 0, 255, 0, 0, 255, 255]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_name() {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let name = FileSink::file_name(&ClipboardContent::Raw {
+            mime: "application/x-custom".to_string(),
+            data: vec![1, 2, 3],
+        }, time);
+        assert!(name.starts_with("1700000000-"));
+        assert!(name.ends_with(".bin"));
+    }
+}
@@ -0,0 +1,96 @@
+//! Wall-clock timestamps that tolerate misconfigured clocks
+//!
+//! Sync messages carry Unix timestamps for conflict resolution. A clock set
+//! before the epoch yields 0 instead of panicking, and timestamps handed out
+//! by a [`SyncClock`] never go backwards, so a clock stepped back between
+//! two local changes can't make the newer one look older.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the current wall-clock time
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's real-time clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Seconds since the Unix epoch, or 0 for times before it
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Monotonic Unix timestamps for outgoing sync messages
+pub struct SyncClock {
+    clock: Box<dyn Clock>,
+    last: AtomicU64,
+}
+
+impl SyncClock {
+    pub fn new(clock: impl Clock) -> Self {
+        Self {
+            clock: Box::new(clock),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Current Unix time in seconds, never less than a previous result
+    pub fn timestamp(&self) -> u64 {
+        let now = unix_seconds(self.clock.now());
+        self.last.fetch_max(now, Ordering::Relaxed).max(now)
+    }
+}
+
+impl Default for SyncClock {
+    fn default() -> Self {
+        Self::new(SystemClock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    struct FakeClock(Arc<Mutex<SystemTime>>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn test_pre_epoch_clock() {
+        let now = Arc::new(Mutex::new(UNIX_EPOCH - Duration::from_secs(3600)));
+        let clock = SyncClock::new(FakeClock(now.clone()));
+        assert_eq!(clock.timestamp(), 0);
+
+        *now.lock().unwrap() = UNIX_EPOCH + Duration::from_secs(100);
+        assert_eq!(clock.timestamp(), 100);
+    }
+
+    #[test]
+    fn test_clock_stepped_back() {
+        let now = Arc::new(Mutex::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let clock = SyncClock::new(FakeClock(now.clone()));
+        assert_eq!(clock.timestamp(), 1_000);
+
+        *now.lock().unwrap() = UNIX_EPOCH + Duration::from_secs(500);
+        assert_eq!(clock.timestamp(), 1_000);
+
+        *now.lock().unwrap() = UNIX_EPOCH + Duration::from_secs(1_200);
+        assert_eq!(clock.timestamp(), 1_200);
+    }
+}
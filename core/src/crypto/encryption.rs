@@ -1,21 +1,27 @@
 //! Symmetric encryption using AES-256-GCM
 
 use aes_gcm::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, AeadInPlace, KeyInit, Payload},
     Aes256Gcm, Nonce,
 };
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use hkdf::Hkdf;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
 use x25519_dalek::SharedSecret;
 
-use crate::protocol::constants::SESSION_KEY_INFO;
+use crate::protocol::constants::{
+    AEAD_TAG_SIZE, HKDF_PROTOCOL_VERSION, KEY_RATCHET_INFO, SESSION_KEY_INFO, SESSION_KEY_SALT,
+};
 use crate::{Error, Result};
 
 /// AES-256-GCM session key derived from ECDH shared secret
 #[derive(Clone)]
 pub struct SessionKey {
     cipher: Aes256Gcm,
+    /// Raw key, kept for persistence since the cipher can't give it back
+    bytes: [u8; 32],
 }
 
 impl std::fmt::Debug for SessionKey {
@@ -25,49 +31,116 @@ impl std::fmt::Debug for SessionKey {
 }
 
 impl SessionKey {
-    /// Derive a session key from an ECDH shared secret
-    pub fn from_shared_secret(shared: &SharedSecret) -> Self {
-        // Use HKDF-like derivation: SHA256(shared_secret || SESSION_KEY_INFO)
-        let mut hasher = Sha256::new();
-        hasher.update(shared.as_bytes());
-        hasher.update(SESSION_KEY_INFO);
-        let key_bytes = hasher.finalize();
-
-        let cipher = Aes256Gcm::new_from_slice(&key_bytes)
-            .expect("SHA256 always produces 32 bytes");
+    /// Derive a session key from an ECDH shared secret, the way the
+    /// protocol version negotiated for the pairing does it
+    pub fn from_shared_secret(shared: &SharedSecret, protocol_version: u16) -> Self {
+        Self::derive(shared.as_bytes(), protocol_version)
+    }
 
-        Self { cipher }
+    fn derive(ikm: &[u8; 32], protocol_version: u16) -> Self {
+        let mut key = [0u8; 32];
+        if protocol_version >= HKDF_PROTOCOL_VERSION {
+            Hkdf::<Sha256>::new(Some(SESSION_KEY_SALT), ikm)
+                .expand(SESSION_KEY_INFO, &mut key)
+                .expect("32 bytes is a valid HKDF-SHA256 output length");
+        } else {
+            // Older peers: SHA256(shared_secret || SESSION_KEY_INFO)
+            let mut hasher = Sha256::new();
+            hasher.update(ikm);
+            hasher.update(SESSION_KEY_INFO);
+            key = hasher.finalize().into();
+        }
+        Self::from_bytes(&key)
     }
 
     /// Create a session key from raw bytes (for persistence)
     pub fn from_bytes(bytes: &[u8; 32]) -> Self {
-        let cipher = Aes256Gcm::new_from_slice(bytes)
-            .expect("32 bytes is valid key length");
-        Self { cipher }
+        Self {
+            cipher: Aes256Gcm::new(bytes.into()),
+            bytes: *bytes,
+        }
+    }
+
+    /// Create a session key from bytes of unchecked length, such as key
+    /// material read from storage or another platform
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes: &[u8; 32] = bytes.try_into()
+            .map_err(|_| Error::Crypto(format!("session key must be 32 bytes, got {}", bytes.len())))?;
+        Ok(Self::from_bytes(bytes))
+    }
+
+    /// Raw key bytes, for persisting the key with [`from_bytes`](Self::from_bytes)
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.bytes
+    }
+
+    /// Derive the key for `epoch` from this one, the key of the epoch
+    /// before it
+    ///
+    /// HKDF-Expand with this key as the pseudorandom key and the epoch in
+    /// the info, so both devices reach the same key without exchanging
+    /// anything, and a later key reveals nothing about earlier ones.
+    pub fn ratchet(&self, epoch: u32) -> SessionKey {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::from_prk(&self.bytes)
+            .expect("a session key is a valid HKDF-SHA256 pseudorandom key")
+            .expand_multi_info(&[KEY_RATCHET_INFO, &epoch.to_be_bytes()], &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self::from_bytes(&key)
     }
 
     /// Encrypt data with a random nonce
     pub fn encrypt(&self, plaintext: &[u8]) -> Result<EncryptedPayload> {
+        self.encrypt_with_aad(plaintext, b"")
+    }
+
+    /// Encrypt data with a random nonce, authenticating `aad` alongside it.
+    ///
+    /// The same `aad` must be given to [`decrypt_with_aad`](Self::decrypt_with_aad),
+    /// which ties the ciphertext to the context it was sent in.
+    pub fn encrypt_with_aad(&self, plaintext: &[u8], aad: &[u8]) -> Result<EncryptedPayload> {
+        let mut buffer = Vec::with_capacity(plaintext.len() + AEAD_TAG_SIZE);
+        buffer.extend_from_slice(plaintext);
+        self.encrypt_in_place_with_aad(buffer, aad)
+    }
+
+    /// Encrypt an owned buffer with a random nonce, reusing its allocation.
+    ///
+    /// The tag is appended to the buffer, so reserving [`AEAD_TAG_SIZE`]
+    /// spare bytes up front avoids a reallocation.
+    pub fn encrypt_in_place(&self, buffer: Vec<u8>) -> Result<EncryptedPayload> {
+        self.encrypt_in_place_with_aad(buffer, b"")
+    }
+
+    /// [`encrypt_in_place`](Self::encrypt_in_place), authenticating `aad`
+    /// alongside the buffer
+    pub fn encrypt_in_place_with_aad(&self, mut buffer: Vec<u8>, aad: &[u8]) -> Result<EncryptedPayload> {
         let mut nonce_bytes = [0u8; 12];
         rand::thread_rng().fill_bytes(&mut nonce_bytes);
         let nonce = Nonce::from_slice(&nonce_bytes);
 
-        let ciphertext = self.cipher
-            .encrypt(nonce, plaintext)
+        self.cipher
+            .encrypt_in_place(nonce, aad, &mut buffer)
             .map_err(|e| Error::Crypto(format!("encryption failed: {}", e)))?;
 
         Ok(EncryptedPayload {
             nonce: nonce_bytes,
-            ciphertext,
+            ciphertext: buffer,
         })
     }
 
     /// Decrypt an encrypted payload
     pub fn decrypt(&self, payload: &EncryptedPayload) -> Result<Vec<u8>> {
+        self.decrypt_with_aad(payload, b"")
+    }
+
+    /// Decrypt a payload encrypted with [`encrypt_with_aad`](Self::encrypt_with_aad);
+    /// fails unless `aad` is what it was encrypted with
+    pub fn decrypt_with_aad(&self, payload: &EncryptedPayload, aad: &[u8]) -> Result<Vec<u8>> {
         let nonce = Nonce::from_slice(&payload.nonce);
 
         self.cipher
-            .decrypt(nonce, payload.ciphertext.as_ref())
+            .decrypt(nonce, Payload { msg: &payload.ciphertext, aad })
             .map_err(|e| Error::Crypto(format!("decryption failed: {}", e)))
     }
 }
@@ -81,10 +154,28 @@ pub struct EncryptedPayload {
     pub ciphertext: Vec<u8>,
 }
 
+/// Generate a random key for pre-shared pairing, base64 encoded
+pub fn generate_preshared_key() -> String {
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    BASE64.encode(key)
+}
+
+/// Decode a base64 pre-shared key, which must be exactly 32 bytes
+pub fn decode_preshared_key(encoded: &str) -> Result<[u8; 32]> {
+    BASE64.decode(encoded.trim())
+        .map_err(|e| Error::Crypto(format!("invalid pre-shared key: {}", e)))?
+        .try_into()
+        .map_err(|bytes: Vec<u8>| Error::Crypto(format!(
+            "pre-shared key must be 32 bytes, got {}", bytes.len()
+        )))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::crypto::EphemeralSecret;
+    use crate::protocol::constants::{LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION};
 
     #[test]
     fn test_encrypt_decrypt_roundtrip() {
@@ -98,8 +189,8 @@ mod tests {
         let alice_shared = alice.diffie_hellman(&bob_pub);
         let bob_shared = bob.diffie_hellman(&alice_pub);
 
-        let alice_key = SessionKey::from_shared_secret(&alice_shared);
-        let bob_key = SessionKey::from_shared_secret(&bob_shared);
+        let alice_key = SessionKey::from_shared_secret(&alice_shared, PROTOCOL_VERSION);
+        let bob_key = SessionKey::from_shared_secret(&bob_shared, PROTOCOL_VERSION);
 
         // Alice encrypts
         let plaintext = b"Hello from Alice!";
@@ -115,7 +206,7 @@ mod tests {
         let alice = EphemeralSecret::generate();
         let bob = EphemeralSecret::generate();
         let shared = alice.diffie_hellman(&bob.public_key());
-        let key = SessionKey::from_shared_secret(&shared);
+        let key = SessionKey::from_shared_secret(&shared, PROTOCOL_VERSION);
 
         let plaintext = b"same message";
         let enc1 = key.encrypt(plaintext).unwrap();
@@ -125,4 +216,87 @@ mod tests {
         assert_ne!(enc1.ciphertext, enc2.ciphertext);
         assert_ne!(enc1.nonce, enc2.nonce);
     }
+
+    #[test]
+    fn test_encrypt_in_place_matches_encrypt() {
+        let key = SessionKey::from_bytes(&[3u8; 32]);
+        let plaintext = b"encrypted in place".to_vec();
+
+        let encrypted = key.encrypt_in_place(plaintext.clone()).unwrap();
+        assert_eq!(encrypted.ciphertext.len(), plaintext.len() + AEAD_TAG_SIZE);
+        assert_eq!(key.decrypt(&encrypted).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn test_key_length_checked() {
+        for len in [0, 16, 31, 33, 64] {
+            let err = SessionKey::try_from_bytes(&vec![1u8; len]).unwrap_err();
+            assert!(matches!(err, Error::Crypto(ref m) if m.contains(&format!("got {}", len))), "{}", err);
+        }
+
+        // Same key as the fixed-size constructor
+        let key = SessionKey::try_from_bytes(&[7u8; 32]).unwrap();
+        let encrypted = SessionKey::from_bytes(&[7u8; 32]).encrypt(b"secret").unwrap();
+        assert_eq!(key.decrypt(&encrypted).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn test_derived_key_survives_to_bytes() {
+        let alice = EphemeralSecret::generate();
+        let bob = EphemeralSecret::generate();
+        let bob_pub = bob.public_key();
+        let key = SessionKey::from_shared_secret(&alice.diffie_hellman(&bob_pub), PROTOCOL_VERSION);
+
+        let restored = SessionKey::from_bytes(&key.to_bytes());
+        let encrypted = key.encrypt(b"after restart").unwrap();
+        assert_eq!(restored.decrypt(&encrypted).unwrap(), b"after restart");
+    }
+
+    #[test]
+    fn test_ratchet_is_deterministic_per_epoch() {
+        let key = SessionKey::from_bytes(&[5u8; 32]);
+        let next = key.ratchet(1);
+        assert_eq!(next.to_bytes(), SessionKey::from_bytes(&[5u8; 32]).ratchet(1).to_bytes());
+        assert_ne!(next.to_bytes(), key.to_bytes());
+        assert_ne!(next.to_bytes(), key.ratchet(2).to_bytes());
+
+        let encrypted = next.encrypt(b"epoch 1").unwrap();
+        assert!(key.decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn test_session_key_derivation_vectors() {
+        let ikm: [u8; 32] = std::array::from_fn(|i| i as u8);
+        let hex = |key: SessionKey| key.to_bytes().iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+        // HKDF-SHA256(salt = SESSION_KEY_SALT, ikm, info = SESSION_KEY_INFO, L = 32)
+        assert_eq!(
+            hex(SessionKey::derive(&ikm, PROTOCOL_VERSION)),
+            "ba4ed287acbbcb6ac4232277e45917d8c6aa6c5fef70b02d02988a6eb34f91f9",
+        );
+        // SHA256(ikm || SESSION_KEY_INFO), which peers paired before HKDF
+        // still derive
+        assert_eq!(
+            hex(SessionKey::derive(&ikm, LEGACY_PROTOCOL_VERSION)),
+            "f3245cbef7b28184a838dc5d8332b9cf6363899189c1be36b39e28e4abd963ff",
+        );
+        assert_eq!(
+            hex(SessionKey::from_bytes(&[5u8; 32]).ratchet(1)),
+            "48fc8f9df0975328b00450c64f28c89a2643d97527797a18b1b69849e128ca95",
+        );
+    }
+
+    #[test]
+    fn test_aad_must_match() {
+        let key = SessionKey::from_bytes(&[4u8; 32]);
+        let encrypted = key.encrypt_with_aad(b"bound", b"context a").unwrap();
+
+        assert_eq!(key.decrypt_with_aad(&encrypted, b"context a").unwrap(), b"bound");
+        assert!(matches!(key.decrypt_with_aad(&encrypted, b"context b"), Err(Error::Crypto(_))));
+        assert!(matches!(key.decrypt(&encrypted), Err(Error::Crypto(_))));
+
+        // No AAD is the same as empty AAD
+        let plain = key.encrypt(b"unbound").unwrap();
+        assert_eq!(key.decrypt_with_aad(&plain, b"").unwrap(), b"unbound");
+    }
 }
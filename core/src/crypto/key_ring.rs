@@ -0,0 +1,207 @@
+//! Recent session keys for a paired device
+//!
+//! Each rekey starts a new key epoch. The previous few keys are kept so
+//! messages encrypted before a peer learned of the rekey (for example while
+//! it was offline) still decrypt during a short grace window.
+//!
+//! Keys are rotated by ratcheting: the next epoch's key is derived from the
+//! current one, so both devices can rotate on their own and still agree.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::crypto::SessionKey;
+use crate::protocol::constants::{
+    KEY_EPOCHS_RETAINED, KEY_ROTATION_INTERVAL_SECS, KEY_ROTATION_MESSAGES, MAX_RATCHET_STEPS,
+};
+
+/// Number of rekeys since a pairing's first session key
+///
+/// Both devices count rekeys the same way, so a message's epoch names the
+/// key it was encrypted with. Carried as a plain `u32` on the wire.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyEpoch(pub u32);
+
+impl KeyEpoch {
+    /// The epoch after this one
+    pub fn next(self) -> Self {
+        KeyEpoch(self.0.wrapping_add(1))
+    }
+}
+
+impl fmt::Display for KeyEpoch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "epoch {}", self.0)
+    }
+}
+
+/// When a session key has been used enough to rotate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyRotation {
+    /// Messages encrypted under one key
+    pub after_messages: u64,
+    /// Time since the key came into use
+    pub after: Duration,
+}
+
+impl Default for KeyRotation {
+    fn default() -> Self {
+        Self {
+            after_messages: KEY_ROTATION_MESSAGES,
+            after: Duration::from_secs(KEY_ROTATION_INTERVAL_SECS),
+        }
+    }
+}
+
+/// Session keys for one device, indexed by key epoch
+#[derive(Clone, Debug)]
+pub struct SessionKeyRing {
+    /// Oldest first; never empty
+    keys: VecDeque<(KeyEpoch, SessionKey)>,
+    /// Messages we encrypted under the current key
+    sent: u64,
+    /// When the current key came into use here
+    since: Instant,
+}
+
+impl SessionKeyRing {
+    /// Start a ring at epoch 0 with the key established during pairing
+    pub fn new(key: SessionKey) -> Self {
+        Self::with_epoch(KeyEpoch::default(), key)
+    }
+
+    /// Start a ring at a known epoch
+    pub fn with_epoch(epoch: KeyEpoch, key: SessionKey) -> Self {
+        Self {
+            keys: VecDeque::from([(epoch, key)]),
+            sent: 0,
+            since: Instant::now(),
+        }
+    }
+
+    /// Epoch of the key used for sending
+    pub fn current_epoch(&self) -> KeyEpoch {
+        self.keys.back().expect("key ring is never empty").0
+    }
+
+    /// Key used for sending
+    pub fn current(&self) -> &SessionKey {
+        &self.keys.back().expect("key ring is never empty").1
+    }
+
+    /// Key for a given epoch, if it is still retained
+    pub fn get(&self, epoch: KeyEpoch) -> Option<&SessionKey> {
+        self.keys.iter()
+            .find(|(e, _)| *e == epoch)
+            .map(|(_, key)| key)
+    }
+
+    /// Retained keys, newest first
+    pub fn iter(&self) -> impl Iterator<Item = &SessionKey> {
+        self.keys.iter().rev().map(|(_, key)| key)
+    }
+
+    /// Switch to a new key, dropping epochs beyond the retention limit.
+    /// Returns the new epoch.
+    pub fn rekey(&mut self, key: SessionKey) -> KeyEpoch {
+        let epoch = self.current_epoch().next();
+        self.keys.push_back((epoch, key));
+        while self.keys.len() > KEY_EPOCHS_RETAINED {
+            self.keys.pop_front();
+        }
+        self.sent = 0;
+        self.since = Instant::now();
+        epoch
+    }
+
+    /// Switch to the next epoch, with a key derived from the current one.
+    /// Returns the new epoch.
+    pub fn ratchet(&mut self) -> KeyEpoch {
+        let epoch = self.current_epoch().next();
+        let key = self.current().ratchet(epoch.0);
+        self.rekey(key)
+    }
+
+    /// This ring ratcheted forward to `epoch`, for following a peer that
+    /// rotated first. `None` if `epoch` isn't ahead of ours, or is more than
+    /// [`MAX_RATCHET_STEPS`] ahead.
+    pub fn ratcheted_to(&self, epoch: KeyEpoch) -> Option<SessionKeyRing> {
+        let current = self.current_epoch();
+        if epoch <= current || epoch.0 - current.0 > MAX_RATCHET_STEPS {
+            return None;
+        }
+        let mut ring = self.clone();
+        while ring.current_epoch() < epoch {
+            ring.ratchet();
+        }
+        Some(ring)
+    }
+
+    /// Count a message encrypted under the current key
+    pub fn record_send(&mut self) {
+        self.sent += 1;
+    }
+
+    /// Whether the current key has been used enough to rotate. A key
+    /// nothing was sent under isn't, so a device that's away doesn't fall
+    /// further behind our epoch.
+    pub fn rotation_due(&self, policy: &KeyRotation) -> bool {
+        self.sent > 0 && (self.sent >= policy.after_messages || self.since.elapsed() >= policy.after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rekey_retains_recent_epochs() {
+        let mut ring = SessionKeyRing::new(SessionKey::from_bytes(&[0u8; 32]));
+        let old = ring.current().encrypt(b"epoch 0").unwrap();
+
+        assert_eq!(ring.rekey(SessionKey::from_bytes(&[1u8; 32])), KeyEpoch(1));
+        assert_eq!(ring.current_epoch(), KeyEpoch(1));
+        assert_eq!(ring.get(KeyEpoch(0)).unwrap().decrypt(&old).unwrap(), b"epoch 0");
+
+        for i in 2..=KEY_EPOCHS_RETAINED as u8 {
+            ring.rekey(SessionKey::from_bytes(&[i; 32]));
+        }
+        assert!(ring.get(KeyEpoch(0)).is_none());
+        assert_eq!(ring.iter().count(), KEY_EPOCHS_RETAINED);
+    }
+
+    #[test]
+    fn test_ratchet_agrees_and_catches_up() {
+        let mut desk = SessionKeyRing::new(SessionKey::from_bytes(&[0u8; 32]));
+        let phone = desk.clone();
+
+        desk.ratchet();
+        desk.ratchet();
+        let sent = desk.current().encrypt(b"epoch 2").unwrap();
+
+        let followed = phone.ratcheted_to(KeyEpoch(2)).unwrap();
+        assert_eq!(followed.current_epoch(), KeyEpoch(2));
+        assert_eq!(followed.current().decrypt(&sent).unwrap(), b"epoch 2");
+
+        assert!(phone.ratcheted_to(KeyEpoch(0)).is_none());
+        assert!(phone.ratcheted_to(KeyEpoch(MAX_RATCHET_STEPS + 1)).is_none());
+    }
+
+    #[test]
+    fn test_rotation_due_after_message_count() {
+        let policy = KeyRotation { after_messages: 3, after: Duration::from_secs(3600) };
+        let mut ring = SessionKeyRing::new(SessionKey::from_bytes(&[0u8; 32]));
+        for _ in 0..3 {
+            assert!(!ring.rotation_due(&policy));
+            ring.record_send();
+        }
+        assert!(ring.rotation_due(&policy));
+
+        ring.ratchet();
+        let expired = KeyRotation { after: Duration::ZERO, ..policy };
+        assert!(!ring.rotation_due(&expired));
+        ring.record_send();
+        assert!(ring.rotation_due(&expired));
+    }
+}
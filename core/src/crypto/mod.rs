@@ -6,7 +6,11 @@
 
 mod keys;
 mod encryption;
+mod key_ring;
+mod transport;
 pub mod serde_utils;
 
 pub use keys::{SigningKey, VerifyingKey, EphemeralSecret, PublicKey};
-pub use encryption::{SessionKey, EncryptedPayload};
+pub use encryption::{SessionKey, EncryptedPayload, generate_preshared_key, decode_preshared_key};
+pub use key_ring::{KeyEpoch, KeyRotation, SessionKeyRing};
+pub use transport::TransportKeys;
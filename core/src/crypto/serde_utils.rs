@@ -1,11 +1,51 @@
 //! Base64 serialization utilities for serde
 //!
 //! This module provides reusable serde modules for serializing/deserializing
-//! byte arrays and vectors as base64 strings in JSON.
+//! byte arrays and vectors as base64 strings in JSON. Binary formats, which
+//! aren't human readable, get the raw bytes instead.
 
-use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use std::fmt;
+
+use base64::{Engine as _, display::Base64Display, engine::general_purpose::STANDARD as BASE64};
+use serde::de::Visitor;
 use serde::{Deserialize, Deserializer, Serializer};
 
+fn serialize_bytes<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        // Encode straight into the output rather than via an intermediate String
+        serializer.collect_str(&Base64Display::new(data, &BASE64))
+    } else {
+        serializer.serialize_bytes(data)
+    }
+}
+
+fn deserialize_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    if deserializer.is_human_readable() {
+        let s: String = Deserialize::deserialize(deserializer)?;
+        BASE64.decode(&s).map_err(serde::de::Error::custom)
+    } else {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a byte string")
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(v)
+    }
+}
+
 /// Serialize/deserialize a `Vec<u8>` as a base64 string.
 ///
 /// Usage:
@@ -16,19 +56,18 @@ use serde::{Deserialize, Deserializer, Serializer};
 pub mod base64_bytes {
     use super::*;
 
-    pub fn serialize<S>(data: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        serializer.serialize_str(&BASE64.encode(data))
+        serialize_bytes(data, serializer)
     }
 
     pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let s: String = Deserialize::deserialize(deserializer)?;
-        BASE64.decode(&s).map_err(serde::de::Error::custom)
+        deserialize_bytes(deserializer)
     }
 }
 
@@ -47,16 +86,14 @@ pub mod base64_array_12 {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&BASE64.encode(data))
+        serialize_bytes(data, serializer)
     }
 
     pub fn deserialize<'de, D>(deserializer: D) -> Result<[u8; 12], D::Error>
     where
         D: Deserializer<'de>,
     {
-        let s: String = Deserialize::deserialize(deserializer)?;
-        let bytes = BASE64.decode(&s).map_err(serde::de::Error::custom)?;
-        bytes.try_into().map_err(|_| {
+        deserialize_bytes(deserializer)?.try_into().map_err(|_| {
             serde::de::Error::custom("invalid length: expected 12 bytes")
         })
     }
@@ -77,16 +114,14 @@ pub mod base64_array_32 {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&BASE64.encode(data))
+        serialize_bytes(data, serializer)
     }
 
     pub fn deserialize<'de, D>(deserializer: D) -> Result<[u8; 32], D::Error>
     where
         D: Deserializer<'de>,
     {
-        let s: String = Deserialize::deserialize(deserializer)?;
-        let bytes = BASE64.decode(&s).map_err(serde::de::Error::custom)?;
-        bytes.try_into().map_err(|_| {
+        deserialize_bytes(deserializer)?.try_into().map_err(|_| {
             serde::de::Error::custom("invalid length: expected 32 bytes")
         })
     }
@@ -1,38 +1,93 @@
 //! mDNS service discovery for finding peers on the local network
 
-use std::collections::HashMap;
+mod net;
+
+pub use net::{AddressFilter, IpNet};
+
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
 use tokio::sync::{mpsc, RwLock};
+use tokio::task::{JoinHandle, JoinSet};
 use uuid::Uuid;
 
-use crate::protocol::constants::{SERVICE_TYPE, PROTOCOL_VERSION};
+use crate::protocol::constants::{
+    DEFAULT_MAX_DISCOVERED_PEERS, DEFAULT_PEER_TTL_SECS, MDNS_GOODBYE_TIMEOUT_MS, MDNS_LABEL_MAX, MDNS_TXT_ENTRY_MAX,
+    PEER_NOTIFY_TIMEOUT_MS,
+    PEER_SWEEP_INTERVAL_SECS, PROTOCOL_VERSION, SERVICE_TYPE, protocol_version_supported,
+};
+use crate::sync::ping_peer;
 use crate::{Error, Result};
 
 /// Information about a discovered peer
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PeerInfo {
     pub device_id: Uuid,
     pub device_name: String,
     pub fingerprint: String,
     pub addresses: Vec<IpAddr>,
     pub port: u16,
+    /// The peer has a pairing session open and is showing a pairing code
+    pub accepting_pairing: bool,
+    /// Protocol version the peer advertises; `None` if it doesn't say
+    pub protocol_version: Option<u16>,
+    /// When the peer last resolved, or last answered after going quiet
+    pub last_seen: Instant,
+}
+
+impl PeerInfo {
+    /// Whether `other` advertises the same as this peer, whenever each was
+    /// seen
+    pub fn advertises_same(&self, other: &PeerInfo) -> bool {
+        PeerInfo { last_seen: other.last_seen, ..self.clone() } == *other
+    }
+
+    /// Whether we can pair and sync with the peer, going by the protocol
+    /// version it advertises. Peers that don't advertise one are given the
+    /// benefit of the doubt; the pairing handshake checks again.
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version.is_none_or(protocol_version_supported)
+    }
 }
 
 /// Event from the discovery service
 #[derive(Debug, Clone)]
 pub enum DiscoveryEvent {
+    /// A peer was seen for the first time, or again after being lost
     PeerFound(PeerInfo),
+    /// A known peer resolved again with a different name, address, port or
+    /// pairing flag
+    PeerUpdated(PeerInfo),
     PeerLost(Uuid),
+    /// Another device advertises our ID or identity key, usually because
+    /// it runs from a copy of our data directory. It is not added as a peer.
+    IdentityConflict(PeerInfo),
 }
 
 /// mDNS discovery service
 pub struct DiscoveryService {
     daemon: ServiceDaemon,
     our_device_id: Uuid,
-    peers: Arc<RwLock<HashMap<Uuid, PeerInfo>>>,
+    peers: Arc<RwLock<PeerTable>>,
+    peer_ttl: Duration,
+    /// Which of our addresses are advertised
+    addresses: AddressFilter,
+    /// Task forwarding mDNS browse results, stopped on shutdown
+    browse_task: Mutex<Option<JoinHandle<()>>>,
+    /// What we advertise, once registered
+    registration: Mutex<Option<Registration>>,
+}
+
+/// Our advertised service, kept to re-announce it when a property changes
+#[derive(Clone)]
+struct Registration {
+    device_name: String,
+    fingerprint: String,
+    port: u16,
+    accepting_pairing: bool,
 }
 
 impl DiscoveryService {
@@ -44,102 +99,209 @@ impl DiscoveryService {
         Ok(Self {
             daemon,
             our_device_id: device_id,
-            peers: Arc::new(RwLock::new(HashMap::new())),
+            peers: Arc::new(RwLock::new(PeerTable::new(DEFAULT_MAX_DISCOVERED_PEERS))),
+            peer_ttl: Duration::from_secs(DEFAULT_PEER_TTL_SECS),
+            addresses: AddressFilter::default(),
+            browse_task: Mutex::new(None),
+            registration: Mutex::new(None),
         })
     }
 
+    /// Remember at most `max` peers, forgetting the least recently resolved
+    ///
+    /// Call before [`browse`](Self::browse). Evicted peers are reported as
+    /// [`DiscoveryEvent::PeerLost`] and found again if they resolve later.
+    pub fn with_max_peers(mut self, max: usize) -> Self {
+        self.peers = Arc::new(RwLock::new(PeerTable::new(max)));
+        self
+    }
+
+    /// Check peers that haven't resolved for `ttl`, forgetting those that
+    /// don't answer a ping
+    ///
+    /// mDNS only resolves a peer again when its records change, and one
+    /// that leaves without a goodbye stays cached until its pointer record
+    /// expires, which can take over an hour. Forgotten peers are reported
+    /// as [`DiscoveryEvent::PeerLost`]. Zero disables the check. Call
+    /// before [`browse`](Self::browse).
+    pub fn with_peer_ttl(mut self, ttl: Duration) -> Self {
+        self.peer_ttl = ttl;
+        self
+    }
+
+    /// Advertise only the addresses `filter` passes, and with an interface
+    /// set, browse only on that interface
+    ///
+    /// Call before [`register`](Self::register).
+    pub fn with_address_filter(mut self, filter: AddressFilter) -> Result<Self> {
+        if let Some(interface) = &filter.interface {
+            self.daemon.disable_interface(IfKind::All)
+                .and_then(|()| self.daemon.enable_interface(IfKind::Name(interface.clone())))
+                .map_err(|e| Error::Discovery(e.to_string()))?;
+        }
+        self.addresses = filter;
+        Ok(self)
+    }
+
     /// Register our service for others to discover
+    ///
+    /// Long device names are truncated to fit the instance name label. TXT
+    /// properties that exceed the mDNS entry limit are rejected.
     pub fn register(
         &self,
         device_name: &str,
         fingerprint: &str,
         port: u16,
     ) -> Result<()> {
-        let instance_name = format!("{}-{}", device_name, &self.our_device_id.to_string()[..8]);
+        let registration = Registration {
+            device_name: device_name.to_string(),
+            fingerprint: fingerprint.to_string(),
+            port,
+            accepting_pairing: false,
+        };
+        self.announce(&registration)?;
+        *self.registration.lock().unwrap() = Some(registration);
+        Ok(())
+    }
+
+    /// Advertise whether we have a pairing session open
+    ///
+    /// Re-announces the service when the flag changes; does nothing before
+    /// [`register`](Self::register).
+    pub fn set_accepting_pairing(&self, accepting: bool) -> Result<()> {
+        let mut registration = self.registration.lock().unwrap();
+        let Some(registration) = registration.as_mut().filter(|r| r.accepting_pairing != accepting) else {
+            return Ok(());
+        };
+        let mut updated = registration.clone();
+        updated.accepting_pairing = accepting;
+        self.announce(&updated)?;
+        *registration = updated;
+        Ok(())
+    }
+
+    /// Register the service again as it stands, so peers learn our current
+    /// addresses after a network change; does nothing before
+    /// [`register`](Self::register).
+    pub fn reannounce(&self) -> Result<()> {
+        match self.registration.lock().unwrap().as_ref() {
+            Some(registration) => self.announce(registration),
+            None => Ok(()),
+        }
+    }
+
+    fn announce(&self, registration: &Registration) -> Result<()> {
+        let instance_name = instance_name(&registration.device_name, self.our_device_id);
 
         let mut properties = HashMap::new();
         properties.insert("id".to_string(), self.our_device_id.to_string());
-        properties.insert("fp".to_string(), fingerprint.to_string());
+        properties.insert("fp".to_string(), registration.fingerprint.clone());
         properties.insert("v".to_string(), PROTOCOL_VERSION.to_string());
+        properties.insert("pair".to_string(), u8::from(registration.accepting_pairing).to_string());
+        validate_txt(&properties)?;
+
+        // Without a filter the daemon advertises the addresses of every
+        // interface, and follows them as they change
+        let addresses = if self.addresses.is_set() {
+            let addresses = self.addresses.local_ips();
+            if addresses.is_empty() {
+                return Err(Error::Discovery(format!("no local address on {}", self.addresses)));
+            }
+            addresses
+        } else {
+            Vec::new()
+        };
 
-        let service = ServiceInfo::new(
+        let mut service = ServiceInfo::new(
             SERVICE_TYPE,
             &instance_name,
             &format!("{}.local.", hostname::get()
                 .map(|h| h.to_string_lossy().to_string())
                 .unwrap_or_else(|_| "omniclip".to_string())),
-            (),
-            port,
+            &addresses[..],
+            registration.port,
             properties,
         ).map_err(|e| Error::Discovery(e.to_string()))?;
+        if addresses.is_empty() {
+            service = service.enable_addr_auto();
+        }
 
         self.daemon
             .register(service)
             .map_err(|e| Error::Discovery(e.to_string()))?;
 
-        tracing::info!("registered mDNS service: {}", instance_name);
+        tracing::info!(
+            "registered mDNS service: {} (pairing {})",
+            instance_name,
+            if registration.accepting_pairing { "open" } else { "closed" }
+        );
         Ok(())
     }
 
     /// Start browsing for peers, returns a channel of discovery events
+    ///
+    /// Call this once per service, after [`register`](Self::register) so
+    /// devices sharing our identity can be recognized. Calling it again stops
+    /// the previous browse task, closing the channel it returned.
     pub fn browse(&self) -> Result<mpsc::Receiver<DiscoveryEvent>> {
         let (tx, rx) = mpsc::channel(32);
+        let our_fingerprint = self.registration.lock().unwrap().as_ref().map(|r| r.fingerprint.clone());
         let peers = self.peers.clone();
         let our_id = self.our_device_id;
+        let peer_ttl = self.peer_ttl;
 
         let receiver = self.daemon
             .browse(SERVICE_TYPE)
             .map_err(|e| Error::Discovery(e.to_string()))?;
 
-        tokio::spawn(async move {
-            while let Ok(event) = receiver.recv() {
+        let task = tokio::spawn(async move {
+            let mut conflicts_reported = HashSet::new();
+            let mut sweep = tokio::time::interval(Duration::from_secs(PEER_SWEEP_INTERVAL_SECS));
+            loop {
+                let event = tokio::select! {
+                    event = receiver.recv_async() => match event {
+                        Ok(event) => event,
+                        Err(_) => break,
+                    },
+                    _ = sweep.tick(), if !peer_ttl.is_zero() => {
+                        if !sweep_stale(&peers, peer_ttl, &tx).await {
+                            break;
+                        }
+                        continue;
+                    }
+                };
                 match event {
                     ServiceEvent::ServiceResolved(info) => {
-                        // Parse device info from TXT records
-                        let props = info.get_properties();
-
-                        let device_id = props.get("id")
-                            .and_then(|v| v.val_str().parse::<Uuid>().ok());
-
-                        let fingerprint = props.get("fp")
-                            .map(|v| v.val_str().to_string())
-                            .unwrap_or_default();
-
-                        if let Some(id) = device_id {
+                        if let Some(peer) = peer_from_service(&info) {
+                            if shares_identity(&peer, our_id, our_fingerprint.as_deref()) {
+                                if conflicts_reported.insert((peer.device_id, peer.fingerprint.clone())) {
+                                    tracing::warn!(
+                                        "{} shares this device's identity, likely a copied data directory",
+                                        peer.device_name
+                                    );
+                                    if tx.send(DiscoveryEvent::IdentityConflict(peer)).await.is_err() {
+                                        break;
+                                    }
+                                }
+                                continue;
+                            }
                             // Don't discover ourselves
-                            if id == our_id {
+                            if peer.device_id == our_id {
                                 continue;
                             }
 
-                            let peer = PeerInfo {
-                                device_id: id,
-                                device_name: info.get_fullname()
-                                    .split('.')
-                                    .next()
-                                    .unwrap_or("Unknown")
-                                    .to_string(),
-                                fingerprint,
-                                addresses: info.get_addresses().iter().copied().collect(),
-                                port: info.get_port(),
-                            };
-
-                            peers.write().await.insert(id, peer.clone());
-
-                            if tx.send(DiscoveryEvent::PeerFound(peer)).await.is_err() {
-                                break;
+                            let events = peers.write().await.record(peer, info.get_fullname().to_string());
+                            for event in events {
+                                if tx.send(event).await.is_err() {
+                                    return;
+                                }
                             }
                         }
                     }
                     ServiceEvent::ServiceRemoved(_, fullname) => {
-                        // Try to find and remove the peer
-                        let mut peers_guard = peers.write().await;
-                        let removed_id = peers_guard.iter()
-                            .find(|(_, p)| fullname.contains(&p.device_name))
-                            .map(|(id, _)| *id);
-
-                        if let Some(id) = removed_id {
-                            peers_guard.remove(&id);
-                            if tx.send(DiscoveryEvent::PeerLost(id)).await.is_err() {
+                        let removed = peers.write().await.remove_by_fullname(&fullname);
+                        if let Some(peer) = removed {
+                            if tx.send(DiscoveryEvent::PeerLost(peer.device_id)).await.is_err() {
                                 break;
                             }
                         }
@@ -149,6 +311,9 @@ impl DiscoveryService {
             }
         });
 
+        if let Some(previous) = self.browse_task.lock().unwrap().replace(task) {
+            previous.abort();
+        }
         Ok(rx)
     }
 
@@ -163,7 +328,26 @@ impl DiscoveryService {
     }
 
     /// Shutdown the discovery service
+    ///
+    /// A registered service is withdrawn first, so peers see us leave at
+    /// once instead of when our record expires. This blocks for up to
+    /// [`MDNS_GOODBYE_TIMEOUT_MS`] while the goodbye is sent.
     pub fn shutdown(self) -> Result<()> {
+        if let Some(task) = self.browse_task.lock().unwrap().take() {
+            task.abort();
+        }
+        if let Some(registration) = self.registration.lock().unwrap().take() {
+            let fullname = format!("{}.{}", instance_name(&registration.device_name, self.our_device_id), SERVICE_TYPE);
+            let sent = self.daemon
+                .unregister(&fullname)
+                .map_err(|e| e.to_string())
+                .and_then(|status| {
+                    status.recv_timeout(Duration::from_millis(MDNS_GOODBYE_TIMEOUT_MS)).map_err(|e| e.to_string())
+                });
+            if let Err(e) = sent {
+                tracing::warn!("couldn't withdraw mDNS service {}: {}", fullname, e);
+            }
+        }
         self.daemon
             .shutdown()
             .map_err(|e| Error::Discovery(e.to_string()))?;
@@ -171,19 +355,211 @@ impl DiscoveryService {
     }
 }
 
-/// Get local IP addresses (non-loopback)
+/// mDNS instance name for a device, truncating the name to fit one label
+fn instance_name(device_name: &str, device_id: Uuid) -> String {
+    let suffix = format!("-{}", &device_id.to_string()[..8]);
+    let mut end = device_name.len().min(MDNS_LABEL_MAX - suffix.len());
+    while !device_name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &device_name[..end], suffix)
+}
+
+/// Check each TXT property fits in a single TXT entry
+fn validate_txt(properties: &HashMap<String, String>) -> Result<()> {
+    for (key, value) in properties {
+        let len = key.len() + 1 + value.len();
+        if len > MDNS_TXT_ENTRY_MAX {
+            return Err(Error::Discovery(format!(
+                "TXT property \"{}\" is {} bytes, mDNS allows at most {}",
+                key, len, MDNS_TXT_ENTRY_MAX
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Read a peer from a resolved service's TXT records; `None` if it doesn't
+/// carry a device ID
+fn peer_from_service(info: &ServiceInfo) -> Option<PeerInfo> {
+    let props = info.get_properties();
+
+    let device_id = props.get("id")
+        .and_then(|v| v.val_str().parse::<Uuid>().ok())?;
+
+    let fingerprint = props.get("fp")
+        .map(|v| v.val_str().to_string())
+        .unwrap_or_default();
+
+    let accepting_pairing = props.get("pair").is_some_and(|v| v.val_str() == "1");
+
+    let protocol_version = props.get("v")
+        .and_then(|v| v.val_str().parse::<u16>().ok());
+
+    let mut addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+    sort_by_reachability(&mut addresses);
+
+    Some(PeerInfo {
+        device_id,
+        device_name: info.get_fullname()
+            .split('.')
+            .next()
+            .unwrap_or("Unknown")
+            .to_string(),
+        fingerprint,
+        addresses,
+        port: info.get_port(),
+        accepting_pairing,
+        protocol_version,
+        last_seen: Instant::now(),
+    })
+}
+
+/// Get local IP addresses (non-loopback), the most likely to be reachable
+/// from other devices first
 pub fn get_local_ips() -> Vec<IpAddr> {
-    let mut ips = Vec::new();
+    AddressFilter::default().local_ips()
+}
+
+/// Order addresses by how likely they are to reach a device on the LAN
+///
+/// IPv4 comes first, then routable IPv6. Link-local addresses go last:
+/// IPv6 ones can't be connected to without the interface they belong to,
+/// which mDNS and pairing URLs don't carry.
+pub fn sort_by_reachability(addresses: &mut [IpAddr]) {
+    addresses.sort_by_key(|ip| (reachability(ip), *ip));
+}
 
-    if let Ok(interfaces) = get_if_addrs::get_if_addrs() {
-        for iface in interfaces {
-            if !iface.is_loopback() {
-                ips.push(iface.ip());
+fn reachability(ip: &IpAddr) -> u8 {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) if v4.is_loopback() || v4.is_unspecified() => 4,
+        IpAddr::V4(v4) if v4.is_link_local() => 2,
+        IpAddr::V4(_) => 0,
+        IpAddr::V6(v6) if v6.is_loopback() || v6.is_unspecified() => 4,
+        IpAddr::V6(v6) if v6.is_unicast_link_local() => 3,
+        IpAddr::V6(_) => 1,
+    }
+}
+
+/// Whether `peer` is a different device using our ID or identity key.
+///
+/// Our own advertisement has both our ID and our fingerprint; a device with
+/// only one of the two is a clone. Without a known fingerprint, nothing can
+/// be told apart.
+fn shares_identity(peer: &PeerInfo, our_id: Uuid, our_fingerprint: Option<&str>) -> bool {
+    let Some(our_fingerprint) = our_fingerprint else {
+        return false;
+    };
+    (peer.device_id == our_id) != (peer.fingerprint == our_fingerprint)
+}
+
+/// Ping the peers that haven't been seen within `ttl`, keeping those that
+/// answer and reporting the rest lost. Returns false once `tx` is closed.
+async fn sweep_stale(peers: &RwLock<PeerTable>, ttl: Duration, tx: &mpsc::Sender<DiscoveryEvent>) -> bool {
+    let stale = peers.read().await.stale(ttl, Instant::now());
+    let mut checks = JoinSet::new();
+    for peer in stale {
+        checks.spawn(async move {
+            let alive = ping_peer(&peer, Duration::from_millis(PEER_NOTIFY_TIMEOUT_MS)).await.is_ok();
+            (peer, alive)
+        });
+    }
+    while let Some(Ok((peer, alive))) = checks.join_next().await {
+        let mut table = peers.write().await;
+        if alive {
+            table.touch(&peer.device_id, Instant::now());
+        } else if table.evict_unseen_since(&peer.device_id, peer.last_seen) {
+            drop(table);
+            tracing::debug!("{} not seen for {:?} and not answering, forgetting it", peer.device_name, ttl);
+            if tx.send(DiscoveryEvent::PeerLost(peer.device_id)).await.is_err() {
+                return false;
             }
         }
     }
+    true
+}
+
+/// Resolved peers, bounded by evicting the least recently resolved
+struct PeerTable {
+    capacity: usize,
+    /// Incremented on every resolve to order entries by recency
+    tick: u64,
+    /// Each peer with the mDNS fullname it resolved under and the tick of
+    /// its last resolve
+    entries: HashMap<Uuid, (PeerInfo, String, u64)>,
+}
+
+impl PeerTable {
+    fn new(capacity: usize) -> Self {
+        Self { capacity: capacity.max(1), tick: 0, entries: HashMap::new() }
+    }
+
+    fn get(&self, id: &Uuid) -> Option<&PeerInfo> {
+        self.entries.get(id).map(|(peer, _, _)| peer)
+    }
+
+    fn values(&self) -> impl Iterator<Item = &PeerInfo> {
+        self.entries.values().map(|(peer, _, _)| peer)
+    }
+
+    /// Remove the peer resolved under `fullname`. Device names needn't be
+    /// unique, so only an exact match on the instance, which carries part
+    /// of the device ID, identifies the peer.
+    fn remove_by_fullname(&mut self, fullname: &str) -> Option<PeerInfo> {
+        let id = self.entries.iter()
+            .find(|(_, (_, name, _))| name == fullname)
+            .map(|(id, _)| *id)?;
+        self.entries.remove(&id).map(|(peer, _, _)| peer)
+    }
+
+    /// Peers last seen more than `ttl` before `now`
+    fn stale(&self, ttl: Duration, now: Instant) -> Vec<PeerInfo> {
+        self.values()
+            .filter(|peer| now.saturating_duration_since(peer.last_seen) > ttl)
+            .cloned()
+            .collect()
+    }
+
+    /// Mark a peer as seen at `now` without it resolving
+    fn touch(&mut self, id: &Uuid, now: Instant) {
+        if let Some((peer, _, _)) = self.entries.get_mut(id) {
+            peer.last_seen = now;
+        }
+    }
 
-    ips
+    /// Remove a peer unless it has been seen after `last_seen`, as when it
+    /// resolved again while being checked
+    fn evict_unseen_since(&mut self, id: &Uuid, last_seen: Instant) -> bool {
+        let unseen = self.entries.get(id).is_some_and(|(peer, _, _)| peer.last_seen <= last_seen);
+        if unseen {
+            self.entries.remove(id);
+        }
+        unseen
+    }
+
+    /// Store a peer resolved under `fullname`, returning the events to
+    /// report
+    fn record(&mut self, peer: PeerInfo, fullname: String) -> Vec<DiscoveryEvent> {
+        self.tick += 1;
+        let mut events = Vec::new();
+        match self.entries.insert(peer.device_id, (peer.clone(), fullname, self.tick)) {
+            None => events.push(DiscoveryEvent::PeerFound(peer)),
+            Some((previous, _, _)) if !previous.advertises_same(&peer) => events.push(DiscoveryEvent::PeerUpdated(peer)),
+            Some(_) => {}
+        }
+
+        while self.entries.len() > self.capacity {
+            // The peer just recorded is the most recent, so never evicted
+            let oldest = self.entries.iter()
+                .min_by_key(|(_, (_, _, tick))| *tick)
+                .map(|(id, _)| *id)
+                .expect("table is over capacity, so not empty");
+            self.entries.remove(&oldest);
+            tracing::debug!("discovered peer table full, forgetting {}", oldest);
+            events.push(DiscoveryEvent::PeerLost(oldest));
+        }
+        events
+    }
 }
 
 #[cfg(test)]
@@ -196,4 +572,243 @@ mod tests {
         // Should have at least one IP in most environments
         println!("Local IPs: {:?}", ips);
     }
+
+    #[test]
+    fn test_reachable_addresses_first() {
+        let mut addresses: Vec<IpAddr> = ["fe80::1", "169.254.3.4", "fd00::2", "2001:db8::7", "192.168.1.10"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        sort_by_reachability(&mut addresses);
+        let sorted: Vec<String> = addresses.iter().map(|ip| ip.to_string()).collect();
+        assert_eq!(sorted, ["192.168.1.10", "2001:db8::7", "fd00::2", "169.254.3.4", "fe80::1"]);
+    }
+
+    #[test]
+    fn test_long_device_name_truncated() {
+        let id = Uuid::new_v4();
+        let name = instance_name(&"é".repeat(100), id);
+        assert!(name.len() <= MDNS_LABEL_MAX);
+        assert!(name.ends_with(&id.to_string()[..8]));
+
+        assert_eq!(instance_name("laptop", id), format!("laptop-{}", &id.to_string()[..8]));
+    }
+
+    #[test]
+    fn test_oversized_txt_rejected() {
+        let discovery = DiscoveryService::new(Uuid::new_v4()).unwrap();
+        let err = discovery.register(&"x".repeat(300), &"f".repeat(300), 0).unwrap_err();
+        assert!(matches!(err, Error::Discovery(ref m) if m.contains("\"fp\"") && m.contains("255")), "{}", err);
+        discovery.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_filter_without_addresses_rejected() {
+        let filter = AddressFilter { interface: Some("omniclip-none0".to_string()), subnet: None };
+        let discovery = DiscoveryService::new(Uuid::new_v4()).unwrap().with_address_filter(filter).unwrap();
+        let err = discovery.register("desk", "abcd", 17394).unwrap_err();
+        assert!(matches!(err, Error::Discovery(ref m) if m.contains("omniclip-none0")), "{}", err);
+        discovery.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_withdraws_registration() {
+        let discovery = DiscoveryService::new(Uuid::new_v4()).unwrap();
+        discovery.register("desk", "abcd", 17394).unwrap();
+        let started = Instant::now();
+        discovery.shutdown().unwrap();
+        // The daemon confirmed the goodbye rather than the wait timing out
+        assert!(started.elapsed() < Duration::from_millis(MDNS_GOODBYE_TIMEOUT_MS));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_browse_task() {
+        let discovery = DiscoveryService::new(Uuid::new_v4()).unwrap();
+        let mut first = discovery.browse().unwrap();
+        let mut second = discovery.browse().unwrap();
+
+        // The events sender is dropped when its task stops
+        let closed = tokio::time::timeout(std::time::Duration::from_secs(1), first.recv()).await;
+        assert!(matches!(closed, Ok(None)), "second browse should stop the first task");
+
+        discovery.shutdown().unwrap();
+        let closed = tokio::time::timeout(std::time::Duration::from_secs(1), second.recv()).await;
+        assert!(matches!(closed, Ok(None)), "shutdown should stop the browse task");
+    }
+
+    #[test]
+    fn test_shared_identity_detected() {
+        let our_id = Uuid::new_v4();
+        let peer = |device_id, fingerprint: &str| PeerInfo {
+            device_id,
+            device_name: "desk".to_string(),
+            fingerprint: fingerprint.to_string(),
+            addresses: Vec::new(),
+            port: 17394,
+            accepting_pairing: false,
+            protocol_version: None,
+            last_seen: Instant::now(),
+        };
+
+        // Ourselves, and an unrelated device
+        assert!(!shares_identity(&peer(our_id, "ours"), our_id, Some("ours")));
+        assert!(!shares_identity(&peer(Uuid::new_v4(), "theirs"), our_id, Some("ours")));
+        // Same ID with another key, or another ID with our key
+        assert!(shares_identity(&peer(our_id, "theirs"), our_id, Some("ours")));
+        assert!(shares_identity(&peer(Uuid::new_v4(), "ours"), our_id, Some("ours")));
+        // Unregistered: can't tell
+        assert!(!shares_identity(&peer(our_id, "theirs"), our_id, None));
+    }
+
+    #[test]
+    fn test_protocol_version_compatibility() {
+        let mut peer = peer("desk");
+        assert!(peer.is_compatible());
+        peer.protocol_version = Some(PROTOCOL_VERSION);
+        assert!(peer.is_compatible());
+        peer.protocol_version = Some(PROTOCOL_VERSION + 1);
+        assert!(!peer.is_compatible());
+        peer.protocol_version = Some(0);
+        assert!(!peer.is_compatible());
+    }
+
+    #[test]
+    fn test_peer_read_from_txt_records() {
+        let id = Uuid::new_v4();
+        let resolved = |v: Option<&str>| {
+            let mut properties = HashMap::from([
+                ("id".to_string(), id.to_string()),
+                ("fp".to_string(), "abcd".to_string()),
+                ("pair".to_string(), "1".to_string()),
+            ]);
+            if let Some(v) = v {
+                properties.insert("v".to_string(), v.to_string());
+            }
+            let info = ServiceInfo::new(
+                SERVICE_TYPE, &instance_name("desk", id), "desk.local.", "192.168.1.10", 17394, properties,
+            ).unwrap();
+            peer_from_service(&info).unwrap()
+        };
+
+        let peer = resolved(Some(&PROTOCOL_VERSION.to_string()));
+        assert_eq!(peer.device_id, id);
+        assert_eq!(peer.fingerprint, "abcd");
+        assert!(peer.accepting_pairing);
+        assert_eq!(peer.protocol_version, Some(PROTOCOL_VERSION));
+
+        assert_eq!(resolved(None).protocol_version, None);
+        assert_eq!(resolved(Some("two")).protocol_version, None);
+    }
+
+    fn peer(name: &str) -> PeerInfo {
+        PeerInfo {
+            device_id: Uuid::new_v4(),
+            device_name: name.to_string(),
+            fingerprint: "abcd".to_string(),
+            addresses: vec!["192.168.1.10".parse().unwrap()],
+            port: 17394,
+            accepting_pairing: false,
+            protocol_version: None,
+            last_seen: Instant::now(),
+        }
+    }
+
+    fn fullname(peer: &PeerInfo) -> String {
+        format!("{}.{}", instance_name(&peer.device_name, peer.device_id), SERVICE_TYPE)
+    }
+
+    #[test]
+    fn test_resolve_again_reports_changes_only() {
+        let mut peers = PeerTable::new(DEFAULT_MAX_DISCOVERED_PEERS);
+        let peer = peer("laptop");
+
+        assert!(matches!(peers.record(peer.clone(), fullname(&peer))[..], [DiscoveryEvent::PeerFound(_)]));
+        assert!(peers.record(peer.clone(), fullname(&peer)).is_empty());
+
+        let roamed = PeerInfo {
+            addresses: vec!["10.0.0.7".parse().unwrap()],
+            ..peer.clone()
+        };
+        assert!(matches!(
+            &peers.record(roamed.clone(), fullname(&roamed))[..],
+            [DiscoveryEvent::PeerUpdated(p)] if *p == roamed
+        ));
+        assert_eq!(peers.get(&peer.device_id), Some(&roamed));
+
+        // Opening a pairing session re-announces the peer
+        let pairing = PeerInfo { accepting_pairing: true, ..roamed };
+        assert!(matches!(&peers.record(pairing, fullname(&peer))[..], [DiscoveryEvent::PeerUpdated(p)] if p.accepting_pairing));
+    }
+
+    #[test]
+    fn test_pairing_flag_needs_registration() {
+        let discovery = DiscoveryService::new(Uuid::new_v4()).unwrap();
+        // Nothing to re-announce yet
+        discovery.set_accepting_pairing(true).unwrap();
+        assert!(discovery.registration.lock().unwrap().is_none());
+
+        discovery.register("desk", "abcd", 17394).unwrap();
+        discovery.set_accepting_pairing(true).unwrap();
+        assert!(discovery.registration.lock().unwrap().as_ref().unwrap().accepting_pairing);
+        discovery.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_least_recently_resolved_evicted() {
+        let mut peers = PeerTable::new(2);
+        let (a, b, c) = (peer("a"), peer("b"), peer("c"));
+        peers.record(a.clone(), fullname(&a));
+        peers.record(b.clone(), fullname(&b));
+        // Resolving `a` again makes `b` the oldest
+        peers.record(a.clone(), fullname(&a));
+
+        let events = peers.record(c.clone(), fullname(&c));
+        assert!(matches!(
+            &events[..],
+            [DiscoveryEvent::PeerFound(found), DiscoveryEvent::PeerLost(lost)]
+                if found.device_id == c.device_id && *lost == b.device_id
+        ));
+        assert!(peers.get(&a.device_id).is_some());
+        assert!(peers.get(&b.device_id).is_none());
+        assert_eq!(peers.values().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unseen_peer_evicted_after_ttl() {
+        // Nothing listens on a port just released, so pings are refused
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let gone = PeerInfo { addresses: vec!["127.0.0.1".parse().unwrap()], port, ..peer("gone") };
+        let peers = RwLock::new(PeerTable::new(DEFAULT_MAX_DISCOVERED_PEERS));
+        peers.write().await.record(gone.clone(), fullname(&gone));
+
+        let ttl = Duration::from_millis(50);
+        let (tx, mut rx) = mpsc::channel(8);
+        assert!(sweep_stale(&peers, ttl, &tx).await);
+        assert!(rx.try_recv().is_err(), "within its TTL");
+
+        tokio::time::sleep(ttl * 2).await;
+        let fresh = peer("fresh");
+        peers.write().await.record(fresh.clone(), fullname(&fresh));
+        assert!(sweep_stale(&peers, ttl, &tx).await);
+        assert!(matches!(rx.try_recv(), Ok(DiscoveryEvent::PeerLost(id)) if id == gone.device_id));
+        assert!(peers.read().await.get(&gone.device_id).is_none());
+        assert!(peers.read().await.get(&fresh.device_id).is_some());
+    }
+
+    #[test]
+    fn test_removal_matches_exact_instance() {
+        let mut peers = PeerTable::new(DEFAULT_MAX_DISCOVERED_PEERS);
+        let (mac, other_mac, mac_pro) = (peer("MacBook"), peer("MacBook"), peer("MacBook Pro"));
+        for p in [&mac, &other_mac, &mac_pro] {
+            peers.record(p.clone(), fullname(p));
+        }
+
+        assert_eq!(peers.remove_by_fullname(&fullname(&other_mac)), Some(other_mac.clone()));
+        assert_eq!(peers.remove_by_fullname(&fullname(&other_mac)), None);
+        assert!(peers.get(&mac.device_id).is_some());
+        assert!(peers.get(&mac_pro.device_id).is_some());
+
+        assert_eq!(peers.remove_by_fullname(&fullname(&mac_pro)), Some(mac_pro));
+        assert!(peers.get(&mac.device_id).is_some());
+    }
 }
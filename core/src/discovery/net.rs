@@ -0,0 +1,146 @@
+//! Choosing which local addresses are advertised to peers
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::{Error, Result};
+
+/// An IP network in CIDR notation, such as `192.168.1.0/24`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Network of `addr` with a `prefix_len`-bit prefix
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max {
+            return Err(Error::Config(format!("prefix length {} is over {} for {}", prefix_len, max, addr)));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+
+    /// Whether `ip` is in this network; IPv4-mapped IPv6 addresses count as
+    /// IPv4
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Config(format!("{:?} isn't a network such as 192.168.1.0/24", s));
+        let (addr, prefix_len) = s.split_once('/').ok_or_else(invalid)?;
+        Self::new(addr.parse().map_err(|_| invalid())?, prefix_len.parse().map_err(|_| invalid())?)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Which local addresses are advertised to peers and put in pairing codes
+///
+/// Without either setting every non-loopback address is used, including
+/// VPN and container bridge addresses that peers may not reach.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddressFilter {
+    /// Only addresses on this network interface, such as `en0`
+    pub interface: Option<String>,
+    /// Only addresses in this network
+    pub subnet: Option<IpNet>,
+}
+
+impl AddressFilter {
+    /// Whether any address is left out
+    pub fn is_set(&self) -> bool {
+        self.interface.is_some() || self.subnet.is_some()
+    }
+
+    /// Whether `ip` on the interface named `interface` passes
+    pub fn matches(&self, interface: &str, ip: &IpAddr) -> bool {
+        self.interface.as_deref().is_none_or(|name| name == interface)
+            && self.subnet.is_none_or(|subnet| subnet.contains(ip))
+    }
+
+    /// Local non-loopback addresses that pass, the most likely to be
+    /// reachable from other devices first
+    pub fn local_ips(&self) -> Vec<IpAddr> {
+        let mut ips: Vec<IpAddr> = get_if_addrs::get_if_addrs()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|iface| !iface.is_loopback() && self.matches(&iface.name, &iface.ip()))
+            .map(|iface| iface.ip())
+            .collect();
+        super::sort_by_reachability(&mut ips);
+        ips
+    }
+}
+
+impl fmt::Display for AddressFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.interface, &self.subnet) {
+            (Some(interface), Some(subnet)) => write!(f, "interface {} in {}", interface, subnet),
+            (Some(interface), None) => write!(f, "interface {}", interface),
+            (None, Some(subnet)) => write!(f, "{}", subnet),
+            (None, None) => write!(f, "any address"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subnet_contains() {
+        let lan: IpNet = "192.168.1.0/24".parse().unwrap();
+        assert!(lan.contains(&"192.168.1.77".parse().unwrap()));
+        assert!(lan.contains(&"::ffff:192.168.1.77".parse().unwrap()));
+        assert!(!lan.contains(&"192.168.2.1".parse().unwrap()));
+        assert!(!lan.contains(&"fd00::1".parse().unwrap()));
+
+        let ula: IpNet = "fd00::/8".parse().unwrap();
+        assert!(ula.contains(&"fd12:3456::1".parse().unwrap()));
+        assert!(!ula.contains(&"fe80::1".parse().unwrap()));
+
+        let any: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&"10.1.2.3".parse().unwrap()));
+
+        for bad in ["192.168.1.0", "192.168.1.0/33", "fd00::/129", "lan/24"] {
+            assert!(bad.parse::<IpNet>().is_err(), "{}", bad);
+        }
+        assert_eq!(lan.to_string(), "192.168.1.0/24");
+    }
+
+    #[test]
+    fn test_filter_matches_interface_and_subnet() {
+        let ip: IpAddr = "192.168.1.20".parse().unwrap();
+        assert!(AddressFilter::default().matches("docker0", &ip));
+
+        let filter = AddressFilter {
+            interface: Some("en0".to_string()),
+            subnet: Some("192.168.1.0/24".parse().unwrap()),
+        };
+        assert!(filter.matches("en0", &ip));
+        assert!(!filter.matches("utun3", &ip));
+        assert!(!filter.matches("en0", &"10.8.0.2".parse().unwrap()));
+    }
+}
@@ -24,6 +24,12 @@ pub enum Error {
     #[error("Device not paired: {0}")]
     NotPaired(String),
 
+    #[error("Timed out: {0}")]
+    Timeout(String),
+
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 }
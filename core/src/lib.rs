@@ -4,11 +4,15 @@
 //! content across devices over LAN using mDNS discovery and encrypted
 //! peer-to-peer connections.
 
+pub mod audit;
 pub mod clipboard;
+pub mod clock;
 pub mod crypto;
 pub mod discovery;
 pub mod protocol;
 pub mod service;
+pub mod session;
+pub mod storage;
 pub mod sync;
 
 mod error;
@@ -33,12 +37,64 @@ impl DeviceIdentity {
         }
     }
 
+    /// Load the identity saved in `data_dir`, or create and save one on
+    /// first use, so the device keeps its ID and key across runs
+    ///
+    /// The key is stored unencrypted; see
+    /// [`load_or_create_encrypted`](Self::load_or_create_encrypted).
+    pub fn load_or_create(data_dir: &std::path::Path, name: String) -> Result<Self> {
+        Self::load_or_create_in(&storage::StateStore::open(data_dir)?, name)
+    }
+
+    /// Like [`load_or_create`](Self::load_or_create), with the identity and
+    /// the rest of the state in `data_dir` sealed under `passphrase`
+    pub fn load_or_create_encrypted(data_dir: &std::path::Path, name: String, passphrase: &str) -> Result<Self> {
+        Self::load_or_create_in(&storage::StateStore::open_encrypted(data_dir, passphrase)?, name)
+    }
+
+    /// Like [`load_or_create`](Self::load_or_create), in a store already
+    /// opened, so it can be handed on to
+    /// [`OmniclipService::with_state_store`] without opening it again
+    pub fn load_or_create_in(store: &storage::StateStore, name: String) -> Result<Self> {
+        if let Some(stored) = store.load::<StoredIdentity>(protocol::constants::IDENTITY_FILE)? {
+            // The key is rarely saved again, so seal a plain one right away
+            if store.needs_sealing(protocol::constants::IDENTITY_FILE) {
+                store.save(protocol::constants::IDENTITY_FILE, &stored)?;
+            }
+            return Ok(Self {
+                id: stored.id,
+                name,
+                signing_key: crypto::SigningKey::from_bytes(&stored.signing_key),
+            });
+        }
+        let identity = Self::new(name);
+        let stored = StoredIdentity {
+            id: identity.id,
+            signing_key: identity.signing_key.to_bytes(),
+        };
+        store.save(protocol::constants::IDENTITY_FILE, &stored)?;
+        Ok(identity)
+    }
+
+    /// Shareable identity for peers to pin ahead of pairing
+    pub fn qr_data(&self) -> protocol::IdentityQrData {
+        protocol::IdentityQrData::new(self.id, &self.signing_key.verifying_key(), &self.name)
+    }
+
     /// Get the public key fingerprint for display/verification
     pub fn fingerprint(&self) -> String {
         self.signing_key.public_key_fingerprint()
     }
 }
 
+/// A device identity as saved in [`IDENTITY_FILE`](protocol::constants::IDENTITY_FILE)
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredIdentity {
+    id: uuid::Uuid,
+    #[serde(with = "crypto::serde_utils::base64_array_32")]
+    signing_key: [u8; 32],
+}
+
 /// Configuration for the Omniclip service
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -48,6 +104,97 @@ pub struct Config {
     pub service_name: String,
     /// Path to store persistent data (keys, paired devices)
     pub data_dir: std::path::PathBuf,
+    /// Record sync and pairing metadata to `audit.log` in `data_dir`
+    pub audit_log: bool,
+    /// How to resolve clipboard changes made on two devices at once
+    pub conflict_policy: sync::ConflictPolicy,
+    /// Pause sync while the desktop session is locked
+    pub pause_when_locked: bool,
+    /// Hold the newest clipboard received while sync is paused and write it
+    /// on resume, rather than dropping everything received meanwhile
+    pub queue_while_paused: bool,
+    /// Local clipboard content larger than this many bytes (pixel bytes for
+    /// images) isn't sent to peers
+    pub max_clip_bytes: usize,
+    /// Kinds of content sent and accepted; others are skipped both ways
+    pub allowed_content_types: std::collections::HashSet<protocol::ContentKind>,
+    /// Send the current clipboard to a device as soon as it pairs
+    pub sync_current_on_pair: bool,
+    /// Channel local changes are sent on, so devices can keep separate
+    /// clipboards (say "work" and "personal") over the same pairings
+    pub channel: String,
+    /// Channels content is accepted from; content on any other channel is
+    /// ignored
+    pub channels: std::collections::HashSet<String>,
+    /// Send the current clipboard to a paired device when it comes back on
+    /// the network, rather than waiting for the next local change
+    pub auto_connect_paired: bool,
+    /// Hold the latest change for paired devices that aren't on the network
+    /// and deliver it when they come back
+    pub queue_for_offline: bool,
+    /// Connections the sync server handles at once; more are refused
+    pub max_connections: usize,
+    /// Discovered peers remembered at once; the least recently resolved
+    /// are forgotten beyond this
+    pub max_discovered_peers: usize,
+    /// Discovered peers that haven't resolved for this long are pinged and
+    /// forgotten if they don't answer, for when mDNS misses their removal;
+    /// zero disables the check
+    pub peer_ttl: std::time::Duration,
+    /// Advertise and put in pairing codes only addresses on this network
+    /// interface, such as `en0`
+    pub interface: Option<String>,
+    /// Advertise and put in pairing codes only addresses in this network,
+    /// to keep VPN and container addresses out
+    pub preferred_subnet: Option<discovery::IpNet>,
+    /// How long the clipboard must stay unchanged before a change is sent,
+    /// so a burst of changes sends only the last one
+    pub send_debounce: std::time::Duration,
+    /// Where content received from peers is written
+    pub sink: clipboard::SinkConfig,
+    /// Local content that is never synced, such as copied passwords
+    pub filter: clipboard::FilterRules,
+    /// Text cleanup for received and sent content; off by default
+    pub normalize: clipboard::NormalizeConfig,
+    /// Paired devices whose clipboard is reported but never written locally
+    pub block_writes_from: std::collections::HashSet<uuid::Uuid>,
+    /// Content received within this long of last being sent or received is
+    /// treated as a bounce and not written or reported again; zero disables
+    pub dedup_window: std::time::Duration,
+    /// Number of sent and received clipboard entries kept in memory for
+    /// [`OmniclipService::history`]; zero keeps none
+    pub history_capacity: usize,
+    /// Clipboard messages stamped further than this from our clock, or
+    /// repeating a message id already accepted from the same device, are
+    /// dropped as replays; zero disables the check
+    pub replay_window: std::time::Duration,
+    /// Read the clipboard a second time before reporting a change and
+    /// ignore it unless both reads agree, for platforms that briefly show
+    /// stale or empty content mid-copy
+    pub confirm_reads: bool,
+    /// Watch for clipboard change notifications where the platform has
+    /// them instead of polling; see [`clipboard::ClipboardMonitorMode`]
+    pub clipboard_events: bool,
+    /// Also sync the X11 primary selection, pasted with a middle click,
+    /// with peers that sync theirs; no effect where there is none
+    pub sync_primary_selection: bool,
+    /// When each pairing's session key is rotated; `None` keeps it until
+    /// [`OmniclipService::rekey_device`] is called
+    pub key_rotation: Option<crypto::KeyRotation>,
+    /// Service events buffered for the application; once full, the service
+    /// waits for room and reports [`ServiceEvent::Backpressure`]
+    pub event_capacity: usize,
+    /// Events from the sync server buffered for the service; once full,
+    /// incoming connections wait for room
+    pub server_event_capacity: usize,
+    /// Encrypt whole connections, not just clipboard content, so device
+    /// names and message types aren't visible on the network. Connections
+    /// without it are refused, so every device needs it set.
+    pub transport_encryption: bool,
+    /// Clipboard messages a device hasn't acknowledged within this long
+    /// are sent again, up to [`protocol::constants::MAX_RETRANSMITS`]
+    /// times; zero disables
+    pub ack_timeout: std::time::Duration,
 }
 
 impl Default for Config {
@@ -56,6 +203,107 @@ impl Default for Config {
             port: protocol::constants::DEFAULT_PORT,
             service_name: protocol::constants::SERVICE_TYPE.to_string(),
             data_dir: dirs_home().join(".omniclip"),
+            audit_log: false,
+            conflict_policy: sync::ConflictPolicy::default(),
+            pause_when_locked: false,
+            queue_while_paused: false,
+            max_clip_bytes: protocol::constants::DEFAULT_MAX_CLIP_BYTES,
+            allowed_content_types: protocol::ContentKind::ALL.into_iter().collect(),
+            sync_current_on_pair: true,
+            channel: protocol::constants::DEFAULT_CHANNEL.to_string(),
+            channels: [protocol::constants::DEFAULT_CHANNEL.to_string()].into(),
+            auto_connect_paired: true,
+            queue_for_offline: false,
+            max_connections: protocol::constants::DEFAULT_MAX_CONNECTIONS,
+            max_discovered_peers: protocol::constants::DEFAULT_MAX_DISCOVERED_PEERS,
+            peer_ttl: std::time::Duration::from_secs(protocol::constants::DEFAULT_PEER_TTL_SECS),
+            interface: None,
+            preferred_subnet: None,
+            send_debounce: std::time::Duration::from_millis(protocol::constants::SEND_DEBOUNCE_MS),
+            sink: clipboard::SinkConfig::default(),
+            filter: clipboard::FilterRules::default(),
+            normalize: clipboard::NormalizeConfig::default(),
+            block_writes_from: std::collections::HashSet::new(),
+            dedup_window: std::time::Duration::from_secs(protocol::constants::DEDUP_WINDOW_SECS),
+            history_capacity: protocol::constants::DEFAULT_HISTORY_CAPACITY,
+            replay_window: std::time::Duration::from_secs(protocol::constants::REPLAY_WINDOW_SECS),
+            confirm_reads: false,
+            clipboard_events: false,
+            sync_primary_selection: false,
+            key_rotation: Some(crypto::KeyRotation::default()),
+            event_capacity: protocol::constants::DEFAULT_EVENT_CAPACITY,
+            server_event_capacity: protocol::constants::DEFAULT_SERVER_EVENT_CAPACITY,
+            transport_encryption: false,
+            ack_timeout: std::time::Duration::from_millis(protocol::constants::ACK_TIMEOUT_MS),
+        }
+    }
+}
+
+/// Settings a config file may set; the rest of [`Config`] keeps its defaults
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    port: Option<u16>,
+    service_name: Option<String>,
+    data_dir: Option<std::path::PathBuf>,
+    exclude_patterns: Option<Vec<String>>,
+    interface: Option<String>,
+    preferred_subnet: Option<String>,
+}
+
+impl Config {
+    /// Load a TOML config file, falling back to defaults for anything it
+    /// leaves out
+    ///
+    /// The file may set `port`, `service_name` and `data_dir`, so separate
+    /// files can run several instances on one host, and
+    /// `exclude_patterns`, regular expressions for text never to sync.
+    /// `interface` and `preferred_subnet` limit the addresses advertised:
+    ///
+    /// ```toml
+    /// port = 17400
+    /// data_dir = "instance-b"
+    /// exclude_patterns = ['^ghp_[A-Za-z0-9]{36}$']
+    /// preferred_subnet = "192.168.1.0/24"
+    /// ```
+    ///
+    /// A relative `data_dir` is taken from the file's directory.
+    pub fn from_file(path: &std::path::Path) -> Result<Config> {
+        let text = std::fs::read_to_string(path)?;
+        let file: ConfigFile = toml::from_str(&text)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+
+        let mut config = Config::default();
+        if let Some(port) = file.port {
+            if port == 0 {
+                return Err(Error::Config(format!("{}: port must be nonzero", path.display())));
+            }
+            config.port = port;
+        }
+        if let Some(service_name) = file.service_name {
+            config.service_name = service_name;
+        }
+        if let Some(data_dir) = file.data_dir {
+            config.data_dir = path.parent().unwrap_or(std::path::Path::new("")).join(data_dir);
+        }
+        if let Some(patterns) = file.exclude_patterns {
+            config.filter.exclude_patterns = patterns;
+            clipboard::ContentFilter::new(&config.filter)
+                .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        }
+        config.interface = file.interface;
+        if let Some(subnet) = file.preferred_subnet {
+            config.preferred_subnet = Some(subnet.parse()
+                .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?);
+        }
+        Ok(config)
+    }
+
+    /// Which local addresses are advertised and put in pairing codes
+    pub fn address_filter(&self) -> discovery::AddressFilter {
+        discovery::AddressFilter {
+            interface: self.interface.clone(),
+            subnet: self.preferred_subnet,
         }
     }
 }
@@ -67,5 +315,75 @@ fn dirs_home() -> std::path::PathBuf {
 // Re-export key types for convenience
 pub use crypto::{EncryptedPayload, SessionKey};
 pub use discovery::PeerInfo;
-pub use protocol::{ClipboardContent, Message};
-pub use service::{OmniclipService, ServiceEvent};
+pub use protocol::{ClipboardContent, ContentKind, Message, NegotiatedFeatures, PairingSessionInfo, WireCodec};
+pub use service::{HistoryEntry, OmniclipService, PairedDeviceSummary, ServiceEvent, SyncStats};
+pub use session::SessionState;
+pub use sync::{ConflictPolicy, SyncDirection};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(text: &str) -> (std::path::PathBuf, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("omniclip-config-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("omniclip.toml");
+        std::fs::write(&path, text).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn test_config_from_file() {
+        let (dir, path) = write_config("port = 17400\ndata_dir = \"instance-b\"\n");
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(config.port, 17400);
+        assert_eq!(config.data_dir, dir.join("instance-b"));
+        assert_eq!(config.service_name, Config::default().service_name);
+
+        std::fs::write(&path, "exclude_patterns = ['^sk-']").unwrap();
+        assert_eq!(Config::from_file(&path).unwrap().filter.exclude_patterns, ["^sk-"]);
+
+        std::fs::write(&path, "interface = \"en0\"\npreferred_subnet = \"192.168.1.0/24\"").unwrap();
+        let addresses = Config::from_file(&path).unwrap().address_filter();
+        assert_eq!(addresses.interface.as_deref(), Some("en0"));
+        assert_eq!(addresses.subnet, Some("192.168.1.0/24".parse().unwrap()));
+
+        for bad in ["port = 0", "port = \"high\"", "prot = 17400", "exclude_patterns = ['(']", "preferred_subnet = \"lan\""] {
+            std::fs::write(&path, bad).unwrap();
+            let err = Config::from_file(&path).unwrap_err();
+            assert!(matches!(err, Error::Config(_)), "{}: {}", bad, err);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(Config::from_file(&path), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_identity_kept_across_runs() {
+        let dir = std::env::temp_dir().join(format!("omniclip-identity-{}", uuid::Uuid::new_v4()));
+        let first = DeviceIdentity::load_or_create_encrypted(&dir, "laptop".to_string(), "correct horse").unwrap();
+        let again = DeviceIdentity::load_or_create_encrypted(&dir, "renamed".to_string(), "correct horse").unwrap();
+        assert_eq!(again.id, first.id);
+        assert_eq!(again.fingerprint(), first.fingerprint());
+        assert_eq!(again.name, "renamed");
+
+        // Sealed, so it can't be read without the passphrase
+        assert!(DeviceIdentity::load_or_create(&dir, "laptop".to_string()).is_err());
+        assert!(DeviceIdentity::load_or_create_encrypted(&dir, "laptop".to_string(), "wrong").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_plain_identity_sealed_when_encrypted() {
+        let dir = std::env::temp_dir().join(format!("omniclip-identity-{}", uuid::Uuid::new_v4()));
+        let plain = DeviceIdentity::load_or_create(&dir, "laptop".to_string()).unwrap();
+        let path = dir.join(protocol::constants::IDENTITY_FILE);
+        assert!(!std::fs::read(&path).unwrap().starts_with(protocol::constants::SEALED_STATE_MAGIC));
+
+        let store = storage::StateStore::open_encrypted(&dir, "correct horse").unwrap();
+        let sealed = DeviceIdentity::load_or_create_in(&store, "laptop".to_string()).unwrap();
+        assert_eq!(sealed.id, plain.id);
+        assert_eq!(sealed.fingerprint(), plain.fingerprint());
+        assert!(std::fs::read(&path).unwrap().starts_with(protocol::constants::SEALED_STATE_MAGIC));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
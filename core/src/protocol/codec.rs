@@ -0,0 +1,90 @@
+//! Wire encodings for protocol messages
+//!
+//! JSON is what every peer speaks, and what the pairing handshake itself is
+//! sent in. Builds with the `cbor` feature can also speak CBOR, which
+//! carries ciphertext as raw bytes rather than base64. Pairing picks the
+//! codec; a receiver tells the two apart by the first byte, since a JSON
+//! message always opens with `{` and a CBOR one with a map header.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// Encoding of messages on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WireCodec {
+    #[default]
+    Json,
+    Cbor,
+}
+
+impl WireCodec {
+    /// Codecs this build can decode, most preferred first
+    pub fn supported() -> Vec<WireCodec> {
+        if cfg!(feature = "cbor") {
+            vec![WireCodec::Cbor, WireCodec::Json]
+        } else {
+            vec![WireCodec::Json]
+        }
+    }
+
+    /// Pick the codec for a pairing: the first one the requester offered
+    /// that we support, or JSON if there is none
+    pub fn negotiate(offered: &[WireCodec]) -> WireCodec {
+        let ours = Self::supported();
+        offered.iter().copied().find(|codec| ours.contains(codec)).unwrap_or_default()
+    }
+
+    /// Codec an encoded message was written with
+    pub fn detect(bytes: &[u8]) -> WireCodec {
+        match bytes.first() {
+            Some(b'{') | None => WireCodec::Json,
+            Some(_) => WireCodec::Cbor,
+        }
+    }
+
+    pub fn is_json(&self) -> bool {
+        *self == WireCodec::Json
+    }
+
+    pub(crate) fn encode_into<T: Serialize>(self, value: &T, out: &mut Vec<u8>) -> Result<()> {
+        match self {
+            WireCodec::Json => serde_json::to_writer(out, value).map_err(Error::Serialization),
+            #[cfg(feature = "cbor")]
+            WireCodec::Cbor => ciborium::into_writer(value, out)
+                .map_err(|e| Error::InvalidMessage(format!("cbor encoding failed: {}", e))),
+            #[cfg(not(feature = "cbor"))]
+            WireCodec::Cbor => Err(not_built()),
+        }
+    }
+
+    pub(crate) fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        match self {
+            WireCodec::Json => serde_json::from_slice(bytes).map_err(Error::Serialization),
+            #[cfg(feature = "cbor")]
+            WireCodec::Cbor => ciborium::from_reader(bytes)
+                .map_err(|e| Error::InvalidMessage(format!("cbor decoding failed: {}", e))),
+            #[cfg(not(feature = "cbor"))]
+            WireCodec::Cbor => Err(not_built()),
+        }
+    }
+}
+
+#[cfg(not(feature = "cbor"))]
+fn not_built() -> Error {
+    Error::InvalidMessage("cbor support isn't built in".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_falls_back_to_json() {
+        assert_eq!(WireCodec::negotiate(&[]), WireCodec::Json);
+        assert_eq!(WireCodec::negotiate(&[WireCodec::Json]), WireCodec::Json);
+        assert_eq!(WireCodec::negotiate(&WireCodec::supported()), WireCodec::supported()[0]);
+    }
+}
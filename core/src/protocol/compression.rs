@@ -0,0 +1,63 @@
+//! Deflate compression of clipboard content
+//!
+//! Content is compressed after serialization and before encryption, since
+//! ciphertext doesn't compress. Hashes are always over the uncompressed
+//! content.
+
+use std::io::{Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use crate::error::{Error, Result};
+
+/// Compress `data`, or return `None` if that doesn't make it smaller
+pub(crate) fn deflate(data: &[u8]) -> Option<Vec<u8>> {
+    // Clipboard content is on the LAN for milliseconds; favour speed
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len() / 2), flate2::Compression::fast());
+    encoder.write_all(data).ok()?;
+    let compressed = encoder.finish().ok()?;
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+/// Decompress `data`, failing if it expands to more than `limit` bytes
+pub(crate) fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    DeflateDecoder::new(data)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|e| Error::InvalidMessage(format!("corrupt compressed content: {}", e)))?;
+    if out.len() > limit {
+        return Err(Error::InvalidMessage(format!(
+            "compressed content expands past {} bytes",
+            limit
+        )));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deflate_roundtrip() {
+        let data = "2026-10-17 INFO request served\n".repeat(2000).into_bytes();
+        let compressed = deflate(&data).unwrap();
+        assert!(compressed.len() < data.len() / 10);
+        assert_eq!(inflate(&compressed, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn test_incompressible_data_is_left_alone() {
+        let data: Vec<u8> = (0..4096).map(|_| rand::random::<u8>()).collect();
+        assert!(deflate(&data).is_none());
+    }
+
+    #[test]
+    fn test_inflate_limit() {
+        let compressed = deflate(&[0u8; 10_000]).unwrap();
+        assert!(inflate(&compressed, 9_999).is_err());
+        assert_eq!(inflate(&compressed, 10_000).unwrap().len(), 10_000);
+    }
+}
@@ -0,0 +1,210 @@
+//! Golden wire-format vectors
+//!
+//! Each vector in `core/tests/vectors` is the exact JSON this crate writes
+//! for a fixed value. Peers on other platforms, such as the iOS app, parse
+//! the same bytes, so a renamed field or changed enum tag here breaks them
+//! silently. These tests fail on any byte-level change instead.
+//!
+//! When a format change is intended, regenerate the vectors and review the
+//! diff before committing:
+//!
+//! ```text
+//! OMNICLIP_UPDATE_VECTORS=1 cargo test -p omniclip-core conformance
+//! ```
+
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::messages::AnnounceMessage;
+use super::{
+    ClipboardContent, ClipboardDeltaMessage, ClipboardSyncMessage, ContentHash, Message,
+    Compression, PairAcceptMessage, PairRequestMessage, TransportProof, WireCodec,
+};
+use super::constants::LEGACY_PROTOCOL_VERSION;
+use crate::crypto::{EncryptedPayload, PublicKey, SigningKey};
+
+const DEVICE_A: Uuid = Uuid::from_u128(0x0a0a0a0a_0a0a4a0a_8a0a0a0a_0a0a0a0a);
+const DEVICE_B: Uuid = Uuid::from_u128(0x0b0b0b0b_0b0b4b0b_8b0b0b0b_0b0b0b0b);
+const SESSION: Uuid = Uuid::from_u128(0x5e551011_00004000_80000000_00000001);
+const MESSAGE: Uuid = Uuid::from_u128(0x3e55a9e0_00004000_80000000_00000002);
+
+fn payload() -> EncryptedPayload {
+    EncryptedPayload {
+        nonce: [7; 12],
+        ciphertext: vec![0, 1, 2, 3, 250, 251, 252, 253, 254, 255],
+    }
+}
+
+fn hash(byte: u8) -> ContentHash {
+    ContentHash([byte; 32])
+}
+
+/// Compare the serialized value against its vector, or rewrite the vector
+fn check<T: Serialize + DeserializeOwned>(name: &str, value: &T) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/vectors")
+        .join(format!("{}.json", name));
+    let bytes = serde_json::to_vec(value).unwrap();
+
+    if std::env::var_os("OMNICLIP_UPDATE_VECTORS").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, &bytes).unwrap();
+        return;
+    }
+
+    let golden = std::fs::read(&path)
+        .unwrap_or_else(|e| panic!("missing vector {}: {}", path.display(), e));
+    assert_eq!(
+        String::from_utf8_lossy(&bytes),
+        String::from_utf8_lossy(&golden),
+        "{} no longer matches its vector",
+        name,
+    );
+
+    // The golden bytes must also still parse to the same value
+    let parsed: T = serde_json::from_slice(&golden).unwrap();
+    assert_eq!(serde_json::to_vec(&parsed).unwrap(), golden, "{} doesn't round-trip", name);
+}
+
+#[test]
+fn test_message_vectors() {
+    let identity = SigningKey::from_bytes(&[1; 32]).verifying_key();
+
+    check("announce", &Message::Announce(AnnounceMessage {
+        device_id: DEVICE_A,
+        device_name: "desk".to_string(),
+        pubkey_fingerprint: identity.fingerprint(),
+        protocol_version: 1,
+    }));
+    check("pair_request", &Message::PairRequest(PairRequestMessage {
+        session_id: SESSION,
+        device_id: DEVICE_B,
+        device_name: "phone".to_string(),
+        ephemeral_pubkey: PublicKey::from_bytes([2; 32]),
+        identity_pubkey: identity.clone(),
+        wire_codecs: vec![],
+        compressions: vec![],
+        protocol_version: LEGACY_PROTOCOL_VERSION,
+    }));
+    check("pair_accept", &Message::PairAccept(PairAcceptMessage {
+        session_id: SESSION,
+        device_id: DEVICE_A,
+        device_name: "desk".to_string(),
+        ephemeral_pubkey: PublicKey::from_bytes([3; 32]),
+        identity_pubkey: identity,
+        signature: vec![4; 64],
+        wire_codec: WireCodec::Json,
+        compression: Compression::None,
+        protocol_version: LEGACY_PROTOCOL_VERSION,
+    }));
+    check("pair_reject", &Message::PairReject {
+        session_id: SESSION,
+        reason: "expired".to_string(),
+    });
+    check("clipboard_sync", &Message::ClipboardSync(ClipboardSyncMessage {
+        message_id: MESSAGE,
+        sender_id: DEVICE_A,
+        content_hash: hash(5),
+        encrypted_content: payload(),
+        timestamp: 1_700_000_000,
+        key_epoch: 1,
+        channel: String::new(),
+        compressed: false,
+    }));
+    check("clipboard_sync_channel", &Message::ClipboardSync(ClipboardSyncMessage {
+        message_id: MESSAGE,
+        sender_id: DEVICE_A,
+        content_hash: hash(5),
+        encrypted_content: payload(),
+        timestamp: 1_700_000_000,
+        key_epoch: 1,
+        channel: "work".to_string(),
+        compressed: false,
+    }));
+    check("clipboard_sync_compressed", &Message::ClipboardSync(ClipboardSyncMessage {
+        message_id: MESSAGE,
+        sender_id: DEVICE_A,
+        content_hash: hash(5),
+        encrypted_content: payload(),
+        timestamp: 1_700_000_000,
+        key_epoch: 1,
+        channel: String::new(),
+        compressed: true,
+    }));
+    check("clipboard_delta", &Message::ClipboardDelta(ClipboardDeltaMessage {
+        message_id: MESSAGE,
+        sender_id: DEVICE_A,
+        base_hash: hash(5),
+        content_hash: hash(6),
+        patch: payload(),
+        timestamp: 1_700_000_001,
+        key_epoch: 0,
+        channel: String::new(),
+    }));
+    check("unpair", &Message::Unpair { device_id: DEVICE_A, proof: payload() });
+    check("key_rotate", &Message::KeyRotate { device_id: DEVICE_A, epoch: 3, proof: payload() });
+    check("clipboard_chunk", &Message::ClipboardChunk {
+        message_id: MESSAGE,
+        sender_id: DEVICE_A,
+        index: 1,
+        total: 3,
+        data: vec![7; 16],
+    });
+    check("ack", &Message::Ack { message_id: MESSAGE });
+    check("ping", &Message::Ping { timestamp: 42 });
+    check("pong", &Message::Pong { timestamp: 42 });
+    check("probe", &Message::Probe { device_id: DEVICE_A, challenge: payload() });
+    check("probe_reply", &Message::ProbeReply { device_id: DEVICE_B, response: payload() });
+    check("transport_hello", &Message::TransportHello { ephemeral_pubkey: PublicKey::from_bytes([9; 32]), proof: None });
+    check("transport_hello_proof", &Message::TransportHello {
+        ephemeral_pubkey: PublicKey::from_bytes([9; 32]),
+        proof: Some(TransportProof {
+            identity_pubkey: SigningKey::from_bytes(&[1; 32]).verifying_key(),
+            signature: vec![6; 64],
+        }),
+    });
+}
+
+#[test]
+fn test_content_vectors() {
+    check("content_text", &ClipboardContent::Text("hello, world ✓".to_string()));
+    check("content_rich_text", &ClipboardContent::RichText {
+        plain: "bold".to_string(),
+        html: "<b>bold</b>".to_string(),
+    });
+    check("content_raw", &ClipboardContent::Raw {
+        mime: "application/x-omniclip-test".to_string(),
+        data: vec![0, 127, 128, 255],
+    });
+    check("content_image", &ClipboardContent::Image {
+        width: 1,
+        height: 2,
+        rgba: vec![255, 0, 0, 255, 0, 0, 255, 128],
+    });
+}
+
+#[test]
+fn test_encoding_vectors() {
+    check("encrypted_payload", &payload());
+    check("content_hash", &hash(9));
+}
+
+#[test]
+fn test_messages_without_key_epoch_parse() {
+    // Peers from before key epochs omit the field
+    let json = format!(
+        r#"{{"ClipboardSync":{{"message_id":"{}","sender_id":"{}","content_hash":"{}","encrypted_content":{},"timestamp":1}}}}"#,
+        MESSAGE,
+        DEVICE_A,
+        serde_json::to_value(hash(5)).unwrap().as_str().unwrap(),
+        serde_json::to_string(&payload()).unwrap(),
+    );
+    let Message::ClipboardSync(msg) = Message::from_bytes(json.as_bytes()).unwrap() else {
+        panic!("expected ClipboardSync");
+    };
+    assert_eq!(msg.key_epoch, 0);
+    assert_eq!(msg.channel, "");
+}
@@ -9,17 +9,261 @@ pub const DEFAULT_PORT: u16 = 17394;
 /// mDNS service type for discovery
 pub const SERVICE_TYPE: &str = "_omniclip._tcp.local.";
 
+/// Maximum length of an mDNS instance name label in bytes
+pub const MDNS_LABEL_MAX: usize = 63;
+
+/// Maximum length of a single mDNS TXT entry (`key=value`) in bytes
+pub const MDNS_TXT_ENTRY_MAX: usize = 255;
+
 /// URL scheme prefix for pairing QR codes
 pub const PAIRING_URL_SCHEME: &str = "omniclip://pair";
 
-/// Info string used in session key derivation (HKDF-like)
+/// Info string used in session key derivation
 pub const SESSION_KEY_INFO: &[u8] = b"omniclip-session-key";
 
+/// HKDF salt for session key derivation
+pub const SESSION_KEY_SALT: &[u8] = b"omniclip-session-salt-v2";
+
+/// Info string for deriving the next epoch's session key from the current one
+pub const KEY_RATCHET_INFO: &[u8] = b"omniclip-key-ratchet";
+
+/// Info string for deriving the record keys of an encrypted transport
+pub const TRANSPORT_KEY_INFO: &[u8] = b"omniclip-transport-keys";
+
+/// HKDF salt for transport key derivation
+pub const TRANSPORT_KEY_SALT: &[u8] = b"omniclip-transport-salt-v1";
+
+/// Prefix of the transport handshake transcript the accepting side signs
+pub const TRANSPORT_SIGNATURE_CONTEXT: &[u8] = b"omniclip-transport-handshake-v1";
+
+/// Prefix of the associated data of a probe's challenge
+pub const PROBE_CHALLENGE_CONTEXT: &[u8] = b"omniclip-probe-challenge-v1";
+
+/// Prefix of the associated data of a probe's response, so a challenge
+/// can't be reflected back as one
+pub const PROBE_RESPONSE_CONTEXT: &[u8] = b"omniclip-probe-response-v1";
+
+/// Size of the AES-GCM authentication tag appended to ciphertext
+pub const AEAD_TAG_SIZE: usize = 16;
+
+/// Number of session key epochs kept per device, including the current one
+pub const KEY_EPOCHS_RETAINED: usize = 2;
+
+/// Most epochs a peer's key is followed ahead in one go, when its messages
+/// show it ratcheted while we weren't told
+pub const MAX_RATCHET_STEPS: u32 = 8;
+
+/// Messages sent under one session key before it is rotated
+pub const KEY_ROTATION_MESSAGES: u64 = 100_000;
+
+/// How long (seconds) a session key is used before it is rotated
+pub const KEY_ROTATION_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+/// How often (seconds) session keys are checked for rotation
+pub const KEY_ROTATION_CHECK_INTERVAL_SECS: u64 = 60;
+
+/// Maximum size of raw clipboard data in an unknown format (4 MB)
+pub const MAX_RAW_CONTENT_SIZE: usize = 4 * 1024 * 1024;
+
+/// Maximum size of image pixel data (4 MB of RGBA, about 1024x1024).
+/// Pixels are base64 encoded once in the content and again in the
+/// encrypted payload, so this keeps an image message under
+/// [`MAX_MESSAGE_SIZE`]; larger images are downscaled when read.
+pub const MAX_IMAGE_CONTENT_SIZE: usize = 4 * 1024 * 1024;
+
+/// Default cap on the size of local clipboard content sent to peers (4 MB),
+/// the most an image is kept at after downscaling
+pub const DEFAULT_MAX_CLIP_BYTES: usize = MAX_IMAGE_CONTENT_SIZE;
+
+/// How long content queued for an offline device stays worth delivering
+pub const OUTBOX_TTL_SECS: u64 = 300;
+
+/// Default cap on connections the sync server handles at once
+pub const DEFAULT_MAX_CONNECTIONS: usize = 32;
+
+/// Default room for service events the application hasn't taken yet
+pub const DEFAULT_EVENT_CAPACITY: usize = 64;
+
+/// Default room for sync server events the service hasn't handled yet
+pub const DEFAULT_SERVER_EVENT_CAPACITY: usize = 64;
+
+/// Default cap on discovered peers remembered at once
+pub const DEFAULT_MAX_DISCOVERED_PEERS: usize = 256;
+
+/// Default time (seconds) a discovered peer may go without resolving before
+/// it is checked and, if it doesn't answer, forgotten
+pub const DEFAULT_PEER_TTL_SECS: u64 = 120;
+
+/// How often (seconds) discovered peers are checked against their TTL
+pub const PEER_SWEEP_INTERVAL_SECS: u64 = 15;
+
+/// How long (milliseconds) shutdown waits for the mDNS goodbye to go out
+pub const MDNS_GOODBYE_TIMEOUT_MS: u64 = 1000;
+
+/// Most plaintext bytes sealed into one record of an encrypted transport
+pub const TRANSPORT_RECORD_SIZE: usize = 16 * 1024;
+
+/// Payload bytes written between yields when sending a frame
+pub const FRAME_WRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Clipboard channel used unless another is configured
+pub const DEFAULT_CHANNEL: &str = "";
+
+/// Appended to the channel for content from the X11 primary selection
+pub const PRIMARY_CHANNEL_SUFFIX: &str = "#primary";
+
 /// Maximum message size (10 MB)
 pub const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
 
+/// Bytes of an encoded clipboard message carried by each chunk, when the
+/// message is too large for one frame
+pub const CLIPBOARD_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Maximum size (64 MB) of a clipboard message sent in chunks
+pub const MAX_CHUNKED_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// How long (milliseconds) to wait for the next chunk of a message before
+/// giving up on it
+pub const CHUNK_TIMEOUT_MS: u64 = 10_000;
+
+/// Minimum text size (64 KB) before changes are sent as deltas
+pub const DELTA_MIN_SIZE: usize = 64 * 1024;
+
+/// Minimum serialized content size (1 KB) before it is compressed
+pub const COMPRESSION_MIN_SIZE: usize = 1024;
+
+/// Maximum size (32 MB) compressed content may expand to when received
+pub const MAX_DECOMPRESSED_SIZE: usize = 32 * 1024 * 1024;
+
+/// Size (5 MB) at which the audit log is rotated
+pub const AUDIT_LOG_MAX_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Changes on two devices this close together (seconds) are treated as a conflict
+pub const CONFLICT_WINDOW_SECS: u64 = 2;
+
+/// Version of the on-disk envelope for persisted state
+pub const STATE_FORMAT_VERSION: u16 = 1;
+
+/// Start of a state file sealed under a passphrase, which a plain JSON
+/// envelope can't begin with
+pub const SEALED_STATE_MAGIC: &[u8] = b"OMNICLIP-SEALED\x01";
+
+/// Size (bytes) of the random salt the state passphrase is stretched with
+pub const STATE_SALT_SIZE: usize = 16;
+
+/// Default delay (seconds) before a changed state file is written, so a
+/// burst of changes costs one write
+pub const STATE_FLUSH_INTERVAL_SECS: u64 = 5;
+
+/// State file holding paired devices and their session keys
+pub const PAIRED_DEVICES_FILE: &str = "paired.json";
+
+/// State file holding unpair notifications still owed to devices, with the
+/// session key each one is proven with
+pub const PENDING_UNPAIRS_FILE: &str = "unpairs.json";
+
+/// State file holding this device's ID and signing key
+pub const IDENTITY_FILE: &str = "identity.json";
+
+/// State file holding peers added by address
+pub const MANUAL_PEERS_FILE: &str = "peers.json";
+
+/// How often (seconds) peers added by address are asked who they are, to
+/// notice them coming and going
+pub const MANUAL_PEER_POLL_INTERVAL_SECS: u64 = 30;
+
 /// Current protocol version
-pub const PROTOCOL_VERSION: u16 = 1;
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Protocol version assumed for a pairing peer that doesn't state one
+pub const LEGACY_PROTOCOL_VERSION: u16 = 1;
+
+/// Whether a peer using protocol `version` can pair and sync with us: any
+/// from the legacy version up to ours. Newer versions frame messages in a
+/// way this build can't read.
+pub const fn protocol_version_supported(version: u16) -> bool {
+    version >= LEGACY_PROTOCOL_VERSION && version <= PROTOCOL_VERSION
+}
+
+/// First protocol version that derives session keys with HKDF; pairings
+/// negotiated below it keep the original SHA-256 derivation
+pub const HKDF_PROTOCOL_VERSION: u16 = 2;
+
+/// Magic bytes that open every frame
+pub const FRAME_MAGIC: [u8; 4] = *b"OMNI";
+
+/// Frame preamble: magic bytes followed by the big-endian protocol version
+pub const FRAME_PREAMBLE: [u8; 6] = {
+    let v = PROTOCOL_VERSION.to_be_bytes();
+    [FRAME_MAGIC[0], FRAME_MAGIC[1], FRAME_MAGIC[2], FRAME_MAGIC[3], v[0], v[1]]
+};
+
+/// How long a new connection has to send its first frame
+pub const HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+
+/// How long (seconds) a pairing session accepts requests after it starts
+pub const PAIRING_SESSION_TTL_SECS: u64 = 600;
+
+/// How often (milliseconds) the advertised pairing flag is checked against
+/// the active pairing sessions, catching sessions used up or expired
+pub const PAIRING_FLAG_POLL_INTERVAL_MS: u64 = 1000;
+
+/// Timeout for best-effort notifications to peers (connect + send)
+pub const PEER_NOTIFY_TIMEOUT_MS: u64 = 3000;
+
+/// How long a device has to acknowledge a clipboard message before it is
+/// sent again
+pub const ACK_TIMEOUT_MS: u64 = 5000;
+
+/// Times an unacknowledged clipboard message is sent again before giving up
+pub const MAX_RETRANSMITS: u32 = 3;
+
+/// Head start each of a peer's addresses gets before the next is tried
+/// alongside it
+pub const CONNECT_ATTEMPT_DELAY_MS: u64 = 250;
+
+/// Wait after the first failed attempt to connect to a pooled peer; doubles
+/// with each failure after
+pub const POOL_BACKOFF_BASE_MS: u64 = 500;
+
+/// Longest wait between attempts to connect to a pooled peer
+pub const POOL_BACKOFF_MAX_SECS: u64 = 60;
+
+/// Session lock polling interval in milliseconds
+pub const SESSION_POLL_INTERVAL_MS: u64 = 2000;
+
+/// Clipboard history entries kept by default
+pub const DEFAULT_HISTORY_CAPACITY: usize = 50;
+
+/// How long (seconds) content stays recent enough to suppress a repeat
+pub const DEDUP_WINDOW_SECS: u64 = 30;
+
+/// Recently exchanged content hashes kept for suppressing echoes
+pub const MAX_RECENT_HASHES: usize = 8;
+
+/// How far (seconds) a clipboard message's timestamp may be from our clock
+/// before it is dropped as stale
+pub const REPLAY_WINDOW_SECS: u64 = 30;
+
+/// Clipboard message ids remembered per device for spotting replays
+pub const MAX_SEEN_MESSAGE_IDS: usize = 1024;
+
+/// Quiet period (milliseconds) after a local change before it is sent
+pub const SEND_DEBOUNCE_MS: u64 = 150;
 
 /// Clipboard polling interval in milliseconds
 pub const CLIPBOARD_POLL_INTERVAL_MS: u64 = 500;
+
+/// How often (milliseconds) a platform change counter is checked when
+/// monitoring for change events on a platform without notifications
+pub const CLIPBOARD_TOKEN_POLL_INTERVAL_MS: u64 = 50;
+
+/// Delay (milliseconds) before the second read that confirms a change
+pub const CLIPBOARD_CONFIRM_DELAY_MS: u64 = 20;
+
+/// Times the clipboard is tried before a read or write fails, for when
+/// another process briefly holds it
+pub const CLIPBOARD_ACCESS_ATTEMPTS: u32 = 3;
+
+/// Delay (milliseconds) between clipboard access attempts
+pub const CLIPBOARD_RETRY_DELAY_MS: u64 = 50;
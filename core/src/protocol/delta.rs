@@ -0,0 +1,155 @@
+//! Line-based text deltas for incremental clipboard sync
+//!
+//! When large text content changes only slightly, sending a patch against
+//! the previously synced version is much cheaper than resending everything.
+//! A patch is a sequence of copy-from-base and literal-insert operations.
+
+use serde::{Deserialize, Serialize};
+use similar::{DiffTag, TextDiff};
+
+use crate::{Error, Result};
+
+/// A single patch operation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PatchOp {
+    /// Copy `len` bytes from the base starting at byte `offset`
+    Copy { offset: usize, len: usize },
+    /// Insert literal text
+    Insert(String),
+}
+
+/// Patch that reconstructs a target text from a base text
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextPatch {
+    pub ops: Vec<PatchOp>,
+}
+
+impl TextPatch {
+    /// Compute a line-based patch turning `base` into `target`
+    pub fn compute(base: &str, target: &str) -> Self {
+        let diff = TextDiff::from_lines(base, target);
+        let old_offsets = line_offsets(diff.old_slices());
+        let new_offsets = line_offsets(diff.new_slices());
+
+        let mut ops: Vec<PatchOp> = Vec::new();
+        for op in diff.ops() {
+            let (tag, old_range, new_range) = op.as_tag_tuple();
+            match tag {
+                DiffTag::Equal => {
+                    let offset = old_offsets[old_range.start];
+                    let len = old_offsets[old_range.end] - offset;
+                    match ops.last_mut() {
+                        Some(PatchOp::Copy { offset: prev, len: prev_len })
+                            if *prev + *prev_len == offset =>
+                        {
+                            *prev_len += len;
+                        }
+                        _ => ops.push(PatchOp::Copy { offset, len }),
+                    }
+                }
+                DiffTag::Delete => {}
+                DiffTag::Insert | DiffTag::Replace => {
+                    let text = &target[new_offsets[new_range.start]..new_offsets[new_range.end]];
+                    match ops.last_mut() {
+                        Some(PatchOp::Insert(prev)) => prev.push_str(text),
+                        _ => ops.push(PatchOp::Insert(text.to_string())),
+                    }
+                }
+            }
+        }
+
+        Self { ops }
+    }
+
+    /// Apply the patch to `base`, returning the reconstructed text
+    pub fn apply(&self, base: &str) -> Result<String> {
+        let mut out = String::with_capacity(base.len());
+        for op in &self.ops {
+            match op {
+                PatchOp::Copy { offset, len } => {
+                    let end = offset.checked_add(*len)
+                        .ok_or_else(|| Error::InvalidMessage("patch range overflow".to_string()))?;
+                    let slice = base.get(*offset..end)
+                        .ok_or_else(|| Error::InvalidMessage("patch range out of bounds".to_string()))?;
+                    out.push_str(slice);
+                }
+                PatchOp::Insert(text) => out.push_str(text),
+            }
+        }
+        Ok(out)
+    }
+
+    /// Number of literal bytes carried by the patch
+    pub fn inserted_len(&self) -> usize {
+        self.ops.iter()
+            .map(|op| match op {
+                PatchOp::Copy { .. } => 0,
+                PatchOp::Insert(text) => text.len(),
+            })
+            .sum()
+    }
+
+    /// Serialize for encryption
+    pub fn to_bytes(&self) -> std::result::Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+
+    /// Deserialize from decrypted bytes
+    pub fn from_bytes(bytes: &[u8]) -> std::result::Result<Self, serde_json::Error> {
+        serde_json::from_slice(bytes)
+    }
+}
+
+/// Byte offset of the start of each line, plus the total length at the end
+fn line_offsets(lines: &[&str]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(lines.len() + 1);
+    let mut pos = 0;
+    offsets.push(pos);
+    for line in lines {
+        pos += line.len();
+        offsets.push(pos);
+    }
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn large_document() -> String {
+        (0..5000)
+            .map(|i| format!("line {} of the document — ünïcödé ✓\n", i))
+            .collect()
+    }
+
+    #[test]
+    fn test_patch_reconstructs_exact_bytes() {
+        let base = large_document();
+        let mut target = base.replace("line 42 of", "LINE 42 OF");
+        let at = target.match_indices('\n').nth(20).unwrap().0 + 1;
+        target.insert_str(at, "inserted\nlines\n");
+        target.push_str("no trailing newline");
+
+        let patch = TextPatch::compute(&base, &target);
+        let bytes = patch.to_bytes().unwrap();
+        let decoded = TextPatch::from_bytes(&bytes).unwrap();
+        let rebuilt = decoded.apply(&base).unwrap();
+
+        assert_eq!(rebuilt.as_bytes(), target.as_bytes());
+        assert!(patch.inserted_len() < target.len() / 10);
+    }
+
+    #[test]
+    fn test_patch_from_empty_and_to_empty() {
+        let text = "a\nb\nc";
+        assert_eq!(TextPatch::compute("", text).apply("").unwrap(), text);
+        assert_eq!(TextPatch::compute(text, "").apply(text).unwrap(), "");
+    }
+
+    #[test]
+    fn test_patch_rejects_wrong_base() {
+        let base = large_document();
+        let patch = TextPatch::compute(&base, &format!("{}extra\n", base));
+        assert!(patch.apply("tiny").is_err());
+    }
+}
@@ -0,0 +1,138 @@
+//! Per-peer protocol features
+//!
+//! The wire codec and compression are negotiated during pairing; ciphers
+//! and capabilities are the baseline below for every pairing. Recording
+//! them per device keeps what a peer speaks inspectable.
+
+use serde::de::value::StrDeserializer;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use super::codec::WireCodec;
+
+/// Symmetric cipher protecting clipboard content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Cipher {
+    Aes256Gcm,
+}
+
+/// Compression applied to clipboard content before encryption
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    #[default]
+    None,
+    Deflate,
+}
+
+impl Compression {
+    /// Compressions this build can undo, most preferred first
+    pub fn supported() -> Vec<Compression> {
+        vec![Compression::Deflate, Compression::None]
+    }
+
+    /// Pick the compression for a pairing: the first one the requester
+    /// offered that we support, or none
+    pub fn negotiate(offered: &[Compression]) -> Compression {
+        let ours = Self::supported();
+        offered.iter().copied().find(|compression| ours.contains(compression)).unwrap_or_default()
+    }
+
+    pub fn is_none(&self) -> bool {
+        *self == Compression::None
+    }
+}
+
+/// Optional protocol behaviour a peer supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    /// Text changes sent as patches
+    Delta,
+    /// Received clipboard messages are acknowledged
+    Ack,
+    /// Messages name the session key epoch they use
+    KeyEpochs,
+    /// Clipboard data in unknown formats is passed through
+    RawContent,
+}
+
+/// Features in effect with one paired device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NegotiatedFeatures {
+    pub cipher: Cipher,
+    pub wire_codec: WireCodec,
+    pub compression: Compression,
+    pub capabilities: Vec<Capability>,
+}
+
+impl NegotiatedFeatures {
+    /// What every peer speaks in this protocol version
+    pub fn baseline() -> Self {
+        Self {
+            cipher: Cipher::Aes256Gcm,
+            wire_codec: WireCodec::Json,
+            compression: Compression::None,
+            capabilities: vec![
+                Capability::Delta,
+                Capability::Ack,
+                Capability::KeyEpochs,
+                Capability::RawContent,
+            ],
+        }
+    }
+
+    /// The baseline, sending messages with `codec` and compressing
+    /// content with `compression`
+    pub fn negotiated(codec: WireCodec, compression: Compression) -> Self {
+        Self { wire_codec: codec, compression, ..Self::baseline() }
+    }
+}
+
+impl Default for NegotiatedFeatures {
+    fn default() -> Self {
+        Self::baseline()
+    }
+}
+
+/// Deserialize a list of offered options, dropping names this build doesn't
+/// know so a newer peer's offer still parses
+pub(crate) fn deserialize_known<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let names: Vec<String> = Deserialize::deserialize(deserializer)?;
+    Ok(names
+        .iter()
+        .filter_map(|name| T::deserialize(StrDeserializer::<serde::de::value::Error>::new(name)).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_offers_are_dropped() {
+        #[derive(Deserialize)]
+        struct Offer {
+            #[serde(deserialize_with = "deserialize_known")]
+            codecs: Vec<WireCodec>,
+            #[serde(deserialize_with = "deserialize_known")]
+            compressions: Vec<Compression>,
+        }
+        let offer: Offer = serde_json::from_str(
+            r#"{"codecs":["zstd-frames","cbor","json"],"compressions":["brotli","deflate"]}"#,
+        ).unwrap();
+        assert_eq!(offer.codecs, vec![WireCodec::Cbor, WireCodec::Json]);
+        assert_eq!(offer.compressions, vec![Compression::Deflate]);
+    }
+
+    #[test]
+    fn test_compression_falls_back_to_none() {
+        assert_eq!(Compression::negotiate(&[]), Compression::None);
+        assert_eq!(Compression::negotiate(&Compression::supported()), Compression::Deflate);
+    }
+}
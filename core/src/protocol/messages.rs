@@ -5,6 +5,12 @@ use sha2::{Sha256, Digest};
 use uuid::Uuid;
 
 use crate::crypto::{EncryptedPayload, PublicKey, VerifyingKey};
+use crate::protocol::codec::WireCodec;
+use crate::protocol::features::{deserialize_known, Compression};
+use crate::protocol::constants::{
+    AEAD_TAG_SIZE, FRAME_PREAMBLE, LEGACY_PROTOCOL_VERSION, MAX_IMAGE_CONTENT_SIZE, MAX_RAW_CONTENT_SIZE,
+    PRIMARY_CHANNEL_SUFFIX,
+};
 
 /// All protocol messages
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,6 +30,30 @@ pub enum Message {
     /// Sync clipboard content to paired devices
     ClipboardSync(ClipboardSyncMessage),
 
+    /// Sync a text change as a patch against previously synced content
+    ClipboardDelta(ClipboardDeltaMessage),
+
+    /// Part `index` of `total` of a clipboard message too large for one
+    /// frame, which has id `message_id` and is from `sender_id`. `data` is
+    /// the next slice of the message as encoded; joined, the parts are
+    /// handled as the message.
+    ClipboardChunk {
+        message_id: Uuid,
+        sender_id: Uuid,
+        index: u32,
+        total: u32,
+        #[serde(with = "crate::crypto::serde_utils::base64_bytes")]
+        data: Vec<u8>,
+    },
+
+    /// Notify a peer that we removed the pairing with it.
+    /// `proof` is `device_id` encrypted with the session key.
+    Unpair { device_id: Uuid, proof: EncryptedPayload },
+
+    /// Notify a peer that we moved to session key `epoch`, ratcheted from
+    /// the previous key. `proof` is `device_id` encrypted with the new key.
+    KeyRotate { device_id: Uuid, epoch: u32, proof: EncryptedPayload },
+
     /// Acknowledge receipt of a message
     Ack { message_id: Uuid },
 
@@ -32,6 +62,25 @@ pub enum Message {
 
     /// Response to ping
     Pong { timestamp: u64 },
+
+    /// Ping from paired device `device_id` that only the holder of the
+    /// session key can answer. `challenge` is random bytes encrypted with
+    /// the session key, bound with [`probe_aad`].
+    Probe { device_id: Uuid, challenge: EncryptedPayload },
+
+    /// Answer to a `Probe` from `device_id`: the challenge's bytes
+    /// encrypted again with the session key, bound with [`probe_aad`].
+    ProbeReply { device_id: Uuid, response: EncryptedPayload },
+
+    /// Open an encrypted transport, sent by each side in turn as the first
+    /// message on a connection. The side that accepted the connection adds
+    /// `proof` of its identity. Everything after it is sealed with keys
+    /// derived from the two ephemeral keys and that identity.
+    TransportHello {
+        ephemeral_pubkey: PublicKey,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        proof: Option<TransportProof>,
+    },
 }
 
 impl Message {
@@ -45,13 +94,70 @@ impl Message {
         serde_json::from_slice(bytes)
     }
 
+    /// Serialize message to bytes with `codec`
+    pub fn to_bytes_with(&self, codec: WireCodec) -> crate::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(self.encoded_len_hint(codec));
+        codec.encode_into(self, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Deserialize message from bytes written with `codec`
+    pub fn from_bytes_with(bytes: &[u8], codec: WireCodec) -> crate::Result<Self> {
+        codec.decode(bytes)
+    }
+
+    /// Deserialize message from bytes in whichever codec they were written
+    /// with
+    pub fn decode(bytes: &[u8]) -> crate::Result<Self> {
+        Self::from_bytes_with(bytes, WireCodec::detect(bytes))
+    }
+
     /// Create a length-prefixed frame for TCP transport
     pub fn to_frame(&self) -> Result<Vec<u8>, serde_json::Error> {
-        let payload = self.to_bytes()?;
-        let len = payload.len() as u32;
-        let mut frame = len.to_be_bytes().to_vec();
-        frame.extend(payload);
-        Ok(frame)
+        let header = FRAME_PREAMBLE.len() + 4;
+        let mut frame = Vec::with_capacity(header + self.encoded_len_hint(WireCodec::Json));
+        frame.extend_from_slice(&FRAME_PREAMBLE);
+        frame.extend_from_slice(&[0u8; 4]);
+        serde_json::to_writer(&mut frame, self)?;
+        Ok(Self::finish_frame(frame))
+    }
+
+    /// Create a length-prefixed frame for TCP transport, encoded with
+    /// `codec`
+    ///
+    /// The message is serialized directly after the preamble and a
+    /// placeholder length, which is filled in afterwards, so the payload is
+    /// never copied.
+    pub fn to_frame_with(&self, codec: WireCodec) -> crate::Result<Vec<u8>> {
+        let header = FRAME_PREAMBLE.len() + 4;
+        let mut frame = Vec::with_capacity(header + self.encoded_len_hint(codec));
+        frame.extend_from_slice(&FRAME_PREAMBLE);
+        frame.extend_from_slice(&[0u8; 4]);
+        codec.encode_into(self, &mut frame)?;
+        Ok(Self::finish_frame(frame))
+    }
+
+    fn finish_frame(mut frame: Vec<u8>) -> Vec<u8> {
+        let header = FRAME_PREAMBLE.len() + 4;
+        let len = (frame.len() - header) as u32;
+        frame[FRAME_PREAMBLE.len()..header].copy_from_slice(&len.to_be_bytes());
+        frame
+    }
+
+    /// Rough upper bound on the serialized size, to size buffers up front
+    fn encoded_len_hint(&self, codec: WireCodec) -> usize {
+        const OVERHEAD: usize = 512;
+        let ciphertext_len = match self {
+            Message::ClipboardSync(m) => m.encrypted_content.ciphertext.len(),
+            Message::ClipboardDelta(m) => m.patch.ciphertext.len(),
+            Message::ClipboardChunk { data, .. } => data.len(),
+            _ => 0,
+        };
+        match codec {
+            // Ciphertext is base64 encoded
+            WireCodec::Json => ciphertext_len.div_ceil(3) * 4 + OVERHEAD,
+            WireCodec::Cbor => ciphertext_len + OVERHEAD,
+        }
     }
 }
 
@@ -72,9 +178,29 @@ pub struct PairRequestMessage {
     pub device_name: String,
     pub ephemeral_pubkey: PublicKey,
     pub identity_pubkey: VerifyingKey,
+    /// Codecs the requester can decode, most preferred first; left out by
+    /// peers that only speak JSON
+    #[serde(default, skip_serializing_if = "Vec::is_empty", deserialize_with = "deserialize_known")]
+    pub wire_codecs: Vec<WireCodec>,
+    /// Compressions the requester can undo, most preferred first
+    #[serde(default, skip_serializing_if = "Vec::is_empty", deserialize_with = "deserialize_known")]
+    pub compressions: Vec<Compression>,
+    /// Highest protocol version the requester speaks
+    #[serde(default = "legacy_protocol_version", skip_serializing_if = "is_legacy_protocol_version")]
+    pub protocol_version: u16,
 }
 
 /// Pairing acceptance (step 2 of pairing handshake)
+/// Proof that the side accepting an encrypted transport holds its identity key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransportProof {
+    pub identity_pubkey: VerifyingKey,
+    /// Signature over the context string and both ephemeral pubkeys, the
+    /// initiator's first
+    #[serde(with = "crate::crypto::serde_utils::base64_bytes")]
+    pub signature: Vec<u8>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PairAcceptMessage {
     pub session_id: Uuid,
@@ -85,6 +211,26 @@ pub struct PairAcceptMessage {
     /// Signature over session_id || both ephemeral pubkeys
     #[serde(with = "crate::crypto::serde_utils::base64_bytes")]
     pub signature: Vec<u8>,
+    /// Codec picked from the request's `wire_codecs` for messages between
+    /// the two devices
+    #[serde(default, skip_serializing_if = "WireCodec::is_json")]
+    pub wire_codec: WireCodec,
+    /// Compression picked from the request's `compressions`
+    #[serde(default, skip_serializing_if = "Compression::is_none")]
+    pub compression: Compression,
+    /// Protocol version for the pairing, the lower of the two devices';
+    /// decides how the session key is derived
+    #[serde(default = "legacy_protocol_version", skip_serializing_if = "is_legacy_protocol_version")]
+    pub protocol_version: u16,
+}
+
+/// Version of a pairing peer from before the handshake carried one
+fn legacy_protocol_version() -> u16 {
+    LEGACY_PROTOCOL_VERSION
+}
+
+fn is_legacy_protocol_version(version: &u16) -> bool {
+    *version == LEGACY_PROTOCOL_VERSION
 }
 
 /// Clipboard content sync message
@@ -95,6 +241,152 @@ pub struct ClipboardSyncMessage {
     pub content_hash: ContentHash,
     pub encrypted_content: EncryptedPayload,
     pub timestamp: u64,
+    /// Epoch of the session key `encrypted_content` was encrypted with
+    #[serde(default)]
+    pub key_epoch: u32,
+    /// Clipboard channel the content belongs to; empty is the default
+    /// channel and is left out on the wire
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub channel: String,
+    /// Whether the content was deflated before encryption
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compressed: bool,
+}
+
+impl ClipboardSyncMessage {
+    /// Associated data `encrypted_content` is encrypted with
+    pub fn aad(&self) -> [u8; 40] {
+        clipboard_aad(self.sender_id, self.message_id, self.timestamp)
+    }
+}
+
+/// Incremental clipboard sync message
+///
+/// `patch` is an encrypted [`TextPatch`](crate::protocol::TextPatch) which,
+/// applied to the content with hash `base_hash`, yields the content with
+/// hash `content_hash`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClipboardDeltaMessage {
+    pub message_id: Uuid,
+    pub sender_id: Uuid,
+    pub base_hash: ContentHash,
+    pub content_hash: ContentHash,
+    pub patch: EncryptedPayload,
+    pub timestamp: u64,
+    /// Epoch of the session key `patch` was encrypted with
+    #[serde(default)]
+    pub key_epoch: u32,
+    /// Clipboard channel the content belongs to; empty is the default
+    /// channel and is left out on the wire
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub channel: String,
+}
+
+impl ClipboardDeltaMessage {
+    /// Associated data `patch` is encrypted with
+    pub fn aad(&self) -> [u8; 40] {
+        clipboard_aad(self.sender_id, self.message_id, self.timestamp)
+    }
+}
+
+/// Associated data binding clipboard ciphertext to the message carrying it:
+/// `sender_id || message_id || timestamp`, the timestamp big-endian.
+///
+/// A ciphertext lifted into a message with a different sender, id or
+/// timestamp then fails to decrypt.
+pub fn clipboard_aad(sender_id: Uuid, message_id: Uuid, timestamp: u64) -> [u8; 40] {
+    let mut aad = [0u8; 40];
+    aad[..16].copy_from_slice(sender_id.as_bytes());
+    aad[16..32].copy_from_slice(message_id.as_bytes());
+    aad[32..].copy_from_slice(&timestamp.to_be_bytes());
+    aad
+}
+
+/// Associated data binding a probe's ciphertext to its sender and
+/// direction: `context || sender_id`, with `context` one of
+/// [`PROBE_CHALLENGE_CONTEXT`](super::constants::PROBE_CHALLENGE_CONTEXT) and
+/// [`PROBE_RESPONSE_CONTEXT`](super::constants::PROBE_RESPONSE_CONTEXT).
+pub fn probe_aad(context: &[u8], sender_id: Uuid) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(context.len() + 16);
+    aad.extend_from_slice(context);
+    aad.extend_from_slice(sender_id.as_bytes());
+    aad
+}
+
+/// Kind of clipboard content, for deciding what to sync
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentKind {
+    Text,
+    RichText,
+    Image,
+    Files,
+    Raw,
+}
+
+impl ContentKind {
+    /// Every kind, in display order
+    pub const ALL: [ContentKind; 5] = [
+        ContentKind::Text,
+        ContentKind::RichText,
+        ContentKind::Image,
+        ContentKind::Files,
+        ContentKind::Raw,
+    ];
+}
+
+impl std::fmt::Display for ContentKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ContentKind::Text => "text",
+            ContentKind::RichText => "rich text",
+            ContentKind::Image => "images",
+            ContentKind::Files => "files",
+            ContentKind::Raw => "other formats",
+        })
+    }
+}
+
+/// Which system selection clipboard content belongs to
+///
+/// X11 has a PRIMARY selection, set by selecting text and pasted with a
+/// middle click, besides the CLIPBOARD most applications use. Content from
+/// the primary selection is sent on its own channel, the configured one
+/// with [`PRIMARY_CHANNEL_SUFFIX`] appended, so peers that don't sync it
+/// ignore it as they do any channel they aren't on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ClipboardSelection {
+    #[default]
+    Clipboard,
+    Primary,
+}
+
+impl ClipboardSelection {
+    /// The channel this selection's content is sent on, given the
+    /// configured one
+    pub fn channel(self, channel: &str) -> String {
+        match self {
+            ClipboardSelection::Clipboard => channel.to_string(),
+            ClipboardSelection::Primary => format!("{}{}", channel, PRIMARY_CHANNEL_SUFFIX),
+        }
+    }
+
+    /// The selection a message's channel is for, and the configured channel
+    /// it was derived from
+    pub fn from_channel(channel: &str) -> (Self, &str) {
+        match channel.strip_suffix(PRIMARY_CHANNEL_SUFFIX) {
+            Some(base) => (ClipboardSelection::Primary, base),
+            None => (ClipboardSelection::Clipboard, channel),
+        }
+    }
+}
+
+impl std::fmt::Display for ClipboardSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ClipboardSelection::Clipboard => "clipboard",
+            ClipboardSelection::Primary => "primary selection",
+        })
+    }
 }
 
 /// Clipboard content types (text only for MVP)
@@ -104,6 +396,23 @@ pub enum ClipboardContent {
     Text(String),
     /// Rich text (HTML)
     RichText { plain: String, html: String },
+    /// Data in a format omniclip doesn't interpret, passed through verbatim
+    ///
+    /// `mime` is the platform's name for the format. Only platforms that
+    /// use the same format names can make sense of the data, so this
+    /// round-trips between like systems but may be unusable elsewhere.
+    Raw {
+        mime: String,
+        #[serde(with = "crate::crypto::serde_utils::base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// Bitmap image, 8-bit RGBA row by row
+    Image {
+        width: usize,
+        height: usize,
+        #[serde(with = "crate::crypto::serde_utils::base64_bytes")]
+        rgba: Vec<u8>,
+    },
 }
 
 impl ClipboardContent {
@@ -120,18 +429,88 @@ impl ClipboardContent {
                 hasher.update(plain.as_bytes());
                 hasher.update(html.as_bytes());
             }
+            ClipboardContent::Raw { mime, data } => {
+                hasher.update(b"raw:");
+                hasher.update(mime.as_bytes());
+                hasher.update([0]);
+                hasher.update(data);
+            }
+            ClipboardContent::Image { width, height, rgba } => {
+                hasher.update(b"image:");
+                hasher.update((*width as u64).to_be_bytes());
+                hasher.update((*height as u64).to_be_bytes());
+                hasher.update(rgba);
+            }
         }
         ContentHash(hasher.finalize().into())
     }
 
+    /// Kind of this content
+    pub fn kind(&self) -> ContentKind {
+        match self {
+            ClipboardContent::Text(_) => ContentKind::Text,
+            ClipboardContent::RichText { .. } => ContentKind::RichText,
+            ClipboardContent::Raw { .. } => ContentKind::Raw,
+            ClipboardContent::Image { .. } => ContentKind::Image,
+        }
+    }
+
+    /// Size of the content in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            ClipboardContent::Text(text) => text.len(),
+            ClipboardContent::RichText { plain, html } => plain.len() + html.len(),
+            ClipboardContent::Raw { mime, data } => mime.len() + data.len(),
+            ClipboardContent::Image { rgba, .. } => rgba.len(),
+        }
+    }
+
     /// Serialize for encryption (using JSON for cross-platform compatibility)
+    ///
+    /// The buffer leaves room for the AES-GCM tag so it can be encrypted
+    /// in place with [`SessionKey::encrypt_in_place`](crate::SessionKey::encrypt_in_place).
     pub fn to_bytes(&self) -> Result<Vec<u8>, serde_json::Error> {
-        serde_json::to_vec(self)
+        const OVERHEAD: usize = 64;
+        // Raw data and pixels are base64 encoded, a third larger than their size
+        let encoded_size = match self {
+            ClipboardContent::Raw { mime, data } => mime.len() + data.len().div_ceil(3) * 4,
+            ClipboardContent::Image { rgba, .. } => rgba.len().div_ceil(3) * 4,
+            _ => self.size(),
+        };
+        let mut bytes = Vec::with_capacity(encoded_size + OVERHEAD + AEAD_TAG_SIZE);
+        serde_json::to_writer(&mut bytes, self)?;
+        Ok(bytes)
     }
 
     /// Deserialize from decrypted bytes
+    ///
+    /// Raw data over [`MAX_RAW_CONTENT_SIZE`], images over
+    /// [`MAX_IMAGE_CONTENT_SIZE`] and images whose pixel data doesn't match
+    /// their dimensions are rejected.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_json::Error> {
-        serde_json::from_slice(bytes)
+        let content: Self = serde_json::from_slice(bytes)?;
+        match &content {
+            ClipboardContent::Raw { data, .. } if data.len() > MAX_RAW_CONTENT_SIZE => {
+                return Err(serde::de::Error::custom(format!(
+                    "raw clipboard data is {} bytes, limit is {}", data.len(), MAX_RAW_CONTENT_SIZE
+                )));
+            }
+            ClipboardContent::Image { rgba, .. } if rgba.len() > MAX_IMAGE_CONTENT_SIZE => {
+                return Err(serde::de::Error::custom(format!(
+                    "image data is {} bytes, limit is {}", rgba.len(), MAX_IMAGE_CONTENT_SIZE
+                )));
+            }
+            ClipboardContent::Image { width, height, rgba } => {
+                let expected = width.checked_mul(*height).and_then(|pixels| pixels.checked_mul(4));
+                if expected != Some(rgba.len()) {
+                    return Err(serde::de::Error::custom(format!(
+                        "{}x{} image has {} bytes of RGBA data", width, height, rgba.len()
+                    )));
+                }
+            }
+            _ => {}
+        }
+        Ok(content)
     }
 }
 
@@ -145,6 +524,13 @@ impl ContentHash {
     }
 }
 
+impl std::fmt::Display for ContentHash {
+    /// Leading bytes in hex, enough to tell hashes apart in logs
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0[..8].iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -169,6 +555,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_frame_length_prefix() {
+        let msg = Message::Ping { timestamp: 42 };
+        let frame = msg.to_frame().unwrap();
+
+        let (preamble, rest) = frame.split_at(FRAME_PREAMBLE.len());
+        assert_eq!(preamble, FRAME_PREAMBLE);
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        assert_eq!(len, rest.len() - 4);
+        assert!(matches!(Message::from_bytes(&rest[4..]).unwrap(), Message::Ping { timestamp: 42 }));
+    }
+
+    #[test]
+    fn test_selection_channel_roundtrip() {
+        assert_eq!(ClipboardSelection::Clipboard.channel("work"), "work");
+        assert_eq!(ClipboardSelection::Primary.channel("work"), "work#primary");
+        assert_eq!(ClipboardSelection::from_channel("work"), (ClipboardSelection::Clipboard, "work"));
+        assert_eq!(ClipboardSelection::from_channel("work#primary"), (ClipboardSelection::Primary, "work"));
+    }
+
     #[test]
     fn test_content_hash_consistency() {
         let content = ClipboardContent::Text("hello".to_string());
@@ -183,4 +589,74 @@ mod tests {
         let content2 = ClipboardContent::Text("world".to_string());
         assert_ne!(content1.hash(), content2.hash());
     }
+
+    #[test]
+    fn test_image_content_roundtrip() {
+        let image = ClipboardContent::Image { width: 2, height: 1, rgba: vec![255, 0, 0, 255, 0, 0, 255, 128] };
+        let decoded = ClipboardContent::from_bytes(&image.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.hash(), image.hash());
+        assert_eq!(decoded.kind(), ContentKind::Image);
+
+        // Same pixels, different shape
+        let column = ClipboardContent::Image { width: 1, height: 2, rgba: vec![255, 0, 0, 255, 0, 0, 255, 128] };
+        assert_ne!(column.hash(), image.hash());
+        let changed = ClipboardContent::Image { width: 2, height: 1, rgba: vec![255, 0, 0, 255, 0, 0, 255, 127] };
+        assert_ne!(changed.hash(), image.hash());
+
+        let short = ClipboardContent::Image { width: 2, height: 2, rgba: vec![0; 8] };
+        assert!(ClipboardContent::from_bytes(&short.to_bytes().unwrap()).is_err());
+        let side = 1024;
+        let oversized = ClipboardContent::Image { width: side, height: side + 1, rgba: vec![0; side * (side + 1) * 4] };
+        assert!(ClipboardContent::from_bytes(&oversized.to_bytes().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_raw_content_roundtrip() {
+        let content = ClipboardContent::Raw {
+            mime: "XML Spreadsheet".to_string(),
+            data: vec![0, 159, 146, 150, 255],
+        };
+        let decoded = ClipboardContent::from_bytes(&content.to_bytes().unwrap()).unwrap();
+        assert!(matches!(&decoded, ClipboardContent::Raw { mime, data } if mime == "XML Spreadsheet" && data == &[0, 159, 146, 150, 255]));
+        assert_eq!(decoded.hash(), content.hash());
+        assert_eq!(decoded.kind(), ContentKind::Raw);
+
+        let oversized = ClipboardContent::Raw {
+            mime: "application/octet-stream".to_string(),
+            data: vec![0; MAX_RAW_CONTENT_SIZE + 1],
+        };
+        assert!(ClipboardContent::from_bytes(&oversized.to_bytes().unwrap()).is_err());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor_roundtrip_keeps_content_hash() {
+        use crate::crypto::SessionKey;
+
+        let content = ClipboardContent::Text("hello ".repeat(1000));
+        let msg = Message::ClipboardSync(ClipboardSyncMessage {
+            message_id: Uuid::new_v4(),
+            sender_id: Uuid::new_v4(),
+            content_hash: content.hash(),
+            encrypted_content: SessionKey::from_bytes(&[9u8; 32]).encrypt(&content.to_bytes().unwrap()).unwrap(),
+            timestamp: 7,
+            key_epoch: 0,
+            channel: String::new(),
+            compressed: false,
+        });
+
+        let cbor = msg.to_bytes_with(WireCodec::Cbor).unwrap();
+        let json = msg.to_bytes_with(WireCodec::Json).unwrap();
+        assert!(cbor.len() < json.len() * 4 / 5);
+        assert_eq!(WireCodec::detect(&cbor), WireCodec::Cbor);
+        assert_eq!(WireCodec::detect(&json), WireCodec::Json);
+
+        for bytes in [cbor, json] {
+            let Message::ClipboardSync(decoded) = Message::decode(&bytes).unwrap() else {
+                panic!("wrong message type");
+            };
+            assert_eq!(decoded.content_hash, content.hash());
+            assert_eq!(decoded.channel, "");
+        }
+    }
 }
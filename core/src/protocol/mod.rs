@@ -1,8 +1,17 @@
 //! Protocol message types and sync logic
 
+mod codec;
+pub(crate) mod compression;
 pub mod constants;
+#[cfg(test)]
+mod conformance;
+mod delta;
+mod features;
 mod messages;
 mod pairing;
 
-pub use messages::{Message, ClipboardContent, ClipboardSyncMessage, ContentHash, PairAcceptMessage, PairRequestMessage};
-pub use pairing::{PairingSession, PairingQrData};
+pub use delta::{PatchOp, TextPatch};
+pub use codec::WireCodec;
+pub use features::{Capability, Cipher, Compression, NegotiatedFeatures};
+pub use messages::{clipboard_aad, probe_aad, AnnounceMessage, Message, ClipboardContent, ClipboardDeltaMessage, ClipboardSelection, ContentKind, ClipboardSyncMessage, ContentHash, PairAcceptMessage, PairRequestMessage, TransportProof};
+pub use pairing::{IdentityQrData, PairingSession, PairingSessionInfo, PairingQrData};
@@ -1,17 +1,33 @@
 //! Pairing session management and QR code generation
 
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
 use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::crypto::{EphemeralSecret, PublicKey, SigningKey, SessionKey};
-use crate::{Error, Result};
+use crate::crypto::{EphemeralSecret, PublicKey, SigningKey, SessionKey, VerifyingKey};
+use crate::protocol::constants::PAIRING_SESSION_TTL_SECS;
+use crate::{DeviceIdentity, Error, Result};
 
 /// Active pairing session state
 pub struct PairingSession {
     pub session_id: Uuid,
     pub ephemeral_secret: EphemeralSecret,
     pub ephemeral_public: PublicKey,
+    /// When the session was started
+    pub created_at: SystemTime,
+    /// Optional label for the device expected to pair
+    pub device_name_hint: Option<String>,
+}
+
+/// Summary of a pending pairing session, without its key material
+#[derive(Debug, Clone)]
+pub struct PairingSessionInfo {
+    pub session_id: Uuid,
+    pub created_at: SystemTime,
+    pub device_name_hint: Option<String>,
 }
 
 impl PairingSession {
@@ -24,24 +40,51 @@ impl PairingSession {
             session_id: Uuid::new_v4(),
             ephemeral_secret,
             ephemeral_public,
+            created_at: SystemTime::now(),
+            device_name_hint: None,
+        }
+    }
+
+    /// Start a new pairing session labelled with the expected device
+    pub fn with_hint(device_name_hint: impl Into<String>) -> Self {
+        Self {
+            device_name_hint: Some(device_name_hint.into()),
+            ..Self::new()
+        }
+    }
+
+    /// Whether the session is too old to accept a pairing request
+    pub fn is_expired(&self) -> bool {
+        self.created_at.elapsed()
+            .is_ok_and(|age| age > Duration::from_secs(PAIRING_SESSION_TTL_SECS))
+    }
+
+    /// Summary for listing
+    pub fn info(&self) -> PairingSessionInfo {
+        PairingSessionInfo {
+            session_id: self.session_id,
+            created_at: self.created_at,
+            device_name_hint: self.device_name_hint.clone(),
         }
     }
 
-    /// Generate QR code data for this session
-    pub fn qr_data(&self, local_ip: &str, port: u16, device_name: &str) -> PairingQrData {
+    /// Generate QR code data for this session, offered by `identity`
+    pub fn qr_data(&self, local_ip: &str, port: u16, identity: &DeviceIdentity) -> PairingQrData {
         PairingQrData {
             session_id: self.session_id,
             pubkey: self.ephemeral_public.to_bytes(),
             ip: local_ip.to_string(),
             port,
-            name: device_name.to_string(),
+            name: identity.name.clone(),
+            fp: Some(identity.fingerprint()),
         }
     }
 
-    /// Complete pairing with peer's public key, derive session key
-    pub fn complete(self, peer_pubkey: &PublicKey) -> SessionKey {
+    /// Complete pairing with peer's public key, derive session key the way
+    /// the negotiated `protocol_version` does
+    pub fn complete(self, peer_pubkey: &PublicKey, protocol_version: u16) -> SessionKey {
         let shared = self.ephemeral_secret.diffie_hellman(peer_pubkey);
-        SessionKey::from_shared_secret(&shared)
+        SessionKey::from_shared_secret(&shared, protocol_version)
     }
 
     /// Sign the pairing data for verification
@@ -65,23 +108,47 @@ impl Default for PairingSession {
 pub struct PairingQrData {
     pub session_id: Uuid,
     pub pubkey: [u8; 32],
+    /// Address of the offering device, IPv6 without brackets
     pub ip: String,
     pub port: u16,
     pub name: String,
+    /// Fingerprint of the offering device's identity key. The device that
+    /// answers the pairing request must hold this key. Absent in codes from
+    /// versions that didn't include it.
+    #[serde(default)]
+    pub fp: Option<String>,
 }
 
 impl PairingQrData {
+    /// Socket address to send the pairing request to
+    pub fn addr(&self) -> Result<SocketAddr> {
+        let ip: IpAddr = self.ip.parse()
+            .map_err(|_| Error::InvalidMessage(format!("invalid pairing address {}", self.ip)))?;
+        Ok(SocketAddr::new(ip, self.port))
+    }
+
     /// Encode as a URL for QR code
+    ///
+    /// IPv6 addresses are bracketed, as in any URL host.
     pub fn to_url(&self) -> String {
         let pubkey_b64 = BASE64URL.encode(self.pubkey);
-        format!(
+        let host = match self.ip.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => format!("[{}]", self.ip),
+            _ => self.ip.clone(),
+        };
+        let mut url = format!(
             "omniclip://pair?s={}&k={}&h={}&p={}&n={}",
             self.session_id,
             pubkey_b64,
-            urlencoding::encode(&self.ip),
+            urlencoding::encode(&host),
             self.port,
             urlencoding::encode(&self.name),
-        )
+        );
+        if let Some(fp) = &self.fp {
+            url.push_str("&f=");
+            url.push_str(&urlencoding::encode(fp));
+        }
+        url
     }
 
     /// Parse from URL
@@ -94,6 +161,7 @@ impl PairingQrData {
         let mut ip = None;
         let mut port = None;
         let mut name = None;
+        let mut fp = None;
 
         for part in url.split('&') {
             let (key, value) = part.split_once('=')
@@ -109,14 +177,21 @@ impl PairingQrData {
                         .map_err(|_| Error::InvalidMessage("invalid pubkey length".to_string()))?;
                     pubkey = Some(arr);
                 }
-                "h" => ip = Some(urlencoding::decode(value)
-                    .map_err(|_| Error::InvalidMessage("invalid ip".to_string()))?
-                    .to_string()),
+                "h" => {
+                    let host = urlencoding::decode(value)
+                        .map_err(|_| Error::InvalidMessage("invalid ip".to_string()))?;
+                    // Codes from older versions left IPv6 unbracketed
+                    let unbracketed = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(&host);
+                    ip = Some(unbracketed.to_string());
+                }
                 "p" => port = Some(value.parse()
                     .map_err(|_| Error::InvalidMessage("invalid port".to_string()))?),
                 "n" => name = Some(urlencoding::decode(value)
                     .map_err(|_| Error::InvalidMessage("invalid name".to_string()))?
                     .to_string()),
+                "f" => fp = Some(urlencoding::decode(value)
+                    .map_err(|_| Error::InvalidMessage("invalid fingerprint".to_string()))?
+                    .to_string()),
                 _ => {}
             }
         }
@@ -127,6 +202,7 @@ impl PairingQrData {
             ip: ip.ok_or_else(|| Error::InvalidMessage("missing ip".to_string()))?,
             port: port.ok_or_else(|| Error::InvalidMessage("missing port".to_string()))?,
             name: name.ok_or_else(|| Error::InvalidMessage("missing name".to_string()))?,
+            fp,
         })
     }
 
@@ -144,16 +220,138 @@ impl PairingQrData {
 
         Ok(svg)
     }
+
+    /// Render the QR code as RGBA pixels, `scale` pixels per module,
+    /// with the standard 4-module quiet zone. Returns width, height and
+    /// the pixel data.
+    pub fn to_qr_rgba(&self, scale: u32) -> Result<(u32, u32, Vec<u8>)> {
+        use qrcode::{Color, QrCode};
+
+        const QUIET_ZONE: u32 = 4;
+
+        if scale == 0 {
+            return Err(Error::InvalidMessage("QR scale must be at least 1".to_string()));
+        }
+        let url = self.to_url();
+        let code = QrCode::new(url.as_bytes())
+            .map_err(|e| Error::Crypto(format!("QR generation failed: {}", e)))?;
+
+        let modules = code.width() as u32;
+        let side = (modules + 2 * QUIET_ZONE) * scale;
+        let colors = code.to_colors();
+        let mut pixels = Vec::with_capacity((side * side * 4) as usize);
+        for y in 0..side {
+            for x in 0..side {
+                let (mx, my) = (x / scale, y / scale);
+                let inside = (QUIET_ZONE..QUIET_ZONE + modules).contains(&mx)
+                    && (QUIET_ZONE..QUIET_ZONE + modules).contains(&my);
+                let dark = inside
+                    && colors[((my - QUIET_ZONE) * modules + (mx - QUIET_ZONE)) as usize] == Color::Dark;
+                let value = if dark { 0 } else { 255 };
+                pixels.extend_from_slice(&[value, value, value, 255]);
+            }
+        }
+        Ok((side, side, pixels))
+    }
+
+    /// Render the QR code as a PNG image, `scale` pixels per module
+    pub fn to_qr_png(&self, scale: u32) -> Result<Vec<u8>> {
+        use image::{ImageFormat, RgbaImage};
+
+        let (width, height, pixels) = self.to_qr_rgba(scale)?;
+        let image = RgbaImage::from_raw(width, height, pixels)
+            .expect("buffer matches image dimensions");
+        let mut png = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut png, ImageFormat::Png)
+            .map_err(|e| Error::Crypto(format!("QR PNG encoding failed: {}", e)))?;
+        Ok(png.into_inner())
+    }
+}
+
+/// A device's long-term identity, shared so peers can pin it in advance
+///
+/// Unlike [`PairingQrData`] this has no session or ephemeral key; it only
+/// says which identity key belongs to which device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityQrData {
+    pub device_id: Uuid,
+    pub identity_pubkey: [u8; 32],
+    pub name: String,
+}
+
+impl IdentityQrData {
+    pub fn new(device_id: Uuid, identity_pubkey: &VerifyingKey, name: &str) -> Self {
+        Self {
+            device_id,
+            identity_pubkey: identity_pubkey.to_bytes(),
+            name: name.to_string(),
+        }
+    }
+
+    /// Encode as a URL for QR code
+    pub fn to_url(&self) -> String {
+        format!(
+            "omniclip://device?id={}&k={}&n={}",
+            self.device_id,
+            BASE64URL.encode(self.identity_pubkey),
+            urlencoding::encode(&self.name),
+        )
+    }
+
+    /// Parse from URL, checking the key is a valid identity key
+    pub fn from_url(url: &str) -> Result<Self> {
+        let url = url.strip_prefix("omniclip://device?")
+            .ok_or_else(|| Error::InvalidMessage("invalid scheme".to_string()))?;
+
+        let mut device_id = None;
+        let mut identity_pubkey = None;
+        let mut name = None;
+
+        for part in url.split('&') {
+            let (key, value) = part.split_once('=')
+                .ok_or_else(|| Error::InvalidMessage("invalid param".to_string()))?;
+
+            match key {
+                "id" => device_id = Some(Uuid::parse_str(value)
+                    .map_err(|_| Error::InvalidMessage("invalid device id".to_string()))?),
+                "k" => {
+                    let bytes = BASE64URL.decode(value)
+                        .map_err(|_| Error::InvalidMessage("invalid identity key".to_string()))?;
+                    let arr: [u8; 32] = bytes.try_into()
+                        .map_err(|_| Error::InvalidMessage("invalid identity key length".to_string()))?;
+                    VerifyingKey::from_bytes(&arr)?;
+                    identity_pubkey = Some(arr);
+                }
+                "n" => name = Some(urlencoding::decode(value)
+                    .map_err(|_| Error::InvalidMessage("invalid name".to_string()))?
+                    .to_string()),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            device_id: device_id.ok_or_else(|| Error::InvalidMessage("missing device id".to_string()))?,
+            identity_pubkey: identity_pubkey.ok_or_else(|| Error::InvalidMessage("missing identity key".to_string()))?,
+            name: name.ok_or_else(|| Error::InvalidMessage("missing name".to_string()))?,
+        })
+    }
+
+    /// The identity key
+    pub fn verifying_key(&self) -> Result<VerifyingKey> {
+        VerifyingKey::from_bytes(&self.identity_pubkey)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::constants::PROTOCOL_VERSION;
 
     #[test]
     fn test_qr_url_roundtrip() {
         let session = PairingSession::new();
-        let qr_data = session.qr_data("192.168.1.100", 17394, "My Device");
+        let identity = DeviceIdentity::new("My Device".to_string());
+        let qr_data = session.qr_data("192.168.1.100", 17394, &identity);
 
         let url = qr_data.to_url();
         let parsed = PairingQrData::from_url(&url).unwrap();
@@ -163,6 +361,79 @@ mod tests {
         assert_eq!(parsed.ip, qr_data.ip);
         assert_eq!(parsed.port, qr_data.port);
         assert_eq!(parsed.name, qr_data.name);
+        assert_eq!(parsed.fp, Some(identity.fingerprint()));
+
+        // Codes from before the fingerprint was added still parse
+        let old = url.split("&f=").next().unwrap();
+        assert_eq!(PairingQrData::from_url(old).unwrap().fp, None);
+    }
+
+    #[test]
+    fn test_ipv6_qr_url_roundtrip() {
+        let identity = DeviceIdentity::new("desk".to_string());
+        let qr_data = PairingSession::new().qr_data("fd00::2", 17394, &identity);
+
+        let url = qr_data.to_url();
+        assert!(url.contains(&format!("&h={}&", urlencoding::encode("[fd00::2]"))), "{}", url);
+        let parsed = PairingQrData::from_url(&url).unwrap();
+        assert_eq!(parsed.ip, "fd00::2");
+        assert_eq!(parsed.addr().unwrap(), "[fd00::2]:17394".parse().unwrap());
+
+        // Unbracketed, as older versions wrote it
+        let old = url.replace(&*urlencoding::encode("[fd00::2]"), &urlencoding::encode("fd00::2"));
+        assert_eq!(PairingQrData::from_url(&old).unwrap().ip, "fd00::2");
+
+        let v4 = PairingSession::new().qr_data("192.168.1.100", 17394, &identity);
+        assert_eq!(PairingQrData::from_url(&v4.to_url()).unwrap().addr().unwrap(), "192.168.1.100:17394".parse().unwrap());
+    }
+
+    #[test]
+    fn test_qr_png_dimensions() {
+        let qr_data = PairingSession::new().qr_data("192.168.1.100", 17394, &DeviceIdentity::new("My Device".to_string()));
+        let modules = qrcode::QrCode::new(qr_data.to_url().as_bytes()).unwrap().width() as u32;
+
+        let png = qr_data.to_qr_png(3).unwrap();
+        let image = image::load_from_memory_with_format(&png, image::ImageFormat::Png).unwrap();
+        assert_eq!(image.width(), image.height());
+        assert_eq!(image.width(), (modules + 8) * 3);
+
+        // Quiet zone is light, the finder pattern's corner is dark
+        let image = image.to_luma8();
+        assert_eq!(image.get_pixel(0, 0).0, [255]);
+        assert_eq!(image.get_pixel(4 * 3, 4 * 3).0, [0]);
+
+        assert!(qr_data.to_qr_png(0).is_err());
+    }
+
+    #[test]
+    fn test_identity_url_roundtrip() {
+        let key = SigningKey::generate().verifying_key();
+        let identity = IdentityQrData::new(Uuid::new_v4(), &key, "Work Laptop & Co");
+
+        let parsed = IdentityQrData::from_url(&identity.to_url()).unwrap();
+        assert_eq!(parsed.device_id, identity.device_id);
+        assert_eq!(parsed.identity_pubkey, key.to_bytes());
+        assert_eq!(parsed.name, "Work Laptop & Co");
+        assert_eq!(parsed.verifying_key().unwrap().fingerprint(), key.fingerprint());
+    }
+
+    #[test]
+    fn test_identity_url_rejects_pairing_url() {
+        let pairing_url = PairingSession::new().qr_data("10.0.0.2", 17394, &DeviceIdentity::new("desk".to_string())).to_url();
+        assert!(IdentityQrData::from_url(&pairing_url).is_err());
+
+        let truncated = format!("omniclip://device?id={}&k=AAAA&n=desk", Uuid::new_v4());
+        assert!(IdentityQrData::from_url(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let mut session = PairingSession::with_hint("phone");
+        assert!(!session.is_expired());
+        assert_eq!(session.info().device_name_hint.as_deref(), Some("phone"));
+
+        session.created_at -= Duration::from_secs(PAIRING_SESSION_TTL_SECS + 1);
+        assert!(session.is_expired());
     }
 
     #[test]
@@ -176,8 +447,8 @@ mod tests {
         let pubkey_b = session_b.ephemeral_public.clone();
 
         // Both derive session keys
-        let key_a = session_a.complete(&pubkey_b);
-        let key_b = session_b.complete(&pubkey_a);
+        let key_a = session_a.complete(&pubkey_b, PROTOCOL_VERSION);
+        let key_b = session_b.complete(&pubkey_a, PROTOCOL_VERSION);
 
         // Keys should work for encryption/decryption
         let plaintext = b"test message";
@@ -1,17 +1,41 @@
 //! High-level Omniclip service that coordinates all components
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use tokio::sync::{mpsc, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::{mpsc, oneshot, Notify, RwLock};
+use tokio::task::JoinHandle;
 use uuid::Uuid;
 
-use crate::clipboard::{self};
-use crate::crypto::SessionKey;
-use crate::discovery::{DiscoveryEvent, DiscoveryService, PeerInfo};
-use crate::protocol::{ClipboardContent, ClipboardSyncMessage, ContentHash, Message, PairingSession};
-use crate::sync::server::{SyncEvent, SyncServer, SyncServerHandle};
+use crate::audit::{AuditEntry, AuditEvent, AuditLog, Direction};
+use crate::clipboard::{
+    self, ChangeReceiver, ClipboardBackend, ClipboardChange, ClipboardSink, ClipboardWriter, ContentFilter, FilterRules,
+    Normalization,
+};
+use crate::clock::SyncClock;
+use crate::crypto::{EncryptedPayload, KeyEpoch, KeyRotation, SessionKey, SessionKeyRing, VerifyingKey};
+use crate::discovery::{AddressFilter, DiscoveryEvent, DiscoveryService, PeerInfo};
+use crate::protocol::constants::{
+    AUDIT_LOG_MAX_SIZE, STATE_FLUSH_INTERVAL_SECS, CLIPBOARD_CONFIRM_DELAY_MS, CLIPBOARD_POLL_INTERVAL_MS, COMPRESSION_MIN_SIZE, DELTA_MIN_SIZE,
+    KEY_ROTATION_CHECK_INTERVAL_SECS, MANUAL_PEERS_FILE, MANUAL_PEER_POLL_INTERVAL_SECS, MAX_DECOMPRESSED_SIZE, MAX_RECENT_HASHES, MAX_RETRANSMITS, MAX_SEEN_MESSAGE_IDS, OUTBOX_TTL_SECS, PAIRED_DEVICES_FILE, PAIRING_FLAG_POLL_INTERVAL_MS,
+    PENDING_UNPAIRS_FILE,
+    PEER_NOTIFY_TIMEOUT_MS, PROBE_CHALLENGE_CONTEXT, PROBE_RESPONSE_CONTEXT, PROTOCOL_VERSION, SESSION_POLL_INTERVAL_MS,
+};
+use crate::protocol::compression;
+use crate::protocol::{
+    clipboard_aad, probe_aad, ClipboardContent, ClipboardDeltaMessage, ClipboardSelection, ClipboardSyncMessage, Compression,
+    ContentHash, ContentKind,
+    Message, NegotiatedFeatures, PairingQrData, PairingSession, PairingSessionInfo, TextPatch, WireCodec,
+};
+use crate::session::{self, SessionState, SystemSession};
+use crate::storage::{DebouncedSave, StateStore};
+use crate::sync::server::{PairedDevice, SyncEvent, SyncServer, SyncServerHandle};
+use crate::sync::{self, ConnectionPool, PeerConnection, PoolStats, SyncDirection};
 use crate::{Config, DeviceIdentity, Error, Result};
 
 /// Events emitted by the Omniclip service
@@ -19,67 +43,680 @@ use crate::{Config, DeviceIdentity, Error, Result};
 pub enum ServiceEvent {
     /// A new device was discovered on the network
     DeviceDiscovered(PeerInfo),
+    /// A known device changed its name, addresses, port or pairing flag
+    DeviceUpdated(PeerInfo),
     /// A device went offline
     DeviceLost(Uuid),
+    /// A paired device removed its pairing with us
+    DeviceUnpaired(Uuid),
     /// Pairing request received from another device
     PairingRequest { device_id: Uuid, device_name: String },
     /// Clipboard was synced from another device
     ClipboardReceived { from_device: Uuid, content: ClipboardContent },
+    /// Clipboard arrived from a device whose writes are blocked, and was
+    /// not written locally
+    ClipboardWithheld { from_device: Uuid, content: ClipboardContent },
+    /// A local clipboard change was too large to send
+    ClipboardTooLarge { size: usize, limit: usize },
+    /// A local clipboard change was not sent because it matches content
+    /// exchanged recently; seeing this often points at a sync loop between
+    /// devices
+    SyncSuppressed { hash: ContentHash },
     /// Our clipboard was sent to other devices
     ClipboardSent { to_devices: Vec<Uuid> },
+    /// A device acknowledged receiving a clipboard message we sent
+    DeliveryConfirmed { device_id: Uuid, message_id: Uuid },
+    /// A paired device's session key was replaced, starting a new key epoch
+    Rekeyed { device_id: Uuid, epoch: KeyEpoch },
+    /// A clipboard message was dropped because it was stale or had been
+    /// received before
+    ReplayRejected { device_id: Uuid, message_id: Uuid },
+    /// A device we asked to pair with refused, saying why
+    PairingRejected { reason: String },
+    /// A device on the network uses a protocol version this one can't
+    /// talk to, so it is neither synced with nor paired; one of the two
+    /// needs updating
+    IncompatiblePeer { device_id: Uuid, device_name: String, protocol_version: u16 },
+    /// Our address changed while a pairing session was open; `url` replaces
+    /// the pairing URL (and QR code) shown before
+    PairingUrlChanged { url: String },
+    /// The desktop session was locked or unlocked (with `pause_when_locked`)
+    SessionStateChanged(SessionState),
+    /// Sync was paused or resumed through [`OmniclipService::pause`] and
+    /// [`OmniclipService::resume`]; `paused` is whether it is now paused for
+    /// any reason
+    PauseChanged { paused: bool },
+    /// Events weren't taken as fast as they were raised, and the service
+    /// waited `waited` for room before going on; raise
+    /// [`Config::event_capacity`] or handle events faster
+    Backpressure { waited: Duration },
     /// Error occurred
     Error(String),
 }
 
+/// Clipboard message counters since the service started
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyncStats {
+    /// Clipboard messages sent to paired devices
+    pub messages_sent: u64,
+    /// Clipboard messages accepted from paired devices
+    pub messages_received: u64,
+    /// Sent messages acknowledged by the receiver
+    pub messages_acked: u64,
+}
+
+/// A paired device, as listed by [`OmniclipService::get_paired_devices`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairedDeviceSummary {
+    pub device_id: Uuid,
+    pub device_name: String,
+    /// Fingerprint of the identity key the device is pinned to
+    pub fingerprint: String,
+    /// When the device last connected to us, or when it was paired
+    pub last_seen: Instant,
+    /// Whether the device has a connection open to us now
+    pub connected: bool,
+    /// Which way clipboard changes flow with the device
+    pub direction: SyncDirection,
+}
+
+/// Reasons clipboard sync is currently paused
+#[derive(Default)]
+struct PauseState {
+    /// Paused through [`OmniclipService::pause`]
+    manual: AtomicBool,
+    /// Paused because the desktop session is locked
+    locked: AtomicBool,
+    /// Signalled when either reason is cleared
+    resumed: tokio::sync::Notify,
+}
+
+impl PauseState {
+    fn is_paused(&self) -> bool {
+        self.manual.load(Ordering::SeqCst) || self.locked.load(Ordering::SeqCst)
+    }
+}
+
+/// Sender for service events that tells the application when it falls
+/// behind
+///
+/// When the channel is full, `send` waits for room as a plain sender would,
+/// but the event goes out after one [`ServiceEvent::Backpressure`] covering
+/// the stall. Events from `try_send` that don't fit are held, a newer one
+/// replacing an older, and delivered once there is room.
+#[derive(Clone)]
+struct EventSender {
+    tx: mpsc::Sender<ServiceEvent>,
+    /// Set while a sender is waiting to report a stall
+    stalled: Arc<AtomicBool>,
+    held: Arc<std::sync::Mutex<Option<ServiceEvent>>>,
+}
+
+/// Event channel holding up to `capacity` events
+fn event_channel(capacity: usize) -> (EventSender, mpsc::Receiver<ServiceEvent>) {
+    let (tx, rx) = mpsc::channel(capacity.max(1));
+    let sender = EventSender {
+        tx,
+        stalled: Arc::new(AtomicBool::new(false)),
+        held: Arc::new(std::sync::Mutex::new(None)),
+    };
+    (sender, rx)
+}
+
+impl EventSender {
+    async fn send(&self, event: ServiceEvent) -> std::result::Result<(), SendError<ServiceEvent>> {
+        match self.tx.try_send(event) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Closed(event)) => Err(SendError(event)),
+            Err(TrySendError::Full(event)) => self.send_after_stall(event).await,
+        }
+    }
+
+    /// Send without waiting, holding the event if there is no room
+    fn try_send(&self, event: ServiceEvent) {
+        let Err(TrySendError::Full(event)) = self.tx.try_send(event) else {
+            return;
+        };
+        // A task is already waiting to deliver the held event
+        if self.held.lock().unwrap().replace(event).is_some() {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let sender = self.clone();
+        runtime.spawn(async move {
+            let Some(event) = sender.held.lock().unwrap().take() else {
+                return;
+            };
+            let _ = sender.send_after_stall(event).await;
+        });
+    }
+
+    async fn send_after_stall(&self, event: ServiceEvent) -> std::result::Result<(), SendError<ServiceEvent>> {
+        let started = Instant::now();
+        let report = !self.stalled.swap(true, Ordering::SeqCst);
+        // Room for the report and the event together, so they arrive in
+        // order, unless the channel only holds one
+        let wanted = if report { 2 } else { 1 };
+        let Ok(mut permits) = self.tx.reserve_many(wanted.min(self.tx.max_capacity())).await else {
+            return Err(SendError(event));
+        };
+        if report {
+            let waited = started.elapsed();
+            tracing::warn!("service events not taken for {:?}, consider a larger event_capacity", waited);
+            if let Some(permit) = permits.next() {
+                permit.send(ServiceEvent::Backpressure { waited });
+            }
+            self.stalled.store(false, Ordering::SeqCst);
+        }
+        match permits.next() {
+            Some(permit) => {
+                permit.send(event);
+                Ok(())
+            }
+            None => self.tx.send(event).await,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+/// Delivery bookkeeping for outbound clipboard messages
+#[derive(Default)]
+struct DeliveryTracker {
+    /// Latest unacknowledged message sent to each device
+    pending: HashMap<Uuid, PendingDelivery>,
+    stats: SyncStats,
+}
+
+/// A clipboard message waiting for its acknowledgement
+struct PendingDelivery {
+    message_id: Uuid,
+    /// What it carried, to send again if the ack doesn't come
+    change: ClipboardChange,
+    sent_at: Instant,
+    /// Times the same content was sent again before this message
+    retransmits: u32,
+}
+
+/// Latest undelivered change for paired devices that are offline
+struct Outbox {
+    enabled: bool,
+    /// Devices currently visible on the network
+    online: HashSet<Uuid>,
+    /// Only the newest change per device matters, so each holds one entry
+    queued: HashMap<Uuid, (ClipboardChange, Instant)>,
+}
+
+impl Outbox {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            online: HashSet::new(),
+            queued: HashMap::new(),
+        }
+    }
+
+    /// Queue `change` for `device_id` if it is offline, replacing anything
+    /// queued before. Returns whether it was queued.
+    fn hold(&mut self, device_id: Uuid, change: &ClipboardChange) -> bool {
+        if !self.enabled || self.online.contains(&device_id) {
+            return false;
+        }
+        self.queued.insert(device_id, (change.clone(), Instant::now()));
+        true
+    }
+
+    /// Mark a device online and take its queued change, unless it has gone stale
+    fn reconnect(&mut self, device_id: Uuid) -> Option<ClipboardChange> {
+        self.online.insert(device_id);
+        self.queued.remove(&device_id)
+            .filter(|(_, queued_at)| queued_at.elapsed() < Duration::from_secs(OUTBOX_TTL_SECS))
+            .map(|(change, _)| change)
+    }
+
+    fn disconnect(&mut self, device_id: Uuid) {
+        self.online.remove(&device_id);
+    }
+}
+
+/// Content recently exchanged in either direction, to spot bounces
+///
+/// Content we sent can come back to us, for example relayed by a third
+/// device, and content we received can turn up again as a local change when
+/// another app writes it back. Neither should go around again while it is
+/// recent, even if other content was exchanged in between.
+struct RecentContent {
+    window: Duration,
+    entries: VecDeque<(ContentHash, Instant)>,
+}
+
+impl RecentContent {
+    fn new(window: Duration) -> Self {
+        Self { window, entries: VecDeque::with_capacity(MAX_RECENT_HASHES) }
+    }
+
+    /// Whether `hash` was exchanged within the window
+    fn contains(&self, hash: ContentHash) -> bool {
+        self.entries.iter().any(|(recent, at)| *recent == hash && at.elapsed() < self.window)
+    }
+
+    /// Record content sent to peers
+    fn sent(&mut self, hash: ContentHash) {
+        self.record(hash);
+    }
+
+    /// Record content received from a peer. Returns `true` if it was
+    /// already exchanged within the window.
+    fn received(&mut self, hash: ContentHash) -> bool {
+        let duplicate = self.contains(hash);
+        self.record(hash);
+        duplicate
+    }
+
+    fn record(&mut self, hash: ContentHash) {
+        self.entries.retain(|(recent, _)| *recent != hash);
+        if self.entries.len() == MAX_RECENT_HASHES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((hash, Instant::now()));
+    }
+}
+
+/// Content sent or received, as kept in the clipboard history
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub content: ClipboardContent,
+    pub hash: ContentHash,
+    /// Unix timestamp the content was copied or sent at
+    pub timestamp: u64,
+    /// Device the content was copied on; our own ID for local copies
+    pub origin: Uuid,
+}
+
+/// The most recent clipboard entries, newest first
+///
+/// Content equal to the newest entry isn't recorded again, so copying the
+/// same thing twice or receiving our own copy back leaves one entry.
+struct ClipboardHistory {
+    capacity: usize,
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl ClipboardHistory {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, entries: VecDeque::with_capacity(capacity) }
+    }
+
+    fn record(&mut self, content: &ClipboardContent, timestamp: u64, origin: Uuid) {
+        let hash = content.hash();
+        if self.capacity == 0 || self.entries.front().is_some_and(|newest| newest.hash == hash) {
+            return;
+        }
+        self.entries.truncate(self.capacity - 1);
+        self.entries.push_front(HistoryEntry { content: content.clone(), hash, timestamp, origin });
+    }
+}
+
+/// Clipboard message ids recently accepted from each device, to drop
+/// captured frames sent again
+///
+/// Messages stamped outside the window are dropped outright, so only ids
+/// within it need remembering. Each device's list is also capped at
+/// [`MAX_SEEN_MESSAGE_IDS`], the oldest going first.
+struct ReplayGuard {
+    window: Duration,
+    seen: HashMap<Uuid, VecDeque<(Uuid, u64)>>,
+}
+
+impl ReplayGuard {
+    fn new(window: Duration) -> Self {
+        Self { window, seen: HashMap::new() }
+    }
+
+    /// Record a message from `device_id` stamped `timestamp`, received at
+    /// `now`. Returns `false` if it is stale or its id was seen before.
+    fn accept(&mut self, device_id: Uuid, message_id: Uuid, timestamp: u64, now: u64) -> bool {
+        if self.window.is_zero() {
+            return true;
+        }
+        let window = self.window.as_secs();
+        if timestamp.abs_diff(now) > window {
+            return false;
+        }
+
+        let seen = self.seen.entry(device_id).or_default();
+        seen.retain(|(_, stamped)| stamped.abs_diff(now) <= window);
+        if seen.iter().any(|(id, _)| *id == message_id) {
+            return false;
+        }
+        if seen.len() >= MAX_SEEN_MESSAGE_IDS {
+            seen.pop_front();
+        }
+        seen.push_back((message_id, timestamp));
+        true
+    }
+}
+
+/// A clipboard message from a paired device, with its content decoded or
+/// the reason it couldn't be
+struct InboundClipboard {
+    /// What the message was, for logging
+    kind: &'static str,
+    message_id: Uuid,
+    timestamp: u64,
+    channel: String,
+    selection: ClipboardSelection,
+    content_hash: ContentHash,
+    /// Size of the encrypted content as received
+    wire_size: usize,
+    content: Result<ClipboardContent>,
+}
+
 /// Paired device storage
 #[derive(Clone)]
 struct PairedDeviceInfo {
     device_id: Uuid,
     device_name: String,
-    session_key: SessionKey,
+    /// Long-term identity key the device is pinned to
+    identity_pubkey: VerifyingKey,
+    /// Current and recently retired session keys
+    keys: SessionKeyRing,
+    features: NegotiatedFeatures,
+    direction: SyncDirection,
     last_seen: std::time::Instant,
 }
 
+/// Let the sync server accept messages from `device`, under its current key
+async fn register(server_devices: &RwLock<HashMap<Uuid, PairedDevice>>, device: &PairedDeviceInfo) {
+    server_devices.write().await.insert(device.device_id, PairedDevice {
+        device_id: device.device_id,
+        device_name: device.device_name.clone(),
+        identity_pubkey: device.identity_pubkey.clone(),
+        session_key: device.keys.current().clone(),
+        features: device.features.clone(),
+    });
+}
+
+/// Move the sync server onto `device_id`'s new session key
+async fn rekey_server(server_devices: &RwLock<HashMap<Uuid, PairedDevice>>, device_id: Uuid, key: &SessionKey) {
+    if let Some(device) = server_devices.write().await.get_mut(&device_id) {
+        device.session_key = key.clone();
+    }
+}
+
+/// A paired device as saved in [`PAIRED_DEVICES_FILE`]
+#[derive(Serialize, Deserialize)]
+struct StoredDevice {
+    device_id: Uuid,
+    device_name: String,
+    identity_pubkey: VerifyingKey,
+    /// Epoch of `session_key`; retired keys aren't kept
+    key_epoch: u32,
+    #[serde(with = "crate::crypto::serde_utils::base64_array_32")]
+    session_key: [u8; 32],
+    /// Codec and compression negotiated when pairing; devices saved
+    /// before they were recorded get JSON and no compression
+    #[serde(default)]
+    wire_codec: WireCodec,
+    #[serde(default)]
+    compression: Compression,
+    #[serde(default)]
+    direction: SyncDirection,
+}
+
+impl StoredDevice {
+    fn new(device: &PairedDeviceInfo) -> Self {
+        Self {
+            device_id: device.device_id,
+            device_name: device.device_name.clone(),
+            identity_pubkey: device.identity_pubkey.clone(),
+            key_epoch: device.keys.current_epoch().0,
+            session_key: device.keys.current().to_bytes(),
+            wire_codec: device.features.wire_codec,
+            compression: device.features.compression,
+            direction: device.direction,
+        }
+    }
+
+    fn into_info(self) -> PairedDeviceInfo {
+        PairedDeviceInfo {
+            device_id: self.device_id,
+            device_name: self.device_name,
+            identity_pubkey: self.identity_pubkey,
+            keys: SessionKeyRing::with_epoch(KeyEpoch(self.key_epoch), SessionKey::from_bytes(&self.session_key)),
+            features: NegotiatedFeatures::negotiated(self.wire_codec, self.compression),
+            direction: self.direction,
+            last_seen: std::time::Instant::now(),
+        }
+    }
+}
+
+/// Writers for the state files, each saving in the background so changes
+/// don't block on the disk or race each other for a file
+struct StateFiles {
+    paired: DebouncedSave<Vec<StoredDevice>>,
+    unpairs: DebouncedSave<Vec<StoredUnpair>>,
+    peers: DebouncedSave<Vec<ManualPeer>>,
+}
+
+impl StateFiles {
+    fn new(store: Arc<StateStore>) -> Self {
+        let interval = Duration::from_secs(STATE_FLUSH_INTERVAL_SECS);
+        Self {
+            paired: DebouncedSave::new(store.clone(), PAIRED_DEVICES_FILE, interval),
+            unpairs: DebouncedSave::new(store.clone(), PENDING_UNPAIRS_FILE, interval),
+            peers: DebouncedSave::new(store, MANUAL_PEERS_FILE, interval),
+        }
+    }
+
+    /// Write every pending change now
+    async fn flush(&self) -> Result<()> {
+        self.paired.flush().await?;
+        self.unpairs.flush().await?;
+        self.peers.flush().await
+    }
+}
+
+/// An unpair notification still owed to a device
+#[derive(Clone)]
+struct OwedUnpair {
+    /// Key the notification is proven with
+    session_key: SessionKey,
+    /// The device's identity key, for encrypted transports; absent for
+    /// notifications saved by versions that didn't record it
+    identity_pubkey: Option<VerifyingKey>,
+}
+
+/// An unpair notification as saved in [`PENDING_UNPAIRS_FILE`]
+#[derive(Serialize, Deserialize)]
+struct StoredUnpair {
+    device_id: Uuid,
+    #[serde(with = "crate::crypto::serde_utils::base64_array_32")]
+    session_key: [u8; 32],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    identity_pubkey: Option<VerifyingKey>,
+}
+
+/// A peer added by address, as saved in [`MANUAL_PEERS_FILE`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ManualPeer {
+    addr: SocketAddr,
+    /// Identity key fingerprint the peer must show, if given
+    #[serde(default)]
+    fingerprint: Option<String>,
+}
+
 /// Main Omniclip service
 pub struct OmniclipService {
     config: Config,
     identity: DeviceIdentity,
-    discovery: Option<DiscoveryService>,
+    discovery: Option<Arc<DiscoveryService>>,
     server: Option<SyncServerHandle>,
     paired_devices: Arc<RwLock<HashMap<Uuid, PairedDeviceInfo>>>,
-    active_pairing: Arc<RwLock<Option<PairingSession>>>,
+    /// The sync server's view of `paired_devices`, shared with it
+    server_devices: Arc<RwLock<HashMap<Uuid, PairedDevice>>>,
+    /// Pairing sessions awaiting a request, keyed by session ID
+    pairing_sessions: Arc<RwLock<HashMap<Uuid, PairingSession>>>,
     last_sent_hash: Arc<RwLock<Option<ContentHash>>>,
+    /// When we last broadcast a local change, for conflict resolution
+    last_local_change: Arc<RwLock<Option<u64>>>,
+    /// Last content exchanged with each device, used as the base for deltas
+    synced_content: Arc<RwLock<HashMap<Uuid, ClipboardContent>>>,
+    /// Devices unpaired while unreachable that still need to be notified
+    pending_unpairs: Arc<RwLock<HashMap<Uuid, OwedUnpair>>>,
+    /// Outstanding acknowledgements and message counters
+    deliveries: Arc<RwLock<DeliveryTracker>>,
+    pause: Arc<PauseState>,
+    /// Timestamps for outgoing clipboard messages
+    clock: Arc<SyncClock>,
+    outbox: Arc<RwLock<Outbox>>,
+    /// Devices whose clipboard content is reported but not written
+    write_blocked: Arc<RwLock<HashSet<Uuid>>>,
+    /// Open connections from each paired device
+    connections: Arc<RwLock<HashMap<Uuid, usize>>>,
+    /// Where each device on the network was last resolved, for sending to it
+    peer_addresses: Arc<RwLock<HashMap<Uuid, PeerInfo>>>,
+    /// Connections clipboard messages are sent over, one per device
+    pool: Arc<ConnectionPool>,
+    /// Replies read from pooled connections, until started
+    pool_replies: Option<mpsc::Receiver<(Uuid, Message)>>,
+    /// Peers added by address, for networks mDNS doesn't reach
+    manual_peers: Arc<RwLock<Vec<ManualPeer>>>,
+    /// Wakes the task polling manual peers when one is added
+    manual_peers_added: Arc<Notify>,
+    recent: Arc<RwLock<RecentContent>>,
+    history: Arc<RwLock<ClipboardHistory>>,
+    /// Rules for local content that isn't synced, shared with the monitor
+    filter: Arc<ContentFilter>,
+    /// Writer for the local clipboard, once started
+    clipboard: Option<ClipboardWriter>,
+    /// Clipboard to use instead of the system's, until started
+    clipboard_backend: std::sync::Mutex<Option<clipboard::ClipboardManager>>,
+    audit: Option<Arc<AuditLog>>,
+    /// Where paired devices are saved, once started
+    state: Option<Arc<StateFiles>>,
+    /// Store to load and save state in, if opened by the caller
+    state_store: Option<Arc<StateStore>>,
+    /// Sender for events raised outside the service's tasks, once started
+    events: Option<EventSender>,
+    /// Tasks spawned by [`start`](Self::start), stopped on shutdown
+    tasks: Vec<JoinHandle<()>>,
+    /// Port the sync server took, once started
+    port: Option<u16>,
 }
 
 impl OmniclipService {
     /// Create a new Omniclip service
     pub fn new(device_name: String) -> Self {
         let identity = DeviceIdentity::new(device_name);
+        let (replies_tx, pool_replies) = mpsc::channel(64);
         Self {
             config: Config::default(),
             identity,
             discovery: None,
             server: None,
             paired_devices: Arc::new(RwLock::new(HashMap::new())),
-            active_pairing: Arc::new(RwLock::new(None)),
+            server_devices: Arc::new(RwLock::new(HashMap::new())),
+            pairing_sessions: Arc::new(RwLock::new(HashMap::new())),
             last_sent_hash: Arc::new(RwLock::new(None)),
+            last_local_change: Arc::new(RwLock::new(None)),
+            synced_content: Arc::new(RwLock::new(HashMap::new())),
+            pending_unpairs: Arc::new(RwLock::new(HashMap::new())),
+            deliveries: Arc::new(RwLock::new(DeliveryTracker::default())),
+            pause: Arc::new(PauseState::default()),
+            clock: Arc::new(SyncClock::default()),
+            outbox: Arc::new(RwLock::new(Outbox::new(false))),
+            write_blocked: Arc::new(RwLock::new(HashSet::new())),
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            peer_addresses: Arc::new(RwLock::new(HashMap::new())),
+            pool: Arc::new(ConnectionPool::new(replies_tx)),
+            pool_replies: Some(pool_replies),
+            manual_peers: Arc::new(RwLock::new(Vec::new())),
+            manual_peers_added: Arc::new(Notify::new()),
+            recent: Arc::new(RwLock::new(RecentContent::new(Config::default().dedup_window))),
+            history: Arc::new(RwLock::new(ClipboardHistory::new(Config::default().history_capacity))),
+            filter: Arc::new(ContentFilter::default()),
+            clipboard: None,
+            clipboard_backend: std::sync::Mutex::new(None),
+            audit: None,
+            state: None,
+            state_store: None,
+            events: None,
+            tasks: Vec::new(),
+            port: None,
         }
     }
 
     /// Create with custom config
     pub fn with_config(device_name: String, config: Config) -> Self {
-        let identity = DeviceIdentity::new(device_name);
+        Self::with_identity(DeviceIdentity::new(device_name), config)
+    }
+
+    /// Create with an existing identity, such as one loaded with
+    /// [`DeviceIdentity::load_or_create`]
+    pub fn with_identity(identity: DeviceIdentity, config: Config) -> Self {
+        let queue_for_offline = config.queue_for_offline;
+        let write_blocked = config.block_writes_from.clone();
+        let dedup_window = config.dedup_window;
+        let history_capacity = config.history_capacity;
+        let filter_rules = config.filter.clone();
+        let transport_encryption = config.transport_encryption;
+        let (replies_tx, pool_replies) = mpsc::channel(64);
         Self {
             config,
             identity,
             discovery: None,
             server: None,
             paired_devices: Arc::new(RwLock::new(HashMap::new())),
-            active_pairing: Arc::new(RwLock::new(None)),
+            server_devices: Arc::new(RwLock::new(HashMap::new())),
+            pairing_sessions: Arc::new(RwLock::new(HashMap::new())),
             last_sent_hash: Arc::new(RwLock::new(None)),
+            last_local_change: Arc::new(RwLock::new(None)),
+            synced_content: Arc::new(RwLock::new(HashMap::new())),
+            pending_unpairs: Arc::new(RwLock::new(HashMap::new())),
+            deliveries: Arc::new(RwLock::new(DeliveryTracker::default())),
+            pause: Arc::new(PauseState::default()),
+            clock: Arc::new(SyncClock::default()),
+            outbox: Arc::new(RwLock::new(Outbox::new(queue_for_offline))),
+            write_blocked: Arc::new(RwLock::new(write_blocked)),
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            peer_addresses: Arc::new(RwLock::new(HashMap::new())),
+            pool: Arc::new(ConnectionPool::new(replies_tx).with_transport_encryption(transport_encryption)),
+            pool_replies: Some(pool_replies),
+            manual_peers: Arc::new(RwLock::new(Vec::new())),
+            manual_peers_added: Arc::new(Notify::new()),
+            recent: Arc::new(RwLock::new(RecentContent::new(dedup_window))),
+            history: Arc::new(RwLock::new(ClipboardHistory::new(history_capacity))),
+            filter: Arc::new(ContentFilter::new(&filter_rules).unwrap_or_default()),
+            clipboard: None,
+            clipboard_backend: std::sync::Mutex::new(None),
+            audit: None,
+            state: None,
+            state_store: None,
+            events: None,
+            tasks: Vec::new(),
+            port: None,
         }
     }
 
+    /// Keep state in `store` rather than opening the data directory
+    /// unencrypted, such as one opened with [`StateStore::open_encrypted`]
+    pub fn with_state_store(mut self, store: StateStore) -> Self {
+        self.state_store = Some(Arc::new(store));
+        self
+    }
+
+    /// Watch and write `backend` instead of the system clipboard, such as an
+    /// in-memory one in tests
+    pub fn with_clipboard_backend(self, backend: impl ClipboardBackend + 'static) -> Self {
+        *self.clipboard_backend.lock().unwrap() = Some(clipboard::ClipboardManager::with_backend(backend));
+        self
+    }
+
     /// Get our device ID
     pub fn device_id(&self) -> Uuid {
         self.identity.id
@@ -95,185 +732,3475 @@ impl OmniclipService {
         self.identity.fingerprint()
     }
 
+    /// URL identifying this device, for peers to pin before pairing
+    pub fn identity_url(&self) -> String {
+        self.identity.qr_data().to_url()
+    }
+
+    /// Directory state is kept in
+    pub fn data_dir(&self) -> &std::path::Path {
+        &self.config.data_dir
+    }
+
+    /// Port the sync server listens on, once started
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Restore devices paired and unpaired in earlier runs from the data
+    /// directory, and save changes there from now on
+    ///
+    /// Changes are written a few seconds after they are made, or on
+    /// [`shutdown`](Self::shutdown).
+    ///
+    /// [`start`](Self::start) does this; calling it first lets paired
+    /// devices be listed or unpaired without starting the service. Pairings
+    /// made before it take precedence and are saved along with the rest.
+    pub async fn load_state(&mut self) -> Result<()> {
+        let state = match &self.state_store {
+            Some(store) => store.clone(),
+            None => Arc::new(StateStore::open(&self.config.data_dir)?),
+        };
+        if !state.is_sealed() {
+            tracing::warn!(
+                "session keys in {} are stored unencrypted; set a passphrase to encrypt them",
+                self.config.data_dir.display()
+            );
+        }
+        {
+            let mut paired = self.paired_devices.write().await;
+            for (device_id, device) in load_paired(&state)? {
+                paired.entry(device_id).or_insert(device);
+            }
+            tracing::info!("{} paired device(s)", paired.len());
+        }
+        {
+            let stored: Vec<StoredUnpair> = state.load(PENDING_UNPAIRS_FILE)?.unwrap_or_default();
+            let mut pending = self.pending_unpairs.write().await;
+            for unpair in stored {
+                pending.entry(unpair.device_id).or_insert_with(|| OwedUnpair {
+                    session_key: SessionKey::from_bytes(&unpair.session_key),
+                    identity_pubkey: unpair.identity_pubkey,
+                });
+            }
+        }
+        {
+            let stored: Vec<ManualPeer> = state.load(MANUAL_PEERS_FILE)?.unwrap_or_default();
+            let mut manual = self.manual_peers.write().await;
+            for peer in stored {
+                if !manual.iter().any(|known| known.addr == peer.addr) {
+                    manual.push(peer);
+                }
+            }
+        }
+        self.state = Some(Arc::new(StateFiles::new(state)));
+        save_paired(&self.state, &self.paired_devices).await;
+        save_pending_unpairs(&self.state, &self.pending_unpairs).await;
+        save_manual_peers(&self.state, &self.manual_peers).await;
+        Ok(())
+    }
+
     /// Start the service and return event channel
     pub async fn start(&mut self) -> Result<mpsc::Receiver<ServiceEvent>> {
-        let (tx, rx) = mpsc::channel(64);
+        let (tx, rx) = event_channel(self.config.event_capacity);
+        self.events = Some(tx.clone());
+
+        if self.config.audit_log {
+            self.audit = Some(Arc::new(AuditLog::open(&self.config.data_dir, AUDIT_LOG_MAX_SIZE)?));
+        }
+
+        self.load_state().await?;
+
+        // Acknowledgements come back on the connections messages went out on
+        if let Some(replies) = self.pool_replies.take() {
+            self.tasks.push(tokio::spawn(handle_replies(replies, self.deliveries.clone(), tx.clone())));
+        }
 
         // Start sync server
-        let server = SyncServer::bind(self.config.port).await?;
+        let server = SyncServer::bind(self.config.port).await?
+            .with_max_connections(self.config.max_connections)
+            .with_event_capacity(self.config.server_event_capacity)
+            .with_transport_encryption(self.config.transport_encryption)
+            .with_paired_devices(self.server_devices.clone());
         let port = server.port();
+        self.port = Some(port);
+        for device in self.paired_devices.read().await.values() {
+            register(&self.server_devices, device).await;
+        }
 
         // Start discovery
-        let discovery = DiscoveryService::new(self.identity.id)?;
+        let discovery = DiscoveryService::new(self.identity.id)?
+            .with_max_peers(self.config.max_discovered_peers)
+            .with_peer_ttl(self.config.peer_ttl)
+            .with_address_filter(self.config.address_filter())?;
         discovery.register(&self.identity.name, &self.fingerprint(), port)?;
 
-        // Browse for peers
-        let mut discovery_rx = discovery.browse()?;
+        // Browse for peers, merging in those added by address
+        let mut mdns_rx = discovery.browse()?;
+        let (found_tx, mut discovery_rx) = mpsc::channel(64);
+        let mdns_tx = found_tx.clone();
+        self.tasks.push(tokio::spawn(async move {
+            while let Some(event) = mdns_rx.recv().await {
+                if mdns_tx.send(event).await.is_err() {
+                    break;
+                }
+            }
+        }));
+        self.tasks.push(tokio::spawn(watch_manual_peers(
+            self.manual_peers.clone(),
+            self.manual_peers_added.clone(),
+            self.identity.clone(),
+            self.peer_addresses.clone(),
+            self.config.transport_encryption,
+            found_tx,
+            tx.clone(),
+        )));
 
         // Start server with pairing support
         let (mut server_rx, server_handle) = server.start_with_pairing(
-            self.active_pairing.clone(),
+            self.pairing_sessions.clone(),
             self.identity.clone(),
         );
 
         self.server = Some(server_handle);
-        self.discovery = Some(discovery);
+        let discovery = Arc::new(discovery);
+        self.discovery = Some(discovery.clone());
+
+        // Keep the advertised pairing flag in step with sessions that are
+        // used up or expire; starting and cancelling update it directly.
+        // The same poll follows our address, so an open pairing QR code
+        // doesn't keep pointing at an address we've lost
+        let sessions = self.pairing_sessions.clone();
+        let tx_pairing = tx.clone();
+        let pairing_port = port;
+        let pairing_identity = self.identity.clone();
+        let pairing_addresses = self.config.address_filter();
+        self.tasks.push(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(PAIRING_FLAG_POLL_INTERVAL_MS));
+            let mut last_ip = primary_ip(&pairing_addresses);
+            loop {
+                interval.tick().await;
+                advertise_pairing(&discovery, &sessions).await;
+
+                let refreshed = refresh_pairing_url(&sessions, &mut last_ip, primary_ip(&pairing_addresses), pairing_port, &pairing_identity).await;
+                if let Some(url) = refreshed {
+                    tracing::info!("address changed to {}, pairing URL updated", last_ip);
+                    if let Err(e) = discovery.reannounce() {
+                        tracing::warn!("couldn't re-register mDNS service: {}", e);
+                    }
+                    let _ = tx_pairing.send(ServiceEvent::PairingUrlChanged { url }).await;
+                }
+            }
+        }));
+
+        // Invalid rules are reported here, since the constructor can't fail
+        ContentFilter::new(&self.config.filter)?;
+        let mut manager = self.clipboard_backend.lock().unwrap().take()
+            .unwrap_or_default()
+            .with_filter(self.filter.clone());
+        if self.config.confirm_reads {
+            manager = manager.with_confirm_reads(Duration::from_millis(CLIPBOARD_CONFIRM_DELAY_MS));
+        }
+        if self.config.sync_primary_selection {
+            manager = manager.with_primary_selection();
+        }
+        let poll_interval = Duration::from_millis(CLIPBOARD_POLL_INTERVAL_MS);
+        let mode = if self.config.clipboard_events {
+            clipboard::ClipboardMonitorMode::Events(poll_interval)
+        } else {
+            clipboard::ClipboardMonitorMode::Poll(poll_interval)
+        };
+        let (clip_rx, clip_writer, clip_monitor) = clipboard::start_monitor_with(manager, mode);
+        self.tasks.push(clip_monitor);
+        let sink = clipboard::build_sink(&self.config.sink, clip_writer.clone());
+        self.clipboard = Some(clip_writer.clone());
+        let primary_rx = clip_writer.primary_changes();
 
         // Spawn task to forward discovery events
         let tx_discovery = tx.clone();
-        tokio::spawn(async move {
+        let pending_unpairs = self.pending_unpairs.clone();
+        let state_discovery = self.state.clone();
+        let our_id = self.identity.id;
+        let outbox = self.outbox.clone();
+        let paired_discovery = self.paired_devices.clone();
+        let synced_discovery = self.synced_content.clone();
+        let deliveries_discovery = self.deliveries.clone();
+        let clock_discovery = self.clock.clone();
+        let audit_discovery = self.audit.clone();
+        let auto_connect = self.config.auto_connect_paired;
+        let encrypted = self.config.transport_encryption;
+        let channel_discovery = self.config.channel.clone();
+        let clip_discovery = clip_writer.clone();
+        let pause_discovery = self.pause.clone();
+        let allowed_discovery = self.config.allowed_content_types.clone();
+        let max_clip_discovery = self.config.max_clip_bytes;
+        let addresses_discovery = self.peer_addresses.clone();
+        let pool_discovery = self.pool.clone();
+        self.tasks.push(tokio::spawn(async move {
             while let Some(event) = discovery_rx.recv().await {
                 let service_event = match event {
-                    DiscoveryEvent::PeerFound(peer) => ServiceEvent::DeviceDiscovered(peer),
-                    DiscoveryEvent::PeerLost(id) => ServiceEvent::DeviceLost(id),
+                    DiscoveryEvent::PeerFound(peer) | DiscoveryEvent::PeerUpdated(peer) if !peer.is_compatible() => {
+                        // Messages to it would only fail, and confusingly
+                        addresses_discovery.write().await.remove(&peer.device_id);
+                        pool_discovery.evict(peer.device_id).await;
+                        tracing::warn!(
+                            "{} uses protocol version {:?}, which this device doesn't support",
+                            peer.device_name, peer.protocol_version
+                        );
+                        ServiceEvent::IncompatiblePeer {
+                            device_id: peer.device_id,
+                            device_name: peer.device_name,
+                            protocol_version: peer.protocol_version.unwrap_or_default(),
+                        }
+                    }
+                    DiscoveryEvent::PeerFound(peer) => {
+                        addresses_discovery.write().await.insert(peer.device_id, peer.clone());
+                        pool_discovery.reset_backoff(peer.device_id).await;
+                        // Deliver the newest change it missed while offline
+                        let missed = outbox.write().await.reconnect(peer.device_id);
+                        if let Some(change) = missed {
+                            deliver_to(
+                                peer.device_id, &change, our_id, &channel_discovery, &paired_discovery,
+                                &addresses_discovery, &pool_discovery, &synced_discovery, &deliveries_discovery,
+                                &clock_discovery, &audit_discovery, &tx_discovery,
+                            ).await;
+                        } else if deliveries_discovery.read().await.pending.contains_key(&peer.device_id) {
+                            // Or the last one it didn't acknowledge
+                            redeliver(
+                                peer.device_id, our_id, &channel_discovery, &paired_discovery, &addresses_discovery,
+                                &pool_discovery, &synced_discovery, &deliveries_discovery, &clock_discovery,
+                                &audit_discovery, &tx_discovery,
+                            ).await;
+                        } else if auto_connect && !pause_discovery.is_paused() {
+                            // A paired device back online catches up right away
+                            push_current_clipboard(
+                                peer.device_id, our_id, &channel_discovery, &clip_discovery, &paired_discovery,
+                                &addresses_discovery, &pool_discovery, &synced_discovery, &deliveries_discovery,
+                                &clock_discovery, &allowed_discovery, max_clip_discovery, &audit_discovery, &tx_discovery,
+                            ).await;
+                        }
+                        // Deliver any unpair notification owed to this device
+                        let owed = pending_unpairs.read().await.get(&peer.device_id).cloned();
+                        if let Some(owed) = owed {
+                            let pending = pending_unpairs.clone();
+                            let state = state_discovery.clone();
+                            let peer = peer.clone();
+                            tokio::spawn(async move {
+                                let notified = notify_unpair(
+                                    &peer, our_id, &owed.session_key, owed.identity_pubkey.as_ref(), encrypted,
+                                ).await;
+                                if notified.is_ok() {
+                                    pending.write().await.remove(&peer.device_id);
+                                    save_pending_unpairs(&state, &pending).await;
+                                }
+                            });
+                        }
+                        ServiceEvent::DeviceDiscovered(peer)
+                    }
+                    DiscoveryEvent::PeerUpdated(peer) => {
+                        addresses_discovery.write().await.insert(peer.device_id, peer.clone());
+                        pool_discovery.reset_backoff(peer.device_id).await;
+                        ServiceEvent::DeviceUpdated(peer)
+                    }
+                    DiscoveryEvent::IdentityConflict(peer) => ServiceEvent::Error(format!(
+                        "{} on the network shares this device's identity, usually because a data directory was copied",
+                        peer.device_name
+                    )),
+                    DiscoveryEvent::PeerLost(id) => {
+                        addresses_discovery.write().await.remove(&id);
+                        pool_discovery.evict(id).await;
+                        outbox.write().await.disconnect(id);
+                        ServiceEvent::DeviceLost(id)
+                    }
                 };
                 if tx_discovery.send(service_event).await.is_err() {
                     break;
                 }
             }
-        });
+        }));
 
         // Spawn task to forward server events
         let tx_server = tx.clone();
         let paired_devices = self.paired_devices.clone();
-        tokio::spawn(async move {
+        let server_devices = self.server_devices.clone();
+        let synced_content = self.synced_content.clone();
+        let last_received = self.last_sent_hash.clone();
+        let last_local = self.last_local_change.clone();
+        let conflict_policy = self.config.conflict_policy;
+        let deliveries = self.deliveries.clone();
+        let pause = self.pause.clone();
+        let inbound = self.config.normalize.inbound;
+        let queue_while_paused = self.config.queue_while_paused;
+        let held: Arc<RwLock<Option<(Uuid, ClipboardContent)>>> = Arc::new(RwLock::new(None));
+        if queue_while_paused {
+            // Write what arrived while paused once sync resumes
+            let pause = pause.clone();
+            let held = held.clone();
+            let sink = sink.clone();
+            let last_received = last_received.clone();
+            let history = self.history.clone();
+            let clock = self.clock.clone();
+            let tx_resume = tx.clone();
+            self.tasks.push(tokio::spawn(async move {
+                loop {
+                    pause.resumed.notified().await;
+                    if pause.is_paused() {
+                        continue;
+                    }
+                    let Some((from_device, content)) = held.write().await.take() else {
+                        continue;
+                    };
+                    tracing::debug!("sync resumed, writing clipboard held from {}", from_device);
+                    if let Err(e) = write_received(&*sink, &last_received, &inbound, &content).await {
+                        tracing::warn!("failed to write received clipboard: {}", e);
+                    }
+                    history.write().await.record(&content, clock.timestamp(), from_device);
+                    if tx_resume.send(ServiceEvent::ClipboardReceived { from_device, content }).await.is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+        let allowed_kinds = self.config.allowed_content_types.clone();
+        let max_clip_bytes = self.config.max_clip_bytes;
+        let write_blocked = self.write_blocked.clone();
+        let connections = self.connections.clone();
+        let recent = self.recent.clone();
+        let history = self.history.clone();
+        let mut replays = ReplayGuard::new(self.config.replay_window);
+        let sync_on_pair = self.config.sync_current_on_pair;
+        let channel = self.config.channel.clone();
+        let channels = self.config.channels.clone();
+        let sync_primary = self.config.sync_primary_selection;
+        // Kept apart from the clipboard's, as the same text is often both
+        // selected and copied
+        let primary_sent: Arc<RwLock<Option<ContentHash>>> = Arc::new(RwLock::new(None));
+        let primary_received = primary_sent.clone();
+        let clock = self.clock.clone();
+        let audit_server = self.audit.clone();
+        let addresses_server = self.peer_addresses.clone();
+        let pool_server = self.pool.clone();
+        let state_server = self.state.clone();
+        self.tasks.push(tokio::spawn(async move {
             while let Some(event) = server_rx.recv().await {
                 match event {
                     SyncEvent::DevicePaired { device } => {
                         tracing::info!("device paired: {} ({})", device.device_name, device.device_id);
+                        audit(&audit_server, AuditEntry::new(AuditEvent::DevicePaired, device.device_id, Direction::Inbound));
                         // Store in our local paired devices
                         paired_devices.write().await.insert(device.device_id, PairedDeviceInfo {
                             device_id: device.device_id,
                             device_name: device.device_name.clone(),
-                            session_key: device.session_key,
+                            identity_pubkey: device.identity_pubkey.clone(),
+                            keys: SessionKeyRing::new(device.session_key),
+                            features: device.features,
+                            direction: SyncDirection::default(),
                             last_seen: std::time::Instant::now(),
                         });
+                        save_paired(&state_server, &paired_devices).await;
                         let _ = tx_server.send(ServiceEvent::PairingRequest {
                             device_id: device.device_id,
                             device_name: device.device_name,
                         }).await;
+                        if sync_on_pair && !pause.is_paused() {
+                            push_current_clipboard(
+                                device.device_id, our_id, &channel, &clip_writer, &paired_devices, &addresses_server,
+                                &pool_server, &synced_content, &deliveries, &clock, &allowed_kinds, max_clip_bytes,
+                                &audit_server, &tx_server,
+                            ).await;
+                        }
                     }
-                    SyncEvent::MessageReceived { peer_id, message } => {
-                        match message {
+                    SyncEvent::MessageReceived { peer_id, message, reply } => {
+                        let (direction, clip) = match message {
                             Message::PairRequest(req) => {
                                 let _ = tx_server.send(ServiceEvent::PairingRequest {
                                     device_id: req.device_id,
                                     device_name: req.device_name,
                                 }).await;
+                                continue;
                             }
                             Message::ClipboardSync(sync_msg) => {
-                                // Try to decrypt if we have the session key
-                                if let Some(device) = paired_devices.read().await.get(&peer_id) {
-                                    if let Ok(decrypted) = device.session_key.decrypt(&sync_msg.encrypted_content) {
-                                        if let Ok(content) = ClipboardContent::from_bytes(&decrypted) {
-                                            let _ = tx_server.send(ServiceEvent::ClipboardReceived {
-                                                from_device: peer_id,
-                                                content,
-                                            }).await;
+                                let Some(mut device) = paired_devices.read().await.get(&peer_id).cloned() else {
+                                    continue;
+                                };
+                                if KeyEpoch(sync_msg.key_epoch) > device.keys.current_epoch() {
+                                    let content = &sync_msg.encrypted_content;
+                                    let aad = sync_msg.aad();
+                                    let followed = follow_ratchet(
+                                        &paired_devices, &server_devices, &state_server, &tx_server, peer_id, KeyEpoch(sync_msg.key_epoch),
+                                        |key| key.decrypt_with_aad(content, &aad).is_ok(),
+                                    ).await;
+                                    if let Some(keys) = followed {
+                                        device.keys = keys;
+                                    }
+                                }
+                                let content = epoch_key(&device.keys, sync_msg.key_epoch)
+                                    .and_then(|key| key.decrypt_with_aad(&sync_msg.encrypted_content, &sync_msg.aad()))
+                                    .and_then(|decrypted| open_content(decrypted, sync_msg.compressed))
+                                    .and_then(|content| verify_hash(content, sync_msg.content_hash));
+                                let (selection, channel) = if sync_primary {
+                                    ClipboardSelection::from_channel(&sync_msg.channel)
+                                } else {
+                                    (ClipboardSelection::Clipboard, sync_msg.channel.as_str())
+                                };
+                                (device.direction, InboundClipboard {
+                                    kind: "clipboard sync",
+                                    message_id: sync_msg.message_id,
+                                    timestamp: sync_msg.timestamp,
+                                    channel: channel.to_string(),
+                                    selection,
+                                    content_hash: sync_msg.content_hash,
+                                    wire_size: sync_msg.encrypted_content.ciphertext.len(),
+                                    content,
+                                })
+                            }
+                            Message::ClipboardDelta(delta_msg) => {
+                                let Some(mut device) = paired_devices.read().await.get(&peer_id).cloned() else {
+                                    continue;
+                                };
+                                if KeyEpoch(delta_msg.key_epoch) > device.keys.current_epoch() {
+                                    let patch = &delta_msg.patch;
+                                    let aad = delta_msg.aad();
+                                    let followed = follow_ratchet(
+                                        &paired_devices, &server_devices, &state_server, &tx_server, peer_id, KeyEpoch(delta_msg.key_epoch),
+                                        |key| key.decrypt_with_aad(patch, &aad).is_ok(),
+                                    ).await;
+                                    if let Some(keys) = followed {
+                                        device.keys = keys;
+                                    }
+                                }
+                                let base = synced_content.read().await.get(&peer_id).cloned();
+                                let content = match base {
+                                    Some(base) if base.hash() == delta_msg.base_hash => {
+                                        epoch_key(&device.keys, delta_msg.key_epoch)
+                                            .and_then(|key| apply_delta(key, &base, &delta_msg))
+                                    }
+                                    _ => Err(Error::InvalidMessage("delta references unknown base content".to_string())),
+                                };
+                                // Only the clipboard is sent as deltas
+                                (device.direction, InboundClipboard {
+                                    kind: "clipboard delta",
+                                    message_id: delta_msg.message_id,
+                                    timestamp: delta_msg.timestamp,
+                                    channel: delta_msg.channel,
+                                    selection: ClipboardSelection::Clipboard,
+                                    content_hash: delta_msg.content_hash,
+                                    wire_size: delta_msg.patch.ciphertext.len(),
+                                    content,
+                                })
+                            }
+                            Message::Unpair { device_id, proof } => {
+                                let verified = paired_devices.read().await.get(&device_id)
+                                    .and_then(|d| d.keys.iter().find_map(|key| key.decrypt(&proof).ok()))
+                                    .is_some_and(|plain| plain == device_id.as_bytes());
+                                if !verified {
+                                    tracing::warn!("ignoring unverified unpair from {}", device_id);
+                                    continue;
+                                }
+                                paired_devices.write().await.remove(&device_id);
+                                server_devices.write().await.remove(&device_id);
+                                synced_content.write().await.remove(&device_id);
+                                save_paired(&state_server, &paired_devices).await;
+                                tracing::info!("device {} unpaired from us", device_id);
+                                audit(&audit_server, AuditEntry::new(AuditEvent::DeviceUnpaired, device_id, Direction::Inbound));
+                                let _ = tx_server.send(ServiceEvent::DeviceUnpaired(device_id)).await;
+                                continue;
+                            }
+                            Message::Probe { device_id, challenge } => {
+                                let answer = paired_devices.read().await.get(&device_id)
+                                    .map(|device| answer_probe(&device.keys, our_id, device_id, &challenge));
+                                match answer {
+                                    Some(Ok(answer)) => {
+                                        if let Some(reply) = reply {
+                                            let _ = reply.send(answer);
                                         }
                                     }
+                                    Some(Err(e)) => tracing::debug!("couldn't answer probe from {}: {}", device_id, e),
+                                    None => tracing::debug!("probe from unknown device {}", device_id),
+                                }
+                                continue;
+                            }
+                            Message::KeyRotate { device_id, epoch, proof } => {
+                                let followed = follow_ratchet(
+                                    &paired_devices, &server_devices, &state_server, &tx_server, device_id, KeyEpoch(epoch),
+                                    |key| key.decrypt(&proof).is_ok_and(|plain| plain == device_id.as_bytes()),
+                                ).await;
+                                if followed.is_none() {
+                                    tracing::debug!("not following key rotation from {} to epoch {}", device_id, epoch);
                                 }
+                                continue;
+                            }
+                            _ => continue,
+                        };
+
+                        let content = match clip.content {
+                            Ok(content) => content,
+                            Err(e) => {
+                                tracing::warn!("rejected {} from {}: {}", clip.kind, peer_id, e);
+                                audit(&audit_server, AuditEntry::new(AuditEvent::ClipboardRejected, peer_id, Direction::Inbound)
+                                    .with_content(clip.content_hash, clip.wire_size));
+                                continue;
+                            }
+                        };
+                        // Checked once decrypted, since that authenticates the id and timestamp
+                        if !replays.accept(peer_id, clip.message_id, clip.timestamp, clock.timestamp()) {
+                            reject_replay(peer_id, clip.message_id, clip.content_hash, clip.wire_size, &audit_server, &tx_server).await;
+                            continue;
+                        }
+                        // Authentic from here on, so dropping it is deliberate and
+                        // acknowledged; otherwise the sender would keep resending it
+                        if pause.is_paused() && !queue_while_paused {
+                            tracing::debug!("sync paused, ignoring {} from {}", clip.kind, peer_id);
+                            send_ack(reply, clip.message_id);
+                            continue;
+                        }
+                        if !direction.receives() {
+                            tracing::debug!("{} is send-only, ignoring {}", peer_id, clip.kind);
+                            send_ack(reply, clip.message_id);
+                            continue;
+                        }
+                        if !in_channels(&channels, &clip.channel, peer_id) {
+                            audit(&audit_server, AuditEntry::new(AuditEvent::ClipboardRejected, peer_id, Direction::Inbound)
+                                .with_content(clip.content_hash, content.size()));
+                            send_ack(reply, clip.message_id);
+                            continue;
+                        }
+                        if !conflict_policy.accepts(our_id, *last_local.read().await, peer_id, clip.timestamp) {
+                            tracing::info!("dropping conflicting {} from {}", clip.kind, peer_id);
+                            audit(&audit_server, AuditEntry::new(AuditEvent::ClipboardRejected, peer_id, Direction::Inbound)
+                                .with_content(clip.content_hash, content.size()));
+                            send_ack(reply, clip.message_id);
+                            continue;
+                        }
+                        if !allowed_kinds.contains(&content.kind()) {
+                            tracing::debug!("ignoring {} from {}, not an allowed content type", content.kind(), peer_id);
+                            audit(&audit_server, AuditEntry::new(AuditEvent::ClipboardRejected, peer_id, Direction::Inbound)
+                                .with_content(clip.content_hash, content.size()));
+                            send_ack(reply, clip.message_id);
+                            continue;
+                        }
+                        audit(&audit_server, AuditEntry::new(AuditEvent::ClipboardSync, peer_id, Direction::Inbound)
+                            .with_content(clip.content_hash, content.size()));
+                        deliveries.write().await.stats.messages_received += 1;
+                        if clip.selection == ClipboardSelection::Primary {
+                            receive_primary(
+                                peer_id, content, &clip_writer, &primary_received, &inbound, &write_blocked, &pause,
+                            ).await;
+                            send_ack(reply, clip.message_id);
+                            continue;
+                        }
+                        synced_content.write().await.insert(peer_id, content.clone());
+                        if recent.write().await.received(content.hash()) {
+                            tracing::debug!("ignoring clipboard from {}, already exchanged", peer_id);
+                            send_ack(reply, clip.message_id);
+                            continue;
+                        }
+                        if write_blocked.read().await.contains(&peer_id) {
+                            tracing::info!("not writing clipboard from {}, writes from it are blocked", peer_id);
+                            send_ack(reply, clip.message_id);
+                            let _ = tx_server.send(ServiceEvent::ClipboardWithheld {
+                                from_device: peer_id,
+                                content,
+                            }).await;
+                            continue;
+                        }
+                        if pause.is_paused() {
+                            tracing::debug!("sync paused, holding clipboard from {} until resumed", peer_id);
+                            send_ack(reply, clip.message_id);
+                            *held.write().await = Some((peer_id, content));
+                            continue;
+                        }
+                        match write_received(&*sink, &last_received, &inbound, &content).await {
+                            Ok(()) => send_ack(reply, clip.message_id),
+                            Err(e) => tracing::warn!("failed to write received clipboard: {}", e),
+                        }
+                        history.write().await.record(&content, clip.timestamp, peer_id);
+                        let _ = tx_server.send(ServiceEvent::ClipboardReceived {
+                            from_device: peer_id,
+                            content,
+                        }).await;
+                    }
+                    SyncEvent::AckReceived { message_id } => {
+                        confirm_delivery(&deliveries, &tx_server, message_id).await;
+                    }
+                    SyncEvent::PeerConnected { peer_id, peer_name } => {
+                        tracing::debug!("{} ({}) connected", peer_name, peer_id);
+                        *connections.write().await.entry(peer_id).or_default() += 1;
+                        if let Some(device) = paired_devices.write().await.get_mut(&peer_id) {
+                            device.last_seen = std::time::Instant::now();
+                        }
+                    }
+                    SyncEvent::PeerDisconnected { peer_id } => {
+                        tracing::debug!("{} disconnected", peer_id);
+                        let mut connections = connections.write().await;
+                        if let Some(open) = connections.get_mut(&peer_id) {
+                            *open -= 1;
+                            if *open == 0 {
+                                connections.remove(&peer_id);
                             }
-                            _ => {}
                         }
                     }
-                    _ => {}
                 }
             }
-        });
+        }));
 
-        // Spawn clipboard monitoring task
-        let tx_clipboard = tx.clone();
-        let paired = self.paired_devices.clone();
-        let last_sent = self.last_sent_hash.clone();
-        let our_id = self.identity.id;
+        // Spawn task to send local clipboard changes to paired devices
+        self.tasks.push(tokio::spawn(forward_local_changes(
+            clip_rx,
+            self.identity.id,
+            self.config.channel.clone(),
+            self.paired_devices.clone(),
+            self.peer_addresses.clone(),
+            self.pool.clone(),
+            self.last_sent_hash.clone(),
+            self.last_local_change.clone(),
+            self.synced_content.clone(),
+            self.deliveries.clone(),
+            self.pause.clone(),
+            self.clock.clone(),
+            self.outbox.clone(),
+            self.recent.clone(),
+            self.history.clone(),
+            self.config.allowed_content_types.clone(),
+            self.config.max_clip_bytes,
+            self.config.send_debounce,
+            self.config.normalize.outbound,
+            self.audit.clone(),
+            tx.clone(),
+        )));
 
-        tokio::spawn(async move {
-            let (mut clip_rx, _handle) = clipboard::start_monitor(Duration::from_millis(500));
+        // The primary selection is sent the same way, without the clipboard's
+        // history, offline queue or conflict tracking
+        if let Some(primary_rx) = primary_rx {
+            self.tasks.push(tokio::spawn(forward_local_changes(
+                primary_rx,
+                self.identity.id,
+                self.config.channel.clone(),
+                self.paired_devices.clone(),
+                self.peer_addresses.clone(),
+                self.pool.clone(),
+                primary_sent,
+                Arc::new(RwLock::new(None)),
+                self.synced_content.clone(),
+                self.deliveries.clone(),
+                self.pause.clone(),
+                self.clock.clone(),
+                Arc::new(RwLock::new(Outbox::new(false))),
+                Arc::new(RwLock::new(RecentContent::new(self.config.dedup_window))),
+                Arc::new(RwLock::new(ClipboardHistory::new(0))),
+                self.config.allowed_content_types.clone(),
+                self.config.max_clip_bytes,
+                self.config.send_debounce,
+                self.config.normalize.outbound,
+                self.audit.clone(),
+                tx.clone(),
+            )));
+        }
 
-            while let Some(change) = clip_rx.recv().await {
-                // Skip if this is content we just received
-                if let Some(last) = last_sent.read().await.as_ref() {
-                    if *last == change.hash {
-                        continue;
+        // Spawn task to pause sync while the session is locked
+        if self.config.pause_when_locked {
+            let tx_session = tx.clone();
+            let pause = self.pause.clone();
+            let (mut session_rx, session_monitor) = session::start_monitor(
+                SystemSession,
+                Duration::from_millis(SESSION_POLL_INTERVAL_MS),
+            );
+            self.tasks.push(session_monitor);
+            self.tasks.push(tokio::spawn(async move {
+                while let Some(state) = session_rx.recv().await {
+                    tracing::info!("session {:?}, sync {}", state, match state {
+                        SessionState::Locked => "paused",
+                        SessionState::Active => "resumed",
+                    });
+                    pause.locked.store(state == SessionState::Locked, Ordering::SeqCst);
+                    if state == SessionState::Active {
+                        pause.resumed.notify_one();
+                    }
+                    if tx_session.send(ServiceEvent::SessionStateChanged(state)).await.is_err() {
+                        break;
                     }
                 }
+            }));
+        }
 
-                // Send to all paired devices
-                let devices = paired.read().await;
-                let mut sent_to = Vec::new();
-
-                for (id, device) in devices.iter() {
-                    if let Ok(plaintext) = change.content.to_bytes() {
-                        if let Ok(encrypted) = device.session_key.encrypt(&plaintext) {
-                            let _msg = Message::ClipboardSync(ClipboardSyncMessage {
-                                message_id: Uuid::new_v4(),
-                                sender_id: our_id,
-                                content_hash: change.hash,
-                                encrypted_content: encrypted,
-                                timestamp: std::time::SystemTime::now()
-                                    .duration_since(std::time::UNIX_EPOCH)
-                                    .unwrap()
-                                    .as_secs(),
-                            });
-
-                            // TODO: Actually send to peer connection
-                            sent_to.push(*id);
-                        }
-                    }
+        // Spawn task to send again what devices haven't acknowledged
+        if !self.config.ack_timeout.is_zero() {
+            let ack_timeout = self.config.ack_timeout;
+            let our_id = self.identity.id;
+            let channel = self.config.channel.clone();
+            let paired = self.paired_devices.clone();
+            let addresses = self.peer_addresses.clone();
+            let pool = self.pool.clone();
+            let synced = self.synced_content.clone();
+            let deliveries = self.deliveries.clone();
+            let clock = self.clock.clone();
+            let audit_log = self.audit.clone();
+            let tx_retransmit = tx.clone();
+            self.tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(ack_timeout / 2);
+                while !tx_retransmit.is_closed() {
+                    interval.tick().await;
+                    retransmit_unacked(
+                        ack_timeout, our_id, &channel, &paired, &addresses, &pool, &synced, &deliveries, &clock,
+                        &audit_log, &tx_retransmit,
+                    ).await;
                 }
+            }));
+        }
 
-                if !sent_to.is_empty() {
-                    *last_sent.write().await = Some(change.hash);
-                    let _ = tx_clipboard.send(ServiceEvent::ClipboardSent { to_devices: sent_to }).await;
+        // Spawn task to rotate session keys that have been used enough
+        if let Some(policy) = self.config.key_rotation {
+            let our_id = self.identity.id;
+            let paired = self.paired_devices.clone();
+            let server_devices = self.server_devices.clone();
+            let addresses = self.peer_addresses.clone();
+            let encrypted = self.config.transport_encryption;
+            let state = self.state.clone();
+            let tx_rotate = tx.clone();
+            self.tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(KEY_ROTATION_CHECK_INTERVAL_SECS));
+                while !tx_rotate.is_closed() {
+                    interval.tick().await;
+                    rotate_due_keys(our_id, &policy, &paired, &server_devices, &addresses, encrypted, &state, &tx_rotate).await;
                 }
-            }
-        });
+            }));
+        }
 
         tracing::info!("omniclip service started on port {}", port);
+        tracing::info!("syncing {}", describe_kinds(&self.config.allowed_content_types));
         Ok(rx)
     }
 
+    /// Start the service and deliver each event to `callback`
+    ///
+    /// For embedders that register a handler rather than poll a channel.
+    /// The callback runs on a tokio task, one event at a time, so it should
+    /// return quickly and hand work to the UI thread itself if needed. The
+    /// task ends when the service's tasks stop; [`start`](Self::start)
+    /// remains the primary interface.
+    pub async fn start_with_callback(
+        &mut self,
+        callback: impl Fn(ServiceEvent) + Send + 'static,
+    ) -> Result<JoinHandle<()>> {
+        let events = self.start().await?;
+        Ok(forward_to_callback(events, callback))
+    }
+
+    /// Stop the service
+    ///
+    /// Withdraws our mDNS record so peers see this device leave right away,
+    /// stops the sync server and the connections it accepted, closes pooled
+    /// connections and writes any change to paired devices and other state
+    /// still waiting to be saved. State files are replaced in one rename, so
+    /// stopping never leaves one half written.
+    pub async fn shutdown(mut self) -> Result<()> {
+        if let Some(server) = self.server.take() {
+            server.stop().await;
+        }
+        // Wait for each task to be dropped, so nothing it holds outlives us
+        for task in self.tasks.drain(..) {
+            task.abort();
+            let _ = task.await;
+        }
+        self.pool.close().await;
+        save_paired(&self.state, &self.paired_devices).await;
+        save_pending_unpairs(&self.state, &self.pending_unpairs).await;
+        save_manual_peers(&self.state, &self.manual_peers).await;
+        let saved = match &self.state {
+            Some(state) => state.flush().await,
+            None => Ok(()),
+        };
+
+        if let Some(discovery) = self.discovery.take() {
+            match Arc::try_unwrap(discovery) {
+                Ok(discovery) => tokio::task::spawn_blocking(move || discovery.shutdown())
+                    .await
+                    .map_err(|e| Error::Discovery(e.to_string()))??,
+                Err(_) => tracing::warn!("discovery still in use, not shut down"),
+            }
+        }
+        tracing::info!("omniclip service stopped");
+        saved
+    }
+
     /// Start a new pairing session and return QR code data
+    ///
+    /// Earlier sessions stay valid until they are used, cancelled or expire.
     pub async fn start_pairing(&self) -> Result<String> {
-        let session = PairingSession::new();
-        let local_ips = crate::discovery::get_local_ips();
-        let ip = local_ips.first()
-            .map(|ip| ip.to_string())
-            .unwrap_or_else(|| "127.0.0.1".to_string());
+        self.begin_pairing(PairingSession::new()).await
+    }
+
+    /// Start a new pairing session labelled with the device expected to use it
+    pub async fn start_pairing_with_hint(&self, device_name_hint: impl Into<String>) -> Result<String> {
+        self.begin_pairing(PairingSession::with_hint(device_name_hint)).await
+    }
 
-        let qr_data = session.qr_data(&ip, self.config.port, &self.identity.name);
+    async fn begin_pairing(&self, session: PairingSession) -> Result<String> {
+        let qr_data = session.qr_data(&primary_ip(&self.config.address_filter()), self.advertised_port(), &self.identity);
         let url = qr_data.to_url();
 
-        *self.active_pairing.write().await = Some(session);
+        {
+            let mut sessions = self.pairing_sessions.write().await;
+            sessions.retain(|_, s| !s.is_expired());
+            sessions.insert(session.session_id, session);
+        }
+        if let Some(discovery) = &self.discovery {
+            advertise_pairing(discovery, &self.pairing_sessions).await;
+        }
         Ok(url)
     }
 
-    /// Get QR code as SVG for current pairing session
+    /// List pairing sessions still waiting for a device, oldest first
+    pub async fn active_pairing_sessions(&self) -> Vec<PairingSessionInfo> {
+        let mut sessions: Vec<PairingSessionInfo> = self.pairing_sessions.read().await
+            .values()
+            .filter(|s| !s.is_expired())
+            .map(PairingSession::info)
+            .collect();
+        sessions.sort_by_key(|s| s.created_at);
+        sessions
+    }
+
+    /// Cancel a pairing session so it can no longer be used.
+    ///
+    /// Returns `false` if there was no such session, including when a device
+    /// completed pairing with it first.
+    pub async fn cancel_pairing(&self, session_id: Uuid) -> bool {
+        let cancelled = self.pairing_sessions.write().await.remove(&session_id).is_some();
+        if let Some(discovery) = &self.discovery {
+            advertise_pairing(discovery, &self.pairing_sessions).await;
+        }
+        cancelled
+    }
+
+    /// Get QR code as SVG for the most recent pairing session
     pub async fn get_pairing_qr_svg(&self) -> Result<String> {
-        let pairing = self.active_pairing.read().await;
-        let session = pairing.as_ref()
-            .ok_or_else(|| Error::InvalidMessage("no active pairing session".to_string()))?;
+        self.pairing_qr_data().await?.to_qr_svg()
+    }
 
-        let local_ips = crate::discovery::get_local_ips();
-        let ip = local_ips.first()
-            .map(|ip| ip.to_string())
-            .unwrap_or_else(|| "127.0.0.1".to_string());
+    /// Get the QR code for the newest active pairing session as a PNG,
+    /// `scale` pixels per module
+    pub async fn get_pairing_qr_png(&self, scale: u32) -> Result<Vec<u8>> {
+        self.pairing_qr_data().await?.to_qr_png(scale)
+    }
+
+    /// QR data for the newest active pairing session
+    async fn pairing_qr_data(&self) -> Result<PairingQrData> {
+        let sessions = self.pairing_sessions.read().await;
+        let session = sessions.values()
+            .filter(|s| !s.is_expired())
+            .max_by_key(|s| s.created_at)
+            .ok_or_else(|| Error::InvalidMessage("no active pairing session".to_string()))?;
 
-        let qr_data = session.qr_data(&ip, self.config.port, &self.identity.name);
-        qr_data.to_qr_svg()
+        Ok(session.qr_data(&primary_ip(&self.config.address_filter()), self.advertised_port(), &self.identity))
     }
 
-    /// Get list of paired devices
-    pub async fn get_paired_devices(&self) -> Vec<(Uuid, String)> {
-        self.paired_devices.read().await
-            .iter()
-            .map(|(id, d)| (*id, d.device_name.clone()))
-            .collect()
+    /// Port to give out in pairing codes: the one bound once started, so
+    /// an ephemeral port (0 in the config) is reachable
+    fn advertised_port(&self) -> u16 {
+        self.port.unwrap_or(self.config.port)
     }
 
-    /// Remove a paired device
+    /// Pair with the device that showed a pairing QR code
+    ///
+    /// Both sides store each other from this one handshake, so the other
+    /// device doesn't need to scan a code of ours.
+    pub async fn pair_with_url(&self, url: &str) -> Result<(Uuid, String)> {
+        let qr = PairingQrData::from_url(url)?;
+        if let Some(peer) = self.discovered_at(&qr).await.filter(|peer| !peer.is_compatible()) {
+            let protocol_version = peer.protocol_version.unwrap_or_default();
+            if let Some(events) = &self.events {
+                let _ = events.send(ServiceEvent::IncompatiblePeer {
+                    device_id: peer.device_id,
+                    device_name: peer.device_name.clone(),
+                    protocol_version,
+                }).await;
+            }
+            return Err(Error::NotPaired(incompatible_version(&peer.device_name, protocol_version)));
+        }
+        let device = match sync::pair_with(&qr, &self.identity, self.config.transport_encryption).await {
+            Ok(device) => device,
+            // The device said why; that beats anything we can guess
+            Err(Error::NotPaired(reason)) => {
+                tracing::info!("pairing rejected: {}", reason);
+                if let Some(events) = &self.events {
+                    let _ = events.send(ServiceEvent::PairingRejected { reason: reason.clone() }).await;
+                }
+                return Err(Error::NotPaired(reason));
+            }
+            Err(e) => return Err(self.explain_pairing_failure(&qr).await.unwrap_or(e)),
+        };
+
+        tracing::info!("paired with {} ({})", device.device_name, device.device_id);
+        audit(&self.audit, AuditEntry::new(AuditEvent::DevicePaired, device.device_id, Direction::Outbound));
+        let info = PairedDeviceInfo {
+            device_id: device.device_id,
+            device_name: device.device_name.clone(),
+            identity_pubkey: device.identity_pubkey,
+            keys: SessionKeyRing::new(device.session_key),
+            features: device.features,
+            direction: SyncDirection::default(),
+            last_seen: std::time::Instant::now(),
+        };
+        register(&self.server_devices, &info).await;
+        self.paired_devices.write().await.insert(device.device_id, info);
+        save_paired(&self.state, &self.paired_devices).await;
+        Ok((device.device_id, device.device_name))
+    }
+
+    /// A clearer error for a failed pairing when discovery shows the device
+    /// at the QR code's address has no pairing session open
+    async fn explain_pairing_failure(&self, qr: &PairingQrData) -> Option<Error> {
+        let peer = self.discovered_at(qr).await.filter(|p| !p.accepting_pairing)?;
+        Some(Error::NotPaired(format!("{} isn't accepting pairing right now", peer.device_name)))
+    }
+
+    /// The discovered device at a pairing QR code's address, if any
+    async fn discovered_at(&self, qr: &PairingQrData) -> Option<PeerInfo> {
+        let ip = qr.addr().ok()?.ip();
+        let peers = self.discovery.as_ref()?.get_peers().await;
+        peers.into_iter().find(|p| p.port == qr.port && p.addresses.contains(&ip))
+    }
+
+    /// Pair with a device using a session key shared out of band
+    ///
+    /// No handshake takes place, so nothing on the network can interfere
+    /// with it. Both devices must add each other with identical key bytes,
+    /// for example from [`generate_preshared_key`](crate::crypto::generate_preshared_key).
+    /// The key is long-lived, so unlike QR pairing there is no forward
+    /// secrecy: anyone who later learns it can decrypt recorded traffic.
+    pub async fn add_preshared_pairing(
+        &self,
+        device_id: Uuid,
+        device_name: String,
+        identity_pubkey: VerifyingKey,
+        key_bytes: &[u8; 32],
+    ) -> Result<()> {
+        if device_id == self.identity.id {
+            return Err(Error::InvalidMessage("cannot pair with self".to_string()));
+        }
+        tracing::info!("paired with {} ({}) using a pre-shared key", device_name, device_id);
+        audit(&self.audit, AuditEntry::new(AuditEvent::DevicePaired, device_id, Direction::Outbound));
+        let info = PairedDeviceInfo {
+            device_id,
+            device_name,
+            identity_pubkey,
+            keys: SessionKeyRing::new(SessionKey::from_bytes(key_bytes)),
+            features: NegotiatedFeatures::baseline(),
+            direction: SyncDirection::default(),
+            last_seen: std::time::Instant::now(),
+        };
+        register(&self.server_devices, &info).await;
+        self.paired_devices.write().await.insert(device_id, info);
+        save_paired(&self.state, &self.paired_devices).await;
+        Ok(())
+    }
+
+    /// Stop sending and accepting clipboard changes until [`resume`](Self::resume)
+    pub fn pause(&self) {
+        if !self.pause.manual.swap(true, Ordering::SeqCst) {
+            self.report_pause();
+        }
+    }
+
+    /// Resume clipboard sync after [`pause`](Self::pause).
+    ///
+    /// Sync stays paused while the session is locked if `pause_when_locked` is set.
+    pub fn resume(&self) {
+        if self.pause.manual.swap(false, Ordering::SeqCst) {
+            self.pause.resumed.notify_one();
+            self.report_pause();
+        }
+    }
+
+    fn report_pause(&self) {
+        tracing::info!("sync {} manually", if self.pause.manual.load(Ordering::SeqCst) { "paused" } else { "resumed" });
+        if let Some(tx) = &self.events {
+            tx.try_send(ServiceEvent::PauseChanged { paused: self.pause.is_paused() });
+        }
+    }
+
+    /// Whether clipboard sync is currently paused, for any reason
+    pub fn is_paused(&self) -> bool {
+        self.pause.is_paused()
+    }
+
+    /// Stop or allow a paired device writing to the local clipboard.
+    ///
+    /// Content from a blocked device is still decrypted and reported as
+    /// [`ServiceEvent::ClipboardWithheld`], so it can be reviewed first.
+    pub async fn set_write_blocked(&self, device_id: Uuid, blocked: bool) {
+        let mut write_blocked = self.write_blocked.write().await;
+        if blocked {
+            write_blocked.insert(device_id);
+        } else {
+            write_blocked.remove(&device_id);
+        }
+    }
+
+    /// Whether writes from a device are blocked
+    pub async fn is_write_blocked(&self, device_id: Uuid) -> bool {
+        self.write_blocked.read().await.contains(&device_id)
+    }
+
+    /// Paired devices with a connection currently open to us
+    pub async fn connected_devices(&self) -> Vec<Uuid> {
+        self.connections.read().await.keys().copied().collect()
+    }
+
+    /// Check a paired device answers on the network, returning the round-trip time
+    ///
+    /// Unlike its presence in discovery, a reply shows the device's sync
+    /// server can actually be reached, and since only the holder of the
+    /// session key can answer the probe, that it is the paired device.
+    /// Fails with [`Error::Timeout`] if it doesn't reply within `timeout`.
+    pub async fn ping_device(&self, device_id: Uuid, timeout: Duration) -> Result<Duration> {
+        let (session_key, identity_pubkey, codec) = {
+            let devices = self.paired_devices.read().await;
+            let device = devices.get(&device_id).ok_or_else(|| Error::NotPaired(device_id.to_string()))?;
+            (device.keys.current().clone(), device.identity_pubkey.clone(), device.features.wire_codec)
+        };
+        // Known addresses cover peers added by address as well as mDNS ones
+        let peer = self.peer_addresses.read().await.get(&device_id).cloned()
+            .ok_or_else(|| Error::Network(format!("{} isn't on the network", device_id)))?;
+
+        let probe = probe(&peer, self.identity.id, &session_key, &identity_pubkey, codec, self.config.transport_encryption);
+        tokio::time::timeout(timeout, probe)
+            .await
+            .map_err(|_| Error::Timeout(format!("{} didn't answer within {:?}", device_id, timeout)))?
+    }
+
+    /// Get clipboard message counters
+    pub async fn stats(&self) -> SyncStats {
+        self.deliveries.read().await.stats
+    }
+
+    /// How many paired devices have an open connection for sending, and how
+    /// many had one that closed or couldn't be opened
+    pub async fn connection_stats(&self) -> PoolStats {
+        self.pool.stats().await
+    }
+
+    /// Protocol features in effect with a paired device
+    pub async fn negotiated_features(&self, device_id: Uuid) -> Option<NegotiatedFeatures> {
+        self.paired_devices.read().await.get(&device_id).map(|d| d.features.clone())
+    }
+
+    /// Current session key epoch with a paired device
+    pub async fn key_epoch(&self, device_id: Uuid) -> Option<KeyEpoch> {
+        self.paired_devices.read().await.get(&device_id).map(|d| d.keys.current_epoch())
+    }
+
+    /// Replace the session key for a paired device with one agreed out of band
+    ///
+    /// Both devices must rekey with the same bytes, so their epochs stay in
+    /// step. Messages under the previous key still decrypt for a short grace
+    /// window. Emits [`ServiceEvent::Rekeyed`] once the service is started.
+    pub async fn rekey_device(&self, device_id: Uuid, key_bytes: &[u8; 32]) -> Result<KeyEpoch> {
+        let key = SessionKey::from_bytes(key_bytes);
+        let epoch = self.paired_devices.write().await
+            .get_mut(&device_id)
+            .ok_or_else(|| Error::InvalidMessage(format!("{} is not paired", device_id)))?
+            .keys
+            .rekey(key.clone());
+        rekey_server(&self.server_devices, device_id, &key).await;
+        save_paired(&self.state, &self.paired_devices).await;
+
+        tracing::info!("rekeyed session with {}, now on {}", device_id, epoch);
+        if let Some(events) = &self.events {
+            let _ = events.send(ServiceEvent::Rekeyed { device_id, epoch }).await;
+        }
+        Ok(epoch)
+    }
+
+    /// Clipboard content sent and received, newest first
+    pub async fn history(&self) -> Vec<HistoryEntry> {
+        self.history.read().await.entries.iter().cloned().collect()
+    }
+
+    /// Write the history entry at `index` (as in [`history`](Self::history))
+    /// back to the local clipboard.
+    ///
+    /// The content isn't sent to peers. Fails if the service isn't started.
+    pub async fn restore(&self, index: usize) -> Result<()> {
+        let clipboard = self.clipboard.as_ref()
+            .ok_or_else(|| Error::Clipboard("service not started".to_string()))?;
+        let content = self.history.read().await.entries.get(index)
+            .map(|entry| entry.content.clone())
+            .ok_or_else(|| Error::Clipboard(format!("no history entry {}", index)))?;
+        clipboard.write(content).await
+    }
+
+    /// Current content of the local clipboard
+    ///
+    /// Read through the service's own clipboard monitor, so embedders don't
+    /// need a clipboard handle of their own. Content the filter keeps from
+    /// being synced reads as `None`. Fails if the service isn't started.
+    pub async fn current_clipboard(&self) -> Result<Option<ClipboardContent>> {
+        let clipboard = self.clipboard.as_ref()
+            .ok_or_else(|| Error::Clipboard("service not started".to_string()))?;
+        clipboard.read().await
+    }
+
+    /// Put `content` on the local clipboard and send it to paired devices,
+    /// as if it had been copied here
+    ///
+    /// It goes through the same checks as a copy, so content that is
+    /// filtered, of a kind not synced or too large is only written locally.
+    /// Fails if the service isn't started.
+    pub async fn set_clipboard(&self, content: ClipboardContent) -> Result<()> {
+        let clipboard = self.clipboard.as_ref()
+            .ok_or_else(|| Error::Clipboard("service not started".to_string()))?;
+        clipboard.publish(content).await
+    }
+
+    /// Replace the rules for local content that isn't synced, taking effect
+    /// from the next clipboard change. On an invalid pattern the current
+    /// rules stay.
+    pub fn set_filter_rules(&self, rules: &FilterRules) -> Result<()> {
+        self.filter.reload(rules)
+    }
+
+    /// Add a peer by address, for networks where mDNS doesn't reach it
+    ///
+    /// The peer is asked who it is right away and then every
+    /// [`MANUAL_PEER_POLL_INTERVAL_SECS`], and is reported like a device
+    /// found over mDNS while it answers. With `expected_fingerprint`, a peer
+    /// showing another identity key is reported as an error instead. Manual
+    /// peers are saved, so they're polled again after a restart; adding one
+    /// again replaces its expected fingerprint.
+    pub async fn add_manual_peer(&self, addr: SocketAddr, expected_fingerprint: Option<String>) -> Result<()> {
+        if addr.ip().is_unspecified() || addr.port() == 0 {
+            return Err(Error::Config(format!("{} isn't a peer address", addr)));
+        }
+        let peer = ManualPeer { addr, fingerprint: expected_fingerprint };
+        {
+            let mut manual = self.manual_peers.write().await;
+            match manual.iter_mut().find(|known| known.addr == addr) {
+                Some(known) => *known = peer,
+                None => manual.push(peer),
+            }
+        }
+        save_manual_peers(&self.state, &self.manual_peers).await;
+        self.manual_peers_added.notify_one();
+        tracing::info!("added peer {}", addr);
+        Ok(())
+    }
+
+    /// Set which way clipboard changes flow with a paired device
+    pub async fn set_direction(&self, device_id: Uuid, direction: SyncDirection) -> Result<()> {
+        self.paired_devices.write().await
+            .get_mut(&device_id)
+            .ok_or_else(|| Error::InvalidMessage(format!("{} is not paired", device_id)))?
+            .direction = direction;
+        save_paired(&self.state, &self.paired_devices).await;
+        tracing::info!("syncing {:?} with {}", direction, device_id);
+        Ok(())
+    }
+
+    /// Get list of paired devices, sorted by name and then ID
+    pub async fn get_paired_devices(&self) -> Vec<PairedDeviceSummary> {
+        let connections = self.connections.read().await;
+        let mut devices: Vec<_> = self.paired_devices.read().await
+            .values()
+            .map(|d| PairedDeviceSummary {
+                device_id: d.device_id,
+                device_name: d.device_name.clone(),
+                fingerprint: d.identity_pubkey.fingerprint(),
+                last_seen: d.last_seen,
+                connected: connections.contains_key(&d.device_id),
+                direction: d.direction,
+            })
+            .collect();
+        devices.sort_by(|a, b| (&a.device_name, a.device_id).cmp(&(&b.device_name, b.device_id)));
+        devices
+    }
+
+    /// Remove a paired device
+    ///
+    /// The device is notified so it drops the pairing too. If it can't be
+    /// reached, the notification is saved and retried when it is next
+    /// discovered, in this run or a later one.
     pub async fn unpair_device(&self, device_id: Uuid) {
-        self.paired_devices.write().await.remove(&device_id);
+        let removed = self.paired_devices.write().await.remove(&device_id);
+        self.server_devices.write().await.remove(&device_id);
+        self.synced_content.write().await.remove(&device_id);
+        self.pool.evict(device_id).await;
+
+        let Some(device) = removed else {
+            return;
+        };
+        save_paired(&self.state, &self.paired_devices).await;
+        audit(&self.audit, AuditEntry::new(AuditEvent::DeviceUnpaired, device_id, Direction::Outbound));
+
+        // Known addresses cover peers added by address as well as mDNS ones
+        let peer = self.peer_addresses.read().await.get(&device_id).cloned();
+        let delivered = match peer {
+            Some(peer) => match notify_unpair(
+                &peer, self.identity.id, device.keys.current(), Some(&device.identity_pubkey), self.config.transport_encryption,
+            ).await {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::debug!("unpair notification to {} failed: {}", device_id, e);
+                    false
+                }
+            },
+            None => false,
+        };
+
+        if !delivered {
+            self.pending_unpairs.write().await.insert(device_id, OwedUnpair {
+                session_key: device.keys.current().clone(),
+                identity_pubkey: Some(device.identity_pubkey),
+            });
+            save_pending_unpairs(&self.state, &self.pending_unpairs).await;
+        }
+    }
+}
+
+/// Load the devices saved in [`PAIRED_DEVICES_FILE`]
+fn load_paired(store: &StateStore) -> Result<HashMap<Uuid, PairedDeviceInfo>> {
+    let stored: Vec<StoredDevice> = store.load(PAIRED_DEVICES_FILE)?.unwrap_or_default();
+    Ok(stored.into_iter()
+        .map(|device| (device.device_id, device.into_info()))
+        .collect())
+}
+
+/// Save the paired devices, if the service has a store
+///
+/// Changes are written shortly after in the background. The map stays
+/// locked until the value is handed over, so saves are queued in the order
+/// the changes were made.
+async fn save_paired(state: &Option<Arc<StateFiles>>, paired: &RwLock<HashMap<Uuid, PairedDeviceInfo>>) {
+    let Some(state) = state else {
+        return;
+    };
+    let paired = paired.read().await;
+    state.paired.update(paired.values().map(StoredDevice::new).collect());
+}
+
+/// Save the unpair notifications still owed, if the service has a store
+async fn save_pending_unpairs(state: &Option<Arc<StateFiles>>, pending: &RwLock<HashMap<Uuid, OwedUnpair>>) {
+    let Some(state) = state else {
+        return;
+    };
+    let pending = pending.read().await;
+    state.unpairs.update(pending.iter()
+        .map(|(device_id, owed)| StoredUnpair {
+            device_id: *device_id,
+            session_key: owed.session_key.to_bytes(),
+            identity_pubkey: owed.identity_pubkey.clone(),
+        })
+        .collect());
+}
+
+async fn save_manual_peers(state: &Option<Arc<StateFiles>>, peers: &RwLock<Vec<ManualPeer>>) {
+    let Some(state) = state else {
+        return;
+    };
+    let peers = peers.read().await;
+    state.peers.update(peers.clone());
+}
+
+/// Ask each peer added by address who it is, reporting it to the discovery
+/// task as found, updated or lost the way mDNS browsing does
+///
+/// A device mDNS already found is left to mDNS, so it isn't reported lost
+/// twice.
+async fn watch_manual_peers(
+    peers: Arc<RwLock<Vec<ManualPeer>>>,
+    added: Arc<Notify>,
+    identity: DeviceIdentity,
+    addresses: Arc<RwLock<HashMap<Uuid, PeerInfo>>>,
+    encrypted: bool,
+    found_tx: mpsc::Sender<DiscoveryEvent>,
+    events: EventSender,
+) {
+    let timeout = Duration::from_millis(PEER_NOTIFY_TIMEOUT_MS);
+    // Peers this task reported found, by the address they were added with
+    let mut found: HashMap<SocketAddr, PeerInfo> = HashMap::new();
+    // Addresses already reported for showing the wrong key
+    let mut mismatched: HashSet<SocketAddr> = HashSet::new();
+    loop {
+        let manual = peers.read().await.clone();
+        for peer in manual {
+            let answered = match sync::identify(peer.addr, &identity, timeout, encrypted).await {
+                Ok(ann) if ann.device_id == identity.id => None,
+                Ok(ann) if peer.fingerprint.as_ref().is_some_and(|fp| *fp != ann.pubkey_fingerprint) => {
+                    if mismatched.insert(peer.addr) {
+                        let message = format!(
+                            "{} at {} doesn't have the expected identity key, so it isn't used",
+                            ann.device_name, peer.addr
+                        );
+                        let _ = events.send(ServiceEvent::Error(message)).await;
+                    }
+                    None
+                }
+                Ok(ann) => {
+                    mismatched.remove(&peer.addr);
+                    Some(PeerInfo {
+                        device_id: ann.device_id,
+                        device_name: ann.device_name,
+                        fingerprint: ann.pubkey_fingerprint,
+                        addresses: vec![peer.addr.ip()],
+                        port: peer.addr.port(),
+                        accepting_pairing: false,
+                        protocol_version: Some(ann.protocol_version),
+                        last_seen: Instant::now(),
+                    })
+                }
+                Err(e) => {
+                    tracing::debug!("peer {} didn't answer: {}", peer.addr, e);
+                    None
+                }
+            };
+            let event = match (found.get(&peer.addr).cloned(), answered) {
+                (Some(known), answered) if answered.as_ref().is_none_or(|info| info.device_id != known.device_id) => {
+                    found.remove(&peer.addr);
+                    DiscoveryEvent::PeerLost(known.device_id)
+                }
+                (Some(known), Some(info)) if !known.advertises_same(&info) => {
+                    found.insert(peer.addr, info.clone());
+                    DiscoveryEvent::PeerUpdated(info)
+                }
+                (None, Some(info)) if !addresses.read().await.contains_key(&info.device_id) => {
+                    found.insert(peer.addr, info.clone());
+                    DiscoveryEvent::PeerFound(info)
+                }
+                _ => continue,
+            };
+            if found_tx.send(event).await.is_err() {
+                return;
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(MANUAL_PEER_POLL_INTERVAL_SECS)) => {}
+            _ = added.notified() => {}
+        }
+    }
+}
+
+/// Append an entry to the audit log, if enabled
+fn audit(log: &Option<Arc<AuditLog>>, entry: AuditEntry) {
+    if let Some(log) = log {
+        if let Err(e) = log.record(&entry) {
+            tracing::warn!("failed to write audit log: {}", e);
+        }
+    }
+}
+
+/// Summarize which content kinds are enabled, for the startup log
+fn describe_kinds(allowed: &HashSet<ContentKind>) -> String {
+    let enabled: Vec<String> = ContentKind::ALL.iter()
+        .filter(|kind| allowed.contains(kind))
+        .map(ToString::to_string)
+        .collect();
+    if enabled.is_empty() {
+        "no content types".to_string()
+    } else {
+        enabled.join(", ")
+    }
+}
+
+/// Send local clipboard changes to every paired device
+#[allow(clippy::too_many_arguments)]
+async fn forward_local_changes(
+    mut clip_rx: ChangeReceiver,
+    our_id: Uuid,
+    channel: String,
+    paired: Arc<RwLock<HashMap<Uuid, PairedDeviceInfo>>>,
+    addresses: Arc<RwLock<HashMap<Uuid, PeerInfo>>>,
+    pool: Arc<ConnectionPool>,
+    last_sent: Arc<RwLock<Option<ContentHash>>>,
+    last_local: Arc<RwLock<Option<u64>>>,
+    synced: Arc<RwLock<HashMap<Uuid, ClipboardContent>>>,
+    deliveries: Arc<RwLock<DeliveryTracker>>,
+    pause: Arc<PauseState>,
+    clock: Arc<SyncClock>,
+    outbox: Arc<RwLock<Outbox>>,
+    recent: Arc<RwLock<RecentContent>>,
+    history: Arc<RwLock<ClipboardHistory>>,
+    allowed_kinds: HashSet<ContentKind>,
+    max_clip_bytes: usize,
+    debounce: Duration,
+    normalization: Normalization,
+    audit_log: Option<Arc<AuditLog>>,
+    tx: EventSender,
+) {
+    while clip_rx.changed().await.is_ok() {
+        // Wait until the clipboard settles and send only its final value.
+        // The monitor closing ends the wait, so the last change still goes out.
+        while !debounce.is_zero() {
+            match tokio::time::timeout(debounce, clip_rx.changed()).await {
+                Ok(Ok(())) => continue,
+                Ok(Err(_)) | Err(_) => break,
+            }
+        }
+        let Some(change) = clip_rx.borrow_and_update().clone() else {
+            continue;
+        };
+        if pause.is_paused() {
+            continue;
+        }
+        if !allowed_kinds.contains(&change.content.kind()) {
+            tracing::debug!("not sending {}, not an allowed content type", change.content.kind());
+            continue;
+        }
+        let size = change.content.size();
+        if size > max_clip_bytes {
+            tracing::warn!("not sending {} of {} bytes, over the {} byte limit", change.content.kind(), size, max_clip_bytes);
+            let _ = tx.send(ServiceEvent::ClipboardTooLarge { size, limit: max_clip_bytes }).await;
+            continue;
+        }
+
+        // Skip if this is content we just received, or anything else
+        // exchanged recently coming back around
+        if *last_sent.read().await == Some(change.hash) || recent.read().await.contains(change.hash) {
+            tracing::debug!("not sending {}, it was exchanged recently", change.hash);
+            let _ = tx.send(ServiceEvent::SyncSuppressed { hash: change.hash }).await;
+            continue;
+        }
+        let change = normalization.apply_change(change);
+
+        // Send to all paired devices
+        let timestamp = clock.timestamp();
+        history.write().await.record(&change.content, timestamp, our_id);
+        let targets: Vec<Uuid> = {
+            let devices = paired.read().await;
+            let mut outbox = outbox.write().await;
+            devices.values()
+                .filter(|device| device.direction.sends())
+                .map(|device| &device.device_id)
+                .filter(|id| {
+                    let held = outbox.hold(**id, &change);
+                    if held {
+                        tracing::debug!("queued clipboard change for offline device {}", id);
+                    }
+                    !held
+                })
+                .copied()
+                .collect()
+        };
+        let mut sent_to = Vec::new();
+
+        for id in targets {
+            let sent = send_to_device(
+                id, our_id, &channel, &change, timestamp, &paired, &addresses, &pool, &synced, &deliveries, &audit_log,
+            ).await;
+            match sent {
+                Ok(()) => sent_to.push(id),
+                Err(e) => report_send_failure(id, &e, &tx).await,
+            }
+        }
+
+        if !sent_to.is_empty() {
+            recent.write().await.sent(change.hash);
+            *last_sent.write().await = Some(change.hash);
+            *last_local.write().await = Some(timestamp);
+            let _ = tx.send(ServiceEvent::ClipboardSent { to_devices: sent_to }).await;
+        }
+    }
+}
+
+/// Send clipboard content to one paired device and record it as pending
+///
+/// The message goes over the device's pooled connection, opened to the
+/// address it was last discovered at if there is none. It counts as sent
+/// once the frame is written; the device's acknowledgement comes back on the
+/// same connection and is handled by [`handle_replies`].
+#[allow(clippy::too_many_arguments)]
+async fn send_to_device(
+    device_id: Uuid,
+    our_id: Uuid,
+    channel: &str,
+    change: &ClipboardChange,
+    timestamp: u64,
+    paired: &RwLock<HashMap<Uuid, PairedDeviceInfo>>,
+    addresses: &RwLock<HashMap<Uuid, PeerInfo>>,
+    pool: &ConnectionPool,
+    synced: &RwLock<HashMap<Uuid, ClipboardContent>>,
+    deliveries: &Arc<RwLock<DeliveryTracker>>,
+    audit_log: &Option<Arc<AuditLog>>,
+) -> Result<()> {
+    // The primary selection is always sent whole, so it never becomes the
+    // base for the clipboard's patches
+    let clipboard = change.selection == ClipboardSelection::Clipboard;
+    let (message, session_key, identity_pubkey, codec) = {
+        let devices = paired.read().await;
+        let device = devices.get(&device_id)
+            .ok_or_else(|| Error::NotPaired(device_id.to_string()))?;
+        let synced = synced.read().await;
+        let message = build_sync_message(
+            our_id, &change.selection.channel(channel), &device.keys, device.features.compression, &change.content,
+            change.hash, synced.get(&device_id).filter(|_| clipboard), timestamp,
+        )?;
+        (message, device.keys.current().clone(), device.identity_pubkey.clone(), device.features.wire_codec)
+    };
+    let peer = addresses.read().await.get(&device_id).cloned()
+        .ok_or_else(|| Error::Network(format!("{} isn't on the network", device_id)))?;
+    let message_id = match &message {
+        Message::ClipboardSync(ClipboardSyncMessage { message_id, .. })
+        | Message::ClipboardDelta(ClipboardDeltaMessage { message_id, .. }) => Some(*message_id),
+        _ => None,
+    };
+    // Pending before sending, since the ack can arrive before the send returns
+    if let Some(message_id) = message_id {
+        let mut deliveries = deliveries.write().await;
+        let retransmits = deliveries.pending.get(&device_id)
+            .filter(|pending| pending.change.hash == change.hash)
+            .map_or(0, |pending| pending.retransmits + 1);
+        deliveries.pending.insert(device_id, PendingDelivery {
+            message_id,
+            change: change.clone(),
+            sent_at: Instant::now(),
+            retransmits,
+        });
+    }
+    if let Err(e) = pool.send(&peer, &identity_pubkey, &session_key, codec, &message).await {
+        let mut deliveries = deliveries.write().await;
+        if message_id.is_some() && deliveries.pending.get(&device_id).map(|pending| pending.message_id) == message_id {
+            deliveries.pending.remove(&device_id);
+        }
+        return Err(e);
+    }
+    if let Some(device) = paired.write().await.get_mut(&device_id) {
+        device.keys.record_send();
+    }
+
+    deliveries.write().await.stats.messages_sent += 1;
+    audit(audit_log, AuditEntry::new(AuditEvent::ClipboardSync, device_id, Direction::Outbound)
+        .with_content(change.hash, change.content.size()));
+    if clipboard {
+        synced.write().await.insert(device_id, change.content.clone());
+    }
+    Ok(())
+}
+
+/// Report a clipboard message that couldn't be sent to a device
+async fn report_send_failure(device_id: Uuid, error: &Error, tx: &EventSender) {
+    tracing::warn!("couldn't send clipboard to {}: {}", device_id, error);
+    let _ = tx.send(ServiceEvent::Error(format!("couldn't send clipboard to {}: {}", device_id, error))).await;
+}
+
+/// Send the current clipboard to a device that just paired or came back
+/// online, so it is in sync without waiting for the next local change.
+///
+/// Does nothing for unpaired devices, or if the device was last sent or
+/// sent us this same content.
+#[allow(clippy::too_many_arguments)]
+async fn push_current_clipboard(
+    device_id: Uuid,
+    our_id: Uuid,
+    channel: &str,
+    clipboard: &ClipboardWriter,
+    paired: &RwLock<HashMap<Uuid, PairedDeviceInfo>>,
+    addresses: &RwLock<HashMap<Uuid, PeerInfo>>,
+    pool: &ConnectionPool,
+    synced: &RwLock<HashMap<Uuid, ClipboardContent>>,
+    deliveries: &Arc<RwLock<DeliveryTracker>>,
+    clock: &SyncClock,
+    allowed_kinds: &HashSet<ContentKind>,
+    max_clip_bytes: usize,
+    audit_log: &Option<Arc<AuditLog>>,
+    tx: &EventSender,
+) {
+    let content = match clipboard.read().await {
+        Ok(Some(content)) => content,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::warn!("couldn't read clipboard for new device: {}", e);
+            return;
+        }
+    };
+    if !allowed_kinds.contains(&content.kind()) {
+        tracing::debug!("not sending {} to new device, not an allowed content type", content.kind());
+        return;
+    }
+    if content.size() > max_clip_bytes {
+        tracing::debug!("not sending {} to new device, over the size limit", content.kind());
+        return;
+    }
+    if synced.read().await.get(&device_id).is_some_and(|last| last.hash() == content.hash()) {
+        return;
+    }
+
+    let change = ClipboardChange { hash: content.hash(), content, selection: ClipboardSelection::Clipboard };
+    deliver_to(device_id, &change, our_id, channel, paired, addresses, pool, synced, deliveries, clock, audit_log, tx).await;
+}
+
+/// Send a change to a single paired device and report it
+#[allow(clippy::too_many_arguments)]
+async fn deliver_to(
+    device_id: Uuid,
+    change: &ClipboardChange,
+    our_id: Uuid,
+    channel: &str,
+    paired: &RwLock<HashMap<Uuid, PairedDeviceInfo>>,
+    addresses: &RwLock<HashMap<Uuid, PeerInfo>>,
+    pool: &ConnectionPool,
+    synced: &RwLock<HashMap<Uuid, ClipboardContent>>,
+    deliveries: &Arc<RwLock<DeliveryTracker>>,
+    clock: &SyncClock,
+    audit_log: &Option<Arc<AuditLog>>,
+    tx: &EventSender,
+) {
+    if !paired.read().await.get(&device_id).is_some_and(|device| device.direction.sends()) {
+        return;
+    }
+    let sent = send_to_device(
+        device_id, our_id, channel, change, clock.timestamp(), paired, addresses, pool, synced, deliveries, audit_log,
+    ).await;
+    match sent {
+        Ok(()) => {
+            let _ = tx.send(ServiceEvent::ClipboardSent { to_devices: vec![device_id] }).await;
+        }
+        Err(e) => report_send_failure(device_id, &e, tx).await,
+    }
+}
+
+/// Send a device's unacknowledged change again, whole and over a new
+/// connection, since the one it went out on may have lost it
+///
+/// A change that can't be sent stays pending, to go out when the device is
+/// next seen or the next check finds it overdue.
+#[allow(clippy::too_many_arguments)]
+async fn redeliver(
+    device_id: Uuid,
+    our_id: Uuid,
+    channel: &str,
+    paired: &RwLock<HashMap<Uuid, PairedDeviceInfo>>,
+    addresses: &RwLock<HashMap<Uuid, PeerInfo>>,
+    pool: &ConnectionPool,
+    synced: &RwLock<HashMap<Uuid, ClipboardContent>>,
+    deliveries: &Arc<RwLock<DeliveryTracker>>,
+    clock: &SyncClock,
+    audit_log: &Option<Arc<AuditLog>>,
+    tx: &EventSender,
+) {
+    let Some((change, retransmits)) = deliveries.read().await.pending.get(&device_id)
+        .map(|pending| (pending.change.clone(), pending.retransmits))
+    else {
+        return;
+    };
+    if !paired.read().await.get(&device_id).is_some_and(|device| device.direction.sends()) {
+        deliveries.write().await.pending.remove(&device_id);
+        return;
+    }
+    tracing::debug!("sending unacknowledged clipboard to {} again", device_id);
+    // Whether it holds the base a patch would need is unknown
+    synced.write().await.remove(&device_id);
+    pool.evict(device_id).await;
+    let sent = send_to_device(
+        device_id, our_id, channel, &change, clock.timestamp(), paired, addresses, pool, synced, deliveries, audit_log,
+    ).await;
+    match sent {
+        Ok(()) => {
+            let _ = tx.send(ServiceEvent::ClipboardSent { to_devices: vec![device_id] }).await;
+        }
+        Err(e) => {
+            tracing::debug!("couldn't send clipboard to {} again: {}", device_id, e);
+            // Kept for next time, under an id nothing acks
+            deliveries.write().await.pending.entry(device_id).or_insert(PendingDelivery {
+                message_id: Uuid::new_v4(),
+                change,
+                sent_at: Instant::now(),
+                retransmits: retransmits + 1,
+            });
+        }
+    }
+}
+
+/// Send again each change a device hasn't acknowledged within
+/// `ack_timeout`, giving up on those already sent again
+/// [`MAX_RETRANSMITS`] times
+#[allow(clippy::too_many_arguments)]
+async fn retransmit_unacked(
+    ack_timeout: Duration,
+    our_id: Uuid,
+    channel: &str,
+    paired: &RwLock<HashMap<Uuid, PairedDeviceInfo>>,
+    addresses: &RwLock<HashMap<Uuid, PeerInfo>>,
+    pool: &ConnectionPool,
+    synced: &RwLock<HashMap<Uuid, ClipboardContent>>,
+    deliveries: &Arc<RwLock<DeliveryTracker>>,
+    clock: &SyncClock,
+    audit_log: &Option<Arc<AuditLog>>,
+    tx: &EventSender,
+) {
+    let (overdue, abandoned) = {
+        let mut deliveries = deliveries.write().await;
+        let (abandoned, overdue): (Vec<_>, Vec<_>) = deliveries.pending.iter()
+            .filter(|(_, pending)| pending.sent_at.elapsed() >= ack_timeout)
+            .map(|(device_id, pending)| (*device_id, pending.retransmits))
+            .partition(|(_, retransmits)| *retransmits >= MAX_RETRANSMITS);
+        for (device_id, _) in &abandoned {
+            deliveries.pending.remove(device_id);
+        }
+        (overdue, abandoned)
+    };
+    for (device_id, retransmits) in abandoned {
+        tracing::warn!("{} didn't acknowledge clipboard after {} attempts", device_id, retransmits + 1);
+        let message = format!("{} didn't acknowledge the clipboard sent to it", device_id);
+        let _ = tx.send(ServiceEvent::Error(message)).await;
+    }
+    for (device_id, _) in overdue {
+        redeliver(device_id, our_id, channel, paired, addresses, pool, synced, deliveries, clock, audit_log, tx).await;
+    }
+}
+
+/// Deliver content received from a peer to the configured sink.
+///
+/// Content is normalized first. The hash of what is actually written is
+/// recorded for echo suppression before writing, and clipboard
+/// writes go through the monitor so they are never reported as a local change.
+async fn write_received(
+    sink: &dyn ClipboardSink,
+    last_sent: &RwLock<Option<ContentHash>>,
+    normalization: &Normalization,
+    content: &ClipboardContent,
+) -> Result<()> {
+    let content = normalization.apply(content);
+    *last_sent.write().await = Some(content.hash());
+    sink.store(&content).await
+}
+
+/// Write content a peer sent from its primary selection to ours
+///
+/// Unlike clipboard content it isn't held while paused, recorded in history
+/// or reported: the primary selection changes with every selection made,
+/// and only the latest matters.
+#[allow(clippy::too_many_arguments)]
+async fn receive_primary(
+    peer_id: Uuid,
+    content: ClipboardContent,
+    clipboard: &ClipboardWriter,
+    last_sent: &RwLock<Option<ContentHash>>,
+    normalization: &Normalization,
+    write_blocked: &RwLock<HashSet<Uuid>>,
+    pause: &PauseState,
+) {
+    if pause.is_paused() || write_blocked.read().await.contains(&peer_id) {
+        tracing::debug!("not writing primary selection from {}", peer_id);
+        return;
+    }
+    let content = normalization.apply(&content);
+    *last_sent.write().await = Some(content.hash());
+    if let Err(e) = clipboard.write_selection(ClipboardSelection::Primary, content).await {
+        tracing::warn!("failed to write received primary selection: {}", e);
+    }
+}
+
+/// Acknowledge a received clipboard message on the connection it arrived on
+fn send_ack(reply: Option<oneshot::Sender<Message>>, message_id: Uuid) {
+    if let Some(reply) = reply {
+        let _ = reply.send(Message::Ack { message_id });
+    }
+}
+
+/// Record a peer's acknowledgement of a message we sent
+async fn confirm_delivery(
+    deliveries: &RwLock<DeliveryTracker>,
+    tx: &EventSender,
+    message_id: Uuid,
+) {
+    let device_id = {
+        let mut deliveries = deliveries.write().await;
+        let Some(device_id) = deliveries.pending.iter()
+            .find(|(_, pending)| pending.message_id == message_id)
+            .map(|(device_id, _)| *device_id)
+        else {
+            tracing::debug!("ignoring ack for unknown message {}", message_id);
+            return;
+        };
+        deliveries.pending.remove(&device_id);
+        deliveries.stats.messages_acked += 1;
+        device_id
+    };
+    let _ = tx.send(ServiceEvent::DeliveryConfirmed { device_id, message_id }).await;
+}
+
+/// Handle what devices send back on pooled connections, which is their
+/// acknowledgements of our clipboard messages
+async fn handle_replies(
+    mut replies: mpsc::Receiver<(Uuid, Message)>,
+    deliveries: Arc<RwLock<DeliveryTracker>>,
+    tx: EventSender,
+) {
+    while let Some((device_id, message)) = replies.recv().await {
+        match message {
+            Message::Ack { message_id } => confirm_delivery(&deliveries, &tx, message_id).await,
+            other => tracing::debug!("expected an ack from {}, got {:?}", device_id, other),
+        }
+    }
+}
+
+/// Send an `Unpair` notification to a peer, trying each of its addresses
+async fn notify_unpair(
+    peer: &PeerInfo,
+    our_id: Uuid,
+    session_key: &SessionKey,
+    peer_identity: Option<&VerifyingKey>,
+    encrypted: bool,
+) -> Result<()> {
+    let message = Message::Unpair {
+        device_id: our_id,
+        proof: session_key.encrypt(our_id.as_bytes())?,
+    };
+    // JSON, which every peer decodes; the pairing may already be gone here
+    transmit(peer, session_key, peer_identity, WireCodec::Json, &message, encrypted).await.map(drop)
+}
+
+/// Send `peer` a challenge only the holder of `session_key` can answer and
+/// return the round-trip time once it does
+async fn probe(
+    peer: &PeerInfo,
+    our_id: Uuid,
+    session_key: &SessionKey,
+    peer_identity: &VerifyingKey,
+    codec: WireCodec,
+    encrypted: bool,
+) -> Result<Duration> {
+    let started = Instant::now();
+    let nonce: [u8; 16] = rand::random();
+    let message = Message::Probe {
+        device_id: our_id,
+        challenge: session_key.encrypt_with_aad(&nonce, &probe_aad(PROBE_CHALLENGE_CONTEXT, our_id))?,
+    };
+    let mut conn = transmit(peer, session_key, Some(peer_identity), codec, &message, encrypted).await?;
+    match conn.recv().await? {
+        Message::ProbeReply { device_id, response } if device_id == peer.device_id => {
+            let echoed = session_key.decrypt_with_aad(&response, &probe_aad(PROBE_RESPONSE_CONTEXT, device_id))?;
+            if echoed != nonce {
+                return Err(Error::Crypto(format!("{} answered a different probe", device_id)));
+            }
+            Ok(started.elapsed())
+        }
+        other => Err(Error::InvalidMessage(format!("expected a ProbeReply, got {:?}", other))),
+    }
+}
+
+/// Answer a probe from `device_id` under whichever of its keys the
+/// challenge was made with, so a peer still on a retired epoch gets through
+fn answer_probe(keys: &SessionKeyRing, our_id: Uuid, device_id: Uuid, challenge: &EncryptedPayload) -> Result<Message> {
+    let aad = probe_aad(PROBE_CHALLENGE_CONTEXT, device_id);
+    let (key, nonce) = keys.iter()
+        .find_map(|key| key.decrypt_with_aad(challenge, &aad).ok().map(|nonce| (key, nonce)))
+        .ok_or_else(|| Error::Crypto(format!("probe from {} isn't under any of its keys", device_id)))?;
+    let response = key.encrypt_with_aad(&nonce, &probe_aad(PROBE_RESPONSE_CONTEXT, our_id))?;
+    Ok(Message::ProbeReply { device_id: our_id, response })
+}
+
+/// Ratchet the session key of each device whose key is due for rotation,
+/// and tell those on the network. One that isn't follows when our next
+/// message arrives under the new epoch.
+#[allow(clippy::too_many_arguments)]
+async fn rotate_due_keys(
+    our_id: Uuid,
+    policy: &KeyRotation,
+    paired: &RwLock<HashMap<Uuid, PairedDeviceInfo>>,
+    server_devices: &RwLock<HashMap<Uuid, PairedDevice>>,
+    addresses: &RwLock<HashMap<Uuid, PeerInfo>>,
+    encrypted: bool,
+    state: &Option<Arc<StateFiles>>,
+    tx: &EventSender,
+) {
+    let rotated: Vec<_> = paired.write().await.values_mut()
+        .filter(|device| device.keys.rotation_due(policy))
+        .map(|device| {
+            let epoch = device.keys.ratchet();
+            (device.device_id, epoch, device.keys.current().clone(), device.identity_pubkey.clone(), device.features.wire_codec)
+        })
+        .collect();
+    if rotated.is_empty() {
+        return;
+    }
+    save_paired(state, paired).await;
+
+    for (device_id, epoch, key, identity_pubkey, codec) in rotated {
+        tracing::info!("rotated session key with {}, now on {}", device_id, epoch);
+        rekey_server(server_devices, device_id, &key).await;
+        let _ = tx.send(ServiceEvent::Rekeyed { device_id, epoch }).await;
+        let Some(peer) = addresses.read().await.get(&device_id).cloned() else {
+            continue;
+        };
+        let message = match key.encrypt(our_id.as_bytes()) {
+            Ok(proof) => Message::KeyRotate { device_id: our_id, epoch: epoch.0, proof },
+            Err(e) => {
+                tracing::warn!("couldn't prove key rotation to {}: {}", device_id, e);
+                continue;
+            }
+        };
+        if let Err(e) = transmit(&peer, &key, Some(&identity_pubkey), codec, &message, encrypted).await {
+            tracing::debug!("couldn't tell {} about key rotation: {}", device_id, e);
+        }
+    }
+}
+
+/// Follow a device's key ratchet forward to `epoch`, if that is a few steps
+/// ahead of ours and `verify` accepts the key derived for it
+///
+/// Saves and reports the new epoch, and returns the device's keys.
+async fn follow_ratchet(
+    paired: &RwLock<HashMap<Uuid, PairedDeviceInfo>>,
+    server_devices: &RwLock<HashMap<Uuid, PairedDevice>>,
+    state: &Option<Arc<StateFiles>>,
+    tx: &EventSender,
+    device_id: Uuid,
+    epoch: KeyEpoch,
+    verify: impl Fn(&SessionKey) -> bool,
+) -> Option<SessionKeyRing> {
+    let keys = {
+        let mut devices = paired.write().await;
+        let device = devices.get_mut(&device_id)?;
+        let keys = device.keys.ratcheted_to(epoch).filter(|keys| verify(keys.current()))?;
+        device.keys = keys.clone();
+        keys
+    };
+    rekey_server(server_devices, device_id, keys.current()).await;
+    save_paired(state, paired).await;
+
+    tracing::info!("{} rotated its session key, now on {}", device_id, epoch);
+    let _ = tx.send(ServiceEvent::Rekeyed { device_id, epoch }).await;
+    Some(keys)
+}
+
+/// Why a device using protocol `version` can't be paired with
+fn incompatible_version(device_name: &str, version: u16) -> String {
+    format!(
+        "{} uses protocol version {} and this device uses {}; update omniclip so both match",
+        device_name, version, PROTOCOL_VERSION
+    )
+}
+
+/// Send a message to a peer over a new connection, trying each of its
+/// addresses, and return the connection for any reply
+async fn transmit(
+    peer: &PeerInfo,
+    session_key: &SessionKey,
+    peer_identity: Option<&VerifyingKey>,
+    codec: WireCodec,
+    message: &Message,
+    encrypted: bool,
+) -> Result<PeerConnection> {
+    let timeout = Duration::from_millis(PEER_NOTIFY_TIMEOUT_MS);
+
+    let mut conn = PeerConnection::connect_peer(peer, session_key.clone(), timeout).await?.with_codec(codec);
+    if encrypted {
+        conn = conn.encrypted(peer_identity).await?;
+    }
+    tokio::time::timeout(timeout, conn.send(message))
+        .await
+        .map_err(|_| Error::Network(format!("timed out sending to {}", peer.device_id)))??;
+    Ok(conn)
+}
+
+/// Build the outbound sync message for one device.
+///
+/// Large text is sent as a patch against `base`, the content last exchanged
+/// with that device, so the receiver is known to hold it. Without a base, or
+/// when the patch would not be meaningfully smaller, the full content is sent,
+/// deflated first if the device negotiated compression and it is at least
+/// [`COMPRESSION_MIN_SIZE`]. Ciphertext is bound to the message's sender,
+/// id and timestamp with [`clipboard_aad`].
+#[allow(clippy::too_many_arguments)]
+fn build_sync_message(
+    our_id: Uuid,
+    channel: &str,
+    keys: &SessionKeyRing,
+    compression: Compression,
+    content: &ClipboardContent,
+    content_hash: ContentHash,
+    base: Option<&ClipboardContent>,
+    timestamp: u64,
+) -> Result<Message> {
+    let message_id = Uuid::new_v4();
+    let aad = clipboard_aad(our_id, message_id, timestamp);
+
+    if let (ClipboardContent::Text(text), Some(base @ ClipboardContent::Text(base_text))) = (content, base) {
+        if text.len() >= DELTA_MIN_SIZE {
+            let patch = TextPatch::compute(base_text, text).to_bytes()?;
+            if patch.len() < text.len() / 2 {
+                return Ok(Message::ClipboardDelta(ClipboardDeltaMessage {
+                    message_id,
+                    sender_id: our_id,
+                    base_hash: base.hash(),
+                    content_hash,
+                    patch: keys.current().encrypt_with_aad(&patch, &aad)?,
+                    timestamp,
+                    key_epoch: keys.current_epoch().0,
+                    channel: channel.to_string(),
+                }));
+            }
+        }
+    }
+
+    let mut plaintext = content.to_bytes()?;
+    let mut compressed = false;
+    if compression == Compression::Deflate && plaintext.len() >= COMPRESSION_MIN_SIZE {
+        if let Some(deflated) = compression::deflate(&plaintext) {
+            plaintext = deflated;
+            compressed = true;
+        }
+    }
+
+    Ok(Message::ClipboardSync(ClipboardSyncMessage {
+        message_id,
+        sender_id: our_id,
+        content_hash,
+        encrypted_content: keys.current().encrypt_in_place_with_aad(plaintext, &aad)?,
+        timestamp,
+        key_epoch: keys.current_epoch().0,
+        channel: channel.to_string(),
+        compressed,
+    }))
+}
+
+/// Parse decrypted clipboard content, decompressing it first if the sender
+/// compressed it
+fn open_content(plaintext: Vec<u8>, compressed: bool) -> Result<ClipboardContent> {
+    let plaintext = if compressed {
+        compression::inflate(&plaintext, MAX_DECOMPRESSED_SIZE)?
+    } else {
+        plaintext
+    };
+    Ok(ClipboardContent::from_bytes(&plaintext)?)
+}
+
+/// `content`, if it has the hash its message claims
+fn verify_hash(content: ClipboardContent, expected: ContentHash) -> Result<ClipboardContent> {
+    if content.hash() != expected {
+        return Err(Error::InvalidMessage("content hash mismatch".to_string()));
+    }
+    Ok(content)
+}
+
+/// Advertise whether any pairing session is still usable
+async fn advertise_pairing(discovery: &DiscoveryService, sessions: &RwLock<HashMap<Uuid, PairingSession>>) {
+    let accepting = sessions.read().await.values().any(|s| !s.is_expired());
+    if let Err(e) = discovery.set_accepting_pairing(accepting) {
+        tracing::warn!("couldn't update pairing flag: {}", e);
+    }
+}
+
+/// The address put in pairing URLs
+fn primary_ip(addresses: &AddressFilter) -> String {
+    addresses.local_ips().first()
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| "127.0.0.1".to_string())
+}
+
+/// Pairing URL for the newest open session if our address has moved on
+/// from `last_ip`, which is updated to `ip`
+async fn refresh_pairing_url(
+    sessions: &RwLock<HashMap<Uuid, PairingSession>>,
+    last_ip: &mut String,
+    ip: String,
+    port: u16,
+    identity: &DeviceIdentity,
+) -> Option<String> {
+    if *last_ip == ip {
+        return None;
+    }
+    *last_ip = ip;
+
+    let sessions = sessions.read().await;
+    let session = sessions.values()
+        .filter(|s| !s.is_expired())
+        .max_by_key(|s| s.created_at)?;
+    Some(session.qr_data(last_ip, port, identity).to_url())
+}
+
+/// Drain `events` into `callback` on a new task
+fn forward_to_callback(
+    mut events: mpsc::Receiver<ServiceEvent>,
+    callback: impl Fn(ServiceEvent) + Send + 'static,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            callback(event);
+        }
+    })
+}
+
+/// Whether content on `channel` is accepted, logging when it isn't
+fn in_channels(channels: &HashSet<String>, channel: &str, peer_id: Uuid) -> bool {
+    let accepted = channels.contains(channel);
+    if !accepted {
+        tracing::debug!("ignoring clipboard from {} on channel {:?}, not one of ours", peer_id, channel);
+    }
+    accepted
+}
+
+/// Drop a clipboard message taken for a replay and report it
+async fn reject_replay(
+    peer_id: Uuid,
+    message_id: Uuid,
+    content_hash: ContentHash,
+    size: usize,
+    audit_log: &Option<Arc<AuditLog>>,
+    tx: &EventSender,
+) {
+    tracing::warn!("dropping clipboard message {} from {}, stale or already received", message_id, peer_id);
+    audit(audit_log, AuditEntry::new(AuditEvent::ClipboardRejected, peer_id, Direction::Inbound)
+        .with_content(content_hash, size));
+    let _ = tx.send(ServiceEvent::ReplayRejected { device_id: peer_id, message_id }).await;
+}
+
+/// Key a message from a paired device was encrypted with
+fn epoch_key(keys: &SessionKeyRing, epoch: u32) -> Result<&SessionKey> {
+    keys.get(KeyEpoch(epoch)).ok_or_else(|| Error::InvalidMessage(format!(
+        "unknown key epoch {} (we are on {})",
+        epoch, keys.current_epoch()
+    )))
+}
+
+/// Reconstruct content from a delta and the base it was computed against
+fn apply_delta(
+    session_key: &SessionKey,
+    base: &ClipboardContent,
+    delta: &ClipboardDeltaMessage,
+) -> Result<ClipboardContent> {
+    let ClipboardContent::Text(base_text) = base else {
+        return Err(Error::InvalidMessage("delta base is not text".to_string()));
+    };
+
+    let patch = TextPatch::from_bytes(&session_key.decrypt_with_aad(&delta.patch, &delta.aad())?)?;
+    let content = ClipboardContent::Text(patch.apply(base_text)?);
+
+    if content.hash() != delta.content_hash {
+        return Err(Error::InvalidMessage("delta result hash mismatch".to_string()));
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::ClipboardManager;
+    use crate::crypto::SigningKey;
+    use crate::protocol::constants::{DEDUP_WINDOW_SECS, DEFAULT_HISTORY_CAPACITY, DEFAULT_MAX_CLIP_BYTES, REPLAY_WINDOW_SECS};
+    use crate::sync::ConflictPolicy;
+    use std::net::IpAddr;
+    use std::sync::Mutex;
+
+    /// In-memory clipboard shared with the test
+    struct MemoryClipboard(Arc<Mutex<Option<ClipboardContent>>>);
+
+    impl ClipboardBackend for MemoryClipboard {
+        fn read(&self) -> Result<Option<ClipboardContent>> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+
+        fn write(&self, content: &ClipboardContent) -> Result<()> {
+            *self.0.lock().unwrap() = Some(content.clone());
+            Ok(())
+        }
+    }
+
+    /// Stand-in for a device's sync server that acknowledges every clipboard
+    /// message sent to it, keeping connections open
+    fn spawn_peer(device_id: Uuid) -> PeerInfo {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    while let Ok(payload) = sync::read_framed_message(&mut stream).await {
+                        let message_id = match Message::from_bytes(&payload) {
+                            Ok(Message::ClipboardSync(m)) => m.message_id,
+                            Ok(Message::ClipboardDelta(m)) => m.message_id,
+                            _ => return,
+                        };
+                        let ack = Message::Ack { message_id }.to_bytes().unwrap();
+                        let _ = sync::write_framed_message(&mut stream, &ack).await;
+                    }
+                });
+            }
+        });
+        PeerInfo {
+            device_id,
+            device_name: "peer".to_string(),
+            fingerprint: String::new(),
+            addresses: vec![IpAddr::from([127, 0, 0, 1])],
+            port,
+            accepting_pairing: false,
+            protocol_version: None,
+            last_seen: Instant::now(),
+        }
+    }
+
+    /// Local clipboard wired to a sender with one paired device
+    struct Harness {
+        clipboard: Arc<Mutex<Option<ClipboardContent>>>,
+        writer: ClipboardWriter,
+        last_sent: Arc<RwLock<Option<ContentHash>>>,
+        events: mpsc::Receiver<ServiceEvent>,
+        peer_id: Uuid,
+        paired: Arc<RwLock<HashMap<Uuid, PairedDeviceInfo>>>,
+        addresses: Arc<RwLock<HashMap<Uuid, PeerInfo>>>,
+        pool: Arc<ConnectionPool>,
+        synced: Arc<RwLock<HashMap<Uuid, ClipboardContent>>>,
+        deliveries: Arc<RwLock<DeliveryTracker>>,
+        outbox: Arc<RwLock<Outbox>>,
+        recent: Arc<RwLock<RecentContent>>,
+    }
+
+    fn spawn_forwarder(allowed_kinds: HashSet<ContentKind>, queue_for_offline: bool, debounce: Duration) -> Harness {
+        let clipboard = Arc::new(Mutex::new(None));
+        let manager = ClipboardManager::with_backend(MemoryClipboard(clipboard.clone()));
+        let (clip_rx, writer, _handle) = clipboard::start_monitor_with(manager, Duration::from_millis(10));
+
+        let peer_id = Uuid::new_v4();
+        let paired = Arc::new(RwLock::new(HashMap::from([(peer_id, PairedDeviceInfo {
+            device_id: peer_id,
+            device_name: "peer".to_string(),
+            identity_pubkey: SigningKey::generate().verifying_key(),
+            keys: SessionKeyRing::new(SessionKey::from_bytes(&[7u8; 32])),
+            features: NegotiatedFeatures::baseline(),
+            direction: SyncDirection::default(),
+            last_seen: std::time::Instant::now(),
+        })])));
+        let addresses = Arc::new(RwLock::new(HashMap::from([(peer_id, spawn_peer(peer_id))])));
+        let last_sent = Arc::new(RwLock::new(None));
+        let synced = Arc::new(RwLock::new(HashMap::new()));
+        let deliveries = Arc::new(RwLock::new(DeliveryTracker::default()));
+        let outbox = Arc::new(RwLock::new(Outbox::new(queue_for_offline)));
+        let recent = Arc::new(RwLock::new(RecentContent::new(Duration::from_secs(DEDUP_WINDOW_SECS))));
+        let (tx, events) = event_channel(8);
+        let (replies_tx, replies) = mpsc::channel(8);
+        let pool = Arc::new(ConnectionPool::new(replies_tx));
+        tokio::spawn(handle_replies(replies, deliveries.clone(), tx.clone()));
+        tokio::spawn(forward_local_changes(
+            clip_rx,
+            Uuid::new_v4(),
+            String::new(),
+            paired.clone(),
+            addresses.clone(),
+            pool.clone(),
+            last_sent.clone(),
+            Arc::new(RwLock::new(None)),
+            synced.clone(),
+            deliveries.clone(),
+            Arc::new(PauseState::default()),
+            Arc::new(SyncClock::default()),
+            outbox.clone(),
+            recent.clone(),
+            Arc::new(RwLock::new(ClipboardHistory::new(DEFAULT_HISTORY_CAPACITY))),
+            allowed_kinds,
+            DEFAULT_MAX_CLIP_BYTES,
+            debounce,
+            Normalization::default(),
+            None,
+            tx,
+        ));
+
+        Harness {
+            clipboard, writer, last_sent, events, peer_id, paired, addresses, pool, synced, deliveries, outbox, recent,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_received_content_is_not_sent_back() {
+        let Harness { clipboard, writer, last_sent, events: mut rx, peer_id, .. } =
+            spawn_forwarder(ContentKind::ALL.into_iter().collect(), false, Duration::ZERO);
+
+        let received = ClipboardContent::Text("from peer".to_string());
+        write_received(&clipboard::SystemClipboardSink::new(writer.clone()), &last_sent, &Normalization::default(), &received).await.unwrap();
+        assert_eq!(clipboard.lock().unwrap().as_ref().map(|c| c.hash()), Some(received.hash()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(rx.try_recv().is_err());
+
+        // A genuine local copy is still sent
+        *clipboard.lock().unwrap() = Some(ClipboardContent::Text("local".to_string()));
+        let event = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::ClipboardSent { to_devices }) if to_devices == vec![peer_id]));
+    }
+
+    #[tokio::test]
+    async fn test_published_content_is_sent() {
+        let Harness { clipboard, writer, last_sent, events: mut rx, peer_id, .. } =
+            spawn_forwarder(ContentKind::ALL.into_iter().collect(), false, Duration::ZERO);
+
+        let content = ClipboardContent::Text("set by an app".to_string());
+        writer.publish(content.clone()).await.unwrap();
+        assert_eq!(clipboard.lock().unwrap().as_ref().map(|c| c.hash()), Some(content.hash()));
+        let event = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::ClipboardSent { to_devices }) if to_devices == vec![peer_id]));
+        assert_eq!(*last_sent.read().await, Some(content.hash()));
+    }
+
+    #[tokio::test]
+    async fn test_echoed_content_reports_suppression() {
+        let Harness { clipboard, last_sent, events: mut rx, .. } =
+            spawn_forwarder(ContentKind::ALL.into_iter().collect(), false, Duration::ZERO);
+
+        // Written back by another app rather than through the writer, so the
+        // monitor reports it
+        let received = ClipboardContent::Text("from peer".to_string());
+        *last_sent.write().await = Some(received.hash());
+        *clipboard.lock().unwrap() = Some(received.clone());
+        let event = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::SyncSuppressed { hash }) if hash == received.hash()));
+    }
+
+    #[tokio::test]
+    async fn test_normalized_content_is_not_sent_back() {
+        let Harness { clipboard, writer, last_sent, events: mut rx, .. } =
+            spawn_forwarder(ContentKind::ALL.into_iter().collect(), false, Duration::ZERO);
+        let lf = Normalization {
+            line_endings: Some(clipboard::LineEnding::Lf),
+            ..Default::default()
+        };
+
+        let received = ClipboardContent::Text("from\r\nwindows".to_string());
+        let sink = clipboard::SystemClipboardSink::new(writer);
+        write_received(&sink, &last_sent, &lf, &received).await.unwrap();
+
+        let written = ClipboardContent::Text("from\nwindows".to_string());
+        assert_eq!(clipboard.lock().unwrap().as_ref().map(|c| c.hash()), Some(written.hash()));
+        assert_eq!(*last_sent.read().await, Some(written.hash()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_disallowed_content_kind_not_sent() {
+        let mut harness = spawn_forwarder(HashSet::from([ContentKind::Text]), false, Duration::ZERO);
+
+        *harness.clipboard.lock().unwrap() = Some(ClipboardContent::RichText {
+            plain: "bold".to_string(),
+            html: "<b>bold</b>".to_string(),
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(harness.events.try_recv().is_err());
+
+        *harness.clipboard.lock().unwrap() = Some(ClipboardContent::Text("plain".to_string()));
+        let event = tokio::time::timeout(Duration::from_secs(1), harness.events.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::ClipboardSent { to_devices }) if to_devices == vec![harness.peer_id]));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_content_not_sent() {
+        let mut harness = spawn_forwarder(ContentKind::ALL.into_iter().collect(), false, Duration::ZERO);
+
+        *harness.clipboard.lock().unwrap() = Some(ClipboardContent::Image {
+            width: 1100,
+            height: 1000,
+            rgba: vec![0u8; 1100 * 1000 * 4],
+        });
+        let event = tokio::time::timeout(Duration::from_secs(1), harness.events.recv()).await.unwrap();
+        assert!(matches!(
+            event,
+            Some(ServiceEvent::ClipboardTooLarge { size: 4_400_000, limit: DEFAULT_MAX_CLIP_BYTES })
+        ));
+
+        *harness.clipboard.lock().unwrap() = Some(ClipboardContent::Text("small".to_string()));
+        let event = tokio::time::timeout(Duration::from_secs(1), harness.events.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::ClipboardSent { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_new_device_receives_current_clipboard() {
+        let current = ClipboardContent::Text("already copied".to_string());
+        let manager = ClipboardManager::with_backend(MemoryClipboard(Arc::new(Mutex::new(Some(current.clone())))));
+        let (_clip_rx, clipboard, _handle) = clipboard::start_monitor_with(manager, Duration::from_millis(10));
+
+        let device_id = Uuid::new_v4();
+        let paired = RwLock::new(HashMap::from([(device_id, PairedDeviceInfo {
+            device_id,
+            device_name: "new device".to_string(),
+            identity_pubkey: SigningKey::generate().verifying_key(),
+            keys: SessionKeyRing::new(SessionKey::from_bytes(&[7u8; 32])),
+            features: NegotiatedFeatures::baseline(),
+            direction: SyncDirection::default(),
+            last_seen: std::time::Instant::now(),
+        })]));
+        let addresses = RwLock::new(HashMap::from([(device_id, spawn_peer(device_id))]));
+        let synced = RwLock::new(HashMap::new());
+        let deliveries = Arc::new(RwLock::new(DeliveryTracker::default()));
+        let (tx, mut rx) = event_channel(8);
+        let (replies_tx, replies) = mpsc::channel(8);
+        let pool = ConnectionPool::new(replies_tx);
+        tokio::spawn(handle_replies(replies, deliveries.clone(), tx.clone()));
+
+        push_current_clipboard(
+            device_id, Uuid::new_v4(), "", &clipboard, &paired, &addresses, &pool, &synced, &deliveries,
+            &SyncClock::default(), &ContentKind::ALL.into_iter().collect(), DEFAULT_MAX_CLIP_BYTES, &None, &tx,
+        ).await;
+
+        assert!(matches!(rx.try_recv(), Ok(ServiceEvent::ClipboardSent { to_devices }) if to_devices == vec![device_id]));
+        assert_eq!(synced.read().await.get(&device_id).map(|c| c.hash()), Some(current.hash()));
+        // The device acks on the connection the message went out on
+        let event = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::DeliveryConfirmed { device_id: id, .. }) if id == device_id));
+        assert!(deliveries.read().await.pending.is_empty());
+
+        // Rediscovered with nothing new: the device already has it
+        push_current_clipboard(
+            device_id, Uuid::new_v4(), "", &clipboard, &paired, &addresses, &pool, &synced, &deliveries,
+            &SyncClock::default(), &ContentKind::ALL.into_iter().collect(), DEFAULT_MAX_CLIP_BYTES, &None, &tx,
+        ).await;
+        assert!(rx.try_recv().is_err());
+
+        // Rediscovered after being lost while the clipboard changed
+        synced.write().await.insert(device_id, ClipboardContent::Text("older".to_string()));
+        push_current_clipboard(
+            device_id, Uuid::new_v4(), "", &clipboard, &paired, &addresses, &pool, &synced, &deliveries,
+            &SyncClock::default(), &ContentKind::ALL.into_iter().collect(), DEFAULT_MAX_CLIP_BYTES, &None, &tx,
+        ).await;
+        assert!(matches!(rx.try_recv(), Ok(ServiceEvent::ClipboardSent { to_devices }) if to_devices == vec![device_id]));
+
+        // Content the allowlist excludes stays local
+        synced.write().await.clear();
+        push_current_clipboard(
+            device_id, Uuid::new_v4(), "", &clipboard, &paired, &addresses, &pool, &synced, &deliveries,
+            &SyncClock::default(), &HashSet::from([ContentKind::Image]), DEFAULT_MAX_CLIP_BYTES, &None, &tx,
+        ).await;
+        assert!(rx.try_recv().is_err());
+        assert!(synced.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_offline_device_gets_latest_change_on_reconnect() {
+        let mut harness = spawn_forwarder(ContentKind::ALL.into_iter().collect(), true, Duration::ZERO);
+        let first = ClipboardContent::Text("first".to_string());
+        let second = ClipboardContent::Text("second".to_string());
+
+        // The peer hasn't been discovered, so both changes are held
+        *harness.clipboard.lock().unwrap() = Some(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        *harness.clipboard.lock().unwrap() = Some(second.clone());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(harness.events.try_recv().is_err());
+        assert!(harness.synced.read().await.is_empty());
+
+        let missed = harness.outbox.write().await.reconnect(harness.peer_id).unwrap();
+        assert_eq!(missed.hash, second.hash());
+        let (tx, mut rx) = event_channel(8);
+        deliver_to(
+            harness.peer_id, &missed, Uuid::new_v4(), "", &harness.paired, &harness.addresses, &harness.pool,
+            &harness.synced, &harness.deliveries, &SyncClock::default(), &None, &tx,
+        ).await;
+        assert!(matches!(rx.try_recv(), Ok(ServiceEvent::ClipboardSent { to_devices }) if to_devices == vec![harness.peer_id]));
+        assert_eq!(harness.synced.read().await.get(&harness.peer_id).map(|c| c.hash()), Some(second.hash()));
+        assert!(harness.outbox.write().await.reconnect(harness.peer_id).is_none());
+        // Acks come back through the pool's replies
+        let event = tokio::time::timeout(Duration::from_secs(1), harness.events.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::DeliveryConfirmed { device_id, .. }) if device_id == harness.peer_id));
+
+        // Once online, changes go straight out
+        *harness.clipboard.lock().unwrap() = Some(ClipboardContent::Text("third".to_string()));
+        let event = tokio::time::timeout(Duration::from_secs(1), harness.events.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::ClipboardSent { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_device_reports_error() {
+        let mut harness = spawn_forwarder(ContentKind::ALL.into_iter().collect(), false, Duration::ZERO);
+        harness.addresses.write().await.clear();
+
+        *harness.clipboard.lock().unwrap() = Some(ClipboardContent::Text("nowhere to go".to_string()));
+        let event = tokio::time::timeout(Duration::from_secs(1), harness.events.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::Error(e)) if e.contains("isn't on the network")));
+        assert!(harness.synced.read().await.is_empty());
+        assert_eq!(harness.deliveries.read().await.stats.messages_sent, 0);
+
+        // A listener that went away counts as not sent too
+        let mut gone = spawn_peer(harness.peer_id);
+        gone.port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        harness.addresses.write().await.insert(harness.peer_id, gone);
+        *harness.clipboard.lock().unwrap() = Some(ClipboardContent::Text("still nowhere".to_string()));
+        let event = tokio::time::timeout(Duration::from_secs(5), harness.events.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::Error(_))));
+        assert!(harness.synced.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unacked_clipboard_is_sent_again() {
+        let harness = spawn_forwarder(ContentKind::ALL.into_iter().collect(), false, Duration::ZERO);
+        let Harness { clipboard, events: mut rx, peer_id, paired, addresses, pool, synced, deliveries, .. } = harness;
+
+        // The first connection is lost along with the message on it
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        addresses.write().await.get_mut(&peer_id).unwrap().port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut lost, _) = listener.accept().await.unwrap();
+            let _ = sync::read_framed_message(&mut lost).await;
+            drop(lost);
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    while let Ok(payload) = sync::read_framed_message(&mut stream).await {
+                        let Ok(Message::ClipboardSync(m)) = Message::from_bytes(&payload) else {
+                            return;
+                        };
+                        let ack = Message::Ack { message_id: m.message_id }.to_bytes().unwrap();
+                        let _ = sync::write_framed_message(&mut stream, &ack).await;
+                    }
+                });
+            }
+        });
+
+        *clipboard.lock().unwrap() = Some(ClipboardContent::Text("copied".to_string()));
+        let event = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::ClipboardSent { .. })));
+        assert!(tokio::time::timeout(Duration::from_millis(200), rx.recv()).await.is_err());
+        assert!(deliveries.read().await.pending.contains_key(&peer_id));
+
+        let (tx, mut retransmitted) = event_channel(8);
+        let clock = SyncClock::default();
+        retransmit_unacked(
+            Duration::ZERO, Uuid::new_v4(), "", &paired, &addresses, &pool, &synced, &deliveries, &clock, &None, &tx,
+        ).await;
+        assert!(matches!(retransmitted.try_recv(), Ok(ServiceEvent::ClipboardSent { .. })));
+        let event = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::DeliveryConfirmed { device_id, .. }) if device_id == peer_id));
+        assert!(deliveries.read().await.pending.is_empty());
+
+        // Something never acknowledged is given up on eventually
+        let change = ClipboardChange {
+            content: ClipboardContent::Text("lost".to_string()),
+            hash: ClipboardContent::Text("lost".to_string()).hash(),
+            selection: ClipboardSelection::Clipboard,
+        };
+        deliveries.write().await.pending.insert(peer_id, PendingDelivery {
+            message_id: Uuid::new_v4(),
+            change,
+            sent_at: Instant::now(),
+            retransmits: MAX_RETRANSMITS,
+        });
+        retransmit_unacked(
+            Duration::ZERO, Uuid::new_v4(), "", &paired, &addresses, &pool, &synced, &deliveries, &clock, &None, &tx,
+        ).await;
+        assert!(matches!(retransmitted.try_recv(), Ok(ServiceEvent::Error(_))));
+        assert!(deliveries.read().await.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rapid_changes_send_only_the_last() {
+        let debounce = Duration::from_millis(150);
+        let mut harness = spawn_forwarder(ContentKind::ALL.into_iter().collect(), false, debounce);
+        let last = ClipboardContent::Text("refined selection".to_string());
+
+        for text in ["sel", "selection"] {
+            *harness.clipboard.lock().unwrap() = Some(ClipboardContent::Text(text.to_string()));
+            tokio::time::sleep(Duration::from_millis(40)).await;
+        }
+        *harness.clipboard.lock().unwrap() = Some(last.clone());
+
+        let event = tokio::time::timeout(Duration::from_secs(1), harness.events.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::ClipboardSent { .. })));
+        assert_eq!(harness.synced.read().await.get(&harness.peer_id).map(|c| c.hash()), Some(last.hash()));
+        let event = tokio::time::timeout(Duration::from_secs(1), harness.events.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::DeliveryConfirmed { .. })));
+
+        tokio::time::sleep(debounce * 2).await;
+        assert!(harness.events.try_recv().is_err());
+        assert_eq!(harness.deliveries.read().await.stats.messages_sent, 1);
+    }
+
+    #[test]
+    fn test_outbox_drops_stale_content() {
+        let mut outbox = Outbox::new(true);
+        let device_id = Uuid::new_v4();
+        let content = ClipboardContent::Text("old".to_string());
+        assert!(outbox.hold(device_id, &ClipboardChange { hash: content.hash(), content, selection: ClipboardSelection::Clipboard }));
+
+        // Instants can't predate boot, so skip where uptime is too short
+        let Some(stale) = Instant::now().checked_sub(Duration::from_secs(OUTBOX_TTL_SECS + 1)) else {
+            return;
+        };
+        outbox.queued.get_mut(&device_id).unwrap().1 = stale;
+        assert!(outbox.reconnect(device_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_write_blocked_devices() {
+        let configured = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let service = OmniclipService::with_config("desk".to_string(), Config {
+            block_writes_from: HashSet::from([configured]),
+            ..Config::default()
+        });
+        assert!(service.is_write_blocked(configured).await);
+        assert!(!service.is_write_blocked(other).await);
+
+        service.set_write_blocked(other, true).await;
+        service.set_write_blocked(configured, false).await;
+        assert!(service.is_write_blocked(other).await);
+        assert!(!service.is_write_blocked(configured).await);
+    }
+
+    #[tokio::test]
+    async fn test_bounced_content_is_deduped() {
+        // A sends X to B, B relays X back to A
+        let mut a = spawn_forwarder(ContentKind::ALL.into_iter().collect(), false, Duration::ZERO);
+        let mut b = RecentContent::new(Duration::from_secs(DEDUP_WINDOW_SECS));
+        let x = ClipboardContent::Text("bounced".to_string());
+
+        *a.clipboard.lock().unwrap() = Some(x.clone());
+        let event = tokio::time::timeout(Duration::from_secs(1), a.events.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::ClipboardSent { .. })));
+
+        // B records it once, A drops it on the way back
+        assert!(!b.received(x.hash()));
+        assert!(a.recent.write().await.received(x.hash()));
+
+        // New content and content outside the window are not duplicates
+        let y = ClipboardContent::Text("new".to_string());
+        assert!(!a.recent.write().await.received(y.hash()));
+        let mut disabled = RecentContent::new(Duration::ZERO);
+        disabled.sent(y.hash());
+        assert!(!disabled.received(y.hash()));
+    }
+
+    #[tokio::test]
+    async fn test_copy_does_not_bounce_between_devices() {
+        /// Deliver content to a device as its sync server would
+        async fn relay(content: &ClipboardContent, to: &Harness) {
+            if !to.recent.write().await.received(content.hash()) {
+                let sink = clipboard::SystemClipboardSink::new(to.writer.clone());
+                write_received(&sink, &to.last_sent, &Normalization::default(), content).await.unwrap();
+            }
+        }
+
+        let all: HashSet<ContentKind> = ContentKind::ALL.into_iter().collect();
+        let mut a = spawn_forwarder(all.clone(), false, Duration::ZERO);
+        let mut b = spawn_forwarder(all, false, Duration::ZERO);
+        let (x, y) = (ClipboardContent::Text("first".to_string()), ClipboardContent::Text("second".to_string()));
+
+        for content in [&x, &y] {
+            *a.clipboard.lock().unwrap() = Some(content.clone());
+            let event = tokio::time::timeout(Duration::from_secs(1), a.events.recv()).await.unwrap();
+            assert!(matches!(event, Some(ServiceEvent::ClipboardSent { .. })));
+            let event = tokio::time::timeout(Duration::from_secs(1), a.events.recv()).await.unwrap();
+            assert!(matches!(event, Some(ServiceEvent::DeliveryConfirmed { .. })));
+            relay(content, &b).await;
+        }
+
+        // A clipboard manager on B restores the older copy; B mustn't send
+        // it back now that something else was received after it
+        *b.clipboard.lock().unwrap() = Some(x.clone());
+        let event = tokio::time::timeout(Duration::from_secs(1), b.events.recv()).await.unwrap();
+        assert!(matches!(event, Some(ServiceEvent::SyncSuppressed { hash }) if hash == x.hash()));
+
+        // Had it gone out anyway, A drops it rather than writing it again
+        assert!(a.recent.write().await.received(x.hash()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(a.events.try_recv().is_err());
+        assert!(b.events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_full_event_channel_reports_backpressure() {
+        let (tx, mut rx) = event_channel(1);
+
+        tx.send(ServiceEvent::DeviceLost(Uuid::nil())).await.unwrap();
+        let blocked = tokio::spawn({
+            let tx = tx.clone();
+            async move { tx.send(ServiceEvent::DeviceUnpaired(Uuid::nil())).await.unwrap() }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!blocked.is_finished());
+
+        // The stall is reported just ahead of the event that waited
+        assert!(matches!(rx.recv().await, Some(ServiceEvent::DeviceLost(_))));
+        assert!(matches!(rx.recv().await, Some(ServiceEvent::Backpressure { .. })));
+        assert!(matches!(rx.recv().await, Some(ServiceEvent::DeviceUnpaired(_))));
+        blocked.await.unwrap();
+
+        // Events that can't wait are coalesced to the newest
+        tx.send(ServiceEvent::DeviceLost(Uuid::nil())).await.unwrap();
+        tx.try_send(ServiceEvent::PauseChanged { paused: true });
+        tx.try_send(ServiceEvent::PauseChanged { paused: false });
+        assert!(matches!(rx.recv().await, Some(ServiceEvent::DeviceLost(_))));
+        assert!(matches!(rx.recv().await, Some(ServiceEvent::Backpressure { .. })));
+        assert!(matches!(rx.recv().await, Some(ServiceEvent::PauseChanged { paused: false })));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_recent_content_is_bounded() {
+        let mut recent = RecentContent::new(Duration::from_secs(DEDUP_WINDOW_SECS));
+        let hashes: Vec<ContentHash> = (0..=MAX_RECENT_HASHES)
+            .map(|i| ClipboardContent::Text(i.to_string()).hash())
+            .collect();
+        for hash in &hashes {
+            recent.sent(*hash);
+        }
+        assert!(!recent.contains(hashes[0]));
+        assert!(hashes[1..].iter().all(|hash| recent.contains(*hash)));
+    }
+
+    #[test]
+    fn test_replayed_and_stale_messages_rejected() {
+        let mut guard = ReplayGuard::new(Duration::from_secs(REPLAY_WINDOW_SECS));
+        let (device, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let message_id = Uuid::new_v4();
+        let now = 1_700_000_000;
+
+        assert!(guard.accept(device, message_id, now, now));
+        assert!(!guard.accept(device, message_id, now, now + 1));
+        // Ids are per device
+        assert!(guard.accept(other, message_id, now, now));
+
+        // Outside the window either way
+        assert!(!guard.accept(device, Uuid::new_v4(), now - REPLAY_WINDOW_SECS - 1, now));
+        assert!(!guard.accept(device, Uuid::new_v4(), now + REPLAY_WINDOW_SECS + 1, now));
+        assert!(guard.accept(device, Uuid::new_v4(), now - REPLAY_WINDOW_SECS, now));
+
+        // Ids that aged out of the window are forgotten, and each device's
+        // list stays bounded
+        let later = now + REPLAY_WINDOW_SECS + 1;
+        for _ in 0..MAX_SEEN_MESSAGE_IDS + 10 {
+            assert!(guard.accept(device, Uuid::new_v4(), later, later));
+        }
+        assert_eq!(guard.seen[&device].len(), MAX_SEEN_MESSAGE_IDS);
+
+        let mut disabled = ReplayGuard::new(Duration::ZERO);
+        assert!(disabled.accept(device, message_id, 0, now));
+        assert!(disabled.accept(device, message_id, 0, now));
+    }
+
+    #[test]
+    fn test_describe_kinds() {
+        assert_eq!(describe_kinds(&ContentKind::ALL.into_iter().collect()), "text, rich text, images, files, other formats");
+        assert_eq!(describe_kinds(&HashSet::from([ContentKind::RichText, ContentKind::Text])), "text, rich text");
+        assert_eq!(describe_kinds(&HashSet::new()), "no content types");
+    }
+
+    #[tokio::test]
+    async fn test_ack_confirms_delivery() {
+        let device_id = Uuid::new_v4();
+        let message_id = Uuid::new_v4();
+        let deliveries = RwLock::new(DeliveryTracker::default());
+        deliveries.write().await.pending.insert(device_id, PendingDelivery {
+            message_id,
+            change: ClipboardChange {
+                content: ClipboardContent::Text("sent".to_string()),
+                hash: ClipboardContent::Text("sent".to_string()).hash(),
+                selection: ClipboardSelection::Clipboard,
+            },
+            sent_at: Instant::now(),
+            retransmits: 0,
+        });
+        let (tx, mut rx) = event_channel(8);
+
+        // Acks for messages we aren't waiting on are ignored
+        confirm_delivery(&deliveries, &tx, Uuid::new_v4()).await;
+        assert!(rx.try_recv().is_err());
+
+        confirm_delivery(&deliveries, &tx, message_id).await;
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServiceEvent::DeliveryConfirmed { device_id: d, message_id: m }) if d == device_id && m == message_id
+        ));
+        assert!(deliveries.read().await.pending.is_empty());
+        assert_eq!(deliveries.read().await.stats.messages_acked, 1);
+
+        // A repeated ack is only counted once
+        confirm_delivery(&deliveries, &tx, message_id).await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(deliveries.read().await.stats.messages_acked, 1);
+    }
+
+    #[tokio::test]
+    async fn test_preshared_pairing() {
+        let a = OmniclipService::new("a".to_string());
+        let b = OmniclipService::new("b".to_string());
+        let key = crate::crypto::decode_preshared_key(&crate::crypto::generate_preshared_key()).unwrap();
+
+        a.add_preshared_pairing(b.device_id(), "b".to_string(), b.identity.signing_key.verifying_key(), &key).await.unwrap();
+        b.add_preshared_pairing(a.device_id(), "a".to_string(), a.identity.signing_key.verifying_key(), &key).await.unwrap();
+        let paired = a.get_paired_devices().await;
+        assert_eq!(paired.len(), 1);
+        assert_eq!((paired[0].device_id, paired[0].device_name.as_str()), (b.device_id(), "b"));
+        assert!(!paired[0].connected);
+
+        // What A sends, B can read
+        let content = ClipboardContent::Text("out of band".to_string());
+        let Message::ClipboardSync(msg) = build_sync_message(
+            a.device_id(), "", &a.paired_devices.read().await[&b.device_id()].keys, Compression::None, &content,
+            content.hash(), None, 0,
+        ).unwrap() else {
+            panic!("expected a full sync message");
+        };
+        let plain = b.paired_devices.read().await[&a.device_id()].keys.current()
+            .decrypt_with_aad(&msg.encrypted_content, &msg.aad())
+            .unwrap();
+        assert_eq!(ClipboardContent::from_bytes(&plain).unwrap().hash(), content.hash());
+
+        let err = a.add_preshared_pairing(a.device_id(), "a".to_string(), a.identity.signing_key.verifying_key(), &key).await;
+        assert!(matches!(err, Err(Error::InvalidMessage(m)) if m == "cannot pair with self"));
+        assert!(crate::crypto::decode_preshared_key("c2hvcnQ=").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_paired_devices_sorted_by_name() {
+        let service = OmniclipService::new("me".to_string());
+        for name in ["desk", "laptop", "desk"] {
+            service.add_preshared_pairing(Uuid::new_v4(), name.to_string(), SigningKey::generate().verifying_key(), &[1u8; 32])
+                .await
+                .unwrap();
+        }
+
+        let paired = service.get_paired_devices().await;
+        let names: Vec<_> = paired.iter().map(|d| d.device_name.as_str()).collect();
+        assert_eq!(names, ["desk", "desk", "laptop"]);
+        assert!(paired[0].device_id < paired[1].device_id);
+    }
+
+    #[test]
+    fn test_content_checked_against_its_hash() {
+        let content = ClipboardContent::Text("joined from chunks".to_string());
+        assert!(verify_hash(content.clone(), content.hash()).is_ok());
+        let other = ClipboardContent::Text("something else".to_string()).hash();
+        assert!(matches!(verify_hash(content, other), Err(Error::InvalidMessage(_))));
+    }
+
+    #[test]
+    fn test_large_content_compressed_before_encryption() {
+        let keys = SessionKeyRing::new(SessionKey::from_bytes(&[4u8; 32]));
+        let log = ClipboardContent::Text("GET /health 200 1ms\n".repeat(5000));
+        let Message::ClipboardSync(msg) = build_sync_message(
+            Uuid::new_v4(), "", &keys, Compression::Deflate, &log, log.hash(), None, 0,
+        ).unwrap() else {
+            panic!("expected a full sync message");
+        };
+        assert!(msg.compressed);
+        assert!(msg.encrypted_content.ciphertext.len() < log.size() / 10);
+        assert_eq!(msg.content_hash, log.hash());
+        let plain = keys.current().decrypt_with_aad(&msg.encrypted_content, &msg.aad()).unwrap();
+        assert_eq!(open_content(plain, msg.compressed).unwrap().hash(), log.hash());
+
+        let short = ClipboardContent::Text("short".to_string());
+        let Message::ClipboardSync(msg) = build_sync_message(
+            Uuid::new_v4(), "", &keys, Compression::Deflate, &short, short.hash(), None, 0,
+        ).unwrap() else {
+            panic!("expected a full sync message");
+        };
+        assert!(!msg.compressed);
+    }
+
+    #[test]
+    fn test_ciphertext_bound_to_its_message() {
+        let keys = SessionKeyRing::new(SessionKey::from_bytes(&[2u8; 32]));
+        let sender = Uuid::new_v4();
+        let content = ClipboardContent::Text("replay me".to_string());
+        let Message::ClipboardSync(msg) = build_sync_message(
+            sender, "", &keys, Compression::None, &content, content.hash(), None, 100,
+        ).unwrap() else {
+            panic!("expected a full sync message");
+        };
+        assert!(keys.current().decrypt_with_aad(&msg.encrypted_content, &msg.aad()).is_ok());
+
+        // The same ciphertext lifted into another message context
+        let moved = [
+            ClipboardSyncMessage { timestamp: 200, ..msg.clone() },
+            ClipboardSyncMessage { message_id: Uuid::new_v4(), ..msg.clone() },
+            ClipboardSyncMessage { sender_id: Uuid::new_v4(), ..msg.clone() },
+        ];
+        for replayed in moved {
+            let err = keys.current().decrypt_with_aad(&replayed.encrypted_content, &replayed.aad()).unwrap_err();
+            assert!(matches!(err, Error::Crypto(_)), "{}", err);
+        }
+
+        let base = ClipboardContent::Text("line of text\n".repeat(DELTA_MIN_SIZE));
+        let edited = ClipboardContent::Text(format!("{}one more line\n", "line of text\n".repeat(DELTA_MIN_SIZE)));
+        let Message::ClipboardDelta(delta) = build_sync_message(
+            sender, "", &keys, Compression::None, &edited, edited.hash(), Some(&base), 100,
+        ).unwrap() else {
+            panic!("expected a delta");
+        };
+        assert_eq!(apply_delta(keys.current(), &base, &delta).unwrap().hash(), edited.hash());
+        let replayed = ClipboardDeltaMessage { timestamp: 200, ..delta };
+        assert!(matches!(apply_delta(keys.current(), &base, &replayed), Err(Error::Crypto(_))));
+    }
+
+    #[test]
+    fn test_cross_channel_content_not_accepted() {
+        let keys = SessionKeyRing::new(SessionKey::from_bytes(&[1u8; 32]));
+        let content = ClipboardContent::Text("meeting notes".to_string());
+        let Message::ClipboardSync(msg) = build_sync_message(
+            Uuid::new_v4(), "work", &keys, Compression::None, &content, content.hash(), None, 0,
+        ).unwrap() else {
+            panic!("expected a full sync message");
+        };
+        assert_eq!(msg.channel, "work");
+
+        let personal = HashSet::from(["".to_string()]);
+        let both = HashSet::from(["".to_string(), "work".to_string()]);
+        assert!(!in_channels(&personal, &msg.channel, Uuid::new_v4()));
+        assert!(in_channels(&both, &msg.channel, Uuid::new_v4()));
+        assert!(in_channels(&Config::default().channels, "", Uuid::new_v4()));
+    }
+
+    #[test]
+    fn test_old_epoch_decrypts_after_rekey() {
+        let a_id = Uuid::new_v4();
+        let mut a_keys = SessionKeyRing::new(SessionKey::from_bytes(&[1u8; 32]));
+        let mut b_keys = a_keys.clone();
+        let content = ClipboardContent::Text("sent before the rekey".to_string());
+
+        // A sends while B is offline; the message is still in flight when
+        // both sides move to a new key
+        let Message::ClipboardSync(old) = build_sync_message(a_id, "", &a_keys, Compression::None, &content, content.hash(), None, 0).unwrap() else {
+            panic!("expected a full sync message");
+        };
+        assert_eq!(old.key_epoch, 0);
+        a_keys.rekey(SessionKey::from_bytes(&[2u8; 32]));
+        b_keys.rekey(SessionKey::from_bytes(&[2u8; 32]));
+
+        // B reconnects: the old epoch is still within the grace window
+        let plain = epoch_key(&b_keys, old.key_epoch)
+            .and_then(|key| key.decrypt_with_aad(&old.encrypted_content, &old.aad()))
+            .unwrap();
+        assert_eq!(ClipboardContent::from_bytes(&plain).unwrap().hash(), content.hash());
+
+        let Message::ClipboardSync(new) = build_sync_message(a_id, "", &a_keys, Compression::None, &content, content.hash(), None, 0).unwrap() else {
+            panic!("expected a full sync message");
+        };
+        assert_eq!(new.key_epoch, 1);
+        assert!(epoch_key(&b_keys, new.key_epoch).unwrap().decrypt_with_aad(&new.encrypted_content, &new.aad()).is_ok());
+
+        // Once the epoch ages out the message is rejected
+        b_keys.rekey(SessionKey::from_bytes(&[3u8; 32]));
+        assert!(matches!(epoch_key(&b_keys, old.key_epoch), Err(Error::InvalidMessage(m)) if m.contains("we are on epoch 2")));
+    }
+
+    #[tokio::test]
+    async fn test_events_forwarded_to_callback() {
+        let (tx, events) = event_channel(8);
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = received.clone();
+        let task = forward_to_callback(events, move |event| sink.lock().unwrap().push(event));
+
+        let device = Uuid::new_v4();
+        tx.send(ServiceEvent::DeviceLost(device)).await.unwrap();
+        tx.send(ServiceEvent::DeviceUnpaired(device)).await.unwrap();
+        drop(tx);
+        task.await.unwrap();
+
+        let received = received.lock().unwrap();
+        assert!(matches!(
+            received[..],
+            [ServiceEvent::DeviceLost(a), ServiceEvent::DeviceUnpaired(b)] if a == device && b == device
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rekey_device_reports_new_epoch() {
+        let mut service = OmniclipService::new("a".to_string());
+        let peer = Uuid::new_v4();
+        service.add_preshared_pairing(peer, "b".to_string(), SigningKey::generate().verifying_key(), &[1u8; 32])
+            .await
+            .unwrap();
+        assert_eq!(service.key_epoch(peer).await, Some(KeyEpoch(0)));
+
+        let (tx, mut events) = event_channel(8);
+        service.events = Some(tx);
+        assert_eq!(service.rekey_device(peer, &[2u8; 32]).await.unwrap(), KeyEpoch(1));
+        assert_eq!(service.key_epoch(peer).await, Some(KeyEpoch(1)));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(ServiceEvent::Rekeyed { device_id, epoch: KeyEpoch(1) }) if device_id == peer
+        ));
+        let sealed = SessionKey::from_bytes(&[2u8; 32]).encrypt(b"rekeyed").unwrap();
+        assert_eq!(service.server_devices.read().await[&peer].session_key.decrypt(&sealed).unwrap(), b"rekeyed");
+
+        assert!(service.rekey_device(Uuid::new_v4(), &[3u8; 32]).await.is_err());
+        assert_eq!(service.key_epoch(Uuid::new_v4()).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_list_and_cancel_pairing_sessions() {
+        let service = OmniclipService::new("desk".to_string());
+        service.start_pairing().await.unwrap();
+        service.start_pairing_with_hint("phone").await.unwrap();
+
+        let sessions = service.active_pairing_sessions().await;
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[1].device_name_hint.as_deref(), Some("phone"));
+
+        assert!(service.cancel_pairing(sessions[0].session_id).await);
+        assert!(!service.cancel_pairing(sessions[0].session_id).await);
+        let remaining = service.active_pairing_sessions().await;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].session_id, sessions[1].session_id);
+    }
+
+    #[tokio::test]
+    async fn test_pairing_url_follows_address_change() {
+        let desk = DeviceIdentity::new("desk".to_string());
+        let sessions = RwLock::new(HashMap::new());
+        let mut last_ip = "192.168.1.20".to_string();
+
+        // Nothing open: the new address is noted but there's no URL to show
+        assert!(refresh_pairing_url(&sessions, &mut last_ip, "192.168.1.21".to_string(), 17394, &desk).await.is_none());
+        assert_eq!(last_ip, "192.168.1.21");
+
+        let session = PairingSession::new();
+        let session_id = session.session_id;
+        sessions.write().await.insert(session_id, session);
+        assert!(refresh_pairing_url(&sessions, &mut last_ip, "192.168.1.21".to_string(), 17394, &desk).await.is_none());
+
+        let url = refresh_pairing_url(&sessions, &mut last_ip, "10.0.0.7".to_string(), 17394, &desk).await.unwrap();
+        let qr = PairingQrData::from_url(&url).unwrap();
+        assert_eq!(qr.ip, "10.0.0.7");
+        assert_eq!(qr.session_id, session_id);
+    }
+
+    #[tokio::test]
+    async fn test_paired_devices_survive_restart() {
+        let dir = std::env::temp_dir().join(format!("omniclip-paired-{}", Uuid::new_v4()));
+        let state = Some(Arc::new(StateFiles::new(Arc::new(StateStore::open(&dir).unwrap()))));
+
+        let device_id = Uuid::new_v4();
+        let mut keys = SessionKeyRing::new(SessionKey::from_bytes(&[7u8; 32]));
+        keys.rekey(SessionKey::from_bytes(&[8u8; 32]));
+        let paired = RwLock::new(HashMap::from([(device_id, PairedDeviceInfo {
+            device_id,
+            device_name: "phone".to_string(),
+            identity_pubkey: SigningKey::generate().verifying_key(),
+            keys,
+            features: NegotiatedFeatures::baseline(),
+            direction: SyncDirection::default(),
+            last_seen: std::time::Instant::now(),
+        })]));
+        save_paired(&state, &paired).await;
+        state.as_ref().unwrap().flush().await.unwrap();
+
+        // A fresh store over the same directory, as after a restart
+        let loaded = load_paired(&StateStore::open(&dir).unwrap()).unwrap();
+        let device = &loaded[&device_id];
+        assert_eq!(device.device_name, "phone");
+        assert_eq!(device.keys.current_epoch(), KeyEpoch(1));
+        let encrypted = SessionKey::from_bytes(&[8u8; 32]).encrypt(b"still paired").unwrap();
+        assert_eq!(device.keys.current().decrypt(&encrypted).unwrap(), b"still paired");
+
+        paired.write().await.clear();
+        save_paired(&state, &paired).await;
+        state.as_ref().unwrap().flush().await.unwrap();
+        assert!(load_paired(&StateStore::open(&dir).unwrap()).unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pairing_url_uses_bound_port() {
+        let dir = std::env::temp_dir().join(format!("omniclip-port-{}", Uuid::new_v4()));
+        let mut service = OmniclipService::with_config("desk".to_string(), Config {
+            data_dir: dir.clone(),
+            port: 0,
+            ..Config::default()
+        });
+        let _events = service.start().await.unwrap();
+        let port = service.port().unwrap();
+        assert_ne!(port, 0);
+
+        let url = service.start_pairing().await.unwrap();
+        assert_eq!(PairingQrData::from_url(&url).unwrap().port, port);
+        assert_eq!(service.pairing_qr_data().await.unwrap().port, port);
+
+        service.shutdown().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_state_saved_in_given_store() {
+        let dir = std::env::temp_dir().join(format!("omniclip-sealed-{}", Uuid::new_v4()));
+        let config = || Config { data_dir: dir.clone(), ..Config::default() };
+        let phone = Uuid::new_v4();
+
+        let store = StateStore::open_encrypted(&dir, "correct horse").unwrap();
+        let mut first = OmniclipService::with_config("me".to_string(), config()).with_state_store(store);
+        first.load_state().await.unwrap();
+        first.add_preshared_pairing(phone, "phone".to_string(), SigningKey::generate().verifying_key(), &[3u8; 32])
+            .await
+            .unwrap();
+        first.shutdown().await.unwrap();
+
+        // Sealed, so the plain store can't read it
+        let mut plain = OmniclipService::with_config("me".to_string(), config());
+        assert!(matches!(plain.load_state().await, Err(Error::Config(_))));
+
+        let store = StateStore::open_encrypted(&dir, "correct horse").unwrap();
+        let mut second = OmniclipService::with_config("me".to_string(), config()).with_state_store(store);
+        second.load_state().await.unwrap();
+        assert!(second.paired_devices.read().await.contains_key(&phone));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ping_device_needs_the_session_key() {
+        let dir = std::env::temp_dir().join(format!("omniclip-probe-{}", Uuid::new_v4()));
+        let service = |name: &str| {
+            let config = Config { data_dir: dir.join(name), port: 0, ..Config::default() };
+            OmniclipService::with_config(name.to_string(), config)
+                .with_clipboard_backend(MemoryClipboard(Arc::new(Mutex::new(None))))
+        };
+        let (mut desk, mut phone) = (service("desk"), service("phone"));
+        let (desk_id, phone_id) = (desk.device_id(), phone.device_id());
+        let desk_key = desk.identity.signing_key.verifying_key();
+        let phone_key = phone.identity.signing_key.verifying_key();
+        desk.add_preshared_pairing(phone_id, "phone".to_string(), phone_key, &[5u8; 32]).await.unwrap();
+        phone.add_preshared_pairing(desk_id, "desk".to_string(), desk_key, &[5u8; 32]).await.unwrap();
+        let _desk_events = desk.start().await.unwrap();
+        let _phone_events = phone.start().await.unwrap();
+
+        // Not found on the network yet
+        assert!(matches!(desk.ping_device(phone_id, Duration::from_secs(2)).await, Err(Error::Network(_))));
+
+        desk.peer_addresses.write().await.insert(phone_id, PeerInfo {
+            device_id: phone_id,
+            device_name: "phone".to_string(),
+            fingerprint: String::new(),
+            addresses: vec![IpAddr::from([127, 0, 0, 1])],
+            port: phone.port().unwrap(),
+            accepting_pairing: false,
+            protocol_version: None,
+            last_seen: Instant::now(),
+        });
+        desk.ping_device(phone_id, Duration::from_secs(2)).await.unwrap();
+
+        // Anyone can answer a plain ping, but not a probe under a key they lack
+        desk.rekey_device(phone_id, &[6u8; 32]).await.unwrap();
+        assert!(desk.ping_device(phone_id, Duration::from_secs(2)).await.is_err());
+
+        // Answered once the phone is rekeyed too, and still while it has
+        // moved on and only retains the desk's key
+        phone.rekey_device(desk_id, &[6u8; 32]).await.unwrap();
+        desk.ping_device(phone_id, Duration::from_secs(2)).await.unwrap();
+        phone.rekey_device(desk_id, &[7u8; 32]).await.unwrap();
+        desk.ping_device(phone_id, Duration::from_secs(2)).await.unwrap();
+
+        desk.shutdown().await.unwrap();
+        phone.shutdown().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A started device on this machine, with a clipboard the test can set
+    async fn started(dir: &std::path::Path, name: &str, conflict_policy: ConflictPolicy)
+        -> (OmniclipService, mpsc::Receiver<ServiceEvent>, Arc<Mutex<Option<ClipboardContent>>>)
+    {
+        let clipboard = Arc::new(Mutex::new(None));
+        let config = Config { data_dir: dir.join(name), port: 0, conflict_policy, ..Config::default() };
+        let mut service = OmniclipService::with_config(name.to_string(), config)
+            .with_clipboard_backend(MemoryClipboard(clipboard.clone()));
+        let events = service.start().await.unwrap();
+        (service, events, clipboard)
+    }
+
+    /// Let `service` reach `peer` on loopback without waiting for discovery
+    async fn reach(service: &OmniclipService, peer: &OmniclipService) {
+        service.peer_addresses.write().await.insert(peer.device_id(), PeerInfo {
+            device_id: peer.device_id(),
+            device_name: peer.identity.name.clone(),
+            fingerprint: String::new(),
+            addresses: vec![IpAddr::from([127, 0, 0, 1])],
+            port: peer.port().unwrap(),
+            accepting_pairing: false,
+            protocol_version: None,
+            last_seen: Instant::now(),
+        });
+    }
+
+    /// The next event on `events` that `wanted` picks out
+    async fn next_event<T>(events: &mut mpsc::Receiver<ServiceEvent>, wanted: impl Fn(ServiceEvent) -> Option<T>) -> T {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(found) = wanted(events.recv().await.unwrap()) {
+                    return found;
+                }
+            }
+        }).await.unwrap()
+    }
+
+    /// The next clipboard content `events` reports as received
+    async fn next_received(events: &mut mpsc::Receiver<ServiceEvent>) -> (Uuid, ClipboardContent) {
+        next_event(events, |event| match event {
+            ServiceEvent::ClipboardReceived { from_device, content } => Some((from_device, content)),
+            _ => None,
+        }).await
+    }
+
+    #[tokio::test]
+    async fn test_pairing_after_start_syncs_both_ways() {
+        let dir = std::env::temp_dir().join(format!("omniclip-runtime-pair-{}", Uuid::new_v4()));
+        let (desk, mut desk_events, desk_clipboard) = started(&dir, "desk", ConflictPolicy::default()).await;
+        // The desk copies right after the phone, which would otherwise conflict
+        let prefer_desk = ConflictPolicy::PreferDevice(desk.device_id());
+        let (phone, mut phone_events, phone_clipboard) = started(&dir, "phone", prefer_desk).await;
+        reach(&desk, &phone).await;
+        reach(&phone, &desk).await;
+
+        let mut qr = PairingQrData::from_url(&desk.start_pairing().await.unwrap()).unwrap();
+        qr.ip = "127.0.0.1".to_string();
+        phone.pair_with_url(&qr.to_url()).await.unwrap();
+
+        *phone_clipboard.lock().unwrap() = Some(ClipboardContent::Text("from phone".to_string()));
+        let (from, content) = next_received(&mut desk_events).await;
+        assert_eq!(from, phone.device_id());
+        assert_eq!(content.hash(), ClipboardContent::Text("from phone".to_string()).hash());
+
+        *desk_clipboard.lock().unwrap() = Some(ClipboardContent::Text("from desk".to_string()));
+        let (from, content) = next_received(&mut phone_events).await;
+        assert_eq!(from, desk.device_id());
+        assert_eq!(content.hash(), ClipboardContent::Text("from desk".to_string()).hash());
+
+        desk.shutdown().await.unwrap();
+        phone.shutdown().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_preshared_pairing_after_start_is_accepted() {
+        let dir = std::env::temp_dir().join(format!("omniclip-runtime-preshared-{}", Uuid::new_v4()));
+        let (desk, mut desk_events, _) = started(&dir, "desk", ConflictPolicy::default()).await;
+        let (phone, _phone_events, phone_clipboard) = started(&dir, "phone", ConflictPolicy::default()).await;
+        reach(&phone, &desk).await;
+
+        let desk_key = desk.identity.signing_key.verifying_key();
+        let phone_key = phone.identity.signing_key.verifying_key();
+        desk.add_preshared_pairing(phone.device_id(), "phone".to_string(), phone_key, &[4u8; 32]).await.unwrap();
+        phone.add_preshared_pairing(desk.device_id(), "desk".to_string(), desk_key, &[4u8; 32]).await.unwrap();
+
+        *phone_clipboard.lock().unwrap() = Some(ClipboardContent::Text("pre-shared".to_string()));
+        let (from, content) = next_received(&mut desk_events).await;
+        assert_eq!(from, phone.device_id());
+        assert_eq!(content.hash(), ClipboardContent::Text("pre-shared".to_string()).hash());
+
+        desk.shutdown().await.unwrap();
+        phone.shutdown().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dropped_sync_is_still_acknowledged() {
+        let dir = std::env::temp_dir().join(format!("omniclip-dropped-ack-{}", Uuid::new_v4()));
+        let (desk, mut desk_events, desk_clipboard) = started(&dir, "desk", ConflictPolicy::default()).await;
+        let (phone, _phone_events, phone_clipboard) = started(&dir, "phone", ConflictPolicy::default()).await;
+        reach(&desk, &phone).await;
+        let (desk_id, phone_id) = (desk.device_id(), phone.device_id());
+        let desk_key = desk.identity.signing_key.verifying_key();
+        let phone_key = phone.identity.signing_key.verifying_key();
+        desk.add_preshared_pairing(phone_id, "phone".to_string(), phone_key, &[9u8; 32]).await.unwrap();
+        phone.add_preshared_pairing(desk_id, "desk".to_string(), desk_key, &[9u8; 32]).await.unwrap();
+        // The phone only sends to the desk, so drops what the desk sends it
+        phone.set_direction(desk_id, SyncDirection::SendOnly).await.unwrap();
+
+        *desk_clipboard.lock().unwrap() = Some(ClipboardContent::Text("not for the phone".to_string()));
+        let confirmed = next_event(&mut desk_events, |event| match event {
+            ServiceEvent::DeliveryConfirmed { device_id, .. } => Some(device_id),
+            ServiceEvent::Error(e) => panic!("unexpected error: {}", e),
+            _ => None,
+        }).await;
+        assert_eq!(confirmed, phone_id);
+        assert!(phone_clipboard.lock().unwrap().is_none());
+
+        desk.shutdown().await.unwrap();
+        phone.shutdown().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unpair_stops_both_sync_servers_accepting() {
+        let dir = std::env::temp_dir().join(format!("omniclip-runtime-unpair-{}", Uuid::new_v4()));
+        let (desk, _desk_events, _) = started(&dir, "desk", ConflictPolicy::default()).await;
+        let (phone, mut phone_events, _) = started(&dir, "phone", ConflictPolicy::default()).await;
+        reach(&desk, &phone).await;
+        let (desk_id, phone_id) = (desk.device_id(), phone.device_id());
+        let desk_key = desk.identity.signing_key.verifying_key();
+        let phone_key = phone.identity.signing_key.verifying_key();
+        desk.add_preshared_pairing(phone_id, "phone".to_string(), phone_key, &[8u8; 32]).await.unwrap();
+        phone.add_preshared_pairing(desk_id, "desk".to_string(), desk_key, &[8u8; 32]).await.unwrap();
+
+        desk.unpair_device(phone_id).await;
+        assert!(!desk.server_devices.read().await.contains_key(&phone_id));
+        let unpaired = next_event(&mut phone_events, |event| match event {
+            ServiceEvent::DeviceUnpaired(device_id) => Some(device_id),
+            _ => None,
+        }).await;
+        assert_eq!(unpaired, desk_id);
+        assert!(!phone.server_devices.read().await.contains_key(&desk_id));
+
+        desk.shutdown().await.unwrap();
+        phone.shutdown().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unpair_before_start_is_saved() {
+        let dir = std::env::temp_dir().join(format!("omniclip-unpair-{}", Uuid::new_v4()));
+        let config = || Config { data_dir: dir.clone(), ..Config::default() };
+        let (phone, desk) = (Uuid::new_v4(), Uuid::new_v4());
+        let desk_key = SigningKey::generate().verifying_key();
+
+        let mut first = OmniclipService::with_config("me".to_string(), config());
+        first.add_preshared_pairing(phone, "phone".to_string(), SigningKey::generate().verifying_key(), &[3u8; 32])
+            .await
+            .unwrap();
+        first.add_preshared_pairing(desk, "desk".to_string(), desk_key.clone(), &[3u8; 32]).await.unwrap();
+        first.load_state().await.unwrap();
+        first.unpair_device(phone).await;
+        first.shutdown().await.unwrap();
+
+        // A later run sees the unpair and still owes the phone its notice
+        let mut second = OmniclipService::with_config("me".to_string(), config());
+        second.load_state().await.unwrap();
+        let paired = second.get_paired_devices().await;
+        assert_eq!(paired.len(), 1);
+        assert_eq!(paired[0].device_id, desk);
+        assert_eq!(paired[0].fingerprint, desk_key.fingerprint());
+        let owed = second.pending_unpairs.read().await;
+        let proof = owed[&phone].session_key.encrypt(b"unpaired").unwrap();
+        assert_eq!(SessionKey::from_bytes(&[3u8; 32]).decrypt(&proof).unwrap(), b"unpaired");
+        drop(owed);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rotated_key_followed_by_peer() {
+        let (a_id, b_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let info = |device_id| PairedDeviceInfo {
+            device_id,
+            device_name: "peer".to_string(),
+            identity_pubkey: SigningKey::generate().verifying_key(),
+            keys: SessionKeyRing::new(SessionKey::from_bytes(&[6u8; 32])),
+            features: NegotiatedFeatures::baseline(),
+            direction: SyncDirection::default(),
+            last_seen: std::time::Instant::now(),
+        };
+        let on_a = RwLock::new(HashMap::from([(b_id, info(b_id))]));
+        let on_b = RwLock::new(HashMap::from([(a_id, info(a_id))]));
+        let (server_a, server_b) = (RwLock::new(HashMap::new()), RwLock::new(HashMap::new()));
+        register(&server_a, &on_a.read().await[&b_id]).await;
+        register(&server_b, &on_b.read().await[&a_id]).await;
+        let policy = KeyRotation { after_messages: 2, after: Duration::from_secs(3600) };
+        let (tx, mut events) = event_channel(8);
+
+        // Not due until enough has been sent under the key
+        on_a.write().await.get_mut(&b_id).unwrap().keys.record_send();
+        rotate_due_keys(a_id, &policy, &on_a, &server_a, &RwLock::new(HashMap::new()), false, &None, &tx).await;
+        assert_eq!(on_a.read().await[&b_id].keys.current_epoch(), KeyEpoch(0));
+
+        on_a.write().await.get_mut(&b_id).unwrap().keys.record_send();
+        rotate_due_keys(a_id, &policy, &on_a, &server_a, &RwLock::new(HashMap::new()), false, &None, &tx).await;
+        assert!(matches!(events.try_recv(), Ok(ServiceEvent::Rekeyed { epoch: KeyEpoch(1), .. })));
+        let new_key = on_a.read().await[&b_id].keys.current().clone();
+
+        // A forged proof doesn't move B
+        let forged = SessionKey::from_bytes(&[1u8; 32]).encrypt(a_id.as_bytes()).unwrap();
+        let followed = follow_ratchet(&on_b, &server_b, &None, &tx, a_id, KeyEpoch(1), |key| key.decrypt(&forged).is_ok()).await;
+        assert!(followed.is_none());
+        assert_eq!(on_b.read().await[&a_id].keys.current_epoch(), KeyEpoch(0));
+
+        let proof = new_key.encrypt(a_id.as_bytes()).unwrap();
+        let followed = follow_ratchet(&on_b, &server_b, &None, &tx, a_id, KeyEpoch(1), |key| key.decrypt(&proof).is_ok()).await;
+        assert_eq!(followed.unwrap().current_epoch(), KeyEpoch(1));
+        let sent = new_key.encrypt(b"after rotation").unwrap();
+        assert_eq!(on_b.read().await[&a_id].keys.current().decrypt(&sent).unwrap(), b"after rotation");
+
+        // Both sync servers moved on with the services
+        assert_eq!(server_a.read().await[&b_id].session_key.decrypt(&sent).unwrap(), b"after rotation");
+        assert_eq!(server_b.read().await[&a_id].session_key.decrypt(&sent).unwrap(), b"after rotation");
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume_report_changes() {
+        let mut service = OmniclipService::new("a".to_string());
+        let (tx, mut events) = event_channel(8);
+        service.events = Some(tx);
+
+        service.pause();
+        service.pause();
+        assert!(service.is_paused());
+        assert!(matches!(events.try_recv(), Ok(ServiceEvent::PauseChanged { paused: true })));
+        assert!(events.try_recv().is_err());
+
+        service.resume();
+        assert!(!service.is_paused());
+        assert!(matches!(events.try_recv(), Ok(ServiceEvent::PauseChanged { paused: false })));
+        tokio::time::timeout(Duration::from_secs(1), service.pause.resumed.notified()).await.unwrap();
+        service.resume();
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_direction_is_saved() {
+        let dir = std::env::temp_dir().join(format!("omniclip-direction-{}", Uuid::new_v4()));
+        let config = || Config { data_dir: dir.clone(), ..Config::default() };
+        let phone = Uuid::new_v4();
+
+        let mut first = OmniclipService::with_config("me".to_string(), config());
+        first.load_state().await.unwrap();
+        first.add_preshared_pairing(phone, "phone".to_string(), SigningKey::generate().verifying_key(), &[4u8; 32])
+            .await
+            .unwrap();
+        assert_eq!(first.get_paired_devices().await[0].direction, SyncDirection::Bidirectional);
+        first.set_direction(phone, SyncDirection::SendOnly).await.unwrap();
+        assert!(first.set_direction(Uuid::new_v4(), SyncDirection::ReceiveOnly).await.is_err());
+        first.shutdown().await.unwrap();
+
+        let mut second = OmniclipService::with_config("me".to_string(), config());
+        second.load_state().await.unwrap();
+        assert_eq!(second.get_paired_devices().await[0].direction, SyncDirection::SendOnly);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_manual_peers_are_saved() {
+        let dir = std::env::temp_dir().join(format!("omniclip-peers-{}", Uuid::new_v4()));
+        let config = || Config { data_dir: dir.clone(), ..Config::default() };
+        let laptop: SocketAddr = "192.168.1.20:7890".parse().unwrap();
+
+        let mut first = OmniclipService::with_config("me".to_string(), config());
+        first.load_state().await.unwrap();
+        first.add_manual_peer(laptop, None).await.unwrap();
+        first.add_manual_peer(laptop, Some("abc".to_string())).await.unwrap();
+        assert!(first.add_manual_peer("0.0.0.0:7890".parse().unwrap(), None).await.is_err());
+        first.shutdown().await.unwrap();
+
+        let mut second = OmniclipService::with_config("me".to_string(), config());
+        second.load_state().await.unwrap();
+        let expected = ManualPeer { addr: laptop, fingerprint: Some("abc".to_string()) };
+        assert_eq!(*second.manual_peers.read().await, vec![expected]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_history_is_bounded_and_skips_repeats() {
+        let (me, peer) = (Uuid::new_v4(), Uuid::new_v4());
+        let text = |s: &str| ClipboardContent::Text(s.to_string());
+        let mut history = ClipboardHistory::new(2);
+        history.record(&text("a"), 1, me);
+        history.record(&text("a"), 2, peer);
+        assert_eq!(history.entries.len(), 1);
+        history.record(&text("b"), 3, peer);
+        history.record(&text("c"), 4, me);
+        let kept: Vec<_> = history.entries.iter().map(|entry| (entry.timestamp, entry.origin)).collect();
+        assert_eq!(kept, [(4, me), (3, peer)]);
+
+        let mut none = ClipboardHistory::new(0);
+        none.record(&text("a"), 1, me);
+        assert!(none.entries.is_empty());
+
+        let service = OmniclipService::new("a".to_string());
+        assert!(service.history().await.is_empty());
+        assert!(service.restore(0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_current_clipboard_read_through_monitor() {
+        let mut service = OmniclipService::new("a".to_string());
+        assert!(service.current_clipboard().await.is_err());
+
+        let current = ClipboardContent::Text("on the clipboard".to_string());
+        let manager = ClipboardManager::with_backend(MemoryClipboard(Arc::new(Mutex::new(Some(current.clone())))));
+        let (_rx, writer, _handle) = clipboard::start_monitor_with(manager, Duration::from_millis(10));
+        service.clipboard = Some(writer);
+        let read = service.current_clipboard().await.unwrap();
+        assert_eq!(read.map(|c| c.hash()), Some(current.hash()));
     }
 }
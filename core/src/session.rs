@@ -0,0 +1,179 @@
+//! Detection of whether the user's desktop session is locked
+//!
+//! The session is polled rather than subscribed to, matching clipboard
+//! monitoring. Platforms without a supported lock indicator report `None`,
+//! and the monitor then never emits a transition.
+
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Whether the local desktop session is in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    /// The session is unlocked
+    Active,
+    /// The screen is locked
+    Locked,
+}
+
+/// Source of the current session state
+pub trait SessionDetector: Send + 'static {
+    /// Current session state, or `None` if it can't be determined
+    ///
+    /// May block, e.g. on a helper process; the monitor calls it on the
+    /// blocking pool.
+    fn state(&self) -> Option<SessionState>;
+}
+
+/// Session state of the machine we're running on
+///
+/// Uses logind's `LockedHint` on Linux, the console lock flag on macOS and
+/// input desktop access on Windows.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemSession;
+
+impl SessionDetector for SystemSession {
+    fn state(&self) -> Option<SessionState> {
+        system_state()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn system_state() -> Option<SessionState> {
+    let session = std::env::var("XDG_SESSION_ID").unwrap_or_else(|_| "auto".to_string());
+    let output = std::process::Command::new("loginctl")
+        .args(["show-session", &session, "--property=LockedHint", "--value"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())?;
+
+    match String::from_utf8_lossy(&output.stdout).trim() {
+        "yes" => Some(SessionState::Locked),
+        "no" => Some(SessionState::Active),
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn system_state() -> Option<SessionState> {
+    let output = std::process::Command::new("ioreg")
+        .args(["-n", "Root", "-d1"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())?;
+
+    let text = String::from_utf8_lossy(&output.stdout);
+    if text.contains("\"IOConsoleLocked\"=Yes") {
+        Some(SessionState::Locked)
+    } else if text.contains("\"IOConsoleLocked\"=No") {
+        Some(SessionState::Active)
+    } else {
+        None
+    }
+}
+
+#[cfg(windows)]
+fn system_state() -> Option<SessionState> {
+    use windows_sys::Win32::System::StationsAndDesktops::{
+        CloseDesktop, OpenInputDesktop, DESKTOP_SWITCHDESKTOP,
+    };
+
+    // The input desktop can't be opened while the lock screen is showing
+    let desktop = unsafe { OpenInputDesktop(0, 0, DESKTOP_SWITCHDESKTOP) };
+    if desktop.is_null() {
+        return Some(SessionState::Locked);
+    }
+    unsafe { CloseDesktop(desktop) };
+    Some(SessionState::Active)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn system_state() -> Option<SessionState> {
+    None
+}
+
+/// Start a task that reports session state transitions
+///
+/// The first known state is reported too, so the receiver starts in sync.
+pub fn start_monitor(
+    detector: impl SessionDetector,
+    poll_interval: Duration,
+) -> (mpsc::Receiver<SessionState>, tokio::task::JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel(4);
+
+    let handle = tokio::spawn(async move {
+        let mut detector = detector;
+        let mut last = None;
+        let mut warned = false;
+
+        loop {
+            // Querying the system runs loginctl or ioreg, so keep it off the runtime
+            let polled = tokio::task::spawn_blocking(move || {
+                let state = detector.state();
+                (detector, state)
+            })
+            .await;
+            let Ok((returned, state)) = polled else {
+                tracing::warn!("session lock detector failed, no longer monitoring");
+                break;
+            };
+            detector = returned;
+
+            match state {
+                Some(state) if last != Some(state) => {
+                    last = Some(state);
+                    if tx.send(state).await.is_err() {
+                        break;
+                    }
+                }
+                Some(_) => {}
+                None if !warned => {
+                    tracing::debug!("session lock state unavailable on this system");
+                    warned = true;
+                }
+                None => {}
+            }
+
+            tokio::time::sleep(poll_interval).await;
+        }
+    });
+
+    (rx, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct FakeSession(Arc<Mutex<Option<SessionState>>>);
+
+    impl SessionDetector for FakeSession {
+        fn state(&self) -> Option<SessionState> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    async fn next(rx: &mut mpsc::Receiver<SessionState>) -> Result<Option<SessionState>, tokio::time::error::Elapsed> {
+        tokio::time::timeout(Duration::from_millis(100), rx.recv()).await
+    }
+
+    #[tokio::test]
+    async fn test_monitor_reports_transitions_only() {
+        let state = Arc::new(Mutex::new(None));
+        let (mut rx, _handle) = start_monitor(FakeSession(state.clone()), Duration::from_millis(5));
+
+        // Unknown state emits nothing
+        assert!(next(&mut rx).await.is_err());
+
+        *state.lock().unwrap() = Some(SessionState::Active);
+        assert_eq!(next(&mut rx).await.unwrap(), Some(SessionState::Active));
+        assert!(next(&mut rx).await.is_err());
+
+        *state.lock().unwrap() = Some(SessionState::Locked);
+        assert_eq!(next(&mut rx).await.unwrap(), Some(SessionState::Locked));
+
+        *state.lock().unwrap() = Some(SessionState::Active);
+        assert_eq!(next(&mut rx).await.unwrap(), Some(SessionState::Active));
+    }
+}
@@ -0,0 +1,517 @@
+//! Integrity-checked storage for persisted state
+//!
+//! Each file holds a JSON envelope with a format version, the serialized
+//! value and an HMAC-SHA256 tag over both and the file name. The MAC key is
+//! generated on first use and kept in `state.key` next to the state files.
+//! Files are replaced atomically by writing a temporary file and renaming
+//! it, so a crash mid-write leaves the previous version intact. A truncated,
+//! corrupted or edited file fails to load as a whole instead of partially.
+//! [`DebouncedSave`] coalesces frequent changes into occasional writes.
+//!
+//! A store opened with [`StateStore::open_encrypted`] also seals each file,
+//! the MAC key included, with AES-256-GCM under a key stretched from a
+//! passphrase with Argon2id. The salt is kept in `state.salt`. Files written
+//! before encryption was turned on still load and are sealed when next saved.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::protocol::constants::{SEALED_STATE_MAGIC, STATE_FORMAT_VERSION, STATE_SALT_SIZE};
+use crate::{Error, Result};
+
+/// File name of the MAC key for persisted state
+pub const STATE_KEY_FILE: &str = "state.key";
+
+/// File name of the salt for the state passphrase
+pub const STATE_SALT_FILE: &str = "state.salt";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// On-disk wrapper around a persisted value
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u16,
+    #[serde(with = "crate::crypto::serde_utils::base64_bytes")]
+    payload: Vec<u8>,
+    #[serde(with = "crate::crypto::serde_utils::base64_bytes")]
+    tag: Vec<u8>,
+}
+
+/// Directory of versioned, tamper-evident state files
+pub struct StateStore {
+    dir: PathBuf,
+    key: [u8; 32],
+    /// Key files are sealed with, if opened with a passphrase
+    sealing: Option<SealingKey>,
+}
+
+impl StateStore {
+    /// Open the store in `data_dir`, creating its MAC key if needed.
+    ///
+    /// Fails with `Error::Config` if the state there is encrypted.
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        fs::create_dir_all(data_dir)?;
+        Self::open_with(data_dir, None)
+    }
+
+    /// Open the store in `data_dir`, sealing its files under `passphrase`
+    ///
+    /// Unencrypted state already there is sealed as it is next saved. Fails
+    /// with `Error::Crypto` if the state was sealed under another passphrase.
+    pub fn open_encrypted(data_dir: &Path, passphrase: &str) -> Result<Self> {
+        fs::create_dir_all(data_dir)?;
+        let salt_path = data_dir.join(STATE_SALT_FILE);
+        let salt = match fs::read(&salt_path) {
+            Ok(salt) => salt,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let mut salt = vec![0u8; STATE_SALT_SIZE];
+                rand::thread_rng().fill_bytes(&mut salt);
+                write_atomic(&salt_path, &salt)?;
+                salt
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Self::open_with(data_dir, Some(SealingKey::derive(passphrase, &salt)?))
+    }
+
+    /// Whether the state in `data_dir` is encrypted and needs a passphrase
+    pub fn is_encrypted(data_dir: &Path) -> bool {
+        fs::read(data_dir.join(STATE_KEY_FILE)).is_ok_and(|bytes| bytes.starts_with(SEALED_STATE_MAGIC))
+    }
+
+    fn open_with(data_dir: &Path, sealing: Option<SealingKey>) -> Result<Self> {
+        let key_path = data_dir.join(STATE_KEY_FILE);
+        let bytes = match fs::read(&key_path) {
+            Ok(bytes) => Some(bytes),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let sealed = bytes.as_ref().is_some_and(|bytes| bytes.starts_with(SEALED_STATE_MAGIC));
+
+        let key = match (bytes, &sealing) {
+            (Some(bytes), Some(sealing)) if sealed => sealing.open(STATE_KEY_FILE, &bytes)
+                .map_err(|_| Error::Crypto(format!("wrong passphrase for the state in {}", data_dir.display())))?
+                .try_into()
+                .map_err(|_| corrupt())?,
+            (Some(_), None) if sealed => {
+                return Err(Error::Config(format!(
+                    "the state in {} is encrypted and needs its passphrase",
+                    data_dir.display()
+                )));
+            }
+            (Some(bytes), _) => bytes.try_into().map_err(|_| corrupt())?,
+            (None, _) => {
+                let mut key = [0u8; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            }
+        };
+
+        let store = Self {
+            dir: data_dir.to_path_buf(),
+            key,
+            sealing,
+        };
+        if !sealed {
+            store.write(STATE_KEY_FILE, &key)?;
+        }
+        Ok(store)
+    }
+
+    /// Whether files are sealed under a passphrase
+    pub fn is_sealed(&self) -> bool {
+        self.sealing.is_some()
+    }
+
+    /// Whether the file `name` is still plain although the store seals
+    /// files, as when state saved before the passphrase was set is loaded
+    pub fn needs_sealing(&self, name: &str) -> bool {
+        self.sealing.is_some()
+            && fs::read(self.dir.join(name)).is_ok_and(|bytes| !bytes.starts_with(SEALED_STATE_MAGIC))
+    }
+
+    /// Atomically replace the file `name` with `value`
+    pub fn save<T: Serialize>(&self, name: &str, value: &T) -> Result<()> {
+        let payload = serde_json::to_vec(value)?;
+        let tag = self.tag(name, STATE_FORMAT_VERSION, &payload).finalize().into_bytes().to_vec();
+        let envelope = Envelope {
+            version: STATE_FORMAT_VERSION,
+            payload,
+            tag,
+        };
+        self.write(name, &serde_json::to_vec(&envelope)?)
+    }
+
+    /// Load the file `name`, or `None` if it doesn't exist.
+    ///
+    /// Fails with `Error::InvalidMessage` if the file doesn't verify.
+    pub fn load<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>> {
+        let bytes = match fs::read(self.dir.join(name)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let bytes = match &self.sealing {
+            Some(sealing) if bytes.starts_with(SEALED_STATE_MAGIC) => sealing.open(name, &bytes).map_err(|_| corrupt())?,
+            None if bytes.starts_with(SEALED_STATE_MAGIC) => {
+                return Err(Error::Config(format!("{} is encrypted and needs its passphrase", name)));
+            }
+            _ => bytes,
+        };
+
+        let envelope: Envelope = serde_json::from_slice(&bytes).map_err(|_| corrupt())?;
+        if envelope.version != STATE_FORMAT_VERSION {
+            return Err(Error::InvalidMessage(format!(
+                "unsupported state version {}", envelope.version
+            )));
+        }
+        self.tag(name, envelope.version, &envelope.payload)
+            .verify_slice(&envelope.tag)
+            .map_err(|_| corrupt())?;
+
+        serde_json::from_slice(&envelope.payload).map(Some).map_err(|_| corrupt())
+    }
+
+    /// Atomically replace the file `name` with `bytes`, sealed if the store is
+    fn write(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        let path = self.dir.join(name);
+        match &self.sealing {
+            Some(sealing) => write_atomic(&path, &sealing.seal(name, bytes)?),
+            None => write_atomic(&path, bytes),
+        }
+    }
+
+    fn tag(&self, name: &str, version: u16, payload: &[u8]) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.key)
+            .expect("HMAC accepts any key length");
+        mac.update(name.as_bytes());
+        mac.update(&[0]);
+        mac.update(&version.to_be_bytes());
+        mac.update(payload);
+        mac
+    }
+}
+
+/// Key sealing state files, stretched from a passphrase
+struct SealingKey(Aes256Gcm);
+
+impl SealingKey {
+    fn derive(passphrase: &str, salt: &[u8]) -> Result<Self> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| Error::Crypto(format!("key derivation failed: {}", e)))?;
+        Ok(Self(Aes256Gcm::new(&key.into())))
+    }
+
+    /// Seal `plaintext` as the file `name`: the magic, a random nonce and
+    /// the ciphertext, with the name authenticated alongside
+    fn seal(&self, name: &str, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self.0
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: name.as_bytes() })
+            .map_err(|e| Error::Crypto(format!("encryption failed: {}", e)))?;
+        Ok([SEALED_STATE_MAGIC, &nonce, &ciphertext].concat())
+    }
+
+    /// Open the file `name` sealed by [`seal`](Self::seal)
+    fn open(&self, name: &str, sealed: &[u8]) -> Result<Vec<u8>> {
+        let (nonce, ciphertext) = sealed
+            .strip_prefix(SEALED_STATE_MAGIC)
+            .and_then(|rest| rest.split_first_chunk::<12>())
+            .ok_or_else(corrupt)?;
+        self.0
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: name.as_bytes() })
+            .map_err(|e| Error::Crypto(format!("decryption failed: {}", e)))
+    }
+}
+
+/// Saves one state file in the background, coalescing rapid changes
+///
+/// [`update`](Self::update) only marks the file dirty with its new value.
+/// The value is written `interval` after the first unsaved change, so a
+/// burst of changes costs a single write of the last one. Writes run on the
+/// blocking pool one at a time, so they neither stall the runtime nor race
+/// each other for the file. Call [`flush`](Self::flush) on shutdown to write
+/// anything still pending.
+pub struct DebouncedSave<T> {
+    state: Arc<SaveState<T>>,
+    task: JoinHandle<()>,
+}
+
+struct SaveState<T> {
+    store: Arc<StateStore>,
+    name: String,
+    /// Latest value not yet written
+    pending: Mutex<Option<T>>,
+    /// Held while writing, so a flush can't overtake a write in progress
+    writing: Mutex<()>,
+    dirty: Notify,
+}
+
+impl<T: Serialize + Send + 'static> DebouncedSave<T> {
+    /// Save to the file `name` in `store`, at most once per `interval`
+    pub fn new(store: Arc<StateStore>, name: &str, interval: Duration) -> Self {
+        let state = Arc::new(SaveState {
+            store,
+            name: name.to_string(),
+            pending: Mutex::new(None),
+            writing: Mutex::new(()),
+            dirty: Notify::new(),
+        });
+
+        let background = state.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                background.dirty.notified().await;
+                tokio::time::sleep(interval).await;
+                if let Err(e) = SaveState::write_in_background(&background).await {
+                    tracing::warn!("couldn't save {}: {}", background.name, e);
+                }
+            }
+        });
+
+        Self { state, task }
+    }
+
+    /// Record a new value to be written
+    pub fn update(&self, value: T) {
+        *self.state.pending.lock().unwrap() = Some(value);
+        self.state.dirty.notify_one();
+    }
+
+    /// Whether a value is waiting to be written
+    pub fn is_dirty(&self) -> bool {
+        self.state.pending.lock().unwrap().is_some()
+    }
+
+    /// Write the pending value now, if any
+    pub async fn flush(&self) -> Result<()> {
+        SaveState::write_in_background(&self.state).await
+    }
+}
+
+impl<T: Serialize + Send + 'static> SaveState<T> {
+    async fn write_in_background(state: &Arc<Self>) -> Result<()> {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || state.write_pending())
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    fn write_pending(&self) -> Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        let Some(value) = self.pending.lock().unwrap().take() else {
+            return Ok(());
+        };
+        self.store.save(&self.name, &value)
+    }
+}
+
+impl<T> Drop for DebouncedSave<T> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn corrupt() -> Error {
+    Error::InvalidMessage("corrupt state".to_string())
+}
+
+/// Write `bytes` to a temporary file and rename it over `path`
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+
+    let mut file = create_private(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp, path)?;
+    // Make the rename itself durable
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Create a file readable only by the current user
+fn create_private(path: &Path) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("omniclip-state-{}", Uuid::new_v4()))
+    }
+
+    fn sample() -> HashMap<String, u32> {
+        HashMap::from([("laptop".to_string(), 1), ("phone".to_string(), 2)])
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let dir = temp_dir();
+        let store = StateStore::open(&dir).unwrap();
+
+        assert!(store.load::<HashMap<String, u32>>("devices.json").unwrap().is_none());
+        store.save("devices.json", &sample()).unwrap();
+
+        // A reopened store uses the same key
+        let store = StateStore::open(&dir).unwrap();
+        assert_eq!(store.load::<HashMap<String, u32>>("devices.json").unwrap(), Some(sample()));
+        assert!(!dir.join("devices.json.tmp").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_file_rejected() {
+        let dir = temp_dir();
+        let store = StateStore::open(&dir).unwrap();
+        store.save("devices.json", &sample()).unwrap();
+
+        let path = dir.join("devices.json");
+        let bytes = fs::read(&path).unwrap();
+        for len in [0, 1, bytes.len() / 2, bytes.len() - 1] {
+            fs::write(&path, &bytes[..len]).unwrap();
+            let err = store.load::<HashMap<String, u32>>("devices.json").unwrap_err();
+            assert!(matches!(err, Error::InvalidMessage(ref m) if m == "corrupt state"), "len {}", len);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_bit_flips_rejected() {
+        let dir = temp_dir();
+        let store = StateStore::open(&dir).unwrap();
+        store.save("devices.json", &sample()).unwrap();
+
+        let path = dir.join("devices.json");
+        let bytes = fs::read(&path).unwrap();
+        for i in 0..bytes.len() {
+            let mut flipped = bytes.clone();
+            flipped[i] ^= 0x01;
+            fs::write(&path, &flipped).unwrap();
+            let result = store.load::<HashMap<String, u32>>("devices.json");
+            assert!(matches!(result, Err(Error::InvalidMessage(_))), "byte {}", i);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_renamed_file_rejected() {
+        let dir = temp_dir();
+        let store = StateStore::open(&dir).unwrap();
+        store.save("a.json", &sample()).unwrap();
+        fs::copy(dir.join("a.json"), dir.join("b.json")).unwrap();
+
+        assert!(store.load::<HashMap<String, u32>>("b.json").is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_roundtrip() {
+        let dir = temp_dir();
+        let store = StateStore::open_encrypted(&dir, "correct horse").unwrap();
+        store.save("devices.json", &sample()).unwrap();
+        drop(store);
+
+        assert!(StateStore::is_encrypted(&dir));
+        for file in ["devices.json", STATE_KEY_FILE] {
+            let bytes = fs::read(dir.join(file)).unwrap();
+            assert!(bytes.starts_with(SEALED_STATE_MAGIC), "{} isn't sealed", file);
+        }
+        assert!(!String::from_utf8_lossy(&fs::read(dir.join("devices.json")).unwrap()).contains("laptop"));
+
+        let store = StateStore::open_encrypted(&dir, "correct horse").unwrap();
+        assert_eq!(store.load::<HashMap<String, u32>>("devices.json").unwrap(), Some(sample()));
+
+        assert!(matches!(StateStore::open_encrypted(&dir, "wrong horse"), Err(Error::Crypto(_))));
+        assert!(matches!(StateStore::open(&dir), Err(Error::Config(_))));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_plain_state_sealed_when_saved() {
+        let dir = temp_dir();
+        StateStore::open(&dir).unwrap().save("devices.json", &sample()).unwrap();
+        assert!(!StateStore::is_encrypted(&dir));
+
+        let store = StateStore::open_encrypted(&dir, "correct horse").unwrap();
+        assert!(StateStore::is_encrypted(&dir));
+        assert_eq!(store.load::<HashMap<String, u32>>("devices.json").unwrap(), Some(sample()));
+        store.save("devices.json", &sample()).unwrap();
+        assert!(fs::read(dir.join("devices.json")).unwrap().starts_with(SEALED_STATE_MAGIC));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_debounced_save_coalesces_changes() {
+        let dir = temp_dir();
+        let interval = Duration::from_secs(5);
+        let saver = DebouncedSave::new(Arc::new(StateStore::open(&dir).unwrap()), "devices.json", interval);
+        let path = dir.join("devices.json");
+        let load = || StateStore::open(&dir).unwrap().load::<HashMap<String, u32>>("devices.json").unwrap();
+
+        for n in 0..10 {
+            saver.update(HashMap::from([("laptop".to_string(), n)]));
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(!path.exists(), "written before the interval passed");
+        assert!(saver.is_dirty());
+
+        // One write, of the last value
+        tokio::time::sleep(interval).await;
+        assert_eq!(load(), Some(HashMap::from([("laptop".to_string(), 9)])));
+        assert!(!saver.is_dirty());
+        fs::remove_file(&path).unwrap();
+        tokio::time::sleep(interval * 2).await;
+        assert!(!path.exists(), "written again with nothing changed");
+
+        // Flushing on shutdown doesn't wait for the interval
+        saver.update(sample());
+        saver.flush().await.unwrap();
+        assert_eq!(load(), Some(sample()));
+
+        drop(saver);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+//! Clipboard messages too large for one frame
+//!
+//! A clipboard message whose encoding is over [`MAX_MESSAGE_SIZE`] is sent
+//! as a run of `ClipboardChunk` messages, each carrying the next
+//! [`CLIPBOARD_CHUNK_SIZE`] bytes of the encoding, back to back on one
+//! connection. The receiver joins them before decoding, so the message is
+//! decrypted and its content hash checked as if it had arrived whole.
+//! Chunks name their sender, so a server can refuse them from devices it
+//! isn't paired with before buffering any.
+//! Peers from before chunking couldn't read a frame that large either, so
+//! sending them chunks loses nothing.
+
+use std::time::Duration;
+
+use tokio::io::AsyncRead;
+use uuid::Uuid;
+
+use crate::protocol::constants::{
+    CHUNK_TIMEOUT_MS, CLIPBOARD_CHUNK_SIZE, FRAME_PREAMBLE, MAX_CHUNKED_MESSAGE_SIZE, MAX_MESSAGE_SIZE,
+};
+use crate::protocol::{ClipboardDeltaMessage, ClipboardSyncMessage, Message, WireCodec};
+use crate::sync::framing::read_framed_message;
+use crate::{Error, Result};
+
+/// Frames to write for `message` encoded with `codec`: the message itself
+/// if it fits in one, its chunks otherwise
+pub fn frames(message: &Message, codec: WireCodec) -> Result<Vec<Vec<u8>>> {
+    let frame = message.to_frame_with(codec)?;
+    let header = FRAME_PREAMBLE.len() + 4;
+    let len = frame.len() - header;
+    if len <= MAX_MESSAGE_SIZE {
+        return Ok(vec![frame]);
+    }
+
+    let (message_id, sender_id) = match message {
+        Message::ClipboardSync(ClipboardSyncMessage { message_id, sender_id, .. })
+        | Message::ClipboardDelta(ClipboardDeltaMessage { message_id, sender_id, .. }) => (*message_id, *sender_id),
+        _ => {
+            return Err(Error::InvalidMessage(format!(
+                "message too large: {} bytes (max {})",
+                len, MAX_MESSAGE_SIZE
+            )));
+        }
+    };
+    if len > MAX_CHUNKED_MESSAGE_SIZE {
+        return Err(Error::InvalidMessage(format!(
+            "clipboard message too large: {} bytes (max {})",
+            len, MAX_CHUNKED_MESSAGE_SIZE
+        )));
+    }
+
+    let parts = frame[header..].chunks(CLIPBOARD_CHUNK_SIZE);
+    let total = parts.len() as u32;
+    parts
+        .enumerate()
+        .map(|(index, data)| {
+            Message::ClipboardChunk { message_id, sender_id, index: index as u32, total, data: data.to_vec() }
+                .to_frame_with(codec)
+        })
+        .collect()
+}
+
+/// Finish reading `message` if it is the first chunk of a larger one,
+/// returning the message the chunks make up; any other message is returned
+/// as is.
+///
+/// The rest of the chunks must follow in order, each within
+/// [`CHUNK_TIMEOUT_MS`] of the last. A message that doesn't arrive whole
+/// is discarded with an error, after which the connection can't be read
+/// further. Callers should only read the rest of chunks from a device they
+/// are paired with, as up to [`MAX_CHUNKED_MESSAGE_SIZE`] bytes are
+/// buffered before the message can be decrypted.
+pub async fn read_rest<R: AsyncRead + Unpin>(reader: &mut R, message: Message) -> Result<Message> {
+    let Message::ClipboardChunk { message_id, sender_id, index, total, data } = message else {
+        return Ok(message);
+    };
+    let mut joined = ChunkedMessage::start(message_id, index, total, data)?;
+    let timeout = Duration::from_millis(CHUNK_TIMEOUT_MS);
+    while !joined.is_complete() {
+        let payload = tokio::time::timeout(timeout, read_framed_message(reader))
+            .await
+            .map_err(|_| Error::Timeout(format!(
+                "message {} stalled after {} of {} chunks",
+                message_id, joined.received, joined.total
+            )))??;
+        let Message::ClipboardChunk { message_id, index, total, data, .. } = Message::decode(&payload)? else {
+            return Err(Error::InvalidMessage(format!("message {} interrupted before its last chunk", joined.message_id)));
+        };
+        joined.add(message_id, index, total, data)?;
+    }
+
+    let message = Message::decode(&joined.data)?;
+    match message {
+        Message::ClipboardSync(ClipboardSyncMessage { sender_id: from, .. })
+        | Message::ClipboardDelta(ClipboardDeltaMessage { sender_id: from, .. })
+            if from != sender_id =>
+        {
+            Err(Error::InvalidMessage(format!("message {} chunked as from {} but sent by {}", message_id, sender_id, from)))
+        }
+        Message::ClipboardSync(_) | Message::ClipboardDelta(_) => Ok(message),
+        other => Err(Error::InvalidMessage(format!("{:?} can't be sent in chunks", other))),
+    }
+}
+
+/// A message being joined from its chunks
+struct ChunkedMessage {
+    message_id: Uuid,
+    total: u32,
+    received: u32,
+    data: Vec<u8>,
+}
+
+impl ChunkedMessage {
+    /// Start joining a message from its first chunk
+    fn start(message_id: Uuid, index: u32, total: u32, data: Vec<u8>) -> Result<Self> {
+        if total as usize > MAX_CHUNKED_MESSAGE_SIZE.div_ceil(CLIPBOARD_CHUNK_SIZE) {
+            return Err(Error::InvalidMessage(format!(
+                "message {} has {} chunks, too many for {} bytes",
+                message_id, total, MAX_CHUNKED_MESSAGE_SIZE
+            )));
+        }
+        let mut joined = Self { message_id, total, received: 0, data: Vec::new() };
+        joined.add(message_id, index, total, data)?;
+        Ok(joined)
+    }
+
+    /// Append the next chunk, failing unless it is the one expected
+    fn add(&mut self, message_id: Uuid, index: u32, total: u32, data: Vec<u8>) -> Result<()> {
+        if index >= total {
+            return Err(Error::InvalidMessage(format!(
+                "chunk {} of {} for message {}", index, total, message_id
+            )));
+        }
+        if message_id != self.message_id || total != self.total || index != self.received {
+            return Err(Error::InvalidMessage(format!(
+                "expected chunk {} of {} for message {}, got chunk {} of {} for {}",
+                self.received, self.total, self.message_id, index, total, message_id
+            )));
+        }
+        if data.len() > CLIPBOARD_CHUNK_SIZE {
+            return Err(Error::InvalidMessage(format!(
+                "chunk of {} bytes (max {})",
+                data.len(), CLIPBOARD_CHUNK_SIZE
+            )));
+        }
+        self.data.extend_from_slice(&data);
+        self.received += 1;
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received == self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::EncryptedPayload;
+    use crate::protocol::ContentHash;
+    use tokio::io::AsyncWriteExt;
+
+    fn sync_message(ciphertext_len: usize) -> Message {
+        Message::ClipboardSync(ClipboardSyncMessage {
+            message_id: Uuid::new_v4(),
+            sender_id: Uuid::new_v4(),
+            content_hash: ContentHash([5; 32]),
+            encrypted_content: EncryptedPayload {
+                nonce: [0; 12],
+                ciphertext: (0..ciphertext_len).map(|i| i as u8).collect(),
+            },
+            timestamp: 0,
+            key_epoch: 0,
+            channel: String::new(),
+            compressed: false,
+        })
+    }
+
+    fn message_sender(message: &Message) -> Uuid {
+        match message {
+            Message::ClipboardSync(m) => m.sender_id,
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_message_sent_in_chunks() {
+        for codec in WireCodec::supported() {
+            let message = sync_message(MAX_MESSAGE_SIZE + 1);
+            let frames = frames(&message, codec).unwrap();
+            assert!(frames.len() > 1);
+
+            let (mut writer, mut reader) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(async move {
+                for frame in frames {
+                    writer.write_all(&frame).await.unwrap();
+                }
+            });
+            let first = Message::decode(&read_framed_message(&mut reader).await.unwrap()).unwrap();
+            assert!(matches!(first, Message::ClipboardChunk { index: 0, sender_id, .. } if sender_id == message_sender(&message)));
+            let joined = read_rest(&mut reader, first).await.unwrap();
+            assert_eq!(joined.to_bytes().unwrap(), message.to_bytes().unwrap());
+        }
+
+        // Small messages go as they are
+        assert_eq!(frames(&sync_message(16), WireCodec::Json).unwrap().len(), 1);
+        let ping = Message::Ping { timestamp: 1 };
+        let (_, mut reader) = tokio::io::duplex(64);
+        assert!(matches!(read_rest(&mut reader, ping).await, Ok(Message::Ping { timestamp: 1 })));
+    }
+
+    #[test]
+    fn test_chunks_join_in_order_only() {
+        let id = Uuid::new_v4();
+        let mut joined = ChunkedMessage::start(id, 0, 3, vec![1]).unwrap();
+        assert!(joined.add(id, 2, 3, vec![3]).is_err());
+        assert!(joined.add(Uuid::new_v4(), 1, 3, vec![2]).is_err());
+        joined.add(id, 1, 3, vec![2]).unwrap();
+        assert!(!joined.is_complete());
+        joined.add(id, 2, 3, vec![3]).unwrap();
+        assert!(joined.is_complete());
+        assert_eq!(joined.data, [1, 2, 3]);
+
+        // Joining starts from the first chunk
+        assert!(ChunkedMessage::start(id, 1, 3, vec![2]).is_err());
+
+        // A message of no chunks would never complete
+        assert!(ChunkedMessage::start(id, 0, 0, vec![1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_empty_chunk_run_rejected() {
+        let chunk = Message::ClipboardChunk {
+            message_id: Uuid::new_v4(),
+            sender_id: Uuid::new_v4(),
+            index: 0,
+            total: 0,
+            data: vec![1],
+        };
+        // Refused before anything more is read
+        let (_writer, mut reader) = tokio::io::duplex(64);
+        assert!(matches!(read_rest(&mut reader, chunk).await, Err(Error::InvalidMessage(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_message_discarded() {
+        let frames = frames(&sync_message(MAX_MESSAGE_SIZE), WireCodec::Json).unwrap();
+        let (mut writer, mut reader) = tokio::io::duplex(4 * 1024 * 1024);
+        writer.write_all(&frames[0]).await.unwrap();
+        writer.write_all(&frames[1]).await.unwrap();
+
+        let first = Message::decode(&read_framed_message(&mut reader).await.unwrap()).unwrap();
+        let err = read_rest(&mut reader, first).await.unwrap_err();
+        assert!(matches!(err, Error::Timeout(ref m) if m.contains("after 2 of")), "{}", err);
+    }
+}
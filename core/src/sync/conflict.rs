@@ -0,0 +1,115 @@
+//! Resolution of clipboard changes made on two devices at nearly the same time
+//!
+//! Each device keeps the timestamp of its own last broadcast change. An
+//! incoming change is compared against it; both devices apply the same rule
+//! to the same pair of changes, so exactly one of them keeps its own content
+//! and the other accepts it.
+
+use uuid::Uuid;
+
+use crate::protocol::constants::CONFLICT_WINDOW_SECS;
+
+/// How to choose between a local and a remote change that conflict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Within the conflict window, keep the change with the later
+    /// timestamp, breaking ties by device ID
+    #[default]
+    LastWriteWins,
+    /// Within the conflict window, the given device's change always wins.
+    /// Conflicts between two other devices fall back to last-write-wins.
+    PreferDevice(Uuid),
+}
+
+impl ConflictPolicy {
+    /// Whether to apply an incoming change.
+    ///
+    /// `local_timestamp` is when we last broadcast a change of our own, or
+    /// `None` if we haven't. Only changes within the conflict window of it
+    /// conflict; further apart, the peer's clock may just be off, so its
+    /// change is applied whatever its timestamp.
+    pub fn accepts(
+        &self,
+        our_id: Uuid,
+        local_timestamp: Option<u64>,
+        sender_id: Uuid,
+        timestamp: u64,
+    ) -> bool {
+        let Some(local) = local_timestamp else {
+            return true;
+        };
+        if timestamp.abs_diff(local) > CONFLICT_WINDOW_SECS {
+            return true;
+        }
+
+        if let ConflictPolicy::PreferDevice(preferred) = *self {
+            if preferred == sender_id {
+                return true;
+            }
+            if preferred == our_id {
+                return false;
+            }
+        }
+
+        match timestamp.cmp(&local) {
+            std::cmp::Ordering::Greater => true,
+            std::cmp::Ordering::Less => false,
+            std::cmp::Ordering::Equal => sender_id > our_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Exchange one change in each direction; returns (a accepts b, b accepts a)
+    fn exchange(policy: ConflictPolicy, a: (Uuid, u64), b: (Uuid, u64)) -> (bool, bool) {
+        (
+            policy.accepts(a.0, Some(a.1), b.0, b.1),
+            policy.accepts(b.0, Some(b.1), a.0, a.1),
+        )
+    }
+
+    #[test]
+    fn test_simultaneous_updates_resolve_to_one_winner() {
+        let a = Uuid::new_v4();
+        let b = Uuid::new_v4();
+        let (low, high) = if a < b { (a, b) } else { (b, a) };
+
+        // Same second: the higher device ID wins on both sides
+        let (low_accepts, high_accepts) = exchange(ConflictPolicy::LastWriteWins, (low, 100), (high, 100));
+        assert!(low_accepts);
+        assert!(!high_accepts);
+
+        // One second apart: the later change wins on both sides
+        let (a_accepts, b_accepts) = exchange(ConflictPolicy::LastWriteWins, (a, 101), (b, 100));
+        assert!(!a_accepts);
+        assert!(b_accepts);
+
+        // Preferred device wins regardless of timestamps or IDs
+        let policy = ConflictPolicy::PreferDevice(low);
+        let (low_accepts, high_accepts) = exchange(policy, (low, 100), (high, 101));
+        assert!(!low_accepts);
+        assert!(high_accepts);
+    }
+
+    #[test]
+    fn test_updates_outside_window_accepted() {
+        let ours = Uuid::new_v4();
+        let peer = Uuid::new_v4();
+        let policy = ConflictPolicy::LastWriteWins;
+
+        assert!(policy.accepts(ours, None, peer, 5));
+        assert!(!policy.accepts(ours, Some(100), peer, 99));
+        assert!(policy.accepts(ours, Some(100), peer, 150));
+
+        // A peer whose clock runs a minute behind still gets its change in
+        assert!(policy.accepts(ours, Some(100), peer, 40));
+
+        // Preference only applies inside the conflict window
+        let policy = ConflictPolicy::PreferDevice(ours);
+        assert!(!policy.accepts(ours, Some(100), peer, 101));
+        assert!(policy.accepts(ours, Some(100), peer, 40));
+    }
+}
@@ -1,20 +1,118 @@
 //! Peer connection handling
 
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::task::JoinSet;
 use uuid::Uuid;
 
-use crate::crypto::SessionKey;
-use crate::protocol::Message;
-use crate::{Error, Result};
+use crate::crypto::{SessionKey, VerifyingKey};
+use crate::discovery::PeerInfo;
+use crate::protocol::constants::{CONNECT_ATTEMPT_DELAY_MS, PROTOCOL_VERSION};
+use crate::protocol::{AnnounceMessage, Message, WireCodec};
+use crate::sync::chunking;
+use crate::sync::framing::{read_framed_message, write_framed_message};
+use crate::sync::transport::Transport;
+use crate::{DeviceIdentity, Error, Result};
+
+/// Check that a sync server answers at `addr`, returning the round-trip time
+///
+/// Sends `Ping` on a new connection and waits for the `Pong` echoing it.
+/// A reply shows the TCP path works and an omniclip server of our protocol
+/// version is listening; the session key isn't involved. Fails with
+/// [`Error::Timeout`] if no reply arrives within `timeout`.
+pub async fn ping(addr: SocketAddr, timeout: Duration) -> Result<Duration> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+    let started = Instant::now();
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr)
+            .await
+            .map_err(|e| Error::Network(e.to_string()))?;
+        write_framed_message(&mut stream, &Message::Ping { timestamp }.to_bytes()?).await?;
+        match Message::from_bytes(&read_framed_message(&mut stream).await?)? {
+            Message::Pong { timestamp: echoed } if echoed == timestamp => Ok(started.elapsed()),
+            other => Err(Error::InvalidMessage(format!("expected a matching Pong, got {:?}", other))),
+        }
+    };
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| Error::Timeout(format!("no reply from {} within {:?}", addr, timeout)))?
+}
+
+/// [`ping`] a discovered peer, trying each of its addresses until one replies
+///
+/// `timeout` covers all the attempts together.
+pub async fn ping_peer(peer: &PeerInfo, timeout: Duration) -> Result<Duration> {
+    let deadline = Instant::now() + timeout;
+    let mut last_err = Error::Network(format!("no known address for {}", peer.device_id));
+    for ip in &peer.addresses {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        match ping(SocketAddr::new(*ip, peer.port), remaining).await {
+            Ok(rtt) => return Ok(rtt),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/// Ask the sync server at `addr` who it is
+///
+/// Sends our own `Announce` and returns the one the server answers with.
+/// This is how peers added by address are found when mDNS doesn't reach
+/// them. The reply isn't signed, so its fingerprint only identifies the
+/// device once pairing or a session key has proven it. With `encrypted`,
+/// the exchange runs over an encrypted [`Transport`]. Fails with
+/// [`Error::Timeout`] if no reply arrives within `timeout`.
+pub async fn identify(
+    addr: SocketAddr,
+    identity: &DeviceIdentity,
+    timeout: Duration,
+    encrypted: bool,
+) -> Result<AnnounceMessage> {
+    let announce = Message::Announce(AnnounceMessage {
+        device_id: identity.id,
+        device_name: identity.name.clone(),
+        pubkey_fingerprint: identity.fingerprint(),
+        protocol_version: PROTOCOL_VERSION,
+    });
+    let exchange = async {
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(|e| Error::Network(e.to_string()))?;
+        let mut stream = Transport::plain(stream);
+        if encrypted {
+            stream.initiate().await?;
+        }
+        write_framed_message(&mut stream, &announce.to_bytes()?).await?;
+        match Message::from_bytes(&read_framed_message(&mut stream).await?)? {
+            Message::Announce(reply) => Ok(reply),
+            other => Err(Error::InvalidMessage(format!("expected Announce, got {:?}", other))),
+        }
+    };
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| Error::Timeout(format!("no reply from {} within {:?}", addr, timeout)))?
+}
+
+/// Open a TCP connection, giving up after `connect_timeout`
+async fn dial(addr: SocketAddr, connect_timeout: Duration) -> Result<TcpStream> {
+    tokio::time::timeout(connect_timeout, TcpStream::connect(addr))
+        .await
+        .map_err(|_| Error::Network(format!("connect timeout to {} after {:?}", addr, connect_timeout)))?
+        .map_err(|e| Error::Network(e.to_string()))
+}
 
 /// Active connection to a peer
 pub struct PeerConnection {
     pub peer_id: Uuid,
     pub peer_name: String,
-    stream: TcpStream,
+    stream: Transport<TcpStream>,
     session_key: SessionKey,
+    codec: WireCodec,
 }
 
 impl PeerConnection {
@@ -28,34 +126,101 @@ impl PeerConnection {
         Self {
             peer_id,
             peer_name,
-            stream,
+            stream: Transport::plain(stream),
             session_key,
+            codec: WireCodec::Json,
         }
     }
 
+    /// Encrypt everything sent and received from here on, for peers whose
+    /// server expects it
+    ///
+    /// Fails if the peer doesn't answer the handshake, as older versions
+    /// don't, or proves an identity other than `peer_identity`. Without
+    /// one, the connection is only hidden from passive observers.
+    pub async fn encrypted(mut self, peer_identity: Option<&VerifyingKey>) -> Result<Self> {
+        match peer_identity {
+            Some(expected) => self.stream.initiate_to(expected).await?,
+            None => {
+                self.stream.initiate().await?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// Send messages encoded with `codec` rather than JSON
+    pub fn with_codec(mut self, codec: WireCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
     /// Connect to a peer
+    ///
+    /// Gives up with `Error::Network("connect timeout ...")` if the
+    /// connection isn't established within `connect_timeout`, as happens
+    /// when the peer advertised an address that is no longer reachable.
     pub async fn connect(
         addr: SocketAddr,
         peer_id: Uuid,
         peer_name: String,
         session_key: SessionKey,
+        connect_timeout: Duration,
     ) -> Result<Self> {
-        let stream = TcpStream::connect(addr)
-            .await
-            .map_err(|e| Error::Network(e.to_string()))?;
-
+        let stream = dial(addr, connect_timeout).await?;
         Ok(Self::new(peer_id, peer_name, stream, session_key))
     }
 
-    /// Send a message to the peer
-    pub async fn send(&mut self, message: &Message) -> Result<()> {
-        let frame = message.to_frame()
-            .map_err(|e| Error::Serialization(e))?;
+    /// Connect to a discovered peer over whichever of its addresses answers
+    /// first
+    ///
+    /// Addresses are tried in order, each with a head start of
+    /// [`CONNECT_ATTEMPT_DELAY_MS`] before the next is tried alongside it,
+    /// or less if it fails sooner. The first connection made is kept and the
+    /// other attempts are cancelled. `connect_timeout` applies to each
+    /// address. Fails with the last error if none can be reached.
+    pub async fn connect_peer(peer: &PeerInfo, session_key: SessionKey, connect_timeout: Duration) -> Result<Self> {
+        let delay = Duration::from_millis(CONNECT_ATTEMPT_DELAY_MS);
+        let addrs: Vec<SocketAddr> = peer.addresses.iter().map(|ip| SocketAddr::new(*ip, peer.port)).collect();
+        let mut addrs = addrs.into_iter().peekable();
+        let mut attempts = JoinSet::new();
+        let mut last_err = Error::Network(format!("no known address for {}", peer.device_id));
+        while addrs.peek().is_some() || !attempts.is_empty() {
+            if let Some(addr) = addrs.next() {
+                attempts.spawn(async move { (addr, dial(addr, connect_timeout).await) });
+            }
+            let finished = if addrs.peek().is_some() {
+                tokio::select! {
+                    finished = attempts.join_next() => finished,
+                    _ = tokio::time::sleep(delay) => continue,
+                }
+            } else {
+                attempts.join_next().await
+            };
+            match finished {
+                Some(Ok((_, Ok(stream)))) => {
+                    // Dropping the set cancels the attempts still running
+                    return Ok(Self::new(peer.device_id, peer.device_name.clone(), stream, session_key));
+                }
+                Some(Ok((addr, Err(e)))) => {
+                    tracing::debug!("couldn't connect to {} at {}: {}", peer.device_id, addr, e);
+                    last_err = e;
+                }
+                Some(Err(e)) => last_err = Error::Network(e.to_string()),
+                None => {}
+            }
+        }
+        Err(last_err)
+    }
 
-        self.stream
-            .write_all(&frame)
-            .await
-            .map_err(|e| Error::Network(e.to_string()))?;
+    /// Send a message to the peer, in chunks if it is too large for one
+    /// frame
+    pub async fn send(&mut self, message: &Message) -> Result<()> {
+        for frame in chunking::frames(message, self.codec)? {
+            self.stream
+                .write_all(&frame)
+                .await
+                .map_err(|e| Error::Network(e.to_string()))?;
+        }
 
         self.stream
             .flush()
@@ -67,29 +232,8 @@ impl PeerConnection {
 
     /// Receive a message from the peer
     pub async fn recv(&mut self) -> Result<Message> {
-        // Read length prefix (4 bytes, big-endian)
-        let mut len_buf = [0u8; 4];
-        self.stream
-            .read_exact(&mut len_buf)
-            .await
-            .map_err(|e| Error::Network(e.to_string()))?;
-
-        let len = u32::from_be_bytes(len_buf) as usize;
-
-        // Sanity check on message size (max 10MB)
-        if len > 10 * 1024 * 1024 {
-            return Err(Error::InvalidMessage("message too large".to_string()));
-        }
-
-        // Read payload
-        let mut payload = vec![0u8; len];
-        self.stream
-            .read_exact(&mut payload)
-            .await
-            .map_err(|e| Error::Network(e.to_string()))?;
-
-        Message::from_bytes(&payload)
-            .map_err(|e| Error::Serialization(e))
+        let payload = read_framed_message(&mut self.stream).await?;
+        Message::decode(&payload)
     }
 
     /// Get the session key for encrypting clipboard content
@@ -99,14 +243,12 @@ impl PeerConnection {
 
     /// Get peer address
     pub fn peer_addr(&self) -> Result<SocketAddr> {
-        self.stream
-            .peer_addr()
-            .map_err(|e| Error::Network(e.to_string()))
+        self.stream.peer_addr()
     }
 
     /// Split into read and write halves for concurrent processing
     pub fn into_split(self) -> (PeerConnectionReader, PeerConnectionWriter) {
-        let (read_half, write_half) = self.stream.into_split();
+        let (read_half, write_half) = tokio::io::split(self.stream);
         (
             PeerConnectionReader {
                 peer_id: self.peer_id,
@@ -115,6 +257,7 @@ impl PeerConnection {
             PeerConnectionWriter {
                 peer_id: self.peer_id,
                 stream: write_half,
+                codec: self.codec,
             },
         )
     }
@@ -123,51 +266,33 @@ impl PeerConnection {
 /// Read half of a peer connection
 pub struct PeerConnectionReader {
     pub peer_id: Uuid,
-    stream: tokio::net::tcp::OwnedReadHalf,
+    stream: ReadHalf<Transport<TcpStream>>,
 }
 
 impl PeerConnectionReader {
     /// Receive a message
     pub async fn recv(&mut self) -> Result<Message> {
-        let mut len_buf = [0u8; 4];
-        self.stream
-            .read_exact(&mut len_buf)
-            .await
-            .map_err(|e| Error::Network(e.to_string()))?;
-
-        let len = u32::from_be_bytes(len_buf) as usize;
-
-        if len > 10 * 1024 * 1024 {
-            return Err(Error::InvalidMessage("message too large".to_string()));
-        }
-
-        let mut payload = vec![0u8; len];
-        self.stream
-            .read_exact(&mut payload)
-            .await
-            .map_err(|e| Error::Network(e.to_string()))?;
-
-        Message::from_bytes(&payload)
-            .map_err(|e| Error::Serialization(e))
+        let payload = read_framed_message(&mut self.stream).await?;
+        Message::decode(&payload)
     }
 }
 
 /// Write half of a peer connection
 pub struct PeerConnectionWriter {
     pub peer_id: Uuid,
-    stream: tokio::net::tcp::OwnedWriteHalf,
+    stream: WriteHalf<Transport<TcpStream>>,
+    codec: WireCodec,
 }
 
 impl PeerConnectionWriter {
-    /// Send a message
+    /// Send a message, in chunks if it is too large for one frame
     pub async fn send(&mut self, message: &Message) -> Result<()> {
-        let frame = message.to_frame()
-            .map_err(|e| Error::Serialization(e))?;
-
-        self.stream
-            .write_all(&frame)
-            .await
-            .map_err(|e| Error::Network(e.to_string()))?;
+        for frame in chunking::frames(message, self.codec)? {
+            self.stream
+                .write_all(&frame)
+                .await
+                .map_err(|e| Error::Network(e.to_string()))?;
+        }
 
         self.stream
             .flush()
@@ -175,3 +300,64 @@ impl PeerConnectionWriter {
             .map_err(|e| Error::Network(e.to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket2::{Domain, Socket, Type};
+
+    /// Listener whose accept queue is already full, so further connection
+    /// attempts hang as they would against an unreachable address
+    fn blackhole() -> (Socket, std::net::TcpStream, SocketAddr) {
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        socket.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+        socket.listen(0).unwrap();
+        let addr = socket.local_addr().unwrap().as_socket().unwrap();
+        let queued = std::net::TcpStream::connect(addr).unwrap();
+        (socket, queued, addr)
+    }
+
+    #[tokio::test]
+    async fn test_connect_times_out_and_falls_back() {
+        let (_socket, _queued, stale) = blackhole();
+        let key = SessionKey::from_bytes(&[7u8; 32]);
+        let timeout = Duration::from_millis(200);
+
+        let started = Instant::now();
+        let connect = PeerConnection::connect(stale, Uuid::new_v4(), "peer".to_string(), key.clone(), timeout);
+        let Err(Error::Network(e)) = connect.await else {
+            panic!("expected a connect timeout");
+        };
+        assert!(e.starts_with("connect timeout"), "{}", e);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // A peer advertising the stale address first is reached on its next one
+        let reachable = SocketAddr::from(([127, 0, 0, 2], stale.port()));
+        let listener = tokio::net::TcpListener::bind(reachable).await.unwrap();
+        let mut peer = PeerInfo {
+            device_id: Uuid::new_v4(),
+            device_name: "peer".to_string(),
+            fingerprint: String::new(),
+            addresses: vec![stale.ip(), reachable.ip()],
+            port: stale.port(),
+            accepting_pairing: false,
+            protocol_version: None,
+            last_seen: Instant::now(),
+        };
+        let conn = PeerConnection::connect_peer(&peer, key.clone(), timeout).await.unwrap();
+        assert_eq!(conn.peer_addr().unwrap(), reachable);
+
+        // Without waiting out the stale address's timeout
+        let started = Instant::now();
+        let conn = PeerConnection::connect_peer(&peer, key.clone(), Duration::from_secs(30)).await.unwrap();
+        assert_eq!(conn.peer_addr().unwrap(), reachable);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(listener);
+
+        peer.addresses = vec![stale.ip()];
+        let Err(Error::Network(e)) = PeerConnection::connect_peer(&peer, key, timeout).await else {
+            panic!("expected a connect timeout");
+        };
+        assert!(e.starts_with("connect timeout"), "{}", e);
+    }
+}
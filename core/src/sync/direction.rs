@@ -0,0 +1,28 @@
+//! Which way clipboard changes flow between us and a paired device
+
+use serde::{Deserialize, Serialize};
+
+/// Directions clipboard changes are synced in with one paired device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncDirection {
+    /// Send our changes to the device and accept its changes
+    #[default]
+    Bidirectional,
+    /// Send our changes to the device, but drop its changes
+    SendOnly,
+    /// Accept the device's changes, but don't send it ours
+    ReceiveOnly,
+}
+
+impl SyncDirection {
+    /// Whether our clipboard changes go to the device
+    pub fn sends(&self) -> bool {
+        *self != SyncDirection::ReceiveOnly
+    }
+
+    /// Whether clipboard changes from the device are accepted
+    pub fn receives(&self) -> bool {
+        *self != SyncDirection::SendOnly
+    }
+}
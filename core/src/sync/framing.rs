@@ -1,22 +1,48 @@
 //! Length-prefixed message framing for TCP transport
 //!
 //! This module provides utilities for reading and writing length-prefixed
-//! messages over TCP streams. Each message is prefixed with a 4-byte
-//! big-endian length, followed by the payload.
+//! messages over TCP streams. Each message starts with the `OMNI` magic
+//! bytes and the protocol version, then a 4-byte big-endian length,
+//! followed by the payload. The preamble lets a reader reject something
+//! that isn't an omniclip peer before trusting its length prefix.
+
+use std::future::Future;
+use std::time::Duration;
 
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::protocol::constants::MAX_MESSAGE_SIZE;
+use crate::protocol::constants::{
+    FRAME_MAGIC, FRAME_PREAMBLE, FRAME_WRITE_CHUNK_SIZE, HANDSHAKE_TIMEOUT_MS, MAX_MESSAGE_SIZE,
+    PROTOCOL_VERSION,
+};
 use crate::{Error, Result};
 
 /// Read a length-prefixed message from an async reader.
 ///
 /// The wire format is:
+/// - 4 bytes: magic `OMNI`
+/// - 2 bytes: big-endian u16 protocol version
 /// - 4 bytes: big-endian u32 length
 /// - N bytes: message payload
 ///
-/// Returns an error if the message exceeds MAX_MESSAGE_SIZE.
+/// Returns an error if the preamble doesn't match or the message exceeds
+/// MAX_MESSAGE_SIZE.
 pub async fn read_framed_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut preamble = [0u8; FRAME_PREAMBLE.len()];
+    reader.read_exact(&mut preamble).await
+        .map_err(|e| Error::Network(e.to_string()))?;
+
+    if preamble[..4] != FRAME_MAGIC {
+        return Err(Error::InvalidMessage("not an omniclip peer".to_string()));
+    }
+    let version = u16::from_be_bytes([preamble[4], preamble[5]]);
+    if version != PROTOCOL_VERSION {
+        return Err(Error::InvalidMessage(format!(
+            "incompatible protocol version {} (expected {})",
+            version, PROTOCOL_VERSION
+        )));
+    }
+
     // Read 4-byte length prefix
     let mut len_buf = [0u8; 4];
     reader.read_exact(&mut len_buf).await
@@ -40,17 +66,61 @@ pub async fn read_framed_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result
     Ok(payload)
 }
 
+/// Read the first message on a new connection, giving up after
+/// HANDSHAKE_TIMEOUT_MS so a silent peer can't hold the connection open.
+pub async fn read_handshake_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    tokio::time::timeout(Duration::from_millis(HANDSHAKE_TIMEOUT_MS), read_framed_message(reader))
+        .await
+        .map_err(|_| Error::Network("handshake timed out".to_string()))?
+}
+
+/// How [`write_framed_message_with`] sends a frame
+#[derive(Debug, Clone, Copy)]
+pub struct FrameWriteOptions {
+    /// Payload bytes written before yielding to other tasks
+    pub chunk_size: usize,
+    /// Give up if the whole frame isn't written within this long
+    pub timeout: Option<Duration>,
+}
+
+impl Default for FrameWriteOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: FRAME_WRITE_CHUNK_SIZE,
+            timeout: None,
+        }
+    }
+}
+
 /// Write a length-prefixed message to an async writer.
 ///
-/// The wire format is:
-/// - 4 bytes: big-endian u32 length
-/// - N bytes: message payload
+/// The wire format is the one read by [`read_framed_message`].
 ///
 /// Returns an error if the message exceeds MAX_MESSAGE_SIZE.
 pub async fn write_framed_message<W: AsyncWrite + Unpin>(
     writer: &mut W,
     payload: &[u8],
 ) -> Result<()> {
+    write_framed_message_with(writer, payload, &FrameWriteOptions::default(), std::future::pending()).await
+}
+
+/// Write a length-prefixed message, stopping early if `cancelled` completes
+/// or the write times out.
+///
+/// The payload is written in chunks, yielding between them, so a
+/// multi-megabyte message doesn't starve other tasks on a fast socket. A
+/// frame cut short leaves the stream mid-message, so it shouldn't be used
+/// for anything else afterwards.
+pub async fn write_framed_message_with<W, C>(
+    writer: &mut W,
+    payload: &[u8],
+    options: &FrameWriteOptions,
+    cancelled: C,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    C: Future<Output = ()>,
+{
     // Validate message size
     if payload.len() > MAX_MESSAGE_SIZE {
         return Err(Error::InvalidMessage(format!(
@@ -59,14 +129,37 @@ pub async fn write_framed_message<W: AsyncWrite + Unpin>(
         )));
     }
 
+    let write = async {
+        match options.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, write_frame(writer, payload, options.chunk_size))
+                .await
+                .map_err(|_| Error::Network("write timed out".to_string()))?,
+            None => write_frame(writer, payload, options.chunk_size).await,
+        }
+    };
+
+    tokio::select! {
+        biased;
+        _ = cancelled => Err(Error::Network("write cancelled".to_string())),
+        result = write => result,
+    }
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8], chunk_size: usize) -> Result<()> {
+    writer.write_all(&FRAME_PREAMBLE).await
+        .map_err(|e| Error::Network(e.to_string()))?;
+
     // Write length prefix
     let len_bytes = (payload.len() as u32).to_be_bytes();
     writer.write_all(&len_bytes).await
         .map_err(|e| Error::Network(e.to_string()))?;
 
     // Write payload
-    writer.write_all(payload).await
-        .map_err(|e| Error::Network(e.to_string()))?;
+    for chunk in payload.chunks(chunk_size.max(1)) {
+        writer.write_all(chunk).await
+            .map_err(|e| Error::Network(e.to_string()))?;
+        tokio::task::yield_now().await;
+    }
 
     // Flush to ensure data is sent
     writer.flush().await
@@ -116,4 +209,98 @@ mod tests {
         let result = write_framed_message(&mut buffer, &large_payload).await;
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn test_not_an_omniclip_peer() {
+        // An HTTP request whose bytes would otherwise read as a huge length
+        let mut cursor = Cursor::new(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n".to_vec());
+        let err = read_framed_message(&mut cursor).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidMessage(ref m) if m == "not an omniclip peer"));
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_version() {
+        let mut buffer = Vec::new();
+        write_framed_message(&mut buffer, b"{}").await.unwrap();
+        buffer[4..6].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+
+        let err = read_framed_message(&mut Cursor::new(buffer)).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidMessage(ref m) if m.starts_with("incompatible protocol version")));
+    }
+
+    #[tokio::test]
+    async fn test_frame_matches_message_to_frame() {
+        let msg = crate::protocol::Message::Ping { timestamp: 7 };
+        let mut buffer = Vec::new();
+        write_framed_message(&mut buffer, &msg.to_bytes().unwrap()).await.unwrap();
+        assert_eq!(buffer, msg.to_frame().unwrap());
+    }
+
+    /// Accepts a little data per call and never blocks
+    struct SlowWriter {
+        written: usize,
+    }
+
+    impl AsyncWrite for SlowWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let n = buf.len().min(1024);
+            self.written += n;
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_interrupts_large_write() {
+        let payload = vec![7u8; MAX_MESSAGE_SIZE];
+        let mut writer = SlowWriter { written: 0 };
+        let (cancel, cancelled) = tokio::sync::oneshot::channel::<()>();
+
+        // Runs in between chunks, since the writer itself never waits
+        tokio::spawn(async move {
+            tokio::task::yield_now().await;
+            let _ = cancel.send(());
+        });
+        let err = write_framed_message_with(&mut writer, &payload, &FrameWriteOptions::default(), async {
+            let _ = cancelled.await;
+        }).await.unwrap_err();
+
+        assert!(matches!(err, Error::Network(ref m) if m == "write cancelled"));
+        assert!(writer.written < payload.len(), "cancelled after the whole payload was written");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_write_times_out() {
+        // Nobody reads the other end, so the write stalls once the buffer fills
+        let (mut client, _server) = tokio::io::duplex(64);
+        let options = FrameWriteOptions {
+            timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+
+        let err = write_framed_message_with(&mut client, &[0u8; 4096], &options, std::future::pending())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Network(ref m) if m == "write timed out"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&FRAME_PREAMBLE).await.unwrap();
+
+        let err = read_handshake_message(&mut server).await.unwrap_err();
+        assert!(matches!(err, Error::Network(ref m) if m == "handshake timed out"));
+    }
 }
@@ -1,9 +1,23 @@
 //! TCP-based peer synchronization
 
+pub mod chunking;
+pub mod conflict;
 pub mod connection;
+pub mod direction;
 pub mod framing;
+pub mod pairing;
+pub mod pool;
 pub mod server;
+pub mod transport;
 
-pub use connection::PeerConnection;
-pub use framing::{read_framed_message, write_framed_message};
+pub use conflict::ConflictPolicy;
+pub use connection::{identify, ping, ping_peer, PeerConnection};
+pub use direction::SyncDirection;
+pub use framing::{
+    read_framed_message, read_handshake_message, write_framed_message, write_framed_message_with,
+    FrameWriteOptions,
+};
+pub use pairing::pair_with;
+pub use pool::{ConnectionPool, PoolStats};
 pub use server::{PairedDevice, SyncEvent, SyncServer, SyncServerHandle};
+pub use transport::Transport;
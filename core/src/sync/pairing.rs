@@ -0,0 +1,255 @@
+//! Initiating side of the pairing handshake
+//!
+//! The device that scans a QR code sends a `PairRequest` to the address in
+//! it and checks the `PairAccept` it gets back. The accepting side's
+//! ephemeral key must match the one in the QR code, and its identity key the
+//! fingerprint there, which is what ties the handshake to the device the
+//! user scanned. Both sides then hold the same session key and a record of
+//! each other.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+use crate::crypto::{EphemeralSecret, SessionKey, VerifyingKey};
+use crate::protocol::constants::{protocol_version_supported, PEER_NOTIFY_TIMEOUT_MS, PROTOCOL_VERSION};
+use crate::protocol::{Compression, Message, NegotiatedFeatures, PairRequestMessage, PairingQrData, WireCodec};
+use crate::sync::framing::{read_framed_message, write_framed_message};
+use crate::sync::transport::Transport;
+use crate::sync::PairedDevice;
+use crate::{DeviceIdentity, Error, Result};
+
+/// Pair with the device that showed `qr`, returning it as a paired device
+///
+/// With `encrypted`, the handshake runs over an encrypted [`Transport`], so
+/// device names and keys aren't visible on the network.
+pub async fn pair_with(qr: &PairingQrData, identity: &DeviceIdentity, encrypted: bool) -> Result<PairedDevice> {
+    let addr = qr.addr()?;
+
+    let attempt = async {
+        let stream = TcpStream::connect(addr).await
+            .map_err(|e| Error::Network(e.to_string()))?;
+        let mut stream = Transport::plain(stream);
+        let mut transport_identity = None;
+        if encrypted {
+            let key = stream.initiate().await?;
+            if qr.fp.as_ref().is_some_and(|fp| *fp != key.fingerprint()) {
+                return Err(Error::Crypto("transport identity doesn't match the QR code".to_string()));
+            }
+            transport_identity = Some(key);
+        }
+        let device = handshake(&mut stream, qr, identity).await?;
+        if transport_identity.is_some_and(|key| key.to_bytes() != device.identity_pubkey.to_bytes()) {
+            return Err(Error::Crypto("pairing identity differs from the transport's".to_string()));
+        }
+        Ok(device)
+    };
+    tokio::time::timeout(Duration::from_millis(PEER_NOTIFY_TIMEOUT_MS), attempt).await
+        .map_err(|_| Error::Network(format!("timed out pairing with {}", addr)))?
+}
+
+/// Refuse to pair with a device that has our ID or identity key.
+///
+/// Both usually mean the other instance runs from a copy of our data
+/// directory, not that it is a separate device.
+pub(crate) fn reject_self(device_id: Uuid, identity_pubkey: &VerifyingKey, identity: &DeviceIdentity) -> Result<()> {
+    if device_id == identity.id
+        || identity_pubkey.to_bytes() == identity.signing_key.verifying_key().to_bytes()
+    {
+        return Err(Error::InvalidMessage("cannot pair with self".to_string()));
+    }
+    Ok(())
+}
+
+/// Run the handshake over an open stream
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    qr: &PairingQrData,
+    identity: &DeviceIdentity,
+) -> Result<PairedDevice> {
+    let secret = EphemeralSecret::generate();
+    let our_ephemeral = secret.public_key();
+
+    let request = Message::PairRequest(PairRequestMessage {
+        session_id: qr.session_id,
+        device_id: identity.id,
+        device_name: identity.name.clone(),
+        ephemeral_pubkey: our_ephemeral.clone(),
+        identity_pubkey: identity.signing_key.verifying_key(),
+        wire_codecs: WireCodec::supported(),
+        compressions: Compression::supported(),
+        protocol_version: PROTOCOL_VERSION,
+    });
+    write_framed_message(stream, &request.to_bytes()?).await?;
+
+    let accept = match Message::from_bytes(&read_framed_message(stream).await?)? {
+        Message::PairAccept(accept) => accept,
+        Message::PairReject { session_id, reason } if session_id == qr.session_id => {
+            return Err(Error::NotPaired(reason));
+        }
+        _ => return Err(Error::InvalidMessage("expected PairAccept".to_string())),
+    };
+    if accept.session_id != qr.session_id {
+        return Err(Error::InvalidMessage("PairAccept for a different session".to_string()));
+    }
+    if accept.ephemeral_pubkey.to_bytes() != qr.pubkey {
+        return Err(Error::Crypto("pairing key doesn't match the QR code".to_string()));
+    }
+
+    let mut signed = Vec::new();
+    signed.extend(accept.session_id.as_bytes());
+    signed.extend(accept.ephemeral_pubkey.to_bytes());
+    signed.extend(our_ephemeral.to_bytes());
+    accept.identity_pubkey.verify(&signed, &accept.signature)?;
+    if qr.fp.as_ref().is_some_and(|fp| *fp != accept.identity_pubkey.fingerprint()) {
+        return Err(Error::Crypto("identity key doesn't match the QR code".to_string()));
+    }
+    reject_self(accept.device_id, &accept.identity_pubkey, identity)?;
+
+    if !WireCodec::supported().contains(&accept.wire_codec) {
+        return Err(Error::InvalidMessage(format!("PairAccept picked unsupported codec {:?}", accept.wire_codec)));
+    }
+    if !Compression::supported().contains(&accept.compression) {
+        return Err(Error::InvalidMessage(format!("PairAccept picked unsupported compression {:?}", accept.compression)));
+    }
+    // The version the other side settled on is what both will speak, so
+    // this is the check that counts
+    if !protocol_version_supported(accept.protocol_version) {
+        return Err(Error::NotPaired(format!(
+            "{} picked protocol version {}, which this device (version {}) doesn't support",
+            accept.device_name, accept.protocol_version, PROTOCOL_VERSION
+        )));
+    }
+
+    let shared = secret.diffie_hellman(&accept.ephemeral_pubkey);
+    Ok(PairedDevice {
+        device_id: accept.device_id,
+        device_name: accept.device_name,
+        identity_pubkey: accept.identity_pubkey,
+        session_key: SessionKey::from_shared_secret(&shared, accept.protocol_version),
+        features: NegotiatedFeatures::negotiated(accept.wire_codec, accept.compression),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    use crate::crypto::SigningKey;
+    use crate::protocol::PairingSession;
+    use crate::sync::{SyncEvent, SyncServer};
+
+    #[tokio::test]
+    async fn test_both_sides_paired() {
+        let desk = DeviceIdentity::new("desk".to_string());
+        let laptop = DeviceIdentity::new("laptop".to_string());
+
+        let server = SyncServer::bind(0).await.unwrap();
+        let session = PairingSession::new();
+        let qr = session.qr_data("127.0.0.1", server.port(), &desk);
+        let sessions = Arc::new(RwLock::new(HashMap::from([(session.session_id, session)])));
+        let (mut events, _handle) = server.start_with_pairing(sessions, desk.clone());
+
+        let on_laptop = pair_with(&qr, &laptop, false).await.unwrap();
+        let Some(SyncEvent::DevicePaired { device: on_desk }) = events.recv().await else {
+            panic!("expected DevicePaired");
+        };
+
+        assert_eq!(on_laptop.device_id, desk.id);
+        assert_eq!(on_laptop.device_name, "desk");
+        assert_eq!(on_laptop.identity_pubkey.to_bytes(), desk.signing_key.verifying_key().to_bytes());
+        assert_eq!(on_desk.device_id, laptop.id);
+        assert_eq!(on_desk.identity_pubkey.to_bytes(), laptop.signing_key.verifying_key().to_bytes());
+        assert_eq!(on_laptop.features, on_desk.features);
+        assert_eq!(on_laptop.features.wire_codec, WireCodec::supported()[0]);
+        assert_eq!(on_laptop.features.compression, Compression::Deflate);
+
+        let to_desk = on_laptop.session_key.encrypt(b"from laptop").unwrap();
+        assert_eq!(on_desk.session_key.decrypt(&to_desk).unwrap(), b"from laptop");
+        let to_laptop = on_desk.session_key.encrypt(b"from desk").unwrap();
+        assert_eq!(on_laptop.session_key.decrypt(&to_laptop).unwrap(), b"from desk");
+    }
+
+    #[tokio::test]
+    async fn test_pairing_over_encrypted_transport() {
+        let desk = DeviceIdentity::new("desk".to_string());
+        let server = SyncServer::bind(0).await.unwrap().with_transport_encryption(true);
+        let session = PairingSession::new();
+        let qr = session.qr_data("127.0.0.1", server.port(), &desk);
+        let sessions = Arc::new(RwLock::new(HashMap::from([(session.session_id, session)])));
+        let (mut events, _handle) = server.start_with_pairing(sessions, desk.clone());
+
+        let on_laptop = pair_with(&qr, &DeviceIdentity::new("laptop".to_string()), true).await.unwrap();
+        assert_eq!(on_laptop.device_id, desk.id);
+        assert!(matches!(events.recv().await, Some(SyncEvent::DevicePaired { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_rejection_reason_returned() {
+        let desk = DeviceIdentity::new("desk".to_string());
+        let server = SyncServer::bind(0).await.unwrap();
+        let session = PairingSession::new();
+        let qr = session.qr_data("127.0.0.1", server.port(), &desk);
+        // Pairing closed: no session open at all
+        let (_events, _handle) = server.start_with_pairing(Arc::new(RwLock::new(HashMap::new())), desk);
+
+        let err = pair_with(&qr, &DeviceIdentity::new("laptop".to_string()), false).await.unwrap_err();
+        assert!(matches!(err, Error::NotPaired(ref reason) if reason == "not accepting pairing"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_accept_with_wrong_key_rejected() {
+        let server = SyncServer::bind(0).await.unwrap();
+        let session = PairingSession::new();
+        let mut qr = session.qr_data("127.0.0.1", server.port(), &DeviceIdentity::new("desk".to_string()));
+        let sessions = Arc::new(RwLock::new(HashMap::from([(session.session_id, session)])));
+        let (_events, _handle) = server.start_with_pairing(sessions, DeviceIdentity::new("desk".to_string()));
+
+        // A QR code whose key doesn't belong to the answering device
+        qr.pubkey = EphemeralSecret::generate().public_key().to_bytes();
+        let err = pair_with(&qr, &DeviceIdentity::new("laptop".to_string()), false).await.unwrap_err();
+        assert!(matches!(err, Error::Crypto(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_accept_from_other_identity_rejected() {
+        let server = SyncServer::bind(0).await.unwrap();
+        let session = PairingSession::new();
+        // The code names one identity, a different one answers
+        let qr = session.qr_data("127.0.0.1", server.port(), &DeviceIdentity::new("desk".to_string()));
+        let sessions = Arc::new(RwLock::new(HashMap::from([(session.session_id, session)])));
+        let (_events, _handle) = server.start_with_pairing(sessions, DeviceIdentity::new("impostor".to_string()));
+
+        let err = pair_with(&qr, &DeviceIdentity::new("laptop".to_string()), false).await.unwrap_err();
+        assert!(matches!(err, Error::Crypto(ref m) if m.contains("identity key")), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_pairing_with_self_rejected() {
+        let desk = DeviceIdentity::new("desk".to_string());
+        let server = SyncServer::bind(0).await.unwrap();
+        let session = PairingSession::new();
+        let session_id = session.session_id;
+        let qr = session.qr_data("127.0.0.1", server.port(), &desk);
+        let sessions = Arc::new(RwLock::new(HashMap::from([(session_id, session)])));
+        let (mut events, handle) = server.start_with_pairing(sessions.clone(), desk.clone());
+
+        // Same ID, as when the data directory was copied
+        let clone = DeviceIdentity { signing_key: SigningKey::generate(), ..desk.clone() };
+        assert!(pair_with(&qr, &clone, false).await.is_err());
+        assert!(sessions.read().await.contains_key(&session_id));
+        handle.abort();
+        assert!(events.recv().await.is_none());
+
+        // The initiator checks what answered, too
+        let other = DeviceIdentity::new("laptop".to_string());
+        let err = reject_self(other.id, &desk.signing_key.verifying_key(), &desk).unwrap_err();
+        assert!(matches!(err, Error::InvalidMessage(ref m) if m == "cannot pair with self"));
+        assert!(reject_self(other.id, &other.signing_key.verifying_key(), &desk).is_ok());
+    }
+}
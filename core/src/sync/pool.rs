@@ -0,0 +1,401 @@
+//! Long-lived connections to paired devices
+//!
+//! Each paired device gets at most one outbound connection, opened the first
+//! time something is sent to it and kept for the messages after. Replies read
+//! from a connection are passed on tagged with the device they came from. A
+//! connection that fails to write is dropped and opened again; failed
+//! attempts to connect back off exponentially up to
+//! [`POOL_BACKOFF_MAX_SECS`]. The address a device was last reached at is
+//! tried first when reconnecting.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::crypto::{SessionKey, VerifyingKey};
+use crate::discovery::PeerInfo;
+use crate::protocol::constants::{PEER_NOTIFY_TIMEOUT_MS, POOL_BACKOFF_BASE_MS, POOL_BACKOFF_MAX_SECS};
+use crate::protocol::{Message, WireCodec};
+use crate::sync::connection::{PeerConnection, PeerConnectionWriter};
+use crate::{Error, Result};
+
+/// Connection counts, for diagnostics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Devices with an open connection
+    pub connected: usize,
+    /// Devices whose connection closed or couldn't be opened
+    pub disconnected: usize,
+}
+
+/// An open connection to one device
+struct Link {
+    writer: PeerConnectionWriter,
+    codec: WireCodec,
+    /// Cleared by the reader once the device closes the connection
+    open: Arc<AtomicBool>,
+    reader: JoinHandle<()>,
+}
+
+impl Link {
+    fn usable(&self, codec: WireCodec) -> bool {
+        self.codec == codec && self.open.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// One device's place in the pool
+#[derive(Default)]
+struct Slot {
+    link: Option<Link>,
+    /// Failed attempts to connect in a row
+    failures: u32,
+    /// No attempt to connect before this
+    retry_at: Option<Instant>,
+    /// Address the last connection was made to
+    preferred: Option<IpAddr>,
+}
+
+/// One connection per paired device, reused across sends
+pub struct ConnectionPool {
+    slots: Mutex<HashMap<Uuid, Arc<Mutex<Slot>>>>,
+    replies: mpsc::Sender<(Uuid, Message)>,
+    /// Open connections with an encrypted transport
+    transport_encryption: bool,
+}
+
+impl ConnectionPool {
+    /// Create a pool that passes messages read from its connections to
+    /// `replies`
+    pub fn new(replies: mpsc::Sender<(Uuid, Message)>) -> Self {
+        Self {
+            slots: Mutex::new(HashMap::new()),
+            replies,
+            transport_encryption: false,
+        }
+    }
+
+    /// Encrypt each connection's transport when `enabled`, for devices
+    /// whose servers require it
+    pub fn with_transport_encryption(mut self, enabled: bool) -> Self {
+        self.transport_encryption = enabled;
+        self
+    }
+
+    /// Send `message` to `peer` over its pooled connection, connecting first
+    /// if there is none
+    ///
+    /// A pooled connection that fails to write is dropped and the message
+    /// sent once more over a new one. While backing off after a failed
+    /// attempt to connect, fails with [`Error::Network`] without trying.
+    /// Encrypted connections must prove they reach `peer_identity`.
+    pub async fn send(
+        &self,
+        peer: &PeerInfo,
+        peer_identity: &VerifyingKey,
+        session_key: &SessionKey,
+        codec: WireCodec,
+        message: &Message,
+    ) -> Result<()> {
+        let slot = self.slot(peer.device_id).await;
+        let mut slot = slot.lock().await;
+        if let Some(link) = slot.link.as_mut().filter(|link| link.usable(codec)) {
+            match write(link, message).await {
+                Ok(()) => return Ok(()),
+                Err(e) => tracing::debug!("pooled connection to {} failed, reconnecting: {}", peer.device_id, e),
+            }
+        }
+        slot.link = None;
+
+        let now = Instant::now();
+        if let Some(retry_at) = slot.retry_at.filter(|at| *at > now) {
+            return Err(Error::Network(format!(
+                "not reconnecting to {} for another {:?}",
+                peer.device_id,
+                retry_at - now
+            )));
+        }
+        let mut link = match self.connect(peer, peer_identity, slot.preferred, session_key, codec).await {
+            Ok((link, ip)) => {
+                slot.preferred = Some(ip);
+                link
+            }
+            Err(e) => {
+                slot.failures = slot.failures.saturating_add(1);
+                slot.retry_at = Some(Instant::now() + backoff(slot.failures));
+                return Err(e);
+            }
+        };
+        slot.failures = 0;
+        slot.retry_at = None;
+        write(&mut link, message).await?;
+        slot.link = Some(link);
+        Ok(())
+    }
+
+    /// Close the connection to a device and forget its backoff
+    pub async fn evict(&self, device_id: Uuid) {
+        self.slots.lock().await.remove(&device_id);
+    }
+
+    /// Close every pooled connection, as when the service stops
+    pub async fn close(&self) {
+        let slots: Vec<_> = self.slots.lock().await.drain().map(|(_, slot)| slot).collect();
+        for slot in slots {
+            slot.lock().await.link = None;
+        }
+    }
+
+    /// Allow connecting to a device again right away, as when it was just
+    /// seen on the network
+    pub async fn reset_backoff(&self, device_id: Uuid) {
+        let slot = self.slots.lock().await.get(&device_id).cloned();
+        if let Some(slot) = slot {
+            let mut slot = slot.lock().await;
+            slot.failures = 0;
+            slot.retry_at = None;
+        }
+    }
+
+    /// How many devices have an open connection and how many don't
+    pub async fn stats(&self) -> PoolStats {
+        let slots: Vec<_> = self.slots.lock().await.values().cloned().collect();
+        let mut stats = PoolStats::default();
+        for slot in slots {
+            if slot.lock().await.link.as_ref().is_some_and(|link| link.open.load(Ordering::Relaxed)) {
+                stats.connected += 1;
+            } else {
+                stats.disconnected += 1;
+            }
+        }
+        stats
+    }
+
+    async fn slot(&self, device_id: Uuid) -> Arc<Mutex<Slot>> {
+        self.slots.lock().await.entry(device_id).or_default().clone()
+    }
+
+    /// Connect over whichever of the peer's addresses answers first,
+    /// starting with `preferred` if the peer still has it, and say which
+    async fn connect(
+        &self,
+        peer: &PeerInfo,
+        peer_identity: &VerifyingKey,
+        preferred: Option<IpAddr>,
+        session_key: &SessionKey,
+        codec: WireCodec,
+    ) -> Result<(Link, IpAddr)> {
+        let mut peer = peer.clone();
+        if let Some(i) = preferred.and_then(|ip| peer.addresses.iter().position(|addr| *addr == ip)) {
+            let ip = peer.addresses.remove(i);
+            peer.addresses.insert(0, ip);
+        }
+        let timeout = Duration::from_millis(PEER_NOTIFY_TIMEOUT_MS);
+        let mut conn = PeerConnection::connect_peer(&peer, session_key.clone(), timeout).await?;
+        if self.transport_encryption {
+            conn = conn.encrypted(Some(peer_identity)).await?;
+        }
+        let ip = conn.peer_addr()?.ip().to_canonical();
+        Ok((self.link(conn.with_codec(codec), codec), ip))
+    }
+
+    /// Split a new connection, reading replies from it in the background
+    fn link(&self, conn: PeerConnection, codec: WireCodec) -> Link {
+        let (mut reader, writer) = conn.into_split();
+        let open = Arc::new(AtomicBool::new(true));
+        let reader_open = open.clone();
+        let replies = self.replies.clone();
+        let reader = tokio::spawn(async move {
+            loop {
+                match reader.recv().await {
+                    Ok(message) => {
+                        if replies.send((reader.peer_id, message)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        tracing::debug!("pooled connection to {} closed: {}", reader.peer_id, e);
+                        break;
+                    }
+                }
+            }
+            reader_open.store(false, Ordering::Relaxed);
+        });
+        Link { writer, codec, open, reader }
+    }
+}
+
+/// Write one message, giving up on a connection that stalls
+async fn write(link: &mut Link, message: &Message) -> Result<()> {
+    let timeout = Duration::from_millis(PEER_NOTIFY_TIMEOUT_MS);
+    tokio::time::timeout(timeout, link.writer.send(message))
+        .await
+        .map_err(|_| Error::Network(format!("timed out sending to {}", link.writer.peer_id)))?
+}
+
+/// Wait before the next attempt to connect after `failures` in a row
+fn backoff(failures: u32) -> Duration {
+    let base = Duration::from_millis(POOL_BACKOFF_BASE_MS);
+    base.saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(Duration::from_secs(POOL_BACKOFF_MAX_SECS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use std::sync::atomic::AtomicUsize;
+
+    use crate::crypto::SigningKey;
+    use crate::sync::framing::{read_framed_message, write_framed_message};
+
+    /// Peer that answers each ping and closes a connection after two
+    fn spawn_peer(accepted: Arc<AtomicUsize>) -> PeerInfo {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    for _ in 0..2 {
+                        let Ok(payload) = read_framed_message(&mut stream).await else {
+                            return;
+                        };
+                        let Ok(Message::Ping { timestamp }) = Message::from_bytes(&payload) else {
+                            return;
+                        };
+                        let pong = Message::Pong { timestamp }.to_bytes().unwrap();
+                        let _ = write_framed_message(&mut stream, &pong).await;
+                    }
+                });
+            }
+        });
+        peer_at(port)
+    }
+
+    fn peer_at(port: u16) -> PeerInfo {
+        PeerInfo {
+            device_id: Uuid::new_v4(),
+            device_name: "peer".to_string(),
+            fingerprint: String::new(),
+            addresses: vec![IpAddr::from([127, 0, 0, 1])],
+            port,
+            accepting_pairing: false,
+            protocol_version: None,
+            last_seen: Instant::now(),
+        }
+    }
+
+    fn identity() -> VerifyingKey {
+        SigningKey::from_bytes(&[5u8; 32]).verifying_key()
+    }
+
+    async fn reply(replies: &mut mpsc::Receiver<(Uuid, Message)>) -> (Uuid, Message) {
+        tokio::time::timeout(Duration::from_secs(1), replies.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connection_reused_and_reopened() {
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let peer = spawn_peer(accepted.clone());
+        let key = SessionKey::from_bytes(&[7u8; 32]);
+        let (tx, mut replies) = mpsc::channel(8);
+        let pool = ConnectionPool::new(tx);
+
+        for timestamp in [1, 2] {
+            pool.send(&peer, &identity(), &key, WireCodec::Json, &Message::Ping { timestamp }).await.unwrap();
+            let (from, message) = reply(&mut replies).await;
+            assert_eq!(from, peer.device_id);
+            assert!(matches!(message, Message::Pong { timestamp: t } if t == timestamp));
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        // The peer closed its end after two messages
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while pool.stats().await.connected > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        assert_eq!(pool.stats().await, PoolStats { connected: 0, disconnected: 1 });
+
+        pool.send(&peer, &identity(), &key, WireCodec::Json, &Message::Ping { timestamp: 3 }).await.unwrap();
+        assert!(matches!(reply(&mut replies).await.1, Message::Pong { timestamp: 3 }));
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.stats().await, PoolStats { connected: 1, disconnected: 0 });
+
+        pool.evict(peer.device_id).await;
+        assert_eq!(pool.stats().await, PoolStats::default());
+
+        pool.send(&peer, &identity(), &key, WireCodec::Json, &Message::Ping { timestamp: 4 }).await.unwrap();
+        assert_eq!(pool.stats().await, PoolStats { connected: 1, disconnected: 0 });
+        pool.close().await;
+        assert_eq!(pool.stats().await, PoolStats::default());
+    }
+
+    #[tokio::test]
+    async fn test_reached_address_remembered() {
+        let mut peer = spawn_peer(Arc::new(AtomicUsize::new(0)));
+        // Nothing listens on the first address
+        let reachable = IpAddr::from([127, 0, 0, 1]);
+        peer.addresses = vec![IpAddr::from([127, 0, 0, 2]), reachable];
+        let key = SessionKey::from_bytes(&[7u8; 32]);
+        let (tx, mut replies) = mpsc::channel(8);
+        let pool = ConnectionPool::new(tx);
+
+        pool.send(&peer, &identity(), &key, WireCodec::Json, &Message::Ping { timestamp: 1 }).await.unwrap();
+        assert!(matches!(reply(&mut replies).await.1, Message::Pong { timestamp: 1 }));
+        let slot = pool.slot(peer.device_id).await;
+        assert_eq!(slot.lock().await.preferred, Some(reachable));
+
+        // A remembered address the peer no longer advertises isn't tried
+        let mut moved = peer.clone();
+        moved.addresses = vec![IpAddr::from([127, 0, 0, 2])];
+        assert!(pool.connect(&moved, &identity(), Some(reachable), &key, WireCodec::Json).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_connect_backs_off() {
+        // A port nothing listens on
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let peer = peer_at(port);
+        let key = SessionKey::from_bytes(&[7u8; 32]);
+        let (tx, _replies) = mpsc::channel(8);
+        let pool = ConnectionPool::new(tx);
+        let ping = Message::Ping { timestamp: 0 };
+
+        assert!(pool.send(&peer, &identity(), &key, WireCodec::Json, &ping).await.is_err());
+        let Err(Error::Network(e)) = pool.send(&peer, &identity(), &key, WireCodec::Json, &ping).await else {
+            panic!("expected to be backing off");
+        };
+        assert!(e.starts_with("not reconnecting"), "{}", e);
+        assert_eq!(pool.stats().await, PoolStats { connected: 0, disconnected: 1 });
+
+        // Seen again, so tried again at once
+        pool.reset_backoff(peer.device_id).await;
+        let Err(Error::Network(e)) = pool.send(&peer, &identity(), &key, WireCodec::Json, &ping).await else {
+            panic!("expected the connection to be refused");
+        };
+        assert!(!e.starts_with("not reconnecting"), "{}", e);
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        assert_eq!(backoff(1), Duration::from_millis(POOL_BACKOFF_BASE_MS));
+        assert_eq!(backoff(2), Duration::from_millis(2 * POOL_BACKOFF_BASE_MS));
+        assert_eq!(backoff(3), Duration::from_millis(4 * POOL_BACKOFF_BASE_MS));
+        assert_eq!(backoff(64), Duration::from_secs(POOL_BACKOFF_MAX_SECS));
+    }
+}
@@ -1,27 +1,50 @@
 //! TCP server for accepting peer connections
 
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::net::TcpListener;
-use tokio::sync::{mpsc, RwLock};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio::task::JoinSet;
 use uuid::Uuid;
 
-use crate::crypto::SessionKey;
-use crate::protocol::{Message, PairAcceptMessage, PairingSession};
-use crate::sync::framing::{read_framed_message, write_framed_message};
+use crate::crypto::{SessionKey, VerifyingKey};
+use crate::protocol::constants::{
+    protocol_version_supported, DEFAULT_MAX_CONNECTIONS, DEFAULT_SERVER_EVENT_CAPACITY, PEER_NOTIFY_TIMEOUT_MS,
+    PROTOCOL_VERSION,
+};
+use crate::protocol::{
+    AnnounceMessage, Compression, Message, NegotiatedFeatures, PairAcceptMessage, PairRequestMessage,
+    PairingSession, WireCodec,
+};
+use crate::sync::chunking::read_rest;
+use crate::sync::framing::{read_framed_message, read_handshake_message, write_framed_message};
+use crate::sync::pairing::reject_self;
+use crate::sync::transport::Transport;
 use crate::{DeviceIdentity, Error, Result};
 
 /// Event from the sync server
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum SyncEvent {
     /// New peer connected
     PeerConnected { peer_id: Uuid, peer_name: String },
     /// Peer disconnected
     PeerDisconnected { peer_id: Uuid },
-    /// Message received from peer
-    MessageReceived { peer_id: Uuid, message: Message },
+    /// Message received from peer.
+    ///
+    /// When `reply` is set, a message sent on it is written back to the peer
+    /// over the same connection.
+    MessageReceived {
+        peer_id: Uuid,
+        message: Message,
+        reply: Option<oneshot::Sender<Message>>,
+    },
+    /// A peer acknowledged one of our messages
+    AckReceived { message_id: Uuid },
     /// Device was paired successfully
     DevicePaired { device: PairedDevice },
 }
@@ -31,7 +54,11 @@ pub enum SyncEvent {
 pub struct PairedDevice {
     pub device_id: Uuid,
     pub device_name: String,
+    /// Long-term identity key the peer signed the handshake with
+    pub identity_pubkey: VerifyingKey,
     pub session_key: SessionKey,
+    /// Protocol features in effect with this device
+    pub features: NegotiatedFeatures,
 }
 
 /// TCP sync server
@@ -39,15 +66,27 @@ pub struct SyncServer {
     listener: TcpListener,
     port: u16,
     paired_devices: Arc<RwLock<HashMap<Uuid, PairedDevice>>>,
+    /// Permits for connections being handled
+    connection_limit: Arc<Semaphore>,
+    /// Room for events not yet taken from the receiver
+    event_capacity: usize,
+    /// Refuse connections that don't open an encrypted transport
+    transport_encryption: bool,
 }
 
 impl SyncServer {
     /// Bind to a port and create the server
     pub async fn bind(port: u16) -> Result<Self> {
-        let addr: SocketAddr = ([0, 0, 0, 0], port).into();
-        let listener = TcpListener::bind(addr)
-            .await
-            .map_err(|e| Error::Network(format!("failed to bind: {}", e)))?;
+        let listener = match bind_dual_stack(port) {
+            Ok(listener) => listener,
+            Err(e) => {
+                // Hosts with IPv6 turned off
+                tracing::debug!("no IPv6 listener ({}), listening on IPv4 only", e);
+                TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port)))
+                    .await
+                    .map_err(|e| Error::Network(format!("failed to bind: {}", e)))?
+            }
+        };
 
         let actual_port = listener.local_addr()
             .map_err(|e| Error::Network(e.to_string()))?
@@ -59,9 +98,53 @@ impl SyncServer {
             listener,
             port: actual_port,
             paired_devices: Arc::new(RwLock::new(HashMap::new())),
+            connection_limit: Arc::new(Semaphore::new(DEFAULT_MAX_CONNECTIONS)),
+            event_capacity: DEFAULT_SERVER_EVENT_CAPACITY,
+            transport_encryption: false,
         })
     }
 
+    /// Refuse connections beyond `max` handled at once
+    ///
+    /// Clipboard messages from paired devices stop counting once they've
+    /// been read, so slow replies don't hold slots open.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.connection_limit = Arc::new(Semaphore::new(max));
+        self
+    }
+
+    /// Buffer up to `capacity` events; connections wait for room when the
+    /// receiver falls further behind
+    pub fn with_event_capacity(mut self, capacity: usize) -> Self {
+        self.event_capacity = capacity.max(1);
+        self
+    }
+
+    /// Refuse connections that don't open an encrypted transport when
+    /// `required`, other than reachability checks
+    ///
+    /// Connections that do open one are accepted either way.
+    pub fn with_transport_encryption(mut self, required: bool) -> Self {
+        self.transport_encryption = required;
+        self
+    }
+
+    /// Take a connection slot, or close the connection if none are free
+    fn admit(&self, addr: SocketAddr) -> Option<OwnedSemaphorePermit> {
+        let permit = self.connection_limit.clone().try_acquire_owned().ok();
+        if permit.is_none() {
+            tracing::warn!("refusing connection from {}: connection limit reached", addr);
+        }
+        permit
+    }
+
+    /// Accept clipboard messages from the devices in `devices`, a map the
+    /// caller keeps updating while the server runs
+    pub fn with_paired_devices(mut self, devices: Arc<RwLock<HashMap<Uuid, PairedDevice>>>) -> Self {
+        self.paired_devices = devices;
+        self
+    }
+
     /// Get the port we're listening on
     pub fn port(&self) -> u16 {
         self.port
@@ -85,26 +168,35 @@ impl SyncServer {
     /// Start accepting connections with pairing support
     pub fn start_with_pairing(
         self,
-        active_pairing: Arc<RwLock<Option<PairingSession>>>,
+        pairing_sessions: Arc<RwLock<HashMap<Uuid, PairingSession>>>,
         identity: DeviceIdentity,
     ) -> (mpsc::Receiver<SyncEvent>, SyncServerHandle) {
-        let (tx, rx) = mpsc::channel(64);
+        let (tx, rx) = mpsc::channel(self.event_capacity);
         let paired_devices = self.paired_devices.clone();
 
         let handle = tokio::spawn(async move {
+            // Owned here so stopping the server closes them too
+            let mut connections = JoinSet::new();
             loop {
                 match self.listener.accept().await {
                     Ok((stream, addr)) => {
+                        // IPv4 peers arrive as mapped IPv6 addresses
+                        let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
                         tracing::debug!("incoming connection from {}", addr);
+                        let Some(permit) = self.admit(addr) else {
+                            continue;
+                        };
                         let tx = tx.clone();
                         let devices = paired_devices.clone();
-                        let pairing = active_pairing.clone();
+                        let pairing = pairing_sessions.clone();
                         let ident = identity.clone();
+                        let require_encryption = self.transport_encryption;
 
-                        tokio::spawn(async move {
+                        while connections.try_join_next().is_some() {}
+                        connections.spawn(async move {
                             tracing::info!("handling connection from {}", addr);
                             if let Err(e) = Self::handle_connection_with_pairing(
-                                stream, addr, tx, devices, pairing, ident
+                                stream, addr, permit, tx, devices, pairing, ident, require_encryption
                             ).await {
                                 tracing::error!("connection error from {}: {}", addr, e);
                             }
@@ -122,19 +214,28 @@ impl SyncServer {
 
     /// Start accepting connections (legacy, without pairing)
     pub fn start(self) -> (mpsc::Receiver<SyncEvent>, SyncServerHandle) {
-        let (tx, rx) = mpsc::channel(64);
+        let (tx, rx) = mpsc::channel(self.event_capacity);
         let paired_devices = self.paired_devices.clone();
 
         let handle = tokio::spawn(async move {
+            // Owned here so stopping the server closes them too
+            let mut connections = JoinSet::new();
             loop {
                 match self.listener.accept().await {
                     Ok((stream, addr)) => {
+                        // IPv4 peers arrive as mapped IPv6 addresses
+                        let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
                         tracing::debug!("incoming connection from {}", addr);
+                        let Some(permit) = self.admit(addr) else {
+                            continue;
+                        };
                         let tx = tx.clone();
                         let devices = paired_devices.clone();
 
-                        tokio::spawn(async move {
+                        while connections.try_join_next().is_some() {}
+                        connections.spawn(async move {
                             tracing::info!("handling connection from {}", addr);
+                            let _permit = permit;
                             if let Err(e) = Self::handle_connection(stream, addr, tx, devices).await {
                                 tracing::error!("connection error from {}: {}", addr, e);
                             }
@@ -150,38 +251,51 @@ impl SyncServer {
         (rx, SyncServerHandle { task: handle })
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn handle_connection_with_pairing(
-        mut stream: tokio::net::TcpStream,
+        stream: TcpStream,
         addr: SocketAddr,
+        permit: OwnedSemaphorePermit,
         tx: mpsc::Sender<SyncEvent>,
         paired_devices: Arc<RwLock<HashMap<Uuid, PairedDevice>>>,
-        active_pairing: Arc<RwLock<Option<PairingSession>>>,
+        pairing_sessions: Arc<RwLock<HashMap<Uuid, PairingSession>>>,
         identity: DeviceIdentity,
+        require_encryption: bool,
     ) -> Result<()> {
+        let mut stream = Transport::plain(stream);
         // Read message using the framing module
-        let payload = read_framed_message(&mut stream).await?;
-        let message = Message::from_bytes(&payload)?;
+        let mut payload = read_handshake_message(&mut stream).await?;
+        if let Ok(Message::TransportHello { ephemeral_pubkey, .. }) = Message::decode(&payload) {
+            stream.respond(&ephemeral_pubkey, &identity.signing_key).await?;
+            payload = read_handshake_message(&mut stream).await?;
+        }
+        // Replies go back in the codec the peer wrote in
+        let codec = WireCodec::detect(&payload);
+        let message = Message::from_bytes_with(&payload, codec)?;
+        if require_encryption && !stream.is_encrypted() && !matches!(message, Message::Ping { .. }) {
+            return Err(Error::Network(format!("refusing unencrypted connection from {}", addr)));
+        }
 
         match message {
             Message::PairRequest(req) => {
                 tracing::info!("pairing request from {} at {}", req.device_name, addr);
-
-                // Take the active pairing session
-                let pairing_session = active_pairing.write().await.take()
-                    .ok_or_else(|| Error::NotPaired("no active pairing session".to_string()))?;
-
-                // Verify session ID matches
-                if req.session_id != pairing_session.session_id {
-                    // Put the session back
-                    *active_pairing.write().await = Some(pairing_session);
-                    return Err(Error::InvalidMessage("session ID mismatch".to_string()));
-                }
+                let pairing_session = match Self::take_session(&pairing_sessions, &req, &identity).await {
+                    Ok(session) => session,
+                    Err(reason) => {
+                        // Tell the requester why rather than just hanging up
+                        let reject = Message::PairReject { session_id: req.session_id, reason: reason.clone() };
+                        write_framed_message(&mut stream, &reject.to_bytes_with(codec)?).await?;
+                        return Err(Error::NotPaired(reason));
+                    }
+                };
 
                 // Get our ephemeral public key before consuming the session
                 let our_ephemeral_pubkey = pairing_session.ephemeral_public.clone();
 
-                // Complete ECDH key exchange
-                let session_key = pairing_session.complete(&req.ephemeral_pubkey);
+                // Complete ECDH key exchange, deriving the key the way the
+                // older of the two devices does
+                let protocol_version = req.protocol_version.min(PROTOCOL_VERSION);
+                let session_key = pairing_session.complete(&req.ephemeral_pubkey, protocol_version);
 
                 // Create signature over session data
                 let mut sign_data = Vec::new();
@@ -189,6 +303,8 @@ impl SyncServer {
                 sign_data.extend(our_ephemeral_pubkey.to_bytes());
                 sign_data.extend(req.ephemeral_pubkey.to_bytes());
                 let signature = identity.signing_key.sign(&sign_data);
+                let wire_codec = WireCodec::negotiate(&req.wire_codecs);
+                let compression = Compression::negotiate(&req.compressions);
 
                 // Create PairAccept message
                 let accept = Message::PairAccept(PairAcceptMessage {
@@ -198,6 +314,9 @@ impl SyncServer {
                     ephemeral_pubkey: our_ephemeral_pubkey,
                     identity_pubkey: identity.signing_key.verifying_key(),
                     signature,
+                    wire_codec,
+                    compression,
+                    protocol_version,
                 });
 
                 // Send PairAccept response using the framing module
@@ -210,30 +329,105 @@ impl SyncServer {
                 let paired_device = PairedDevice {
                     device_id: req.device_id,
                     device_name: req.device_name.clone(),
+                    identity_pubkey: req.identity_pubkey.clone(),
                     session_key: session_key.clone(),
+                    features: NegotiatedFeatures::negotiated(wire_codec, compression),
                 };
                 paired_devices.write().await.insert(req.device_id, paired_device.clone());
 
                 // Notify the service
-                let _ = tx.send(SyncEvent::DevicePaired { device: paired_device }).await;
+                let _ = tx.send(SyncEvent::DevicePaired { device: paired_device.clone() }).await;
 
                 tracing::info!("paired successfully with {} ({})", req.device_name, req.device_id);
 
-                // Keep connection open for potential follow-up messages
-                // For now, we just return - a more complete implementation would
-                // loop reading messages here
+                // Keep serving the new device on this connection, so its
+                // first sync doesn't need a new one
+                drop(permit);
+                Self::serve_after_pairing(&mut stream, &paired_device, &tx).await?;
             }
             Message::ClipboardSync(sync_msg) => {
-                // Try to decrypt if we have the session key
-                if let Some(device) = paired_devices.read().await.get(&sync_msg.sender_id) {
+                // Only forward content from devices we hold a session key for
+                let device = paired_devices.read().await.get(&sync_msg.sender_id).cloned();
+                if let Some(device) = device {
+                    drop(permit);
+                    Self::serve_paired(&mut stream, &device, Message::ClipboardSync(sync_msg), codec, &tx).await?;
+                } else {
+                    tracing::warn!("clipboard sync from unknown device {}", sync_msg.sender_id);
+                }
+            }
+            Message::ClipboardDelta(delta_msg) => {
+                let device = paired_devices.read().await.get(&delta_msg.sender_id).cloned();
+                if let Some(device) = device {
+                    drop(permit);
+                    Self::serve_paired(&mut stream, &device, Message::ClipboardDelta(delta_msg), codec, &tx).await?;
+                } else {
+                    tracing::warn!("clipboard delta from unknown device {}", delta_msg.sender_id);
+                }
+            }
+            Message::ClipboardChunk { sender_id, .. } => {
+                // Only buffer the rest for a device we hold a session key for
+                let device = paired_devices.read().await.get(&sender_id).cloned();
+                if let Some(device) = device {
+                    let message = read_rest(&mut stream, message).await?;
+                    drop(permit);
+                    Self::serve_paired(&mut stream, &device, message, codec, &tx).await?;
+                } else {
+                    tracing::warn!("clipboard chunk from unknown device {}", sender_id);
+                }
+            }
+            Message::Unpair { device_id, proof } => {
+                if paired_devices.read().await.contains_key(&device_id) {
                     let _ = tx.send(SyncEvent::MessageReceived {
-                        peer_id: sync_msg.sender_id,
-                        message: Message::ClipboardSync(sync_msg),
+                        peer_id: device_id,
+                        message: Message::Unpair { device_id, proof },
+                        reply: None,
                     }).await;
                 } else {
-                    tracing::warn!("clipboard sync from unknown device {}", sync_msg.sender_id);
+                    tracing::debug!("unpair from unknown device {}", device_id);
+                }
+            }
+            Message::KeyRotate { device_id, epoch, proof } => {
+                if paired_devices.read().await.contains_key(&device_id) {
+                    let _ = tx.send(SyncEvent::MessageReceived {
+                        peer_id: device_id,
+                        message: Message::KeyRotate { device_id, epoch, proof },
+                        reply: None,
+                    }).await;
+                } else {
+                    tracing::debug!("key rotation from unknown device {}", device_id);
                 }
             }
+            Message::Ack { message_id } => {
+                let _ = tx.send(SyncEvent::AckReceived { message_id }).await;
+            }
+            Message::Ping { timestamp } => Self::pong(&mut stream, timestamp).await?,
+            Message::Probe { device_id, challenge } => {
+                // Answered by the receiver, which holds the device's retained
+                // keys as well as its current one
+                if paired_devices.read().await.contains_key(&device_id) {
+                    let (reply_tx, reply_rx) = oneshot::channel();
+                    let _ = tx.send(SyncEvent::MessageReceived {
+                        peer_id: device_id,
+                        message: Message::Probe { device_id, challenge },
+                        reply: Some(reply_tx),
+                    }).await;
+                    Self::write_reply(&mut stream, reply_rx, codec).await?;
+                } else {
+                    tracing::debug!("probe from unknown device {}", device_id);
+                }
+            }
+            Message::Announce(ann) => {
+                // Peers added by address rather than found over mDNS learn
+                // who we are this way
+                tracing::debug!("announce from {} at {}", ann.device_name, addr);
+                let reply = Message::Announce(AnnounceMessage {
+                    device_id: identity.id,
+                    device_name: identity.name.clone(),
+                    pubkey_fingerprint: identity.fingerprint(),
+                    protocol_version: PROTOCOL_VERSION,
+                });
+                write_framed_message(&mut stream, &reply.to_bytes_with(codec)?).await?;
+            }
             other => {
                 tracing::debug!("received {:?} from {}", other, addr);
             }
@@ -242,15 +436,167 @@ impl SyncServer {
         Ok(())
     }
 
+    /// Serve a paired device's connection, starting with the message it
+    /// opened with, until it closes.
+    ///
+    /// The connection is reported as connected once the device is known and
+    /// as disconnected when it ends, whether or not it ended cleanly. Devices
+    /// keep the connection open to send later messages over it.
+    async fn serve_paired(
+        stream: &mut Transport<TcpStream>,
+        device: &PairedDevice,
+        message: Message,
+        codec: WireCodec,
+        tx: &mpsc::Sender<SyncEvent>,
+    ) -> Result<()> {
+        let peer_id = device.device_id;
+        let _ = tx.send(SyncEvent::PeerConnected {
+            peer_id,
+            peer_name: device.device_name.clone(),
+        }).await;
+
+        let result = async {
+            Self::handle_paired(stream, peer_id, message, codec, tx).await?;
+            Self::serve_messages(stream, peer_id, tx).await
+        }.await;
+
+        let _ = tx.send(SyncEvent::PeerDisconnected { peer_id }).await;
+        result
+    }
+
+    /// Serve a device on the connection it just paired over, until it
+    /// closes.
+    async fn serve_after_pairing(
+        stream: &mut Transport<TcpStream>,
+        device: &PairedDevice,
+        tx: &mpsc::Sender<SyncEvent>,
+    ) -> Result<()> {
+        let peer_id = device.device_id;
+        let _ = tx.send(SyncEvent::PeerConnected {
+            peer_id,
+            peer_name: device.device_name.clone(),
+        }).await;
+
+        let result = Self::serve_messages(stream, peer_id, tx).await;
+
+        let _ = tx.send(SyncEvent::PeerDisconnected { peer_id }).await;
+        result
+    }
+
+    /// Handle messages from a paired device until it closes the connection
+    async fn serve_messages(
+        stream: &mut Transport<TcpStream>,
+        peer_id: Uuid,
+        tx: &mpsc::Sender<SyncEvent>,
+    ) -> Result<()> {
+        loop {
+            let payload = match read_framed_message(stream).await {
+                Ok(payload) => payload,
+                Err(Error::Network(e)) => {
+                    tracing::debug!("connection from {} closed: {}", peer_id, e);
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            let codec = WireCodec::detect(&payload);
+            let message = Message::from_bytes_with(&payload, codec)?;
+            let message = read_rest(stream, message).await?;
+            Self::handle_paired(stream, peer_id, message, codec, tx).await?;
+        }
+    }
+
+    /// Handle one message on a paired device's connection.
+    ///
+    /// Clipboard messages are forwarded with a reply channel and the reply
+    /// written back, pings are answered here, and anything else is forwarded
+    /// as is.
+    async fn handle_paired(
+        stream: &mut Transport<TcpStream>,
+        peer_id: Uuid,
+        message: Message,
+        codec: WireCodec,
+        tx: &mpsc::Sender<SyncEvent>,
+    ) -> Result<()> {
+        match message {
+            Message::Ping { timestamp } => Self::pong(stream, timestamp).await?,
+            Message::ClipboardSync(ref m) if m.sender_id != peer_id => {
+                tracing::warn!("clipboard sync for {} on {}'s connection", m.sender_id, peer_id);
+            }
+            Message::ClipboardDelta(ref m) if m.sender_id != peer_id => {
+                tracing::warn!("clipboard delta for {} on {}'s connection", m.sender_id, peer_id);
+            }
+            message @ (Message::ClipboardSync(_) | Message::ClipboardDelta(_)) => {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                let _ = tx.send(SyncEvent::MessageReceived {
+                    peer_id,
+                    message,
+                    reply: Some(reply_tx),
+                }).await;
+                Self::write_reply(stream, reply_rx, codec).await?;
+            }
+            message => {
+                let _ = tx.send(SyncEvent::MessageReceived { peer_id, message, reply: None }).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the open session a pairing request names, or say why it can't
+    /// pair. Removal under the lock means a concurrent cancel either wins or
+    /// finds the session gone.
+    async fn take_session(
+        sessions: &RwLock<HashMap<Uuid, PairingSession>>,
+        req: &PairRequestMessage,
+        identity: &DeviceIdentity,
+    ) -> std::result::Result<PairingSession, String> {
+        if reject_self(req.device_id, &req.identity_pubkey, identity).is_err() {
+            return Err("cannot pair with self".to_string());
+        }
+        if !protocol_version_supported(req.protocol_version.min(PROTOCOL_VERSION)) {
+            return Err(format!(
+                "protocol version {} isn't supported by {} (version {})",
+                req.protocol_version, identity.name, PROTOCOL_VERSION
+            ));
+        }
+        let mut sessions = sessions.write().await;
+        if let Some(session) = sessions.remove(&req.session_id).filter(|session| !session.is_expired()) {
+            return Ok(session);
+        }
+        if sessions.values().any(|session| !session.is_expired()) {
+            Err("pairing code is unknown or has expired".to_string())
+        } else {
+            Err("not accepting pairing".to_string())
+        }
+    }
+
+    /// Answer a reachability check
+    async fn pong(stream: &mut Transport<TcpStream>, timestamp: u64) -> Result<()> {
+        write_framed_message(stream, &Message::Pong { timestamp }.to_bytes()?).await
+    }
+
+    /// Write the service's reply to a received message, if it sends one in time
+    async fn write_reply(
+        stream: &mut Transport<TcpStream>,
+        reply: oneshot::Receiver<Message>,
+        codec: WireCodec,
+    ) -> Result<()> {
+        let timeout = Duration::from_millis(PEER_NOTIFY_TIMEOUT_MS);
+        if let Ok(Ok(message)) = tokio::time::timeout(timeout, reply).await {
+            write_framed_message(stream, &message.to_bytes_with(codec)?).await?;
+        }
+        Ok(())
+    }
+
     async fn handle_connection(
-        mut stream: tokio::net::TcpStream,
+        stream: TcpStream,
         addr: SocketAddr,
         tx: mpsc::Sender<SyncEvent>,
         _paired_devices: Arc<RwLock<HashMap<Uuid, PairedDevice>>>,
     ) -> Result<()> {
+        let mut stream = Transport::plain(stream);
         // Read message using the framing module
-        let payload = read_framed_message(&mut stream).await?;
-        let message = Message::from_bytes(&payload)?;
+        let payload = read_handshake_message(&mut stream).await?;
+        let message = Message::decode(&payload)?;
 
         // Handle based on message type
         match &message {
@@ -259,11 +605,13 @@ impl SyncServer {
                 let _ = tx.send(SyncEvent::MessageReceived {
                     peer_id: req.device_id,
                     message,
+                    reply: None,
                 }).await;
             }
             Message::Announce(ann) => {
                 tracing::info!("announce from {} at {}", ann.device_name, addr);
             }
+            Message::Ping { timestamp } => Self::pong(&mut stream, *timestamp).await?,
             _ => {
                 tracing::debug!("received {:?} from {}", message, addr);
             }
@@ -273,14 +621,459 @@ impl SyncServer {
     }
 }
 
+/// Listen on `port` for IPv6 and, through mapped addresses, IPv4
+fn bind_dual_stack(port: u16) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    // As `TcpListener::bind` does, so a restart can reuse the port at once
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
 /// Handle to the running sync server
+///
+/// Dropping the handle stops the server too, so a forgotten handle doesn't
+/// keep the port bound.
 pub struct SyncServerHandle {
     task: tokio::task::JoinHandle<()>,
 }
 
 impl SyncServerHandle {
-    /// Stop the server
+    /// Stop the server, closing the connections it accepted
+    ///
+    /// The port is released once the runtime drops the stopped task; use
+    /// [`stop`](Self::stop) to wait for that.
     pub fn abort(self) {
         self.task.abort();
     }
+
+    /// Stop the server and wait until it has, so the port can be bound
+    /// again as soon as this returns
+    pub async fn stop(mut self) {
+        self.task.abort();
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for SyncServerHandle {
+    fn drop(&mut self) {
+        // Aborting a finished or already aborted task does nothing
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{EphemeralSecret, SigningKey};
+    use crate::protocol::constants::LEGACY_PROTOCOL_VERSION;
+    use crate::protocol::PairRequestMessage;
+
+    async fn request_pairing(port: u16, session_id: Uuid) -> Result<Message> {
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await
+            .map_err(|e| Error::Network(e.to_string()))?;
+        let request = Message::PairRequest(PairRequestMessage {
+            session_id,
+            device_id: Uuid::new_v4(),
+            device_name: "phone".to_string(),
+            ephemeral_pubkey: EphemeralSecret::generate().public_key(),
+            identity_pubkey: SigningKey::generate().verifying_key(),
+            wire_codecs: WireCodec::supported(),
+            compressions: Compression::supported(),
+            protocol_version: PROTOCOL_VERSION,
+        });
+        write_framed_message(&mut stream, &request.to_bytes()?).await?;
+        Ok(Message::from_bytes(&read_framed_message(&mut stream).await?)?)
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_session_rejects_pairing() {
+        let server = SyncServer::bind(0).await.unwrap();
+        let port = server.port();
+        let cancelled = PairingSession::new();
+        let active = PairingSession::new();
+        let cancelled_id = cancelled.session_id;
+        let active_id = active.session_id;
+
+        let sessions = Arc::new(RwLock::new(HashMap::from([
+            (cancelled_id, cancelled),
+            (active_id, active),
+        ])));
+        let (mut events, handle) = server.start_with_pairing(sessions.clone(), DeviceIdentity::new("desk".to_string()));
+
+        assert!(sessions.write().await.remove(&cancelled_id).is_some());
+        let reply = request_pairing(port, cancelled_id).await.unwrap();
+        assert!(matches!(reply, Message::PairReject { session_id, ref reason } if session_id == cancelled_id && reason.contains("expired")));
+
+        let reply = request_pairing(port, active_id).await.unwrap();
+        assert!(matches!(reply, Message::PairAccept(a) if a.session_id == active_id));
+        assert!(matches!(events.recv().await, Some(SyncEvent::DevicePaired { .. })));
+        assert!(sessions.read().await.is_empty());
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_paired_connection_lifecycle_events() {
+        use crate::protocol::{ClipboardSyncMessage, ContentHash};
+
+        let server = SyncServer::bind(0).await.unwrap();
+        let port = server.port();
+        let device = PairedDevice {
+            device_id: Uuid::new_v4(),
+            device_name: "phone".to_string(),
+            identity_pubkey: SigningKey::generate().verifying_key(),
+            session_key: SessionKey::from_bytes(&[7u8; 32]),
+            features: NegotiatedFeatures::baseline(),
+        };
+        server.paired_devices.write().await.insert(device.device_id, device.clone());
+        let sessions = Arc::new(RwLock::new(HashMap::new()));
+        let (mut events, handle) = server.start_with_pairing(sessions, DeviceIdentity::new("desk".to_string()));
+
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let message = Message::ClipboardSync(ClipboardSyncMessage {
+            message_id: Uuid::new_v4(),
+            sender_id: device.device_id,
+            content_hash: ContentHash([0u8; 32]),
+            encrypted_content: device.session_key.encrypt(b"hi").unwrap(),
+            timestamp: 0,
+            key_epoch: 0,
+            channel: String::new(),
+            compressed: false,
+        });
+        write_framed_message(&mut stream, &message.to_bytes().unwrap()).await.unwrap();
+
+        assert!(matches!(
+            events.recv().await,
+            Some(SyncEvent::PeerConnected { peer_id, peer_name }) if peer_id == device.device_id && peer_name == "phone"
+        ));
+        let Some(SyncEvent::MessageReceived { reply: Some(reply), .. }) = events.recv().await else {
+            panic!("expected the clipboard message");
+        };
+        reply.send(Message::Ack { message_id: Uuid::new_v4() }).unwrap();
+        let ack = Message::from_bytes(&read_framed_message(&mut stream).await.unwrap()).unwrap();
+        assert!(matches!(ack, Message::Ack { .. }));
+
+        // The connection stays open for the device's next message
+        write_framed_message(&mut stream, &message.to_bytes().unwrap()).await.unwrap();
+        assert!(matches!(events.recv().await, Some(SyncEvent::MessageReceived { reply: Some(_), .. })));
+        drop(stream);
+        assert!(matches!(
+            events.recv().await,
+            Some(SyncEvent::PeerDisconnected { peer_id }) if peer_id == device.device_id
+        ));
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_chunked_message_received_whole() {
+        use crate::crypto::EncryptedPayload;
+        use crate::protocol::constants::MAX_MESSAGE_SIZE;
+        use crate::protocol::{ClipboardSyncMessage, ContentHash};
+        use crate::sync::PeerConnection;
+
+        let server = SyncServer::bind(0).await.unwrap();
+        let port = server.port();
+        let device = PairedDevice {
+            device_id: Uuid::new_v4(),
+            device_name: "phone".to_string(),
+            identity_pubkey: SigningKey::generate().verifying_key(),
+            session_key: SessionKey::from_bytes(&[7u8; 32]),
+            features: NegotiatedFeatures::baseline(),
+        };
+        server.paired_devices.write().await.insert(device.device_id, device.clone());
+        let sessions = Arc::new(RwLock::new(HashMap::new()));
+        let (mut events, handle) = server.start_with_pairing(sessions, DeviceIdentity::new("desk".to_string()));
+
+        let stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut connection = PeerConnection::new(device.device_id, "desk".to_string(), stream, device.session_key.clone());
+        let sync = |message_id| Message::ClipboardSync(ClipboardSyncMessage {
+            message_id,
+            sender_id: device.device_id,
+            content_hash: ContentHash([0u8; 32]),
+            encrypted_content: EncryptedPayload { nonce: [0; 12], ciphertext: vec![9; MAX_MESSAGE_SIZE] },
+            timestamp: 0,
+            key_epoch: 0,
+            channel: String::new(),
+            compressed: false,
+        });
+
+        // Chunked as the connection's first message and as a later one
+        for _ in 0..2 {
+            let message_id = Uuid::new_v4();
+            connection.send(&sync(message_id)).await.unwrap();
+            let reply = loop {
+                match events.recv().await {
+                    Some(SyncEvent::PeerConnected { .. }) => continue,
+                    Some(SyncEvent::MessageReceived { message: Message::ClipboardSync(m), reply: Some(reply), .. }) => {
+                        assert_eq!(m.message_id, message_id);
+                        assert_eq!(m.encrypted_content.ciphertext.len(), MAX_MESSAGE_SIZE);
+                        break reply;
+                    }
+                    other => panic!("expected the clipboard message, got {:?}", other),
+                }
+            };
+            reply.send(Message::Ack { message_id }).unwrap();
+            assert!(matches!(connection.recv().await.unwrap(), Message::Ack { message_id: id } if id == message_id));
+        }
+
+        // A stranger's chunks are refused at the first, not buffered
+        let mut stranger = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let chunk = Message::ClipboardChunk {
+            message_id: Uuid::new_v4(),
+            sender_id: Uuid::new_v4(),
+            index: 0,
+            total: 64,
+            data: vec![0; 1024],
+        };
+        tokio::io::AsyncWriteExt::write_all(&mut stranger, &chunk.to_frame_with(WireCodec::Json).unwrap()).await.unwrap();
+        let mut rest = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut stranger, &mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stopped_server_frees_port() {
+        let server = SyncServer::bind(0).await.unwrap();
+        let port = server.port();
+        let (_events, handle) = server.start();
+        handle.stop().await;
+
+        // Free as soon as stop returns
+        let (_events, handle) = SyncServer::bind(port).await.unwrap().start();
+        handle.abort();
+
+        // Dropping the handle, even after aborting, stops the server too
+        let rebind = async {
+            loop {
+                match SyncServer::bind(port).await {
+                    Ok(server) => break server,
+                    Err(_) => tokio::task::yield_now().await,
+                }
+            }
+        };
+        let (_events, handle) = tokio::time::timeout(Duration::from_secs(1), rebind).await.unwrap().start();
+        drop(handle);
+        let rebind = async {
+            loop {
+                if SyncServer::bind(port).await.is_ok() {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), rebind).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connections_beyond_limit_refused() {
+        use std::time::Duration;
+        use tokio::io::AsyncReadExt;
+        use tokio::net::TcpStream;
+
+        let server = SyncServer::bind(0).await.unwrap().with_max_connections(2);
+        let port = server.port();
+        let sessions = Arc::new(RwLock::new(HashMap::new()));
+        let (_events, handle) = server.start_with_pairing(sessions, DeviceIdentity::new("desk".to_string()));
+
+        // Idle connections hold their slots while the server waits for a frame
+        let mut open = Vec::new();
+        for _ in 0..2 {
+            open.push(TcpStream::connect(("127.0.0.1", port)).await.unwrap());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut excess = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(1), excess.read(&mut buf)).await
+            .expect("excess connection should be closed promptly");
+        assert!(matches!(read, Ok(0) | Err(_)));
+
+        // Closing a connection frees its slot
+        drop(open.pop());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut next = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(200), next.read(&mut buf)).await.is_err());
+
+        // Stopping the server closes what it accepted
+        handle.abort();
+        let read = tokio::time::timeout(Duration::from_secs(1), next.read(&mut buf)).await
+            .expect("connection should be closed when the server stops");
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn test_ping_reports_round_trip() {
+        use std::time::Duration;
+
+        let server = SyncServer::bind(0).await.unwrap();
+        let addr: SocketAddr = ([127, 0, 0, 1], server.port()).into();
+        let sessions = Arc::new(RwLock::new(HashMap::new()));
+        let (_events, handle) = server.start_with_pairing(sessions, DeviceIdentity::new("desk".to_string()));
+
+        let rtt = crate::sync::ping(addr, Duration::from_secs(2)).await.unwrap();
+        assert!(rtt < Duration::from_secs(2));
+        handle.abort();
+
+        // A listener that accepts but never answers
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let err = crate::sync::ping(silent.local_addr().unwrap(), Duration::from_millis(200)).await.unwrap_err();
+        assert!(matches!(err, Error::Timeout(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_identify_returns_server_identity() {
+        use std::time::Duration;
+
+        let server = SyncServer::bind(0).await.unwrap();
+        let addr: SocketAddr = ([127, 0, 0, 1], server.port()).into();
+        let desk = DeviceIdentity::new("desk".to_string());
+        let sessions = Arc::new(RwLock::new(HashMap::new()));
+        let (_events, handle) = server.start_with_pairing(sessions, desk.clone());
+
+        let phone = DeviceIdentity::new("phone".to_string());
+        let reply = crate::sync::identify(addr, &phone, Duration::from_secs(2), false).await.unwrap();
+        assert_eq!(reply.device_id, desk.id);
+        assert_eq!(reply.device_name, "desk");
+        assert_eq!(reply.pubkey_fingerprint, desk.fingerprint());
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_required_transport_encryption() {
+        use std::time::Duration;
+        use crate::sync::PeerConnection;
+
+        let server = SyncServer::bind(0).await.unwrap().with_transport_encryption(true);
+        let addr: SocketAddr = ([127, 0, 0, 1], server.port()).into();
+        let desk = DeviceIdentity::new("desk".to_string());
+        let sessions = Arc::new(RwLock::new(HashMap::new()));
+        let (_events, handle) = server.start_with_pairing(sessions, desk.clone());
+        let phone = DeviceIdentity::new("phone".to_string());
+        let timeout = Duration::from_secs(2);
+
+        // Reachability checks still work in the clear; nothing else does
+        crate::sync::ping(addr, timeout).await.unwrap();
+        assert!(crate::sync::identify(addr, &phone, timeout, false).await.is_err());
+        let reply = crate::sync::identify(addr, &phone, timeout, true).await.unwrap();
+        assert_eq!(reply.device_id, desk.id);
+
+        // Only the desk can finish the handshake as the desk
+        let key = SessionKey::from_bytes(&[3u8; 32]);
+        let connect = || PeerConnection::connect(addr, desk.id, "desk".to_string(), key.clone(), timeout);
+        let wrong = phone.signing_key.verifying_key();
+        assert!(matches!(connect().await.unwrap().encrypted(Some(&wrong)).await, Err(Error::Crypto(_))));
+
+        // Replies come back over the same encrypted connection
+        let mut conn = connect().await.unwrap()
+            .encrypted(Some(&desk.signing_key.verifying_key())).await.unwrap();
+        conn.send(&Message::Ping { timestamp: 9 }).await.unwrap();
+        assert!(matches!(conn.recv().await.unwrap(), Message::Pong { timestamp: 9 }));
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_pairing_checks_protocol_version() {
+        let server = SyncServer::bind(0).await.unwrap();
+        let port = server.port();
+        let session = PairingSession::new();
+        let session_id = session.session_id;
+        let sessions = Arc::new(RwLock::new(HashMap::from([(session_id, session)])));
+        let (_events, handle) = server.start_with_pairing(sessions, DeviceIdentity::new("desk".to_string()));
+
+        let request = |protocol_version| Message::PairRequest(PairRequestMessage {
+            session_id,
+            device_id: Uuid::new_v4(),
+            device_name: "phone".to_string(),
+            ephemeral_pubkey: EphemeralSecret::generate().public_key(),
+            identity_pubkey: SigningKey::generate().verifying_key(),
+            wire_codecs: vec![],
+            compressions: vec![],
+            protocol_version,
+        });
+        let exchange = |message: Message| async move {
+            let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            write_framed_message(&mut stream, &message.to_bytes().unwrap()).await.unwrap();
+            Message::from_bytes(&read_framed_message(&mut stream).await.unwrap()).unwrap()
+        };
+
+        // Refused with a reason, leaving the session open
+        let reply = exchange(request(0)).await;
+        assert!(matches!(reply, Message::PairReject { ref reason, .. } if reason.contains("protocol version 0")), "{:?}", reply);
+
+        // A newer requester is answered with the version we speak
+        let reply = exchange(request(PROTOCOL_VERSION + 1)).await;
+        assert!(matches!(reply, Message::PairAccept(a) if a.protocol_version == PROTOCOL_VERSION));
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_pairing_connection_stays_open() {
+        use crate::protocol::{ClipboardSyncMessage, ContentHash};
+
+        let server = SyncServer::bind(0).await.unwrap();
+        let port = server.port();
+        let session = PairingSession::new();
+        let session_id = session.session_id;
+        let sessions = Arc::new(RwLock::new(HashMap::from([(session_id, session)])));
+        let (mut events, handle) = server.start_with_pairing(sessions, DeviceIdentity::new("desk".to_string()));
+
+        let phone_id = Uuid::new_v4();
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let request = Message::PairRequest(PairRequestMessage {
+            session_id,
+            device_id: phone_id,
+            device_name: "phone".to_string(),
+            ephemeral_pubkey: EphemeralSecret::generate().public_key(),
+            identity_pubkey: SigningKey::generate().verifying_key(),
+            wire_codecs: vec![],
+            compressions: vec![],
+            protocol_version: LEGACY_PROTOCOL_VERSION,
+        });
+        write_framed_message(&mut stream, &request.to_bytes().unwrap()).await.unwrap();
+        let reply = Message::from_bytes(&read_framed_message(&mut stream).await.unwrap()).unwrap();
+        // A requester that doesn't state a version keeps the old key derivation
+        assert!(matches!(reply, Message::PairAccept(a) if a.protocol_version == LEGACY_PROTOCOL_VERSION));
+        let Some(SyncEvent::DevicePaired { device }) = events.recv().await else {
+            panic!("expected DevicePaired");
+        };
+        assert!(matches!(events.recv().await, Some(SyncEvent::PeerConnected { peer_id, .. }) if peer_id == phone_id));
+
+        // The first sync goes over the same connection
+        let message_id = Uuid::new_v4();
+        let sync = Message::ClipboardSync(ClipboardSyncMessage {
+            message_id,
+            sender_id: phone_id,
+            content_hash: ContentHash([0u8; 32]),
+            encrypted_content: device.session_key.encrypt(b"hi").unwrap(),
+            timestamp: 0,
+            key_epoch: 0,
+            channel: String::new(),
+            compressed: false,
+        });
+        write_framed_message(&mut stream, &sync.to_bytes().unwrap()).await.unwrap();
+        let Some(SyncEvent::MessageReceived { peer_id, reply: Some(reply), .. }) = events.recv().await else {
+            panic!("expected the clipboard message");
+        };
+        assert_eq!(peer_id, phone_id);
+        reply.send(Message::Ack { message_id }).unwrap();
+        let ack = Message::from_bytes(&read_framed_message(&mut stream).await.unwrap()).unwrap();
+        assert!(matches!(ack, Message::Ack { message_id: id } if id == message_id));
+
+        write_framed_message(&mut stream, &Message::Ping { timestamp: 9 }.to_bytes().unwrap()).await.unwrap();
+        let pong = Message::from_bytes(&read_framed_message(&mut stream).await.unwrap()).unwrap();
+        assert!(matches!(pong, Message::Pong { timestamp: 9 }));
+
+        drop(stream);
+        assert!(matches!(events.recv().await, Some(SyncEvent::PeerDisconnected { peer_id }) if peer_id == phone_id));
+
+        handle.abort();
+    }
 }
@@ -0,0 +1,370 @@
+//! Optional encryption of a whole connection
+//!
+//! Clipboard content is already sealed with the pairing's session key, but
+//! the frames around it aren't: device names, message types and sizes are
+//! visible to anyone on the network. A [`Transport`] that has run the
+//! handshake seals everything written to it, frame preambles included.
+//!
+//! The handshake is a `TransportHello` from each side carrying a fresh
+//! X25519 key. The side that accepted the connection also signs both
+//! ephemeral keys with its identity key, and that key goes into the key
+//! derivation. Each direction is then a series of records: a 4-byte
+//! big-endian length, then that many bytes of AES-256-GCM ciphertext of at
+//! most [`TRANSPORT_RECORD_SIZE`] plaintext bytes.
+//!
+//! An initiator that knows which device it is calling checks the signature
+//! against that device's key with [`Transport::initiate_to`], so a relay on
+//! the network can't stand in for it. The initiator doesn't prove who it
+//! is: the accepting side learns that from pairing and the session keys as
+//! before. A connection opened without an expected key, as for finding
+//! devices added by address, is only protected from passive observers.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::crypto::{EphemeralSecret, PublicKey, SigningKey, TransportKeys, VerifyingKey};
+use crate::protocol::constants::{AEAD_TAG_SIZE, TRANSPORT_RECORD_SIZE, TRANSPORT_SIGNATURE_CONTEXT};
+use crate::protocol::{Message, TransportProof};
+use crate::sync::framing::{read_handshake_message, write_framed_message};
+use crate::{Error, Result};
+
+/// A connection's byte stream, encrypted once a handshake has run over it
+///
+/// Until then reads and writes pass straight through, so the handshake
+/// itself and connections to peers not using encryption look as before.
+pub struct Transport<S> {
+    inner: S,
+    keys: Option<TransportKeys>,
+    /// Plaintext written but not yet sealed
+    pending: Vec<u8>,
+    /// Sealed records not yet written to `inner`
+    sealed: Vec<u8>,
+    sealed_written: usize,
+    /// Bytes read from `inner` that don't yet make up a whole record
+    received: Vec<u8>,
+    /// Plaintext opened but not yet read
+    opened: Vec<u8>,
+    opened_read: usize,
+}
+
+impl<S> Transport<S> {
+    /// Wrap a stream without encrypting it
+    pub fn plain(inner: S) -> Self {
+        Self {
+            inner,
+            keys: None,
+            pending: Vec::new(),
+            sealed: Vec::new(),
+            sealed_written: 0,
+            received: Vec::new(),
+            opened: Vec::new(),
+            opened_read: 0,
+        }
+    }
+
+    /// Whether a handshake has run and traffic is encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    /// The wrapped stream
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl Transport<TcpStream> {
+    /// Address of the other end of the connection
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.inner.peer_addr().map_err(|e| Error::Network(e.to_string()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Transport<S> {
+    /// Run the handshake as the side that opened the connection, returning
+    /// the identity key the other side proved it holds
+    ///
+    /// Any device can prove its own key, so this only hides traffic from
+    /// passive observers unless the caller checks the key returned. A peer
+    /// that doesn't support encryption closes the connection or answers
+    /// with something else, either of which fails the handshake.
+    pub async fn initiate(&mut self) -> Result<VerifyingKey> {
+        self.handshake(None).await
+    }
+
+    /// Run the handshake with the device holding `expected`, failing with
+    /// `Error::Crypto` if the other side proves any other identity
+    pub async fn initiate_to(&mut self, expected: &VerifyingKey) -> Result<()> {
+        self.handshake(Some(expected)).await.map(drop)
+    }
+
+    async fn handshake(&mut self, expected: Option<&VerifyingKey>) -> Result<VerifyingKey> {
+        let secret = EphemeralSecret::generate();
+        let ours = secret.public_key();
+        let hello = Message::TransportHello { ephemeral_pubkey: ours.clone(), proof: None };
+        write_framed_message(self, &hello.to_bytes()?).await?;
+        let (theirs, proof) = match Message::decode(&read_handshake_message(self).await?)? {
+            Message::TransportHello { ephemeral_pubkey, proof: Some(proof) } => (ephemeral_pubkey, proof),
+            Message::TransportHello { proof: None, .. } => {
+                return Err(Error::Crypto("transport peer didn't prove its identity".to_string()));
+            }
+            other => {
+                return Err(Error::InvalidMessage(format!("expected TransportHello, got {:?}", other)));
+            }
+        };
+        let identity = proof.identity_pubkey;
+        identity.verify(&transcript(&ours, &theirs), &proof.signature)
+            .map_err(|_| Error::Crypto("transport handshake signature doesn't verify".to_string()))?;
+        if let Some(expected) = expected.filter(|expected| expected.to_bytes() != identity.to_bytes()) {
+            return Err(Error::Crypto(format!(
+                "transport peer holds key {}, expected {}",
+                identity.fingerprint(), expected.fingerprint()
+            )));
+        }
+        self.keys = Some(TransportKeys::derive(&secret.diffie_hellman(&theirs), &ours, &theirs, &identity, true));
+        Ok(identity)
+    }
+
+    /// Run the handshake as the side that accepted the connection, given
+    /// the key from the `TransportHello` it opened with, proving that we
+    /// hold `identity`
+    pub async fn respond(&mut self, theirs: &PublicKey, identity: &SigningKey) -> Result<()> {
+        let secret = EphemeralSecret::generate();
+        let ours = secret.public_key();
+        let identity_pubkey = identity.verifying_key();
+        let proof = TransportProof {
+            identity_pubkey: identity_pubkey.clone(),
+            signature: identity.sign(&transcript(theirs, &ours)),
+        };
+        let hello = Message::TransportHello { ephemeral_pubkey: ours.clone(), proof: Some(proof) };
+        write_framed_message(self, &hello.to_bytes()?).await?;
+        self.keys = Some(TransportKeys::derive(&secret.diffie_hellman(theirs), theirs, &ours, &identity_pubkey, false));
+        Ok(())
+    }
+}
+
+/// What the accepting side signs: both ephemeral keys, the initiator's first
+fn transcript(initiator: &PublicKey, responder: &PublicKey) -> Vec<u8> {
+    [TRANSPORT_SIGNATURE_CONTEXT, &initiator.to_bytes(), &responder.to_bytes()].concat()
+}
+
+impl<S: AsyncWrite + Unpin> Transport<S> {
+    /// Seal what has been written since the last record
+    fn seal_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let keys = self.keys.as_mut().expect("only encrypted transports buffer writes");
+        let len = ((self.pending.len() + AEAD_TAG_SIZE) as u32).to_be_bytes();
+        let record = keys.seal(&self.pending, &len).map_err(io::Error::other)?;
+        self.sealed.extend_from_slice(&len);
+        self.sealed.extend_from_slice(&record);
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Write out sealed records
+    fn poll_write_sealed(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.sealed_written < self.sealed.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.sealed[self.sealed_written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.sealed_written += n;
+        }
+        self.sealed.clear();
+        self.sealed_written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Transport<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.keys.is_none() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        loop {
+            if this.opened_read < this.opened.len() {
+                let n = buf.remaining().min(this.opened.len() - this.opened_read);
+                buf.put_slice(&this.opened[this.opened_read..this.opened_read + n]);
+                this.opened_read += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(header) = this.received.first_chunk::<4>() {
+                let len = u32::from_be_bytes(*header) as usize;
+                if len > TRANSPORT_RECORD_SIZE + AEAD_TAG_SIZE {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("transport record too large: {} bytes", len),
+                    )));
+                }
+                if this.received.len() >= 4 + len {
+                    let keys = this.keys.as_mut().expect("checked above");
+                    this.opened = keys.open(&this.received[4..4 + len], header)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                    this.opened_read = 0;
+                    this.received.drain(..4 + len);
+                    continue;
+                }
+            }
+
+            let mut chunk = [0u8; 8 * 1024];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                // A clean close falls between records
+                return if this.received.is_empty() {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            this.received.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Transport<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.keys.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        if this.pending.len() >= TRANSPORT_RECORD_SIZE {
+            this.seal_pending()?;
+        }
+        ready!(this.poll_write_sealed(cx))?;
+        let n = buf.len().min(TRANSPORT_RECORD_SIZE - this.pending.len());
+        this.pending.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.keys.is_some() {
+            this.seal_pending()?;
+            ready!(this.poll_write_sealed(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::framing::read_framed_message;
+
+    /// Answer the handshake opened on `stream` as `identity`
+    async fn respond_as<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut Transport<S>, identity: &SigningKey) {
+        let Message::TransportHello { ephemeral_pubkey, .. } =
+            Message::decode(&read_framed_message(stream).await.unwrap()).unwrap()
+        else {
+            panic!("expected TransportHello");
+        };
+        stream.respond(&ephemeral_pubkey, identity).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_frames_roundtrip() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (mut client, mut server) = (Transport::plain(client), Transport::plain(server));
+        let identity = SigningKey::generate();
+        let expected = identity.verifying_key();
+
+        let accept = tokio::spawn(async move {
+            respond_as(&mut server, &identity).await;
+            let payload = read_framed_message(&mut server).await.unwrap();
+            write_framed_message(&mut server, &payload).await.unwrap();
+            server
+        });
+        client.initiate_to(&expected).await.unwrap();
+        assert!(client.is_encrypted());
+
+        // Larger than a record, so it spans several
+        let payload: Vec<u8> = (0..3 * TRANSPORT_RECORD_SIZE + 5).map(|i| i as u8).collect();
+        write_framed_message(&mut client, &payload).await.unwrap();
+        assert_eq!(read_framed_message(&mut client).await.unwrap(), payload);
+        assert!(accept.await.unwrap().is_encrypted());
+    }
+
+    #[tokio::test]
+    async fn test_tampered_record_is_rejected() {
+        let (client, relay) = tokio::io::duplex(64 * 1024);
+        let (relayed, server) = tokio::io::duplex(64 * 1024);
+        let mut client = Transport::plain(client);
+
+        let accept = tokio::spawn(async move {
+            let mut server = Transport::plain(server);
+            respond_as(&mut server, &SigningKey::generate()).await;
+            read_framed_message(&mut server).await
+        });
+
+        // Pass the handshake along untouched, then flip a bit in the first record
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut relay_read, mut relay_write) = tokio::io::split(relay);
+            let (mut relayed_read, mut relayed_write) = tokio::io::split(relayed);
+            tokio::spawn(async move { tokio::io::copy(&mut relayed_read, &mut relay_write).await });
+            let hello = read_framed_message(&mut relay_read).await.unwrap();
+            write_framed_message(&mut relayed_write, &hello).await.unwrap();
+            let mut record = vec![0u8; 64];
+            let n = relay_read.read(&mut record).await.unwrap();
+            record[n - 1] ^= 1;
+            relayed_write.write_all(&record[..n]).await.unwrap();
+        });
+
+        client.initiate().await.unwrap();
+        write_framed_message(&mut client, b"secret").await.unwrap();
+        assert!(accept.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay_cannot_stand_in_for_peer() {
+        let server_identity = SigningKey::generate();
+        let expected = server_identity.verifying_key();
+
+        // A relay answering with its own hello and identity
+        let (client, relay) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            respond_as(&mut Transport::plain(relay), &SigningKey::generate()).await;
+        });
+        let err = Transport::plain(client).initiate_to(&expected).await.unwrap_err();
+        assert!(matches!(err, Error::Crypto(_)), "{}", err);
+
+        // A relay handshaking with the real server, then passing its proof
+        // along with an ephemeral key of its own
+        let (client, relay) = tokio::io::duplex(64 * 1024);
+        let (relayed, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            respond_as(&mut Transport::plain(server), &server_identity).await;
+        });
+        tokio::spawn(async move {
+            let (mut relay, mut relayed) = (Transport::plain(relay), Transport::plain(relayed));
+            let _hello = read_framed_message(&mut relay).await.unwrap();
+            let ours = EphemeralSecret::generate().public_key();
+            let hello = Message::TransportHello { ephemeral_pubkey: ours.clone(), proof: None };
+            write_framed_message(&mut relayed, &hello.to_bytes().unwrap()).await.unwrap();
+            let Message::TransportHello { proof, .. } =
+                Message::decode(&read_framed_message(&mut relayed).await.unwrap()).unwrap()
+            else {
+                panic!("expected TransportHello");
+            };
+            let substituted = Message::TransportHello { ephemeral_pubkey: EphemeralSecret::generate().public_key(), proof };
+            write_framed_message(&mut relay, &substituted.to_bytes().unwrap()).await.unwrap();
+        });
+        let err = Transport::plain(client).initiate_to(&expected).await.unwrap_err();
+        assert!(matches!(err, Error::Crypto(ref m) if m.contains("signature")), "{}", err);
+    }
+}